@@ -0,0 +1,255 @@
+use crate::{
+    concurrency::IsolationLevel,
+    storage::{ScanDirection, Table},
+    Result, DB,
+};
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Maximum number of rows sampled from a table
+const ANALYZE_SAMPLE_ROWS: usize = 3000;
+/// Maximum number of most common values and histogram buckets kept for a column
+const STATS_TARGET: usize = 10;
+
+type DecodeFn<'a> = dyn Fn(&[u8]) -> Result<Option<Vec<u8>>> + 'a;
+type CompareFn<'a> = dyn Fn(&[u8], &[u8]) -> Result<Ordering> + 'a;
+
+/// A column to analyze
+///
+/// Like index keys, the tuple format is left to the frontend. `decode` extracts the value of the
+/// column from a tuple (`None` for null) and `compare` orders the values.
+pub struct AnalyzeColumn<'a> {
+    decode: Box<DecodeFn<'a>>,
+    compare: Box<CompareFn<'a>>,
+}
+
+impl<'a> AnalyzeColumn<'a> {
+    pub fn new<D, C>(decode: D, compare: C) -> Self
+    where
+        D: Fn(&[u8]) -> Result<Option<Vec<u8>>> + 'a,
+        C: Fn(&[u8], &[u8]) -> Result<Ordering> + 'a,
+    {
+        Self {
+            decode: Box::new(decode),
+            compare: Box::new(compare),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ColumnStats {
+    /// Fraction of rows where the column is null
+    pub null_frac: f64,
+    /// Estimated number of distinct non-null values
+    pub n_distinct: f64,
+    /// Most common values and their frequencies, most common first
+    pub most_common_values: Vec<(Vec<u8>, f64)>,
+    /// Bounds of equal-height buckets over the values not in `most_common_values`
+    pub histogram_bounds: Vec<Vec<u8>>,
+}
+
+/// Take a uniform sample of the visible tuples in the table. Return the sample and the total
+/// number of visible tuples.
+pub(crate) fn sample_rows(db: &DB, table: &dyn Table) -> Result<(Vec<Vec<u8>>, usize)> {
+    let mut txn = db.start_transaction(IsolationLevel::ReadCommitted)?;
+
+    let result = (|| {
+        let mut rows = Vec::new();
+        let mut total_rows = 0;
+        let mut random = 0x2545_f491_4f6c_dd1du64;
+        let mut iter = table.begin_scan(db, &mut txn)?;
+
+        while let Some(tuple) = iter.next(db, ScanDirection::Forward)? {
+            total_rows += 1;
+
+            if rows.len() < ANALYZE_SAMPLE_ROWS {
+                rows.push(tuple.get_data().to_vec());
+            } else {
+                // reservoir sampling
+                random ^= random << 13;
+                random ^= random >> 7;
+                random ^= random << 17;
+                let index = (random % total_rows as u64) as usize;
+
+                if index < ANALYZE_SAMPLE_ROWS {
+                    rows[index] = tuple.get_data().to_vec();
+                }
+            }
+        }
+
+        Ok((rows, total_rows))
+    })();
+
+    db.commit_transaction(txn)?;
+    result
+}
+
+/// Compute the statistics of a column from the sample rows.
+pub(crate) fn compute_column_stats(
+    rows: &[Vec<u8>],
+    total_rows: usize,
+    column: &AnalyzeColumn,
+) -> Result<ColumnStats> {
+    if rows.is_empty() {
+        return Ok(Default::default());
+    }
+
+    let mut values = Vec::new();
+    for row in rows {
+        if let Some(value) = (column.decode)(row)? {
+            values.push(value);
+        }
+    }
+
+    let mut error = None;
+    values.sort_by(|a, b| match (column.compare)(a, b) {
+        Ok(ordering) => ordering,
+        Err(e) => {
+            error.get_or_insert(e);
+            Ordering::Equal
+        }
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    // group the sorted values into (first index, count)
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        match groups.last_mut() {
+            Some((first, count))
+                if (column.compare)(&values[*first], value)? == Ordering::Equal =>
+            {
+                *count += 1
+            }
+            _ => groups.push((i, 1)),
+        }
+    }
+
+    let num_rows = rows.len() as f64;
+    let null_frac = (rows.len() - values.len()) as f64 / num_rows;
+    let sample_values = values.len() as f64;
+    let total_values = total_rows as f64 * (1.0 - null_frac);
+    let distinct = groups.len() as f64;
+    let singletons = groups.iter().filter(|(_, count)| *count == 1).count() as f64;
+
+    let n_distinct = if values.is_empty() {
+        0.0
+    } else if rows.len() >= total_rows {
+        // the whole table is sampled
+        distinct
+    } else if singletons == distinct {
+        // every sampled value is unique, assume the column is unique
+        total_values
+    } else {
+        // the Haas and Stokes estimator
+        let estimate = sample_values * distinct
+            / (sample_values - singletons + singletons * sample_values / total_values);
+        estimate.max(distinct).min(total_values)
+    };
+
+    // values that are noticeably more common than the average are the most common values
+    let average_count = sample_values / distinct;
+    let mut common_groups = groups
+        .iter()
+        .filter(|(_, count)| *count > 1 && *count as f64 > 1.25 * average_count)
+        .copied()
+        .collect::<Vec<_>>();
+    common_groups.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    common_groups.truncate(STATS_TARGET);
+
+    let most_common_values = common_groups
+        .iter()
+        .map(|(first, count)| (values[*first].clone(), *count as f64 / num_rows))
+        .collect::<Vec<_>>();
+
+    let rest = groups
+        .iter()
+        .filter(|group| !common_groups.contains(group))
+        .flat_map(|(first, count)| &values[*first..*first + *count])
+        .collect::<Vec<_>>();
+
+    let mut histogram_bounds = Vec::new();
+    if rest.len() >= 2 {
+        let num_bounds = std::cmp::min(STATS_TARGET + 1, rest.len());
+
+        for i in 0..num_bounds {
+            histogram_bounds.push(rest[i * (rest.len() - 1) / (num_bounds - 1)].clone());
+        }
+    }
+
+    Ok(ColumnStats {
+        null_frac,
+        n_distinct,
+        most_common_values,
+        histogram_bounds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AnalyzeColumn;
+    use crate::{concurrency::IsolationLevel, test_util::get_temp_db, DBConfig, DB};
+
+    use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+    #[test]
+    fn can_analyze_skewed_column() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+
+        for i in 0..5000u32 {
+            // half of the rows are 7, a tenth are null and the others take 100 values
+            let (value, is_null) = match i % 10 {
+                0..=4 => (7, 0),
+                5 => (0, 1),
+                _ => (1000 + (i / 10) % 100, 0),
+            };
+
+            let mut data = Vec::new();
+            data.write_u32::<LittleEndian>(value).unwrap();
+            data.push(is_null);
+            heap.insert_tuple(&db, &txn, &data).unwrap();
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        let column = AnalyzeColumn::new(
+            |tuple: &[u8]| {
+                Ok(if tuple[4] == 1 {
+                    None
+                } else {
+                    Some(tuple[..4].to_vec())
+                })
+            },
+            |a: &[u8], b: &[u8]| Ok(LittleEndian::read_u32(a).cmp(&LittleEndian::read_u32(b))),
+        );
+        db.analyze(0, 0, &[column]).unwrap();
+
+        let stats = db.get_column_stats(0, 0, 0).unwrap();
+        assert!((stats.null_frac - 0.1).abs() < 0.05);
+        assert!(stats.n_distinct >= 80.0 && stats.n_distinct <= 130.0);
+
+        let (value, frac) = &stats.most_common_values[0];
+        assert_eq!(LittleEndian::read_u32(value), 7);
+        assert!((frac - 0.5).abs() < 0.05);
+
+        assert!(stats.histogram_bounds.len() >= 2);
+        assert!(stats
+            .histogram_bounds
+            .iter()
+            .all(|bound| LittleEndian::read_u32(bound) >= 1000));
+
+        assert!(db.get_column_stats(0, 0, 1).is_none());
+
+        // the statistics are kept in the catalog
+        drop(db);
+        let db = DB::open(&DBConfig::new().root_path(db_dir.path())).unwrap();
+        assert_eq!(db.get_column_stats(0, 0, 0), Some(stats));
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+}
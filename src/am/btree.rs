@@ -3,59 +3,24 @@ mod btree_page;
 
 use crate::{
     am::{
-        index::{IndexScanIterator, IndexScanPredicate},
-        Index,
+        index::{IndexScanIterator, IndexScanPredicate, ScanMark},
+        Index, IndexStats,
     },
-    concurrency::{Snapshot, Transaction, XID},
+    concurrency::{InterruptCheck, LockMode, Snapshot, Transaction, XID},
     storage::{
         consts::PAGE_SIZE, DiskPageReader, DiskPageWriter, ForkType, ItemPageReader,
-        ItemPageWriter, ItemPointer, PageReadGuard, PageWriteGuard, PinnedPagePtr, RelFileRef,
-        RelationWithStorage, ScanDirection, StorageHandle, Table, TuplePtr,
+        ItemPageWriter, ItemPointer, OwningPageReadLock, OwningPageWriteLock, PinnedPagePtr,
+        RelFileRef, RelationWithStorage, ScanDirection, StorageHandle, Table, TuplePtr,
     },
     Error, Relation, RelationEntry, RelationKind, Result, DB, OID,
 };
 
 pub(crate) use self::btree_log::BTreeLogRecord;
 
-use self::btree_page::{views::*, BTreePageFlags, BTreePageType};
+use self::btree_page::{views::*, BTreePageFlags, BTreePageType, BTREE_META_MAGIC};
 
-use ouroboros::self_referencing;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, cmp::Ordering, sync::Mutex};
-
-/// Own a pinned page and a read lock on that page
-#[self_referencing]
-struct OwningPageReadLock {
-    page_ptr: PinnedPagePtr,
-    #[borrows(page_ptr)]
-    #[covariant]
-    page_guard: PageReadGuard<'this>,
-}
-
-/// Own a pinned page and a write lock on that page
-#[self_referencing]
-struct OwningPageWriteLock {
-    page_ptr: PinnedPagePtr,
-    #[borrows(page_ptr)]
-    #[covariant]
-    page_guard: PageWriteGuard<'this>,
-}
-
-fn owning_page_read_lock(page_ptr: PinnedPagePtr) -> OwningPageReadLock {
-    OwningPageReadLockBuilder {
-        page_ptr,
-        page_guard_builder: |page_ptr| page_ptr.read().unwrap(),
-    }
-    .build()
-}
-
-fn owning_page_write_lock(page_ptr: PinnedPagePtr) -> OwningPageWriteLock {
-    OwningPageWriteLockBuilder {
-        page_ptr,
-        page_guard_builder: |page_ptr| page_ptr.write().unwrap(),
-    }
-    .build()
-}
+use std::{borrow::Cow, cmp::Ordering, collections::HashMap, sync::Mutex};
 
 #[derive(Serialize, Deserialize)]
 struct IndexTuple<'a> {
@@ -79,12 +44,129 @@ impl<'a> IndexTuple<'a> {
             item_pointer: self.item_pointer,
         }
     }
+
+    /// Deserialize an entry stored without the key prefix shared by the entries on its page.
+    fn decode(buf: &'a [u8], prefix: &[u8]) -> Result<Self> {
+        let mut itup = bincode::deserialize::<IndexTuple>(buf)?;
+
+        if !prefix.is_empty() {
+            itup.key = Cow::from([prefix, &itup.key].concat());
+        }
+        Ok(itup)
+    }
+
+    /// Serialize the entry without the key prefix shared by the entries on its page.
+    fn encode(&self, prefix: &[u8]) -> Result<Vec<u8>> {
+        let key = self.key.strip_prefix(prefix).ok_or_else(|| {
+            Error::DataCorrupted("index key is out of the bounds of its page".to_owned())
+        })?;
+
+        Ok(bincode::serialize(&IndexTuple {
+            key: Cow::from(key),
+            item_pointer: self.item_pointer,
+        })?)
+    }
+}
+
+/// Put the high key (if any), the low key (if any) and then the entries (with full keys) onto a
+/// page formed by a split. The next page number and the flags of the page must be set first.
+fn fill_split_page<E>(
+    page_view: &mut BTreeDataPageViewMut,
+    high_key: Option<&[u8]>,
+    low_key: Option<&[u8]>,
+    entries: &[E],
+) -> Result<()>
+where
+    E: AsRef<[u8]>,
+{
+    let mut offset = page_view.high_key_offset();
+    if let Some(high_key) = high_key {
+        page_view.put_item(high_key, Some(offset), false)?;
+        offset += 1;
+    }
+
+    match low_key {
+        Some(low_key) => {
+            page_view.set_flags(BTreePageFlags::HAS_LOW_KEY);
+            page_view.put_item(low_key, Some(offset), false)?;
+            offset += 1;
+        }
+        None => page_view.clear_flags(BTreePageFlags::HAS_LOW_KEY),
+    }
+
+    let prefix = get_key_prefix(page_view)?;
+    for entry in entries {
+        let entry = entry.as_ref();
+        if prefix.is_empty() {
+            page_view.put_item(entry, Some(offset), false)?;
+        } else {
+            let entry = bincode::deserialize::<IndexTuple>(entry)?.encode(&prefix)?;
+            page_view.put_item(&entry, Some(offset), false)?;
+        }
+        offset += 1;
+    }
+
+    Ok(())
+}
+
+/// Get the serialized tuple as it is stored on a leaf page, i.e. without the key prefix of the
+/// page. The insert records carry the full tuple, which is encoded again when they are redone.
+fn encode_page_entry<'a, P>(page_view: &P, tuple: &'a [u8]) -> Result<Cow<'a, [u8]>>
+where
+    P: BTreeDataPageReader,
+{
+    let prefix = get_key_prefix(page_view)?;
+
+    if prefix.is_empty() {
+        Ok(Cow::from(tuple))
+    } else {
+        Ok(Cow::from(
+            bincode::deserialize::<IndexTuple>(tuple)?.encode(&prefix)?,
+        ))
+    }
+}
+
+/// Get the prefix that is left out of the keys of the entries on a leaf page. The keys on a page
+/// with a low key sort between the low key and the high key, so they all start with the common
+/// prefix of the two if the keys are compared lexicographically.
+fn get_key_prefix<P>(page_view: &P) -> Result<Vec<u8>>
+where
+    P: BTreeDataPageReader,
+{
+    if !page_view.has_low_key() || page_view.is_rightmost() {
+        return Ok(Vec::new());
+    }
+
+    let low_key =
+        bincode::deserialize::<IndexTuple>(page_view.get_item(page_view.low_key_offset()))?;
+    let high_key =
+        bincode::deserialize::<IndexTuple>(page_view.get_item(page_view.high_key_offset()))?;
+    let len = low_key
+        .key
+        .iter()
+        .zip(high_key.key.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    Ok(high_key.key[..len].to_vec())
 }
 
 type TreePath = Vec<ItemPointer>;
 
+/// Result of checking a key against the existing entries of a unique index
+enum UniqueCheck {
+    Unique,
+    /// A live tuple has the key
+    Duplicate,
+    /// A tuple with the key is inserted or deleted by the in-progress transaction
+    InProgress(XID),
+}
+
 const BTREE_META_PAGE_NUM: usize = 0;
 
+/// Number of leaf pages to sample when estimating the distinct key count
+const BTREE_STATS_SAMPLE_PAGES: usize = 8;
+
 pub struct BTree<KCmp>
 where
     KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
@@ -92,6 +174,12 @@ where
     rel_entry: RelationEntry,
     shandle: Mutex<Option<StorageHandle>>,
     key_comparator: KCmp,
+    // truncate the separator keys formed by leaf splits to the shortest prefix of the first key
+    // on the right page that is still greater than the last key on the left page
+    suffix_truncation: bool,
+    // keep the low keys of the leaf pages formed by splits and store the entries on them without
+    // the prefix shared by the low key and the high key
+    prefix_compression: bool,
 }
 
 impl<KCmp> BTree<KCmp>
@@ -105,21 +193,41 @@ where
             rel_entry,
             shandle: Mutex::new(None),
             key_comparator,
+            suffix_truncation: false,
+            prefix_compression: false,
         }
     }
 
+    /// Truncate the separator keys formed by leaf splits. This is only correct if a prefix of
+    /// a key compares as if it is padded with the smallest possible suffix, e.g. for keys
+    /// compared lexicographically. Prefixes the comparator rejects are not used.
+    pub fn suffix_truncation(mut self, enabled: bool) -> Self {
+        self.suffix_truncation = enabled;
+        self
+    }
+
+    /// Store the entries on the leaf pages formed by splits without the key prefix they share.
+    /// This is only correct if the keys are compared lexicographically, so that all keys between
+    /// two keys start with the common prefix of them.
+    pub fn prefix_compression(mut self, enabled: bool) -> Self {
+        self.prefix_compression = enabled;
+        self
+    }
+
     // Basically, we need to implement everything twice, once for read and once or write...
 
     /// Get a page by page number for read.
     fn get_tree_page_read(&self, db: &DB, page_num: Option<usize>) -> Result<OwningPageReadLock> {
-        self.with_storage(db.get_storage_manager(), |storage| match page_num {
+        // the page is locked after the storage handle is released, as the holder of the page
+        // lock may need the handle to fetch other pages
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| match page_num {
             Some(page_num) => {
-                let page_ptr =
-                    db.get_buffer_manager()
-                        .fetch_page(db, storage, ForkType::Main, page_num)?;
-                Ok(owning_page_read_lock(page_ptr))
+                db.get_buffer_manager()
+                    .fetch_page(db, storage, ForkType::Main, page_num)
             }
             None => {
+                let extension_guard =
+                    db.lock_relation_extension(storage.file_ref(), ForkType::Main);
                 let page_ptr = db
                     .get_buffer_manager()
                     .new_page(db, storage, ForkType::Main)?;
@@ -128,22 +236,27 @@ where
                     page_view.init_page();
                     Ok(())
                 })?;
+                drop(extension_guard);
 
-                Ok(owning_page_read_lock(page_ptr))
+                Ok(page_ptr)
             }
-        })
+        })?;
+
+        page_ptr.read_owned()
     }
 
     /// Get a page by page number for write.
     fn get_tree_page_write(&self, db: &DB, page_num: Option<usize>) -> Result<OwningPageWriteLock> {
-        self.with_storage(db.get_storage_manager(), |storage| match page_num {
+        // the page is locked after the storage handle is released, as the holder of the page
+        // lock may need the handle to fetch other pages
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| match page_num {
             Some(page_num) => {
-                let page_ptr =
-                    db.get_buffer_manager()
-                        .fetch_page(db, storage, ForkType::Main, page_num)?;
-                Ok(owning_page_write_lock(page_ptr))
+                db.get_buffer_manager()
+                    .fetch_page(db, storage, ForkType::Main, page_num)
             }
             None => {
+                let extension_guard =
+                    db.lock_relation_extension(storage.file_ref(), ForkType::Main);
                 let page_ptr = db
                     .get_buffer_manager()
                     .new_page(db, storage, ForkType::Main)?;
@@ -152,10 +265,13 @@ where
                     page_view.init_page();
                     Ok(())
                 })?;
+                drop(extension_guard);
 
-                Ok(owning_page_write_lock(page_ptr))
+                Ok(page_ptr)
             }
-        })
+        })?;
+
+        page_ptr.write_owned()
     }
 
     /// Get the root page for read.
@@ -206,6 +322,14 @@ where
 
                     // update metadata
                     meta_page_view.set_root(root_page_num);
+                    bufmgr.mark_resident(
+                        RelFileRef {
+                            db: self.rel_db(),
+                            rel_id: self.rel_id(),
+                        },
+                        ForkType::Main,
+                        root_page_num,
+                    );
 
                     // WAL stuffs
                     let new_root_log = BTreeLogRecord::create_btree_new_root_log(
@@ -255,7 +379,7 @@ where
 
         let mut meta_page_lock = self.get_tree_page_write(db, Some(BTREE_META_PAGE_NUM))?;
 
-        meta_page_lock.with_page_guard_mut(|meta_page_guard| {
+        meta_page_lock.with_page_guard_mut::<Result<()>>(|meta_page_guard| {
             let mut meta_page_view = BTreeMetaPageViewMut::new(meta_page_guard.buffer_mut());
 
             // create tuples for downlinks
@@ -266,24 +390,17 @@ where
                 item_pointer: ItemPointer::default(),
             };
             left_tuple.set_downlink(left_page_num);
-            let left_tuple_buf = bincode::serialize(&left_tuple).unwrap();
+            let left_tuple_buf = bincode::serialize(&left_tuple)?;
 
             let left_page_view = BTreeDataPageView::new(lchild.borrow_page_guard().buffer());
             let high_key_buf = left_page_view.get_item(left_page_view.high_key_offset());
-            let high_key = match bincode::deserialize::<IndexTuple>(high_key_buf) {
-                Ok(itup) => itup.key,
-                _ => {
-                    return Err(Error::DataCorrupted(
-                        "cannot deserialize index tuple".to_owned(),
-                    ));
-                }
-            };
+            let high_key = bincode::deserialize::<IndexTuple>(high_key_buf)?.key;
             let mut right_tuple = IndexTuple {
                 key: high_key,
                 item_pointer: ItemPointer::default(),
             };
             right_tuple.set_downlink(right_page_num);
-            let right_tuple_buf = bincode::serialize(&right_tuple).unwrap();
+            let right_tuple_buf = bincode::serialize(&right_tuple)?;
 
             let level = left_page_view.get_level() + 1;
 
@@ -299,6 +416,14 @@ where
                 // update metadata
                 meta_page_view.set_root(root_page_num);
 
+                let bufmgr = db.get_buffer_manager();
+                let file_ref = RelFileRef {
+                    db: self.rel_db(),
+                    rel_id: self.rel_id(),
+                };
+                bufmgr.unmark_resident(file_ref, ForkType::Main, left_page_num);
+                bufmgr.mark_resident(file_ref, ForkType::Main, root_page_num);
+
                 // insert the page pointers into the new root page
                 let left_offset = root_page_view.high_key_offset();
                 root_page_view.put_item(&left_tuple_buf, Some(left_offset), false)?;
@@ -337,8 +462,15 @@ where
         Ok(root_page_lock)
     }
 
-    /// Search for the first leaf page containing the key and return the page with read lock.
-    fn search_read(&self, db: &DB, key: &[u8]) -> Result<(OwningPageReadLock, TreePath)> {
+    /// Search for the first leaf page containing the key and return the page with read lock. If
+    /// `next_key` is true, search for the last leaf page containing the key instead, which is
+    /// where the first greater key is found unless it starts the next page.
+    fn search_read(
+        &self,
+        db: &DB,
+        key: &[u8],
+        next_key: bool,
+    ) -> Result<(OwningPageReadLock, TreePath)> {
         let mut page_lock = self.get_root_page_read(db)?;
         let mut path = Vec::new();
 
@@ -351,16 +483,17 @@ where
             }
 
             let child_offset =
-                self.binary_search_page(&page_view, key, ItemPointer::default(), false)?;
+                match self.binary_search_page(&page_view, key, ItemPointer::default(), next_key) {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        // the comparator may fail on the keys
+                        db.get_buffer_manager()
+                            .release_page(page_lock.into_heads().page_ptr)?;
+                        return Err(e);
+                    }
+                };
             let child_tuple_buf = page_view.get_item(child_offset);
-            let child_tuple = match bincode::deserialize::<IndexTuple>(child_tuple_buf) {
-                Ok(itup) => itup,
-                _ => {
-                    return Err(Error::DataCorrupted(
-                        "cannot deserialize index tuple".to_owned(),
-                    ))
-                }
-            };
+            let child_tuple = bincode::deserialize::<IndexTuple>(child_tuple_buf)?;
             let child_page_num = child_tuple.get_downlink();
             let child_page_lock = self.get_tree_page_read(db, Some(child_page_num))?;
 
@@ -396,14 +529,7 @@ where
             };
 
             let child_tuple_buf = page_view.get_item(child_offset);
-            let child_tuple = match bincode::deserialize::<IndexTuple>(child_tuple_buf) {
-                Ok(itup) => itup,
-                _ => {
-                    return Err(Error::DataCorrupted(
-                        "cannot deserialize index tuple".to_owned(),
-                    ))
-                }
-            };
+            let child_tuple = bincode::deserialize::<IndexTuple>(child_tuple_buf)?;
             let child_page_num = child_tuple.get_downlink();
             let child_page_lock = self.get_tree_page_read(db, Some(child_page_num))?;
 
@@ -433,16 +559,17 @@ where
             }
 
             let child_offset =
-                self.binary_search_page(&page_view, key, ItemPointer::default(), false)?;
+                match self.binary_search_page(&page_view, key, ItemPointer::default(), false) {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        // the comparator may fail on the keys
+                        db.get_buffer_manager()
+                            .release_page(page_lock.into_heads().page_ptr)?;
+                        return Err(e);
+                    }
+                };
             let child_tuple_buf = page_view.get_item(child_offset);
-            let child_tuple = match bincode::deserialize::<IndexTuple>(child_tuple_buf) {
-                Ok(itup) => itup,
-                _ => {
-                    return Err(Error::DataCorrupted(
-                        "cannot deserialize index tuple".to_owned(),
-                    ));
-                }
-            };
+            let child_tuple = bincode::deserialize::<IndexTuple>(child_tuple_buf)?;
             let child_page_num = child_tuple.get_downlink();
             let child_page_lock = self.get_tree_page_write(db, Some(child_page_num))?;
 
@@ -458,13 +585,15 @@ where
         Ok((page_lock, path))
     }
 
-    /// Compare the given key with a key on the page.
+    /// Compare the given key with a key on the page. Equal keys are ordered by the item pointers
+    /// if `item_ptr` is given.
     fn compare_key<P>(
         &self,
         page_view: &P,
         key: &[u8],
-        item_ptr: ItemPointer,
+        item_ptr: Option<ItemPointer>,
         offset: usize,
+        prefix: &[u8],
     ) -> Result<Ordering>
     where
         P: ItemPageReader + BTreePageReader,
@@ -476,23 +605,17 @@ where
             return Ok(Ordering::Greater);
         }
 
-        let itup_buf = page_view.get_item(offset);
-        let itup = match bincode::deserialize::<IndexTuple>(itup_buf) {
-            Ok(itup) => itup,
-            _ => {
-                return Err(Error::DataCorrupted(
-                    "cannot deserialize index tuple".to_owned(),
-                ))
-            }
-        };
+        let itup = IndexTuple::decode(page_view.get_item(offset), prefix)?;
 
-        match (self.key_comparator)(key, &itup.key)? {
-            Ordering::Equal => Ok(item_ptr.cmp(&itup.item_pointer)),
-            ord => Ok(ord),
+        match ((self.key_comparator)(key, &itup.key)?, item_ptr) {
+            (Ordering::Equal, Some(item_ptr)) => Ok(item_ptr.cmp(&itup.item_pointer)),
+            (ord, _) => Ok(ord),
         }
     }
 
-    /// Do a binary search in the page to find the lower bound to insert the key.
+    /// Do a binary search in the page to find the lower bound to insert the key. If `next_key` is
+    /// true, the item pointer is ignored and the search finds the upper bound past all entries
+    /// with an equal key instead.
     fn binary_search_page<P>(
         &self,
         page_view: &P,
@@ -506,6 +629,7 @@ where
         let mut low = page_view.first_key_offset();
         let mut high = page_view.num_line_pointers();
         high += 1;
+        let prefix = get_key_prefix(page_view)?;
 
         let cond = if next_key {
             Ordering::Equal
@@ -520,7 +644,9 @@ where
             while low < high {
                 let mid = low + (high - low) / 2;
 
-                if self.compare_key(page_view, key, item_ptr, mid)? >= cond {
+                let item_ptr = if next_key { None } else { Some(item_ptr) };
+
+                if self.compare_key(page_view, key, item_ptr, mid, &prefix)? >= cond {
                     // key > mid
                     low = mid + 1;
                 } else {
@@ -536,27 +662,166 @@ where
             }
         }
     }
-    /// Get the best offset to split the page.
-    fn get_split_location<P>(&self, page_view: &P) -> Result<usize>
-    where
-        P: BTreeDataPageReader,
-    {
-        Ok(page_view.num_line_pointers() / 2 + 1)
+    /// Get the number of entries that stay on the left page when a page is split.
+    fn get_split_location(&self, num_entries: usize) -> usize {
+        num_entries / 2
     }
 
     /// Find the page and offset to insert an index tuple.
     fn get_insert_location(
         &self,
-        _db: &DB,
+        db: &DB,
         key: &[u8],
         item_ptr: ItemPointer,
         start_page: OwningPageWriteLock,
     ) -> Result<(OwningPageWriteLock, usize)> {
-        let page_lock = start_page;
+        let bufmgr = db.get_buffer_manager();
+        let mut page_lock = start_page;
+
+        // the search stops at the first page that may contain the key, but the duplicates may
+        // continue on the right siblings. move right past the entries with smaller item pointers
+        // so that equal keys stay in the order of their item pointers
+        loop {
+            let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+            if page_view.is_rightmost() {
+                break;
+            }
+
+            let high_key_offset = page_view.high_key_offset();
+            match self.compare_key(&page_view, key, Some(item_ptr), high_key_offset, &[]) {
+                Ok(Ordering::Greater) => {}
+                Ok(_) => break,
+                Err(e) => {
+                    bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                    return Err(e);
+                }
+            }
+
+            let next_page_num = page_view.get_next();
+            let next_page_lock = match self.get_tree_page_write(db, Some(next_page_num)) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                    return Err(e);
+                }
+            };
+
+            bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+            page_lock = next_page_lock;
+        }
 
         let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
-        let offset = self.binary_search_page(&page_view, key, item_ptr, false)?;
-        Ok((page_lock, offset))
+        match self.binary_search_page(&page_view, key, item_ptr, false) {
+            Ok(offset) => Ok((page_lock, offset)),
+            Err(e) => {
+                bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Collect the item pointers of the entries with keys equal to `key` on a leaf page from
+    /// `offset`. The duplicates may continue on the right sibling, whose page number is returned
+    /// if the last entry matches.
+    fn collect_equal_keys(
+        &self,
+        page_view: &BTreeDataPageView,
+        key: &[u8],
+        offset: usize,
+    ) -> Result<(Vec<ItemPointer>, Option<usize>)> {
+        let mut item_pointers = Vec::new();
+        let prefix = get_key_prefix(page_view)?;
+
+        for off in offset..=page_view.num_line_pointers() {
+            let itup = IndexTuple::decode(page_view.get_item(off), &prefix)?;
+
+            if (self.key_comparator)(key, &itup.key)? != Ordering::Equal {
+                return Ok((item_pointers, None));
+            }
+
+            item_pointers.push(itup.item_pointer);
+        }
+
+        let next_page = if page_view.is_rightmost() {
+            None
+        } else {
+            Some(page_view.get_next())
+        };
+        Ok((item_pointers, next_page))
+    }
+
+    /// Check the tuples of the entries with keys equal to `key` with a dirty snapshot. `page_lock`
+    /// is the first leaf page that may contain the key.
+    fn check_unique(
+        &self,
+        db: &DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        table: &dyn Table,
+        key: &[u8],
+        page_lock: &OwningPageWriteLock,
+    ) -> Result<UniqueCheck> {
+        let bufmgr = db.get_buffer_manager();
+        let (mut item_pointers, mut next_page) = {
+            let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+            let offset = self.binary_search_page(&page_view, key, ItemPointer::default(), false)?;
+            self.collect_equal_keys(&page_view, key, offset)?
+        };
+
+        while let Some(page_num) = next_page {
+            let next_lock = self.get_tree_page_read(db, Some(page_num))?;
+            let result = {
+                let page_view = BTreeDataPageView::new(next_lock.borrow_page_guard().buffer());
+                self.collect_equal_keys(&page_view, key, page_view.first_key_offset())
+            };
+            bufmgr.release_page(next_lock.into_heads().page_ptr)?;
+
+            let (more, next) = result?;
+            item_pointers.extend(more);
+            next_page = next;
+        }
+
+        for item_pointer in item_pointers {
+            match table.fetch_tuple_dirty(db, xid, snapshot, item_pointer)? {
+                Some((_, Some(xid))) => return Ok(UniqueCheck::InProgress(xid)),
+                Some((_, None)) => return Ok(UniqueCheck::Duplicate),
+                None => {}
+            }
+        }
+
+        Ok(UniqueCheck::Unique)
+    }
+
+    /// Get the high key of the left page of a leaf split. Instead of the first tuple on the right
+    /// page, the key is truncated to the shortest prefix that is greater than the last key on
+    /// the left page if suffix truncation is enabled. The truncated high key has the smallest
+    /// item pointer so that all entries with the truncated key go to the right page.
+    fn get_split_high_key<'a>(
+        &self,
+        last_left: &[u8],
+        first_right: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>> {
+        if !self.suffix_truncation {
+            return Ok(Cow::from(first_right));
+        }
+
+        let last_left = bincode::deserialize::<IndexTuple>(last_left)?;
+        let right = bincode::deserialize::<IndexTuple>(first_right)?;
+
+        for len in 0..right.key.len() {
+            let prefix = &right.key[..len];
+
+            // the comparator may reject a prefix, e.g. one that ends inside a UTF-8 sequence
+            if let Ok(Ordering::Less) = (self.key_comparator)(&last_left.key, prefix) {
+                let high_key = IndexTuple {
+                    key: Cow::from(prefix),
+                    item_pointer: ItemPointer::default(),
+                };
+                return Ok(Cow::from(bincode::serialize(&high_key)?));
+            }
+        }
+
+        Ok(Cow::from(first_right))
     }
 
     /// Split the target page into the left page and the right page, both write-locked and pinned.
@@ -570,8 +835,48 @@ where
         let mut page_lock = page;
         let (_, _, page_num) = page_lock.borrow_page_guard().get_fork_and_num();
         let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+        let is_leaf = page_view.page_type() == BTreePageType::Leaf;
+
+        // collect the entries with their full keys, including the new tuple
+        let prefix = get_key_prefix(&page_view)?;
+        let mut entries = Vec::new();
+        for i in page_view.first_key_offset()..=page_view.num_line_pointers() + 1 {
+            if i == offset {
+                entries.push(Cow::from(tuple));
+            }
+
+            if i <= page_view.num_line_pointers() {
+                let item = page_view.get_item(i);
+                entries.push(if prefix.is_empty() {
+                    Cow::from(item)
+                } else {
+                    Cow::from(bincode::serialize(&IndexTuple::decode(item, &prefix)?)?)
+                });
+            }
+        }
+
+        let right_entries = entries.split_off(self.get_split_location(entries.len()));
+        let left_entries = entries;
+
+        // the high key for the left page is the first key in the right page
+        let high_key = if is_leaf {
+            self.get_split_high_key(&left_entries[left_entries.len() - 1], &right_entries[0])?
+        } else {
+            Cow::from(&right_entries[0][..])
+        };
 
-        let first_right = self.get_split_location(&page_view)?;
+        // the left page keeps the low key of the page and the right page gets the high key of
+        // the left page as its low key
+        let left_low_key = if page_view.has_low_key() {
+            Some(page_view.get_item(page_view.low_key_offset()))
+        } else {
+            None
+        };
+        let right_low_key = if is_leaf && self.prefix_compression {
+            Some(&high_key[..])
+        } else {
+            None
+        };
 
         // allocate and initialize temp buffer for the left page
         let mut left_page_buffer = *page_lock.borrow_page_guard().buffer();
@@ -583,23 +888,18 @@ where
 
         left_page_view.set_lsn(page_view.get_lsn());
 
-        // the high key for the left page is the first key in the right page
-        let high_key = if first_right == offset {
-            tuple
-        } else {
-            page_view.get_item(first_right)
-        };
-
-        // add the high key to the left page
-        let mut left_offset = left_page_view.high_key_offset();
-        left_page_view.put_item(high_key, Some(left_offset), false)?;
-        left_offset += 1;
-
         // allocate and initialize the right page
         let mut right_page_lock = self.get_tree_page_write(db, None)?;
         let (_, _, right_page_num) = right_page_lock.borrow_page_guard().get_fork_and_num();
         left_page_view.set_next(right_page_num);
 
+        fill_split_page(
+            &mut left_page_view,
+            Some(&high_key),
+            left_low_key,
+            &left_entries,
+        )?;
+
         right_page_lock.with_page_guard_mut::<Result<()>>(|page_guard| {
             let mut right_page_view = BTreeDataPageViewMut::new(page_guard.buffer_mut());
             right_page_view.set_flags(page_view.get_flags());
@@ -607,41 +907,19 @@ where
             right_page_view.set_prev(page_num);
             right_page_view.set_next(page_view.get_next());
 
-            // add the high key (if any) to the right page
-            let mut right_offset = page_view.high_key_offset();
-            if !page_view.is_rightmost() {
-                let high_key = page_view.get_item(page_view.high_key_offset());
-                right_page_view.put_item(high_key, Some(right_offset), false)?;
-                right_offset += 1;
-            }
-
-            // copy keys into the two pages
-            for i in page_view.first_key_offset()..=page_view.num_line_pointers() {
-                let key = page_view.get_item(i);
-
-                if i == offset {
-                    if offset < first_right {
-                        left_page_view.put_item(tuple, Some(left_offset), false)?;
-                        left_offset += 1;
-                    } else {
-                        right_page_view.put_item(tuple, Some(right_offset), false)?;
-                        right_offset += 1;
-                    }
-                }
-
-                if i < first_right {
-                    left_page_view.put_item(key, Some(left_offset), false)?;
-                    left_offset += 1;
-                } else {
-                    right_page_view.put_item(key, Some(right_offset), false)?;
-                    right_offset += 1;
-                }
-            }
+            // the right page takes over the high key (if any) of the page
+            let right_high_key = if page_view.is_rightmost() {
+                None
+            } else {
+                Some(page_view.get_item(page_view.high_key_offset()))
+            };
 
-            // add the new tuple if it is at the end
-            if offset > page_view.num_line_pointers() {
-                right_page_view.put_item(tuple, Some(right_offset), false)?;
-            }
+            fill_split_page(
+                &mut right_page_view,
+                right_high_key,
+                right_low_key,
+                &right_entries,
+            )?;
 
             // fetch the right sibling (if any) to update prev page number
             let mut right_sibling_lock = if page_view.is_rightmost() {
@@ -696,7 +974,16 @@ where
         let (_, _, page_num) = page_lock.borrow_page_guard().get_fork_and_num();
         let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
 
-        if page_view.get_free_space() < tuple.len() {
+        let stored_tuple = match encode_page_entry(&page_view, tuple) {
+            Ok(stored_tuple) => stored_tuple,
+            Err(e) => {
+                db.get_buffer_manager()
+                    .release_page(page_lock.into_heads().page_ptr)?;
+                return Err(e);
+            }
+        };
+
+        if page_view.get_free_space() < stored_tuple.len() {
             // split
             let is_root = page_view.is_root();
 
@@ -708,7 +995,7 @@ where
             page_lock.with_page_guard_mut::<Result<()>>(|page_guard| {
                 let mut page_view = BTreeDataPageViewMut::new(page_guard.buffer_mut());
 
-                page_view.put_item(tuple, Some(offset), false)?;
+                page_view.put_item(&stored_tuple, Some(offset), false)?;
 
                 let insert_log = BTreeLogRecord::create_btree_insert_log(
                     RelFileRef {
@@ -760,14 +1047,7 @@ where
 
                 for i in offset..=max_off {
                     let tuple_buf = page_view.get_item(i);
-                    let tuple = match bincode::deserialize::<IndexTuple>(tuple_buf) {
-                        Ok(itup) => itup,
-                        _ => {
-                            return Err(Error::DataCorrupted(
-                                "cannot deserialize index tuple".to_owned(),
-                            ));
-                        }
-                    };
+                    let tuple = bincode::deserialize::<IndexTuple>(tuple_buf)?;
 
                     if tuple.get_downlink() == child_page_num {
                         return Ok((parent_lock, path, ItemPointer::new(page_num, i)));
@@ -776,14 +1056,7 @@ where
 
                 for i in (min_off..offset).rev() {
                     let tuple_buf = page_view.get_item(i);
-                    let tuple = match bincode::deserialize::<IndexTuple>(tuple_buf) {
-                        Ok(itup) => itup,
-                        _ => {
-                            return Err(Error::DataCorrupted(
-                                "cannot deserialize index tuple".to_owned(),
-                            ));
-                        }
-                    };
+                    let tuple = bincode::deserialize::<IndexTuple>(tuple_buf)?;
 
                     if tuple.get_downlink() == child_page_num {
                         return Ok((parent_lock, path, ItemPointer::new(page_num, i)));
@@ -829,20 +1102,13 @@ where
             let (_, _, right_page_num) = rchild_lock.borrow_page_guard().get_fork_and_num();
             let left_page_view = BTreeDataPageView::new(lchild_lock.borrow_page_guard().buffer());
             let high_key_buf = left_page_view.get_item(left_page_view.high_key_offset());
-            let high_key = match bincode::deserialize::<IndexTuple>(high_key_buf) {
-                Ok(itup) => itup.key,
-                _ => {
-                    return Err(Error::DataCorrupted(
-                        "cannot deserialize index tuple".to_owned(),
-                    ));
-                }
-            };
+            let high_key = bincode::deserialize::<IndexTuple>(high_key_buf)?.key;
             let mut right_tuple = IndexTuple {
                 key: high_key,
                 item_pointer: ItemPointer::default(),
             };
             right_tuple.set_downlink(right_page_num);
-            let right_tuple_buf = bincode::serialize(&right_tuple).unwrap();
+            let right_tuple_buf = bincode::serialize(&right_tuple)?;
 
             let (parent_lock, path, ItemPointer { offset, .. }) =
                 self.walk_up_path(db, path, left_page_num)?;
@@ -866,6 +1132,7 @@ where
         let offset = match dir {
             ScanDirection::Forward => page_view.first_key_offset(),
             ScanDirection::Backward => page_view.num_line_pointers(),
+            ScanDirection::NoMovement => unreachable!(),
         };
 
         iterator.read_page(&page_view, dir, offset)?;
@@ -894,11 +1161,23 @@ where
 
         match start_key {
             Some(start_key) => {
-                let (page_lock, _) = self.search_read(db, &start_key)?;
+                let next_key = iterator.start_after;
+                let (page_lock, _) = self.search_read(db, &start_key, next_key)?;
                 let (_, _, page_num) = page_lock.borrow_page_guard().get_fork_and_num();
                 let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
-                let offset =
-                    self.binary_search_page(&page_view, &start_key, ItemPointer::default(), false)?;
+                let offset = match self.binary_search_page(
+                    &page_view,
+                    &start_key,
+                    ItemPointer::default(),
+                    next_key,
+                ) {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        db.get_buffer_manager()
+                            .release_page(page_lock.into_heads().page_ptr)?;
+                        return Err(e);
+                    }
+                };
 
                 iterator.read_page(&page_view, dir, offset)?;
 
@@ -938,18 +1217,39 @@ where
                 let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
 
                 iterator.read_page(&page_view, dir, page_view.first_key_offset())?;
+                let next_page_num = page_view.get_next();
+
+                db.get_buffer_manager()
+                    .release_page(page_lock.into_heads().page_ptr)?;
 
                 if !iterator.items.is_empty() {
                     break;
                 }
 
-                page_num = page_view.get_next();
+                page_num = next_page_num;
+            },
+            ScanDirection::Backward => loop {
+                if page_num == 0 {
+                    iterator.invalidate();
+                    return Ok(None);
+                }
+
+                let page_lock = self.get_tree_page_read(db, Some(page_num))?;
+                let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+
+                iterator.read_page(&page_view, dir, page_view.num_line_pointers())?;
+                let prev_page_num = page_view.get_prev();
+
                 db.get_buffer_manager()
                     .release_page(page_lock.into_heads().page_ptr)?;
+
+                if !iterator.items.is_empty() {
+                    break;
+                }
+
+                page_num = prev_page_num;
             },
-            ScanDirection::Backward => {
-                return Ok(None);
-            }
+            ScanDirection::NoMovement => unreachable!(),
         }
 
         if iterator.items.is_empty() {
@@ -970,67 +1270,292 @@ where
         iterator: &mut BTreeScanIterator<KCmp>,
         dir: ScanDirection,
     ) -> Result<Option<ItemPointer>> {
-        let next_page_num = match dir {
-            ScanDirection::Forward => {
-                let cur_page = iterator.cur_page.take();
-                if let Some(page) = cur_page {
-                    db.get_buffer_manager().release_page(page)?;
-                }
+        let cur_page = iterator.cur_page.take();
+        if let Some(page) = cur_page {
+            db.get_buffer_manager().release_page(page)?;
+        }
 
-                iterator.next_page
-            }
-            ScanDirection::Backward => match iterator.cur_page_num {
-                Some(cur_page_num) => cur_page_num,
-                _ => unreachable!(),
-            },
+        // the backward scan follows the prev links, which are repaired by recovery
+        let next_page_num = match dir {
+            ScanDirection::Forward => iterator.next_page,
+            ScanDirection::Backward => iterator.prev_page,
+            ScanDirection::NoMovement => unreachable!(),
         };
 
         self.read_next_page(db, iterator, dir, next_page_num)
     }
-}
 
-impl<KCmp> Relation for BTree<KCmp>
-where
-    KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
-{
-    fn get_relation_entry(&self) -> &RelationEntry {
-        &self.rel_entry
-    }
-}
+    /// Re-read the page of a saved scan position. The marked entry is looked up by its item
+    /// pointer and may have been moved right by a split.
+    fn restore_position(
+        &self,
+        db: &DB,
+        iterator: &mut BTreeScanIterator<KCmp>,
+        mark: ScanMark,
+    ) -> Result<()> {
+        let (mut page_num, item_pointer) = match (mark.page_num, mark.item_pointer) {
+            (Some(page_num), Some(item_pointer)) => (page_num, item_pointer),
+            _ => {
+                // the scan was not positioned, start over from the start key
+                iterator.invalidate();
+                return Ok(());
+            }
+        };
 
-impl<KCmp> RelationWithStorage for BTree<KCmp>
-where
-    KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
-{
-    fn get_storage_handle(&self) -> &Mutex<Option<StorageHandle>> {
-        &self.shandle
-    }
-}
+        while page_num != 0 {
+            let page_lock = self.get_tree_page_read(db, Some(page_num))?;
+            let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
 
-impl<KCmp> Index for BTree<KCmp>
-where
-    KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
-{
-    fn build_empty(&self, db: &DB) -> Result<()> {
-        let smgr = db.get_storage_manager();
-        self.with_storage(smgr, |storage| {
+            iterator.read_page(
+                &page_view,
+                ScanDirection::Forward,
+                page_view.first_key_offset(),
+            )?;
+
+            db.get_buffer_manager()
+                .release_page(page_lock.into_heads().page_ptr)?;
+
+            let item_index = match iterator.items.get(mark.item_index) {
+                Some(itup) if itup.item_pointer == item_pointer => Some(mark.item_index),
+                _ => iterator
+                    .items
+                    .iter()
+                    .position(|itup| itup.item_pointer == item_pointer),
+            };
+
+            if let Some(item_index) = item_index {
+                iterator.cur_page_num = Some(page_num);
+                iterator.item_index = item_index;
+                return Ok(());
+            }
+
+            page_num = iterator.next_page;
+        }
+
+        iterator.invalidate();
+        Err(Error::InvalidState(
+            "marked index entry no longer exists".to_owned(),
+        ))
+    }
+
+    /// Walk the leaf level from the leftmost leaf and call `f` on each tuple in at most
+    /// `max_pages` leaf pages. Return the number of pages visited and whether the whole leaf
+    /// level has been visited.
+    fn walk_leaves<F>(&self, db: &DB, max_pages: Option<usize>, mut f: F) -> Result<(usize, bool)>
+    where
+        F: FnMut(&IndexTuple) -> Result<()>,
+    {
+        let bufmgr = db.get_buffer_manager();
+        let mut page_lock = self.get_endpoint(db, false)?;
+        let mut num_pages = 0;
+
+        loop {
+            let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+            let prefix = match get_key_prefix(&page_view) {
+                Ok(prefix) => prefix,
+                Err(e) => {
+                    bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                    return Err(e);
+                }
+            };
+
+            for off in page_view.first_key_offset()..=page_view.num_line_pointers() {
+                let result =
+                    IndexTuple::decode(page_view.get_item(off), &prefix).and_then(|itup| f(&itup));
+
+                if let Err(e) = result {
+                    bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                    return Err(e);
+                }
+            }
+
+            num_pages += 1;
+            let next_page_num = page_view.get_next();
+            bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+
+            if next_page_num == 0 {
+                return Ok((num_pages, true));
+            }
+
+            if let Some(max_pages) = max_pages {
+                if num_pages >= max_pages {
+                    return Ok((num_pages, false));
+                }
+            }
+
+            page_lock = self.get_tree_page_read(db, Some(next_page_num))?;
+        }
+    }
+
+    /// Count the distinct keys in at most `max_pages` leaf pages. Return the number of pages
+    /// visited, the number of distinct keys and whether the whole leaf level has been visited.
+    fn sample_leaves(&self, db: &DB, max_pages: usize) -> Result<(usize, usize, bool)> {
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut distinct_keys = 0;
+
+        let (num_pages, complete) = self.walk_leaves(db, Some(max_pages), |itup| {
+            let is_new_key = match &last_key {
+                Some(key) => (self.key_comparator)(key, &itup.key)? != Ordering::Equal,
+                None => true,
+            };
+
+            if is_new_key {
+                distinct_keys += 1;
+                last_key = Some(itup.key.to_vec());
+            }
+
+            Ok(())
+        })?;
+
+        Ok((num_pages, distinct_keys, complete))
+    }
+
+    fn get_root_page_num(&self, db: &DB) -> Result<usize> {
+        let meta_page_lock = self.get_tree_page_read(db, Some(BTREE_META_PAGE_NUM))?;
+        let meta_page_view = BTreeMetaPageView::new(meta_page_lock.borrow_page_guard().buffer());
+        let magic = meta_page_view.get_magic();
+        let root_page_num = meta_page_view.get_root();
+        db.get_buffer_manager()
+            .release_page(meta_page_lock.into_heads().page_ptr)?;
+
+        if magic != BTREE_META_MAGIC {
+            return Err(Error::DataCorrupted("bad btree meta page magic".to_owned()));
+        }
+
+        Ok(root_page_num)
+    }
+
+    /// Return the pages whose `prev` link does not point to the page that links to them with
+    /// `next`.
+    pub fn verify_siblings(&self, db: &DB) -> Result<Vec<usize>> {
+        self.check_siblings(db, false)
+    }
+
+    /// Recompute the `prev` links from the `next` links on every level of the tree and return
+    /// the repaired pages. Splits update the `prev` link of the right sibling without logging, so
+    /// the link can be stale after recovery. The tree must not be modified concurrently.
+    pub fn repair_siblings(&self, db: &DB) -> Result<Vec<usize>> {
+        self.check_siblings(db, true)
+    }
+
+    fn check_siblings(&self, db: &DB, repair: bool) -> Result<Vec<usize>> {
+        let bufmgr = db.get_buffer_manager();
+        let mut bad_pages = Vec::new();
+
+        let root_page_num = self.get_root_page_num(db)?;
+        let mut level_start = if root_page_num == 0 {
+            None
+        } else {
+            Some(root_page_num)
+        };
+
+        // follow the next links on each level, starting from the leftmost page
+        while let Some(first_page_num) = level_start.take() {
+            let mut prev_page_num = 0;
+            let mut page_num = first_page_num;
+
+            loop {
+                let page_lock = self.get_tree_page_read(db, Some(page_num))?;
+                let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+                let prev_link = page_view.get_prev();
+                let next_page_num = if page_view.is_rightmost() {
+                    None
+                } else {
+                    Some(page_view.get_next())
+                };
+
+                if page_num == first_page_num && page_view.page_type() == BTreePageType::Internal {
+                    let child_tuple_buf = page_view.get_item(page_view.first_key_offset());
+                    match bincode::deserialize::<IndexTuple>(child_tuple_buf) {
+                        Ok(child_tuple) => level_start = Some(child_tuple.get_downlink()),
+                        Err(e) => {
+                            bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                            return Err(e.into());
+                        }
+                    }
+                }
+
+                bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+
+                if prev_link != prev_page_num {
+                    bad_pages.push(page_num);
+
+                    if repair {
+                        let mut page_lock = self.get_tree_page_write(db, Some(page_num))?;
+                        page_lock.with_page_guard_mut(|page_guard| {
+                            let mut page_view = BTreeDataPageViewMut::new(page_guard.buffer_mut());
+                            page_view.set_prev(prev_page_num);
+                            page_guard.set_dirty(true);
+                        });
+                        bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+                    }
+                }
+
+                match next_page_num {
+                    Some(next_page_num) => {
+                        prev_page_num = page_num;
+                        page_num = next_page_num;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        Ok(bad_pages)
+    }
+}
+
+impl<KCmp> Relation for BTree<KCmp>
+where
+    KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
+{
+    fn get_relation_entry(&self) -> &RelationEntry {
+        &self.rel_entry
+    }
+}
+
+impl<KCmp> RelationWithStorage for BTree<KCmp>
+where
+    KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
+{
+    fn get_storage_handle(&self) -> &Mutex<Option<StorageHandle>> {
+        &self.shandle
+    }
+}
+
+impl<KCmp> Index for BTree<KCmp>
+where
+    KCmp: Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send,
+{
+    fn build_empty(&self, db: &DB) -> Result<()> {
+        let smgr = db.get_storage_manager();
+        self.with_storage(smgr, |storage| {
             let mut buffer = [0u8; PAGE_SIZE];
             let mut meta_view = BTreeMetaPageViewMut::new(&mut buffer);
             meta_view.init_page(0);
+            meta_view.set_checksum();
 
             smgr.write(storage, ForkType::Main, BTREE_META_PAGE_NUM, &buffer)?;
-            smgr.sync(storage, ForkType::Main)
+            smgr.sync(storage, ForkType::Main)?;
+
+            // the meta page is visited by every index operation
+            db.get_buffer_manager().mark_resident(
+                storage.file_ref(),
+                ForkType::Main,
+                BTREE_META_PAGE_NUM,
+            );
+            Ok(())
         })
     }
 
-    fn insert<'a>(&'a self, db: &DB, key: &[u8], item_pointer: ItemPointer) -> Result<()> {
+    fn insert(&self, db: &DB, key: &[u8], item_pointer: ItemPointer) -> Result<()> {
         let (page_lock, path) = self.search_write(db, key)?;
 
         let itup = IndexTuple {
             key: key.into(),
             item_pointer,
         };
-        let itup_buf = bincode::serialize(&itup).unwrap();
+        let itup_buf = bincode::serialize(&itup)?;
 
         let (page_lock, offset) = self.get_insert_location(db, key, item_pointer, page_lock)?;
 
@@ -1043,10 +1568,19 @@ where
         txn: &'a mut Transaction,
         table: &'a dyn Table,
     ) -> Result<Box<dyn IndexScanIterator<'a> + 'a>> {
+        db.get_transaction_manager().lock_relation(
+            txn,
+            RelFileRef {
+                db: self.rel_db(),
+                rel_id: self.rel_id(),
+            },
+            LockMode::AccessShare,
+        )?;
         let xid = txn.xid();
+        let interrupts = txn.interrupt_check();
         let snapshot = db.get_transaction_manager().get_snapshot(txn)?;
         let btree_it = BTreeScanIterator {
-            btree: &self,
+            btree: self,
             xid,
             snapshot,
             table,
@@ -1055,12 +1589,168 @@ where
             cur_page_num: None,
             next_page: 0,
             start_key: None,
+            start_after: false,
+            prev_page: 0,
             items: Vec::new(),
             item_index: 0,
+            interrupts,
         };
 
         Ok(Box::new(btree_it))
     }
+
+    fn find_one<'a>(
+        &self,
+        db: &'a DB,
+        txn: &mut Transaction,
+        table: &'a dyn Table,
+        key: &[u8],
+    ) -> Result<Option<TuplePtr<'a>>> {
+        let bufmgr = db.get_buffer_manager();
+        let xid = txn.xid();
+        let snapshot = db.get_transaction_manager().get_snapshot(txn)?;
+
+        let (mut page_lock, _) = self.search_read(db, key, false)?;
+        let mut offset = {
+            let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+            self.binary_search_page(&page_view, key, ItemPointer::default(), false)?
+        };
+
+        loop {
+            let (item_pointers, next_page) = {
+                let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+                self.collect_equal_keys(&page_view, key, offset)?
+            };
+
+            bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+
+            for item_pointer in item_pointers {
+                if let Some(tuple) = table.fetch_tuple(db, xid, snapshot, item_pointer)? {
+                    return Ok(Some(tuple));
+                }
+            }
+
+            match next_page {
+                Some(page_num) => {
+                    page_lock = self.get_tree_page_read(db, Some(page_num))?;
+                    let page_view = BTreeDataPageView::new(page_lock.borrow_page_guard().buffer());
+                    offset = page_view.first_key_offset();
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn insert_unique(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        table: &dyn Table,
+        key: &[u8],
+        item_pointer: ItemPointer,
+    ) -> Result<()> {
+        let txnmgr = db.get_transaction_manager();
+        let snapshot = txnmgr.get_dirty_snapshot(txn);
+
+        loop {
+            // the first page that may contain the key stays locked until the entry is inserted,
+            // so concurrent inserts of the same key are checked one after another
+            let (page_lock, path) = self.search_write(db, key)?;
+
+            let check = self.check_unique(db, txn.xid(), &snapshot, table, key, &page_lock);
+            let conflict_xid = match check {
+                Ok(UniqueCheck::Unique) => {
+                    let itup = IndexTuple {
+                        key: key.into(),
+                        item_pointer,
+                    };
+                    let itup_buf = bincode::serialize(&itup)?;
+
+                    let (page_lock, offset) =
+                        self.get_insert_location(db, key, item_pointer, page_lock)?;
+                    return self.insert_into_page(db, &itup_buf[..], offset, page_lock, path);
+                }
+                Ok(UniqueCheck::Duplicate) => Err(Error::UniqueViolation(format!(
+                    "duplicate key in index {}",
+                    self.rel_id()
+                ))),
+                Ok(UniqueCheck::InProgress(xid)) => Ok(xid),
+                Err(e) => Err(e),
+            };
+
+            db.get_buffer_manager()
+                .release_page(page_lock.into_heads().page_ptr)?;
+            // check again after the conflicting transaction ends
+            txnmgr.wait_for_xid_in_transaction(txn, conflict_xid?)?;
+        }
+    }
+
+    fn rebuild(&self, db: &DB, item_pointer_map: &HashMap<ItemPointer, ItemPointer>) -> Result<()> {
+        let mut entries = Vec::new();
+
+        if self.get_root_page_num(db)? != 0 {
+            // the rebuild can only be canceled before the index is truncated
+            self.walk_leaves(db, None, |itup| {
+                db.check_interrupts()?;
+
+                if let Some(item_pointer) = item_pointer_map.get(&itup.item_pointer) {
+                    entries.push((itup.key.to_vec(), *item_pointer));
+                }
+                Ok(())
+            })?;
+        }
+
+        let smgr = db.get_storage_manager();
+        self.with_storage(smgr, |storage| {
+            db.get_buffer_manager()
+                .invalidate_relation(storage.file_ref())
+        })?;
+        // keep the meta page so that redoing the truncation does not remove the new meta page
+        self.truncate_storage(db, XID::default(), BTREE_META_PAGE_NUM + 1)?;
+
+        self.build_empty(db)?;
+
+        for (key, item_pointer) in entries {
+            self.insert(db, &key, item_pointer)?;
+        }
+
+        Ok(())
+    }
+
+    fn index_stats(&self, db: &DB) -> Result<IndexStats> {
+        let num_pages = self.get_size_in_page(db.get_storage_manager())?;
+
+        if self.get_root_page_num(db)? == 0 {
+            return Ok(IndexStats {
+                num_pages,
+                ..Default::default()
+            });
+        }
+
+        let root_page_lock = self.get_root_page_read(db)?;
+        let root_page_view = BTreeDataPageView::new(root_page_lock.borrow_page_guard().buffer());
+        let height = root_page_view.get_level() as usize + 1;
+        db.get_buffer_manager()
+            .release_page(root_page_lock.into_heads().page_ptr)?;
+
+        let (sampled_pages, sampled_keys, complete) =
+            self.sample_leaves(db, BTREE_STATS_SAMPLE_PAGES)?;
+
+        let distinct_keys = if complete {
+            sampled_keys
+        } else {
+            // extrapolate to all pages except the meta page, internal pages are few enough to be
+            // ignored
+            let leaf_pages = std::cmp::max(num_pages - 1, sampled_pages);
+            sampled_keys * leaf_pages / sampled_pages
+        };
+
+        Ok(IndexStats {
+            num_pages,
+            height,
+            distinct_keys,
+        })
+    }
 }
 
 pub struct BTreeScanIterator<'a, KCmp>
@@ -1075,11 +1765,15 @@ where
     cur_page: Option<PinnedPagePtr>,
     cur_page_num: Option<usize>,
     start_key: Option<Vec<u8>>,
+    // start after the entries equal to the start key
+    start_after: bool,
     next_page: usize,
+    prev_page: usize,
 
     // these members are valid when cur_page_num is not None
     items: Vec<IndexTuple<'a>>,
     item_index: usize,
+    interrupts: InterruptCheck,
 }
 
 impl<'a, KCmp> BTreeScanIterator<'a, KCmp>
@@ -1095,22 +1789,17 @@ where
         let maxoff = page_view.num_line_pointers();
 
         self.next_page = page_view.get_next();
+        self.prev_page = page_view.get_prev();
+        let prefix = get_key_prefix(page_view)?;
 
         let offsets = match dir {
             ScanDirection::Forward => std::cmp::max(minoff, offset)..=maxoff,
             ScanDirection::Backward => minoff..=std::cmp::min(maxoff, offset),
+            ScanDirection::NoMovement => unreachable!(),
         };
 
         for off in offsets {
-            let itup_buf = page_view.get_item(off);
-            let itup = match bincode::deserialize::<IndexTuple>(itup_buf) {
-                Ok(itup) => itup,
-                _ => {
-                    return Err(Error::DataCorrupted(
-                        "cannot deserialize index tuple".to_owned(),
-                    ))
-                }
-            };
+            let itup = IndexTuple::decode(page_view.get_item(off), &prefix)?;
 
             if self.check_predicate(&itup)? {
                 self.items.push(itup.materialize());
@@ -1128,6 +1817,7 @@ where
                     self.items.len() - 1
                 };
             }
+            ScanDirection::NoMovement => unreachable!(),
         }
 
         Ok(())
@@ -1163,6 +1853,7 @@ where
                     false
                 }
             }
+            ScanDirection::NoMovement => unreachable!(),
         };
 
         if step {
@@ -1172,7 +1863,31 @@ where
         }
     }
 
+    /// Move to the next item on the current leaf page without stepping to another page.
+    fn scan_next_on_page(&mut self, dir: ScanDirection) -> Option<ItemPointer> {
+        match dir {
+            ScanDirection::Forward if self.item_index + 1 < self.items.len() => {
+                self.item_index += 1;
+            }
+            ScanDirection::Backward if self.item_index > 0 => {
+                self.item_index -= 1;
+            }
+            _ => return None,
+        }
+
+        self.current_item_pointer()
+    }
+
     fn next_item_pointer(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<ItemPointer>> {
+        if let Err(e) = self.interrupts.check() {
+            // end the scan so that the page is not leaked
+            if let Some(page_ptr) = self.cur_page.take() {
+                db.get_buffer_manager().release_page(page_ptr)?;
+            }
+            self.invalidate();
+            return Err(e);
+        }
+
         if self.is_valid() {
             self.scan_next(db, dir)
         } else {
@@ -1205,12 +1920,37 @@ where
             db.get_buffer_manager().release_page(page_ptr)?;
         }
 
+        // start over from the new start key on the next call
+        self.invalidate();
         self.start_key = start_key.map(|key| key.to_vec());
+        self.start_after = false;
         self.predicate = Some(predicate);
         Ok(())
     }
 
+    fn rescan_after(
+        &mut self,
+        db: &'a DB,
+        start_key: &[u8],
+        predicate: IndexScanPredicate<'a>,
+    ) -> Result<()> {
+        self.rescan(db, Some(start_key), predicate)?;
+        self.start_after = true;
+        Ok(())
+    }
+
     fn next(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<TuplePtr<'a>>> {
+        if dir == ScanDirection::NoMovement {
+            // fetch the tuple of the current item again, if the scan has started
+            return match self.current_item_pointer() {
+                Some(item_pointer) => {
+                    self.table
+                        .fetch_tuple(db, self.xid, self.snapshot, item_pointer)
+                }
+                None => Ok(None),
+            };
+        }
+
         loop {
             let item_pointer = match self.next_item_pointer(db, dir)? {
                 Some(item_pointer) => item_pointer,
@@ -1225,16 +1965,122 @@ where
             }
         }
     }
+
+    fn mark(&mut self) -> ScanMark {
+        ScanMark {
+            page_num: self.cur_page_num,
+            item_index: self.item_index,
+            item_pointer: self.current_item_pointer(),
+        }
+    }
+
+    fn restore(&mut self, db: &'a DB, mark: ScanMark) -> Result<()> {
+        if let Some(page_ptr) = self.cur_page.take() {
+            db.get_buffer_manager().release_page(page_ptr)?;
+        }
+
+        self.btree.restore_position(db, self, mark)
+    }
+
+    fn next_batch(
+        &mut self,
+        db: &'a DB,
+        dir: ScanDirection,
+        max: usize,
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        if dir == ScanDirection::NoMovement {
+            return Ok(self.next(db, dir)?.into_iter().collect());
+        }
+
+        let mut tuples = Vec::new();
+
+        while tuples.len() < max {
+            let item_pointer = match self.next_item_pointer(db, dir)? {
+                Some(item_pointer) => item_pointer,
+                _ => break,
+            };
+
+            // collect the remaining items on the leaf page and fetch the tuples together
+            let mut item_pointers = vec![item_pointer];
+            while item_pointers.len() < max - tuples.len() {
+                match self.scan_next_on_page(dir) {
+                    Some(item_pointer) => item_pointers.push(item_pointer),
+                    None => break,
+                }
+            }
+
+            tuples.extend(
+                self.table
+                    .fetch_tuples(db, self.xid, self.snapshot, &item_pointers)?,
+            );
+        }
+
+        Ok(tuples)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        am::index::IndexScanPredicate, concurrency::IsolationLevel, storage::ScanDirection,
+        am::index::IndexScanPredicate,
+        concurrency::{IsolationLevel, Transaction},
+        storage::{ForkType, ItemPointer, RelFileRef, ScanDirection, Table},
         test_util::get_temp_db,
+        DBConfig, Error, DB,
     };
 
+    use super::{btree_page::views::*, BTree, Index, IndexScanIterator};
+
     use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+    use std::{sync::Barrier, thread, time::Duration};
+
+    #[test]
+    fn can_tell_page_kinds() {
+        use crate::storage::{consts::PAGE_SIZE, DiskPageReader, DiskPageView, PageKind};
+
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+            .unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr = heap.insert_tuple(&db, &txn, &[1u8; 10]).unwrap();
+        btree.insert(&db, &[1u8; 4], item_ptr).unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let page_kind = |rel_id, page_num| {
+            let shandle = smgr.open(RelFileRef { db: 0, rel_id }).unwrap();
+            let page_ptr = bufmgr
+                .fetch_page(&db, &shandle, ForkType::Main, page_num)
+                .unwrap();
+            let (kind, version) = page_ptr
+                .with_read(|page| {
+                    let page_view = DiskPageView::new(page.buffer());
+                    Ok((page_view.page_kind(), page_view.get_page_version()))
+                })
+                .unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+
+            assert_eq!(version, crate::storage::PAGE_LAYOUT_VERSION);
+            kind
+        };
+
+        assert_eq!(page_kind(0, 0), PageKind::Heap);
+        // meta page and root page
+        assert_eq!(page_kind(1, 0), PageKind::BTree);
+        assert_eq!(page_kind(1, 1), PageKind::BTree);
+
+        let zero_page = [0u8; PAGE_SIZE];
+        assert_eq!(
+            DiskPageView::new(&zero_page).page_kind(),
+            PageKind::Uninitialized
+        );
+
+        db_dir.close().unwrap();
+    }
 
     #[test]
     fn can_create_btree() {
@@ -1254,12 +2100,46 @@ mod tests {
     }
 
     #[test]
-    fn can_insert_and_scan_btree() {
+    fn can_cancel_btree_rebuild() {
         let (db, db_dir) = get_temp_db();
-        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
-        let heap = db.create_table(0, 0).unwrap();
         let btree = db
-            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+            .create_index(0, 0, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        let mut item_pointer_map = std::collections::HashMap::new();
+        for i in 0..100u32 {
+            let item_pointer = ItemPointer::new(1, i as usize + 1);
+            btree.insert(&db, &i.to_le_bytes(), item_pointer).unwrap();
+            item_pointer_map.insert(item_pointer, item_pointer);
+        }
+
+        // the index is left untouched by a canceled rebuild
+        let cancel_token = db.cancel_handle();
+        cancel_token.cancel();
+        assert!(matches!(
+            btree.rebuild(&db, &item_pointer_map),
+            Err(Error::QueryCanceled(_))
+        ));
+        assert_eq!(btree.index_stats(&db).unwrap().distinct_keys, 100);
+
+        cancel_token.reset();
+        btree.rebuild(&db, &item_pointer_map).unwrap();
+        assert_eq!(btree.index_stats(&db).unwrap().distinct_keys, 100);
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_insert_and_scan_btree() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
                 let a = LittleEndian::read_u32(a);
                 let b = LittleEndian::read_u32(b);
                 Ok(a.cmp(&b))
@@ -1285,6 +2165,8 @@ mod tests {
             assert!(btree.insert(&db, &key, item_ptr).is_ok());
         }
 
+        txn.advance_command_id().unwrap();
+
         {
             let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
             iter.rescan(&db, None, predicate).unwrap();
@@ -1302,4 +2184,956 @@ mod tests {
 
         assert!(db_dir.close().is_ok());
     }
+
+    #[test]
+    fn can_scan_btree_in_batches() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        for i in 0..300 {
+            let mut data = vec![0u8; 64];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+            btree.insert(&db, &data[..4], item_ptr).unwrap();
+        }
+
+        txn.advance_command_id().unwrap();
+
+        let heap_ref = RelFileRef { db: 0, rel_id: 0 };
+        let bufmgr = db.get_buffer_manager();
+
+        let fetches = bufmgr.fetch_count(heap_ref);
+        let mut single = Vec::new();
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_| Ok(true)))
+                .unwrap();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                single.push(tuple.get_data().to_vec());
+            }
+        }
+        let single_fetches = bufmgr.fetch_count(heap_ref) - fetches;
+
+        let fetches = bufmgr.fetch_count(heap_ref);
+        let mut batched = Vec::new();
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_| Ok(true)))
+                .unwrap();
+            loop {
+                // a short batch means the end of the scan
+                let batch = iter.next_batch(&db, ScanDirection::Forward, 32).unwrap();
+                let done = batch.len() < 32;
+                batched.extend(batch.into_iter().map(|tuple| tuple.get_data().to_vec()));
+                if done {
+                    break;
+                }
+            }
+        }
+        let batched_fetches = bufmgr.fetch_count(heap_ref) - fetches;
+
+        assert_eq!(single.len(), 300);
+        assert_eq!(single, batched);
+        assert!(batched_fetches * 4 < single_fetches);
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_refetch_current_tuple_from_btree() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        for i in 0..10 {
+            let mut data = vec![0u8; 4];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+            btree.insert(&db, &data, item_ptr).unwrap();
+        }
+
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_| Ok(true)))
+                .unwrap();
+
+            assert!(iter.next(&db, ScanDirection::NoMovement).unwrap().is_none());
+
+            iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), 1);
+
+            for _ in 0..2 {
+                let again = iter.next(&db, ScanDirection::NoMovement).unwrap().unwrap();
+                assert_eq!(again.get_data(), tuple.get_data());
+                assert_eq!(again.get_item_pointer(), tuple.get_item_pointer());
+            }
+
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), 2);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_reject_concurrent_duplicate_keys() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+            .unwrap();
+        let barrier = Barrier::new(2);
+
+        let results = thread::scope(|s| {
+            let handles = (0..2u8)
+                .map(|i| {
+                    let (db, heap, btree, barrier) = (&db, &heap, &btree, &barrier);
+                    s.spawn(move || {
+                        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                        let item_ptr = heap.insert_tuple(db, &txn, &[i; 10]).unwrap();
+
+                        // both tuples are inserted before either index entry
+                        barrier.wait();
+
+                        let result = btree.insert_unique(db, &txn, &**heap, &[1u8; 4], item_ptr);
+                        if result.is_ok() {
+                            // keep the other insert waiting for the outcome
+                            thread::sleep(Duration::from_millis(50));
+                            db.commit_transaction(txn).unwrap();
+                        }
+                        result
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(Error::UniqueViolation(_)))));
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr = heap.insert_tuple(&db, &txn, &[2u8; 10]).unwrap();
+        assert!(matches!(
+            btree.insert_unique(&db, &txn, &*heap, &[1u8; 4], item_ptr),
+            Err(Error::UniqueViolation(_))
+        ));
+        assert!(btree
+            .insert_unique(&db, &txn, &*heap, &[2u8; 4], item_ptr)
+            .is_ok());
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_detect_unique_insert_deadlock() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+            .unwrap();
+
+        let txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr1 = heap.insert_tuple(&db, &txn1, &[1u8; 10]).unwrap();
+        let item_ptr2 = heap.insert_tuple(&db, &txn2, &[2u8; 10]).unwrap();
+        btree
+            .insert_unique(&db, &txn1, &*heap, &[1u8; 4], item_ptr1)
+            .unwrap();
+        btree
+            .insert_unique(&db, &txn2, &*heap, &[2u8; 4], item_ptr2)
+            .unwrap();
+
+        thread::scope(|s| {
+            let inserter =
+                s.spawn(|| btree.insert_unique(&db, &txn1, &*heap, &[2u8; 4], item_ptr1));
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(!inserter.is_finished());
+
+            // each transaction waits for the other
+            assert!(matches!(
+                btree.insert_unique(&db, &txn2, &*heap, &[1u8; 4], item_ptr2),
+                Err(Error::Deadlock(_))
+            ));
+            db.abort_transaction(txn2).unwrap();
+
+            assert!(inserter.join().unwrap().is_ok());
+        });
+        db.commit_transaction(txn1).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_find_one_by_key() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        let make_tuple = |key: u32, value: u32| {
+            let mut data = vec![0u8; 64];
+            (&mut data[..]).write_u32::<LittleEndian>(key).unwrap();
+            (&mut data[4..]).write_u32::<LittleEndian>(value).unwrap();
+            data
+        };
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for i in 0..500 {
+            let data = make_tuple(i * 2, 0);
+            let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+            btree.insert(&db, &data[..4], item_ptr).unwrap();
+            item_ptrs.push(item_ptr);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        // replace the tuple of key 100 so that the index has an invisible duplicate
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.delete_tuple(&db, &txn, item_ptrs[50]).unwrap();
+        let data = make_tuple(100, 1);
+        let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+        btree.insert(&db, &data[..4], item_ptr).unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 0..500u32 {
+            let key = make_tuple(i * 2, 0);
+            let tuple = btree
+                .find_one(&db, &mut txn, &*heap, &key[..4])
+                .unwrap()
+                .unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), i * 2);
+            assert_eq!(
+                LittleEndian::read_u32(&tuple.get_data()[4..]),
+                (i == 50) as u32
+            );
+
+            let key = make_tuple(i * 2 + 1, 0);
+            assert!(btree
+                .find_one(&db, &mut txn, &*heap, &key[..4])
+                .unwrap()
+                .is_none());
+        }
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_find_tuple_updated_on_same_page() {
+        let (db, db_dir) = get_temp_db();
+        db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        let make_tuple = |key: u32, value: u32| {
+            let mut data = vec![0u8; 64];
+            (&mut data[..]).write_u32::<LittleEndian>(key).unwrap();
+            (&mut data[4..]).write_u32::<LittleEndian>(value).unwrap();
+            data
+        };
+        let find_value = |txn: &mut Transaction, heap: &dyn Table, key: u32| {
+            let key = make_tuple(key, 0);
+            btree
+                .find_one(&db, txn, heap, &key[..4])
+                .unwrap()
+                .map(|tuple| LittleEndian::read_u32(&tuple.get_data()[4..]))
+        };
+
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for i in 0..20 {
+            let data = make_tuple(i, 0);
+            let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+            btree.insert(&db, &data[..4], item_ptr).unwrap();
+            item_ptrs.push(item_ptr);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let mut old_txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert_eq!(find_value(&mut old_txn, &*heap, 5), Some(0));
+
+        // update the row twice without touching the index
+        let mut item_ptr = item_ptrs[5];
+        for value in 1..3 {
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let new_item_ptr = heap
+                .update_tuple(&db, &txn, item_ptr, &make_tuple(5, value), true)
+                .unwrap();
+            assert_eq!(new_item_ptr.page_num, item_ptr.page_num);
+            assert_ne!(new_item_ptr, item_ptr);
+            db.commit_transaction(txn).unwrap();
+            item_ptr = new_item_ptr;
+        }
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(find_value(&mut txn, &*heap, 5), Some(2));
+        assert_eq!(find_value(&mut txn, &*heap, 6), Some(0));
+        // the old snapshot still finds the old version through the same entry
+        assert_eq!(find_value(&mut old_txn, &*heap, 5), Some(0));
+        db.commit_transaction(old_txn).unwrap();
+
+        // the newer version is found by the uniqueness check as well
+        let data = make_tuple(5, 3);
+        let dup_item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+        assert!(matches!(
+            btree.insert_unique(&db, &txn, &*heap, &data[..4], dup_item_ptr),
+            Err(Error::UniqueViolation(_))
+        ));
+        db.abort_transaction(txn).unwrap();
+
+        drop(heap);
+        db.vacuum_full(0, 0, std::slice::from_ref(&btree)).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 0..20 {
+            assert_eq!(find_value(&mut txn, &*heap, i), Some((i == 5) as u32 * 2));
+        }
+        db.commit_transaction(txn).unwrap();
+
+        drop(heap);
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_update_indexed_key() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                Ok(LittleEndian::read_u32(a).cmp(&LittleEndian::read_u32(b)))
+            })
+            .unwrap();
+
+        let make_tuple = |key: u32| {
+            let mut data = vec![0u8; 64];
+            (&mut data[..]).write_u32::<LittleEndian>(key).unwrap();
+            data
+        };
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for i in 0..20 {
+            let data = make_tuple(i);
+            let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+            btree.insert(&db, &data[..4], item_ptr).unwrap();
+            item_ptrs.push(item_ptr);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        // the key changes, so the new version gets its own index entry
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let data = make_tuple(100);
+        let new_item_ptr = heap
+            .update_tuple(&db, &txn, item_ptrs[5], &data, false)
+            .unwrap();
+        btree.insert(&db, &data[..4], new_item_ptr).unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let key = make_tuple(5);
+        assert!(btree
+            .find_one(&db, &mut txn, &*heap, &key[..4])
+            .unwrap()
+            .is_none());
+        let key = make_tuple(100);
+        let tuple = btree
+            .find_one(&db, &mut txn, &*heap, &key[..4])
+            .unwrap()
+            .unwrap();
+        assert_eq!(LittleEndian::read_u32(tuple.get_data()), 100);
+        drop(tuple);
+
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let mut keys = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                keys.push(LittleEndian::read_u32(tuple.get_data()));
+            }
+            let mut expected = (0..20).filter(|i| *i != 5).collect::<Vec<_>>();
+            expected.push(100);
+            assert_eq!(keys, expected);
+        }
+
+        // the old key is free again
+        let data = make_tuple(5);
+        let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+        btree
+            .insert_unique(&db, &txn, &*heap, &data[..4], item_ptr)
+            .unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        drop(heap);
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_estimate_btree_stats() {
+        let (db, db_dir) = get_temp_db();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        let stats = btree.index_stats(&db).unwrap();
+        assert_eq!(stats.num_pages, 1);
+        assert_eq!(stats.height, 0);
+        assert_eq!(stats.distinct_keys, 0);
+
+        // 1000 distinct keys, each inserted twice
+        for i in 0..2000 {
+            let mut key = [0u8; 4];
+            (&mut key[..]).write_u32::<LittleEndian>(i / 2).unwrap();
+            btree
+                .insert(&db, &key, ItemPointer::new(1 + i as usize / 100, 1))
+                .unwrap();
+        }
+
+        let stats = btree.index_stats(&db).unwrap();
+        assert_eq!(stats.height, 2);
+        assert!(stats.num_pages > 16 && stats.num_pages < 80);
+        assert!(stats.distinct_keys > 500 && stats.distinct_keys < 2000);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_truncate_separator_keys() {
+        use crate::{am::comparators, catalog::KeyType};
+
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let truncated = db.create_typed_index(0, 1, KeyType::Bytes).unwrap();
+        let plain = db.create_index(0, 2, comparators::compare_bytes).unwrap();
+
+        // long keys that differ only in the first few bytes
+        let mut keys = Vec::new();
+        for i in 0..3000 {
+            let mut key = format!("{:08}", i * 7919 % 3000).into_bytes();
+            key.resize(200, b'x');
+            let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+            truncated.insert(&db, &key, item_ptr).unwrap();
+            plain.insert(&db, &key, item_ptr).unwrap();
+            keys.push(key);
+        }
+        txn.advance_command_id().unwrap();
+        keys.sort();
+
+        let truncated_stats = truncated.index_stats(&db).unwrap();
+        let plain_stats = plain.index_stats(&db).unwrap();
+        assert!(truncated_stats.height <= plain_stats.height);
+        assert!(truncated_stats.num_pages < plain_stats.num_pages);
+
+        for btree in &[truncated, plain] {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+
+            // point lookups go through the truncated separators
+            let key = &keys[1234];
+            iter.rescan(&db, Some(key), IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(tuple.get_data(), &key[..]);
+            drop(tuple);
+
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let mut scanned = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                scanned.push(tuple.get_data().to_vec());
+            }
+            assert_eq!(scanned, keys);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_compress_leaf_key_prefixes() {
+        use crate::{am::comparators, catalog::KeyType};
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        // long keys that differ only in the last few bytes
+        let mut keys = Vec::new();
+        for i in 0..3000 {
+            let mut key = vec![b'x'; 192];
+            key.extend_from_slice(format!("{:08}", i * 7919 % 3000).as_bytes());
+            keys.push(key);
+        }
+
+        {
+            let db = DB::open(&config).unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+            let compressed = db.create_typed_index(0, 1, KeyType::Bytes).unwrap();
+            let plain = db.create_index(0, 2, comparators::compare_bytes).unwrap();
+
+            for key in &keys {
+                let item_ptr = heap.insert_tuple(&db, &txn, key).unwrap();
+                compressed.insert(&db, key, item_ptr).unwrap();
+                plain.insert(&db, key, item_ptr).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+
+            let compressed_stats = compressed.index_stats(&db).unwrap();
+            let plain_stats = plain.index_stats(&db).unwrap();
+            assert!(compressed_stats.num_pages * 2 < plain_stats.num_pages);
+
+            // leaf splits are not logged yet so write the pages out before reopening
+            db.create_checkpoint().unwrap();
+        }
+        keys.sort();
+
+        let db = DB::open(&config).unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let btree = db.open_typed_index(0, 1).unwrap().unwrap();
+        let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+
+        for key in keys.iter().step_by(97) {
+            iter.rescan(&db, Some(key), IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(tuple.get_data(), &key[..]);
+        }
+
+        for dir in &[ScanDirection::Forward, ScanDirection::Backward] {
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let mut scanned = Vec::new();
+            while let Some(tuple) = iter.next(&db, *dir).unwrap() {
+                scanned.push(tuple.get_data().to_vec());
+            }
+            if *dir == ScanDirection::Backward {
+                scanned.reverse();
+            }
+            assert_eq!(scanned, keys);
+        }
+        drop(iter);
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_redo_inserts_into_compressed_leaves() {
+        use crate::catalog::KeyType;
+
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db.create_typed_index(0, 1, KeyType::Bytes).unwrap();
+        let get_key = |i: usize, suffix: &str| {
+            let mut key = vec![b'x'; 192];
+            key.extend_from_slice(format!("{:08}{}", i * 7919 % 1000, suffix).as_bytes());
+            key
+        };
+
+        for i in 0..1000 {
+            let key = get_key(i, "");
+            let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+            btree.insert(&db, &key, item_ptr).unwrap();
+        }
+        db.create_checkpoint().unwrap();
+        let num_pages = btree.index_stats(&db).unwrap().num_pages;
+
+        // no split is needed for these, which are logged with their full keys
+        let wal = db.get_wal();
+        let start_lsn = wal.current_lsn();
+        for i in (0..1000).step_by(100) {
+            let key = get_key(i, "a");
+            let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+            btree.insert(&db, &key, item_ptr).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+        assert_eq!(btree.index_stats(&db).unwrap().num_pages, num_pages);
+
+        let shandle = smgr
+            .open(smgr.relation_file(RelFileRef { db: 0, rel_id: 1 }))
+            .unwrap();
+        let read_pages = || {
+            (0..smgr.file_size_in_page(&shandle, ForkType::Main).unwrap())
+                .map(|page_num| {
+                    let page_ptr = bufmgr
+                        .fetch_page(&db, &shandle, ForkType::Main, page_num)
+                        .unwrap();
+                    let page = page_ptr
+                        .with_read(|page| Ok(page.buffer().to_vec()))
+                        .unwrap();
+                    bufmgr.release_page(page_ptr).unwrap();
+                    page
+                })
+                .collect::<Vec<_>>()
+        };
+        let live_pages = read_pages();
+
+        // the inserts are redone on the pages written by the checkpoint
+        bufmgr.invalidate_relation(shandle.file_ref()).unwrap();
+        wal.flush(None).unwrap();
+        wal.replay_logs(&db, start_lsn).unwrap();
+        assert_eq!(read_pages(), live_pages);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_keep_meta_page_resident() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path()).cache_capacity(16);
+        let db = DB::open(&config).unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+            .unwrap();
+
+        btree
+            .insert(&db, &[1u8; 4], ItemPointer::new(0, 1))
+            .unwrap();
+
+        // push a lot of pages through the cache
+        let data: &[u8] = &[1u8; 500];
+        for _ in 0..500 {
+            heap.insert_tuple(&db, &txn, data).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {}
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let file_ref = RelFileRef { db: 0, rel_id: 1 };
+        let bufmgr = db.get_buffer_manager();
+        assert!(!bufmgr.is_cached(RelFileRef { db: 0, rel_id: 0 }, ForkType::Main, 0));
+        assert!(bufmgr.is_cached(file_ref, ForkType::Main, 0));
+        assert!(bufmgr.is_cached(file_ref, ForkType::Main, 1));
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_cancel_btree_scan_on_statement_timeout() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                Ok(LittleEndian::read_u32(a).cmp(&LittleEndian::read_u32(b)))
+            })
+            .unwrap();
+        btree.build_empty(&db).unwrap();
+
+        for i in 0..1000u32 {
+            let mut key = Vec::new();
+            key.write_u32::<LittleEndian>(i).unwrap();
+            let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+            btree.insert(&db, &key, item_ptr).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        txn.set_statement_timeout(std::time::Duration::from_millis(0));
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+
+            let mut count = 0;
+            let result = loop {
+                match iter.next(&db, ScanDirection::Forward) {
+                    Ok(Some(_)) => count += 1,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+
+            assert!(matches!(result, Err(Error::QueryCanceled(_))));
+            assert!(count > 0 && count < 1000);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_scan_past_duplicate_keys() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                Ok(LittleEndian::read_u32(a).cmp(&LittleEndian::read_u32(b)))
+            })
+            .unwrap();
+        btree.build_empty(&db).unwrap();
+
+        // the duplicates of a key span several leaf pages
+        for i in 0..3000u32 {
+            let mut tuple = Vec::new();
+            tuple.write_u32::<LittleEndian>(i % 10).unwrap();
+            tuple.write_u32::<LittleEndian>(i).unwrap();
+            let item_ptr = heap.insert_tuple(&db, &txn, &tuple).unwrap();
+            btree.insert(&db, &tuple[..4], item_ptr).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            let mut key = Vec::new();
+            key.write_u32::<LittleEndian>(5).unwrap();
+
+            // all duplicates in insertion order
+            iter.rescan(
+                &db,
+                Some(&key),
+                IndexScanPredicate::new(|_: &[u8]| Ok(true)),
+            )
+            .unwrap();
+            let mut seqs = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                if LittleEndian::read_u32(&tuple.get_data()[..4]) != 5 {
+                    break;
+                }
+                seqs.push(LittleEndian::read_u32(&tuple.get_data()[4..]));
+            }
+            assert_eq!(seqs, (0..300).map(|i| i * 10 + 5).collect::<Vec<_>>());
+
+            // the first greater key
+            iter.rescan_after(&db, &key, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(tuple.get_data()[..4], 6u32.to_le_bytes());
+            assert_eq!(LittleEndian::read_u32(&tuple.get_data()[4..]), 6);
+            drop(tuple);
+
+            // page through the distinct keys
+            let mut keys = Vec::new();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                let key = tuple.get_data()[..4].to_vec();
+                drop(tuple);
+                keys.push(LittleEndian::read_u32(&key));
+                iter.rescan_after(&db, &key, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                    .unwrap();
+            }
+            assert_eq!(keys, (0..10).collect::<Vec<_>>());
+
+            // past the last key
+            key.clear();
+            key.write_u32::<LittleEndian>(9).unwrap();
+            iter.rescan_after(&db, &key, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_none());
+        }
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_repair_sibling_links() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let compare_keys =
+            |a: &[u8], b: &[u8]| Ok(LittleEndian::read_u32(a).cmp(&LittleEndian::read_u32(b)));
+        let last_leaf;
+
+        {
+            let db = DB::open(&config).unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+            db.create_index(0, 1, compare_keys).unwrap();
+            let btree = BTree::new(1, 0, compare_keys);
+
+            for i in 0..2000u32 {
+                let mut key = Vec::new();
+                key.write_u32::<LittleEndian>(i).unwrap();
+                let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+                btree.insert(&db, &key, item_ptr).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+            assert!(btree.verify_siblings(&db).unwrap().is_empty());
+
+            let page_lock = btree.get_endpoint(&db, true).unwrap();
+            let (_, _, page_num) = page_lock.borrow_page_guard().get_fork_and_num();
+            db.get_buffer_manager()
+                .release_page(page_lock.into_heads().page_ptr)
+                .unwrap();
+            last_leaf = page_num;
+
+            // break the back link of the rightmost leaf
+            let mut page_lock = btree.get_tree_page_write(&db, Some(last_leaf)).unwrap();
+            page_lock.with_page_guard_mut(|page_guard| {
+                let mut page_view = BTreeDataPageViewMut::new(page_guard.buffer_mut());
+                page_view.set_prev(12345);
+                page_guard.set_dirty(true);
+            });
+            db.get_buffer_manager()
+                .release_page(page_lock.into_heads().page_ptr)
+                .unwrap();
+
+            assert_eq!(btree.verify_siblings(&db).unwrap(), vec![last_leaf]);
+            assert_eq!(btree.repair_siblings(&db).unwrap(), vec![last_leaf]);
+            assert!(btree.verify_siblings(&db).unwrap().is_empty());
+
+            // break it again and let recovery repair it
+            let mut page_lock = btree.get_tree_page_write(&db, Some(last_leaf)).unwrap();
+            page_lock.with_page_guard_mut(|page_guard| {
+                let mut page_view = BTreeDataPageViewMut::new(page_guard.buffer_mut());
+                page_view.set_prev(0);
+                page_guard.set_dirty(true);
+            });
+            db.get_buffer_manager()
+                .release_page(page_lock.into_heads().page_ptr)
+                .unwrap();
+            db.flush_all_buffers().unwrap();
+        }
+
+        let db = DB::open(&config).unwrap();
+        let btree = BTree::new(1, 0, compare_keys);
+        assert!(btree.verify_siblings(&db).unwrap().is_empty());
+
+        // a backward scan visits all entries
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let index = db.open_index(0, 1, compare_keys).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = index.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let mut count = 0;
+            while iter.next(&db, ScanDirection::Backward).unwrap().is_some() {
+                count += 1;
+            }
+            assert_eq!(count, 2000);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_restore_marked_scan_position() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                Ok(LittleEndian::read_u32(a).cmp(&LittleEndian::read_u32(b)))
+            })
+            .unwrap();
+        btree.build_empty(&db).unwrap();
+
+        for i in 0..2000u32 {
+            let key = i.to_le_bytes();
+            let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+            btree.insert(&db, &key, item_ptr).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+
+            fn read<'a>(
+                db: &'a DB,
+                iter: &mut Box<dyn IndexScanIterator<'a> + 'a>,
+                n: usize,
+            ) -> Vec<u32> {
+                let mut values = Vec::new();
+                for _ in 0..n {
+                    let tuple = iter.next(db, ScanDirection::Forward).unwrap().unwrap();
+                    values.push(LittleEndian::read_u32(tuple.get_data()));
+                }
+                values
+            }
+
+            assert_eq!(read(&db, &mut iter, 5), (0..5).collect::<Vec<_>>());
+            let mark = iter.mark();
+            assert!(mark.page_num.is_some());
+
+            // advance past the end of the marked leaf page
+            let values = read(&db, &mut iter, 1000);
+            assert_eq!(values, (5..1005).collect::<Vec<_>>());
+
+            iter.restore(&db, mark).unwrap();
+            let tuple = iter.next(&db, ScanDirection::NoMovement).unwrap().unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), 4);
+            drop(tuple);
+            assert_eq!(read(&db, &mut iter, 1000), values);
+
+            // a mark taken before the scan starts restarts the scan
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+            let mark = iter.mark();
+            read(&db, &mut iter, 10);
+            iter.restore(&db, mark).unwrap();
+            assert_eq!(read(&db, &mut iter, 3), vec![0, 1, 2]);
+        }
+
+        db.commit_transaction(txn).unwrap();
+        assert!(db_dir.close().is_ok());
+    }
 }
@@ -19,6 +19,7 @@ pub struct BTreeInsertLog<'a> {
     fork: ForkType,
     page_num: usize,
     offset: u16,
+    // the tuple with its full key, which is stored without the key prefix of the page
     #[serde(with = "serde_bytes")]
     tuple_data: &'a [u8],
 }
@@ -29,7 +30,7 @@ impl<'a> BTreeInsertLog<'a> {
         let bufmgr = db.get_buffer_manager();
 
         let shandle = smgr.open(self.file_ref)?;
-        let page_ptr = bufmgr.fetch_page(db, &shandle, self.fork, self.page_num)?;
+        let page_ptr = bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.page_num)?;
 
         page_ptr.with_write(|page| {
             let buffer = page.buffer_mut();
@@ -43,7 +44,8 @@ impl<'a> BTreeInsertLog<'a> {
                 page_view.init_page();
             }
 
-            page_view.put_item(self.tuple_data, Some(self.offset as usize), false)?;
+            let tuple = super::encode_page_entry(&page_view, self.tuple_data)?;
+            page_view.put_item(&tuple, Some(self.offset as usize), false)?;
             page_view.set_lsn(lsn);
             page.set_dirty(true);
             Ok(())
@@ -70,8 +72,10 @@ impl BTreeNewRootLog {
         let bufmgr = db.get_buffer_manager();
 
         let shandle = smgr.open(self.file_ref)?;
-        let meta_page_ptr = bufmgr.fetch_page(db, &shandle, self.fork, self.meta_page_num)?;
-        let root_page_ptr = bufmgr.fetch_page(db, &shandle, self.fork, self.root_page_num)?;
+        let meta_page_ptr =
+            bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.meta_page_num)?;
+        let root_page_ptr =
+            bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.root_page_num)?;
 
         // restore the root page
         root_page_ptr.with_write(|page| {
@@ -142,7 +146,7 @@ impl<'a> BTreeLogRecord<'a> {
         page_num: usize,
         offset: usize,
         tuple_data: &[u8],
-    ) -> LogRecord {
+    ) -> LogRecord<'_> {
         let btree_insert_record = BTreeInsertLog {
             file_ref,
             fork,
@@ -3,7 +3,7 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crate::{
     storage::{
         consts::PAGE_SIZE, DiskPageReader, DiskPageWriter, ItemPageReader, ItemPageWriter,
-        PinnedPagePtr,
+        PageKind, PinnedPagePtr,
     },
     Result,
 };
@@ -22,6 +22,8 @@ bitflags! {
         const IS_LEAF = 0b0000_0001;
         const IS_META = 0b0000_0010;
         const IS_ROOT = 0b0000_0100;
+        /// The separator key of the downlink to the page is kept after the high key
+        const HAS_LOW_KEY = 0b0000_1000;
     }
 }
 
@@ -31,15 +33,14 @@ const P_LEVEL: usize = P_NEXT + 8;
 const P_FLAGS: usize = P_LEVEL + 4;
 const P_PAYLOAD: usize = P_FLAGS + 4;
 
-const BTREE_META_MAGIC: u32 = 0x4254_7239u32;
+pub(super) const BTREE_META_MAGIC: u32 = 0x4254_7239u32;
 const P_META_MAGIC: usize = 0;
 const P_META_ROOT: usize = P_META_MAGIC + 4;
 
 pub mod views {
     pub use super::{
         BTreeDataPageReader, BTreeDataPageView, BTreeDataPageViewMut, BTreeMetaPageReader,
-        BTreeMetaPageView, BTreeMetaPageViewMut, BTreePageReader, BTreePageView, BTreePageViewMut,
-        BTreePageWriter,
+        BTreeMetaPageView, BTreeMetaPageViewMut, BTreePageReader, BTreePageWriter,
     };
 }
 
@@ -93,9 +94,17 @@ pub trait BTreePageReader: DiskPageReader {
         1
     }
 
-    fn first_key_offset(&self) -> usize {
+    fn has_low_key(&self) -> bool {
+        self.get_flags().contains(BTreePageFlags::HAS_LOW_KEY)
+    }
+
+    fn low_key_offset(&self) -> usize {
         self.high_key_offset() + if self.is_rightmost() { 0 } else { 1 }
     }
+
+    fn first_key_offset(&self) -> usize {
+        self.low_key_offset() + if self.has_low_key() { 1 } else { 0 }
+    }
 }
 
 pub trait BTreePageWriter: BTreePageReader + DiskPageWriter {
@@ -104,6 +113,8 @@ pub trait BTreePageWriter: BTreePageReader + DiskPageWriter {
     }
 
     fn init_btree_page(&mut self) {
+        self.set_page_kind(PageKind::BTree);
+
         for i in self.get_disk_page_payload_mut()[P_PREV..P_PAYLOAD].iter_mut() {
             *i = 0;
         }
@@ -159,7 +170,7 @@ pub trait BTreePageWriter: BTreePageReader + DiskPageWriter {
 pub trait BTreeMetaPageReader: BTreePageReader {
     fn get_magic(&self) -> u32 {
         let buf = self.get_btree_page_payload();
-        (&buf[P_META_MAGIC..]).read_u32::<LittleEndian>().unwrap() as u32
+        (&buf[P_META_MAGIC..]).read_u32::<LittleEndian>().unwrap()
     }
 
     fn get_root(&self) -> usize {
@@ -393,3 +404,4 @@ impl<'a> ItemPageWriter for BTreeDataPageViewMut<'a> {
 }
 
 impl<'a> BTreePageWriter for BTreeDataPageViewMut<'a> {}
+impl<'a> BTreeDataPageReader for BTreeDataPageViewMut<'a> {}
@@ -0,0 +1,162 @@
+//! Key comparators for common key types
+//!
+//! Integers are encoded in big-endian so that the keys of unsigned integers also sort correctly
+//! as bytes. All comparators return `Error::InvalidArgument` for malformed keys.
+
+use crate::{Error, Result};
+
+use std::cmp::Ordering;
+
+use byteorder::{BigEndian, ByteOrder};
+
+fn check_len(key: &[u8], len: usize, type_name: &str) -> Result<()> {
+    if key.len() != len {
+        Err(Error::InvalidArgument(format!(
+            "invalid {} key length {}, expected {}",
+            type_name,
+            key.len(),
+            len
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn compare_i32(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    check_len(a, 4, "i32")?;
+    check_len(b, 4, "i32")?;
+    Ok(BigEndian::read_i32(a).cmp(&BigEndian::read_i32(b)))
+}
+
+pub fn compare_i64(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    check_len(a, 8, "i64")?;
+    check_len(b, 8, "i64")?;
+    Ok(BigEndian::read_i64(a).cmp(&BigEndian::read_i64(b)))
+}
+
+pub fn compare_u32(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    check_len(a, 4, "u32")?;
+    check_len(b, 4, "u32")?;
+    Ok(BigEndian::read_u32(a).cmp(&BigEndian::read_u32(b)))
+}
+
+pub fn compare_u64(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    check_len(a, 8, "u64")?;
+    check_len(b, 8, "u64")?;
+    Ok(BigEndian::read_u64(a).cmp(&BigEndian::read_u64(b)))
+}
+
+pub fn compare_bytes(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    Ok(a.cmp(b))
+}
+
+/// Compare UTF-8 strings by code points.
+pub fn compare_str(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    let decode = |key| {
+        std::str::from_utf8(key)
+            .map_err(|e| Error::InvalidArgument(format!("invalid UTF-8 key: {}", e)))
+    };
+
+    Ok(decode(a)?.cmp(decode(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        am::index::IndexScanPredicate, concurrency::IsolationLevel, storage::ScanDirection,
+        test_util::get_temp_db,
+    };
+
+    #[test]
+    fn can_compare_signed_integers() {
+        let mut keys = vec![5i32, -1, i32::MIN, 0, -300, i32::MAX, 300]
+            .into_iter()
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        // -1 is 0xffffffff and sorts last as bytes
+        assert_eq!(
+            compare_bytes(&keys[1], &keys[0]).unwrap(),
+            Ordering::Greater
+        );
+        assert_eq!(compare_i32(&keys[1], &keys[0]).unwrap(), Ordering::Less);
+
+        keys.sort_by(|a, b| compare_i32(a, b).unwrap());
+        let values = keys
+            .iter()
+            .map(|key| BigEndian::read_i32(key))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![i32::MIN, -300, -1, 0, 5, 300, i32::MAX]);
+
+        let a = (-2i64).to_be_bytes();
+        let b = 1i64.to_be_bytes();
+        assert_eq!(compare_i64(&a, &b).unwrap(), Ordering::Less);
+        assert_eq!(compare_u64(&a, &b).unwrap(), Ordering::Greater);
+        assert_eq!(
+            compare_u32(&3u32.to_be_bytes(), &256u32.to_be_bytes()).unwrap(),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn can_reject_malformed_keys() {
+        assert!(matches!(
+            compare_i32(&[0u8; 3], &[0u8; 4]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            compare_u64(&[0u8; 8], &[0u8; 4]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            compare_str(b"abc", &[0xff, 0xfe]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(
+            compare_str("ab".as_bytes(), "é".as_bytes()).unwrap(),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn can_scan_index_with_signed_keys() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db.create_index(0, 1, compare_i32).unwrap();
+
+        for i in -150..150i32 {
+            let key = (i * 37 % 150).to_be_bytes();
+            let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+            btree.insert(&db, &key, item_ptr).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            let start_key = (-10i32).to_be_bytes();
+            iter.rescan(
+                &db,
+                Some(&start_key),
+                IndexScanPredicate::new(|_: &[u8]| Ok(true)),
+            )
+            .unwrap();
+
+            let mut prev = -10;
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                let value = BigEndian::read_i32(tuple.get_data());
+                assert!(value >= prev);
+                prev = value;
+                count += 1;
+            }
+
+            let expected = (-150..150i32).filter(|i| i * 37 % 150 >= -10).count();
+            assert_eq!(count, expected);
+        }
+
+        db.commit_transaction(txn).unwrap();
+        db_dir.close().unwrap();
+    }
+}
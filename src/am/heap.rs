@@ -1,27 +1,45 @@
 mod heap_log;
 mod heap_page;
+mod sync_scan;
 
 use crate::{
-    concurrency::{Snapshot, Transaction, TransactionStatus, XID},
+    catalog,
+    concurrency::{
+        CommandId, InterruptCheck, IsolationLevel, LockMode, Snapshot, Transaction,
+        TransactionStatus, XID,
+    },
     storage::{
-        consts::PAGE_SIZE, BufferManager, DiskPageWriter, ForkType, ItemPageReader, ItemPageWriter,
-        ItemPointer, PinnedPagePtr, RelFileRef, RelationWithStorage, ScanDirection, StorageHandle,
-        Table, TableScanIterator, Tuple, TuplePtr,
+        consts::PAGE_SIZE, BufferManager, DiskPageReader, DiskPageWriter, ForkType, ItemPageReader,
+        ItemPageWriter, ItemPointer, PageBuffer, PinnedPagePtr, RelFileRef, RelationWithStorage,
+        ScanDirection, StorageHandle, StorageManager, Table, TableScanIterator, TableScanMark,
+        TableScanPredicate, Tuple, TuplePtr, LINE_POINTER_SIZE, MAX_ITEM_SIZE,
     },
-    Error, Relation, RelationEntry, RelationKind, Result, DB, OID,
+    wal::LogRecord,
+    Error, Relation, RelationEntry, RelationKind, Result, TupleDescriptor, DB, OID,
 };
 
-use self::heap_page::{HeapPageView, HeapPageViewMut};
+use self::heap_page::{
+    HeapPageReader, HeapPageView, HeapPageViewMut, HeapPageWriter, HEAP_PAGE_HEADER_SIZE,
+};
 
 pub(crate) use self::heap_log::HeapLogRecord;
-
-use std::{borrow::Cow, sync::Mutex};
+pub use self::sync_scan::{SyncScan, SyncScanRegistry};
+
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    hash::BuildHasher,
+    sync::Mutex,
+};
 
 use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 
+/// Largest item a heap page can hold, which is smaller than for other pages because of the heap
+/// page header.
 fn tuple_size_limit() -> usize {
-    PAGE_SIZE
+    MAX_ITEM_SIZE - HEAP_PAGE_HEADER_SIZE
 }
 
 bitflags! {
@@ -30,6 +48,11 @@ bitflags! {
         const MAX_XID_COMMITTED = 0b0000_0010;
         const MIN_XID_INVALID = 0b0000_0100;
         const MAX_XID_INVALID = 0b0000_1000;
+        // a newer version put on the page of the older one by an update. no index entries point
+        // to it, it is found through the older version instead
+        const HEAP_ONLY = 0b0001_0000;
+        // the data is a row built with the tuple descriptor of the table
+        const HAS_ATTRS = 0b0010_0000;
     }
 }
 
@@ -43,8 +66,12 @@ struct HeapTuple<'a> {
     flags: u32,
     min_xid: XID,
     max_xid: XID,
+    command_id: CommandId,
     #[serde(borrow)]
     data: Cow<'a, [u8]>,
+    // newer version of the tuple written by the update that deletes this one, the offset is 0 if
+    // there is none. the pointer has a fixed size so that it can be set in place
+    next_version: ItemPointer,
 }
 
 impl<'a> HeapTuple<'a> {
@@ -55,7 +82,9 @@ impl<'a> HeapTuple<'a> {
             flags: 0,
             min_xid: XID::default(),
             max_xid: XID::default(),
+            command_id: 0,
             data: data.into(),
+            next_version: ItemPointer::default(),
         }
     }
 
@@ -67,43 +96,113 @@ impl<'a> HeapTuple<'a> {
         HeapTuple {
             table_id: self.table_id,
             ptr: self.ptr,
-            flags: 0,
+            flags: self.flags & HeapTupleFlags::HAS_ATTRS.bits(),
             min_xid: self.min_xid,
             max_xid: self.max_xid,
+            command_id: self.command_id,
             data: Cow::from(self.data.to_vec()),
+            next_version: self.next_version,
+        }
+    }
+
+    fn get_next_version(&self) -> Option<ItemPointer> {
+        if self.next_version.offset == 0 {
+            None
+        } else {
+            Some(self.next_version)
+        }
+    }
+
+    /// Mark the tuple deleted by the transaction. The link to a newer version left by an aborted
+    /// update is cleared, an update sets it again after the delete.
+    fn mark_deleted(&mut self, xid: XID) {
+        let mut flags = HeapTupleFlags::from_bits_truncate(self.flags);
+        flags.remove(HeapTupleFlags::MAX_XID_INVALID | HeapTupleFlags::MAX_XID_COMMITTED);
+        self.max_xid = xid;
+        self.flags = flags.bits();
+        self.next_version = ItemPointer::default();
+    }
+
+    /// Test if the heap tuple cannot be visible to any running or future transaction. `oldest_xid`
+    /// is the oldest XID that may still be running.
+    fn is_dead(&self, db: &DB, oldest_xid: XID) -> Result<bool> {
+        let flags = HeapTupleFlags::from_bits_truncate(self.flags);
+        let txnmgr = db.get_transaction_manager();
+
+        if flags.contains(HeapTupleFlags::MIN_XID_INVALID) || self.min_xid.is_invalid() {
+            return Ok(true);
         }
+
+        if !flags.contains(HeapTupleFlags::MIN_XID_COMMITTED) {
+            if self.min_xid >= oldest_xid {
+                return Ok(false);
+            }
+
+            if txnmgr.get_transaction_status(self.min_xid)? != TransactionStatus::Committed {
+                // the inserting transaction is aborted
+                return Ok(true);
+            }
+        }
+
+        if flags.contains(HeapTupleFlags::MAX_XID_INVALID)
+            || self.max_xid.is_invalid()
+            || self.max_xid >= oldest_xid
+        {
+            return Ok(false);
+        }
+
+        Ok(flags.contains(HeapTupleFlags::MAX_XID_COMMITTED)
+            || txnmgr.get_transaction_status(self.max_xid)? == TransactionStatus::Committed)
     }
 
-    /// Test if the heap tuple is visible for the given snapshot
-    fn is_visible(&self, db: &DB, snapshot: &Snapshot, current_xid: XID) -> Result<(bool, u32)> {
+    /// Test if the heap tuple is visible in the given context. Under read uncommitted, the
+    /// changes of other in-progress transactions are visible regardless of the snapshot.
+    fn is_visible(&self, db: &DB, ctx: &VisibilityContext) -> Result<(bool, u32)> {
+        let VisibilityContext {
+            snapshot,
+            xid: current_xid,
+            ..
+        } = *ctx;
         let flags = HeapTupleFlags::from_bits_truncate(self.flags);
         let mut new_flags = HeapTupleFlags::empty();
+        let read_uncommitted = snapshot.isolation_level() == IsolationLevel::ReadUncommitted;
+        let is_in_progress = |xid: XID| {
+            if read_uncommitted {
+                db.get_transaction_manager().is_transaction_active(xid)
+            } else {
+                snapshot.is_xid_in_progress(xid)
+            }
+        };
 
         if !flags.contains(HeapTupleFlags::MIN_XID_COMMITTED) {
             if self.min_xid.is_invalid() {
                 return Ok((false, 0));
-            } else if self.min_xid == current_xid {
+            } else if self.min_xid == current_xid || snapshot.is_current_xid(self.min_xid) {
+                if self.command_id >= snapshot.command_id() {
+                    // inserted by the current command or later
+                    return Ok((false, 0));
+                }
+
                 if flags.contains(HeapTupleFlags::MAX_XID_INVALID) {
                     // not deleted
                     return Ok((true, 0));
                 }
 
-                if self.max_xid != current_xid {
+                if self.max_xid != current_xid && !snapshot.is_current_xid(self.max_xid) {
                     // impossible (delete a tuple inserted by an in-progress transaction)
                     return Ok((false, HeapTupleFlags::MAX_XID_INVALID.bits()));
                 }
 
                 // the tuple is deleted by the current transaction
                 return Ok((false, 0)); // XXX: determine whether the tuple is deleted before or after the scan
-            } else if snapshot.is_xid_in_progress(self.min_xid) {
-                // inserted by another in-progress transaction
-                return Ok((false, 0));
+            } else if is_in_progress(self.min_xid) {
+                // inserted by another in-progress transaction, only visible to dirty reads
+                if !read_uncommitted {
+                    return Ok((false, 0));
+                }
             }
             // by here, the inserting transaction must be committed or aborted
-            else if db
-                .get_transaction_manager()
-                .get_transaction_status(self.min_xid)?
-                == TransactionStatus::Committed
+            else if ctx.get_transaction_status(db, self.min_xid)? == TransactionStatus::Committed
             {
                 new_flags |= HeapTupleFlags::MIN_XID_COMMITTED;
             } else {
@@ -111,41 +210,39 @@ impl<'a> HeapTuple<'a> {
                 return Ok((false, HeapTupleFlags::MIN_XID_INVALID.bits()));
             }
         } else {
-            // the transaction is marked committed but is in-progress according to the snapshot
-            if snapshot.is_xid_in_progress(self.min_xid) {
+            // the transaction is marked committed but is in-progress according to the snapshot.
+            // dirty reads see the tuple either way
+            if !read_uncommitted && is_in_progress(self.min_xid) {
                 return Ok((false, 0));
             }
         }
 
-        // by here, the inserting transaction is committed
+        // by here, the inserting transaction is committed (or in progress for dirty reads)
         if flags.contains(HeapTupleFlags::MAX_XID_INVALID) {
             // the transaction that deletes the tuple is invalid or aborted
             return Ok((true, new_flags.bits()));
         }
 
         if !flags.contains(HeapTupleFlags::MAX_XID_COMMITTED) {
-            if self.max_xid == current_xid {
+            if self.max_xid == current_xid || snapshot.is_current_xid(self.max_xid) {
                 return Ok((false, new_flags.bits())); // XXX: determine whether the tuple is deleted before or after the scan
             }
 
-            if snapshot.is_xid_in_progress(self.max_xid) {
+            if is_in_progress(self.max_xid) {
                 // the deleting transaction is still in-progress
-                return Ok((true, new_flags.bits()));
+                return Ok((!read_uncommitted, new_flags.bits()));
             }
 
-            if db
-                .get_transaction_manager()
-                .get_transaction_status(self.max_xid)?
-                != TransactionStatus::Committed
-            {
+            if ctx.get_transaction_status(db, self.max_xid)? != TransactionStatus::Committed {
                 // the deleting transaction is aborted
                 return Ok((true, HeapTupleFlags::MAX_XID_INVALID.bits()));
             } else {
                 return Ok((false, HeapTupleFlags::MAX_XID_COMMITTED.bits()));
             }
         } else {
-            // the deleting transaction is committed but is in-progress in the snapshot
-            if snapshot.is_xid_in_progress(self.max_xid) {
+            // the deleting transaction is committed but is in-progress in the snapshot. dirty
+            // reads see the delete either way
+            if !read_uncommitted && is_in_progress(self.max_xid) {
                 return Ok((true, new_flags.bits()));
             }
         }
@@ -153,6 +250,55 @@ impl<'a> HeapTuple<'a> {
         // the deleteing transaction is committed
         Ok((false, new_flags.bits()))
     }
+
+    /// Test if the latest version of the heap tuple is live for a dirty snapshot. The tuple is
+    /// reported as live if it is inserted or deleted by another in-progress transaction, along
+    /// with the XID of that transaction.
+    fn is_visible_dirty(
+        &self,
+        db: &DB,
+        snapshot: &Snapshot,
+        current_xid: XID,
+    ) -> Result<(bool, Option<XID>)> {
+        let flags = HeapTupleFlags::from_bits_truncate(self.flags);
+        let txnmgr = db.get_transaction_manager();
+        let is_current = |xid: XID| xid == current_xid || snapshot.is_current_xid(xid);
+
+        if !flags.contains(HeapTupleFlags::MIN_XID_COMMITTED) {
+            if flags.contains(HeapTupleFlags::MIN_XID_INVALID) || self.min_xid.is_invalid() {
+                return Ok((false, None));
+            } else if is_current(self.min_xid) {
+                // inserted by the current transaction, live unless it also deletes the tuple
+                let deleted = !flags.contains(HeapTupleFlags::MAX_XID_INVALID)
+                    && !self.max_xid.is_invalid()
+                    && is_current(self.max_xid);
+                return Ok((!deleted, None));
+            } else if txnmgr.is_transaction_active(self.min_xid) {
+                return Ok((true, Some(self.min_xid)));
+            } else if txnmgr.get_transaction_status(self.min_xid)? != TransactionStatus::Committed {
+                // the status is final once the transaction is not active
+                return Ok((false, None));
+            }
+        }
+
+        // by here, the inserting transaction is committed
+        if flags.contains(HeapTupleFlags::MAX_XID_INVALID) || self.max_xid.is_invalid() {
+            return Ok((true, None));
+        }
+
+        if flags.contains(HeapTupleFlags::MAX_XID_COMMITTED) || is_current(self.max_xid) {
+            return Ok((false, None));
+        }
+
+        if txnmgr.is_transaction_active(self.max_xid) {
+            return Ok((true, Some(self.max_xid)));
+        }
+
+        Ok((
+            txnmgr.get_transaction_status(self.max_xid)? != TransactionStatus::Committed,
+            None,
+        ))
+    }
 }
 
 struct BufferHeapTuple<'a> {
@@ -170,6 +316,15 @@ impl<'a> Tuple for BufferHeapTuple<'a> {
         self.tuple.ptr
     }
 
+    fn get_attr(&self, index: usize) -> Option<&[u8]> {
+        let flags = HeapTupleFlags::from_bits_truncate(self.tuple.flags);
+        if flags.contains(HeapTupleFlags::HAS_ATTRS) {
+            catalog::get_attr(&self.tuple.data, index)
+        } else {
+            None
+        }
+    }
+
     fn materialize<'ret>(self: Box<Self>) -> Box<dyn Tuple + 'ret> {
         let tuple = BufferHeapTuple {
             tuple: self.tuple.materialize(),
@@ -195,27 +350,163 @@ impl<'a> Drop for BufferHeapTuple<'a> {
     }
 }
 
+/// What a visibility check needs to know about the reading transaction
+#[derive(Clone, Copy)]
+struct VisibilityContext<'a> {
+    snapshot: &'a Snapshot,
+    xid: XID,
+    // final transaction statuses prefetched for the current page
+    statuses: Option<&'a HashMap<XID, TransactionStatus>>,
+}
+
+impl<'a> VisibilityContext<'a> {
+    fn new(snapshot: &'a Snapshot, xid: XID) -> Self {
+        Self {
+            snapshot,
+            xid,
+            statuses: None,
+        }
+    }
+
+    fn get_transaction_status(&self, db: &DB, xid: XID) -> Result<TransactionStatus> {
+        match self.statuses.and_then(|statuses| statuses.get(&xid)) {
+            Some(status) => Ok(*status),
+            None => db.get_transaction_manager().get_transaction_status(xid),
+        }
+    }
+}
+
+/// Outcome of trying to delete a tuple
+enum DeleteOutcome {
+    Deleted,
+    /// The tuple is being deleted or updated by the in-progress transaction
+    BeingModified(XID),
+    /// The tuple is updated by a committed transaction to the newer version
+    Updated(ItemPointer),
+}
+
+/// How to choose the page for a new tuple
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapInsertStrategy {
+    /// Insert into the page used by the last insert until it is full
+    InsertHint,
+    /// Pick a random page with enough free space to spread concurrent inserts across pages
+    Spread,
+}
+
+/// Number of pages with free space to keep around for spreading inserts
+const HEAP_INSERT_SPREAD: usize = 4;
+
+/// Number of pages rewritten by `vacuum_full` that are appended and logged together
+const REWRITE_BATCH_PAGES: usize = 8;
+
+thread_local! {
+    static INSERT_RANDOM_STATE: Cell<u64> =
+        Cell::new(RandomState::new().hash_one(std::thread::current().id()) | 1);
+}
+
+fn next_insert_random() -> u64 {
+    INSERT_RANDOM_STATE.with(|state| {
+        // xorshift64
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
 pub struct Heap {
     rel_entry: RelationEntry,
     shandle: Mutex<Option<StorageHandle>>,
     insert_hint: Mutex<Option<usize>>,
+    insert_strategy: HeapInsertStrategy,
+    // percentage of a page filled by inserts
+    fillfactor: u8,
+    tuple_desc: Option<TupleDescriptor>,
+    // free space of pages, loaded on the first insert with the spread strategy
+    free_space_map: Mutex<Option<BTreeMap<usize, usize>>>,
+    // file and truncation count of the storage when the insert hint and the free space map are
+    // valid
+    hint_storage: Mutex<Option<(RelFileRef, usize)>>,
 }
 
 impl Heap {
-    pub fn new(rel_id: OID, db: OID) -> Self {
+    pub fn new(rel_id: OID, db: OID, insert_strategy: HeapInsertStrategy) -> Self {
         let rel_entry = RelationEntry::new(rel_id, db, RelationKind::Table);
 
         Self {
             rel_entry,
             shandle: Mutex::new(None),
             insert_hint: Mutex::new(None),
+            insert_strategy,
+            fillfactor: 100,
+            tuple_desc: None,
+            free_space_map: Mutex::new(None),
+            hint_storage: Mutex::new(None),
+        }
+    }
+
+    /// Leave `100 - fillfactor` percent of each page free when inserting into an existing page.
+    pub fn fillfactor(mut self, fillfactor: u8) -> Self {
+        self.fillfactor = fillfactor;
+        self
+    }
+
+    /// Store the rows as built by `TupleDescriptor::form_tuple` so that the attributes can be read
+    /// with `Tuple::get_attr`.
+    pub fn tuple_descriptor(mut self, tuple_desc: TupleDescriptor) -> Self {
+        self.tuple_desc = Some(tuple_desc);
+        self
+    }
+
+    fn check_row(&self, data: &[u8]) -> Result<()> {
+        match &self.tuple_desc {
+            Some(tuple_desc) => tuple_desc.check_tuple(data),
+            None => Ok(()),
         }
     }
 
-    fn prepare_heap_tuple_for_insert<'a>(&self, xid: XID, data: &'a [u8]) -> HeapTuple<'a> {
+    // free space of a page that inserts leave untouched
+    fn reserved_space(&self) -> usize {
+        PAGE_SIZE * (100 - self.fillfactor as usize) / 100
+    }
+
+    /// Create the init fork of an unlogged heap, which holds a single empty page.
+    pub(crate) fn create_init_fork(&self, smgr: &StorageManager) -> Result<()> {
+        self.with_storage(smgr, |storage| {
+            smgr.create(storage, ForkType::Init, false)?;
+
+            let mut buffer = [0u8; PAGE_SIZE];
+            let mut page_view = HeapPageViewMut::new(&mut buffer);
+            page_view.init_page();
+            page_view.set_checksum();
+
+            smgr.write(storage, ForkType::Init, 0, &buffer)?;
+            smgr.sync(storage, ForkType::Init)
+        })
+    }
+
+    /// Size of the largest tuple that can be stored in a heap page
+    pub fn max_inline_tuple_size() -> usize {
+        let header_size = bincode::serialized_size(&HeapTuple::new(0, &[])).unwrap() as usize;
+        tuple_size_limit() - header_size
+    }
+
+    fn prepare_heap_tuple_for_insert<'a>(
+        &self,
+        xid: XID,
+        command_id: CommandId,
+        data: &'a [u8],
+    ) -> HeapTuple<'a> {
         let mut htup = HeapTuple::new(self.rel_id(), data).materialize();
-        let flags = HeapTupleFlags::MAX_XID_INVALID;
+        let mut flags = HeapTupleFlags::MAX_XID_INVALID;
+        if self.tuple_desc.is_some() {
+            flags |= HeapTupleFlags::HAS_ATTRS;
+        }
         htup.min_xid = xid;
+        htup.command_id = command_id;
         htup.flags = flags.bits();
         htup
     }
@@ -230,6 +521,178 @@ impl Heap {
         *guard = Some(hint);
     }
 
+    /// Choose a page with enough free space from the free space map. Return `None` if the heap
+    /// should be extended.
+    fn get_page_from_fsm(&self, db: &DB, tuple_len: usize) -> Result<Option<usize>> {
+        let mut guard = self.free_space_map.lock().unwrap();
+
+        if guard.is_none() {
+            let smgr = db.get_storage_manager();
+            let bufmgr = db.get_buffer_manager();
+            let mut fsm = BTreeMap::new();
+
+            for page_num in 0..self.get_size_in_page(smgr)? {
+                let page_ptr = self.with_storage(smgr, |storage| {
+                    bufmgr.fetch_page(db, storage, ForkType::Main, page_num)
+                })?;
+                let free_space = HeapPageView::with_page(&page_ptr, |page_view| {
+                    Ok(if page_view.is_new() {
+                        tuple_size_limit()
+                    } else {
+                        page_view.get_free_space()
+                    })
+                })?;
+                bufmgr.release_page(page_ptr)?;
+
+                fsm.insert(page_num, free_space);
+            }
+
+            *guard = Some(fsm);
+        }
+
+        let candidates = match &*guard {
+            Some(fsm) => fsm
+                .iter()
+                .filter(|(_, free_space)| **free_space >= tuple_len)
+                .map(|(page_num, _)| *page_num)
+                .take(HEAP_INSERT_SPREAD)
+                .collect::<Vec<_>>(),
+            None => unreachable!(),
+        };
+
+        if candidates.len() < HEAP_INSERT_SPREAD {
+            // extend the heap so that there are more pages to spread the inserts
+            Ok(None)
+        } else {
+            let index = next_insert_random() as usize % candidates.len();
+            Ok(Some(candidates[index]))
+        }
+    }
+
+    fn record_free_space(&self, page_num: usize, free_space: usize) {
+        if self.insert_strategy != HeapInsertStrategy::Spread {
+            return;
+        }
+
+        let mut guard = self.free_space_map.lock().unwrap();
+        if let Some(fsm) = &mut *guard {
+            fsm.insert(page_num, free_space);
+        }
+    }
+
+    fn get_target_page(&self, db: &DB, tuple_len: usize) -> Result<Option<usize>> {
+        // the pages may be gone if the heap is truncated since the hints are recorded
+        let smgr = db.get_storage_manager();
+        let storage_state = self.with_storage(smgr, |storage| {
+            Ok((storage.file_ref(), storage.truncation_count()))
+        })?;
+        {
+            let mut guard = self.hint_storage.lock().unwrap();
+            if *guard != Some(storage_state) {
+                *self.insert_hint.lock().unwrap() = None;
+                *self.free_space_map.lock().unwrap() = None;
+                *guard = Some(storage_state);
+            }
+        }
+
+        match self.insert_strategy {
+            HeapInsertStrategy::InsertHint => Ok(self.get_insert_hint()),
+            HeapInsertStrategy::Spread => self.get_page_from_fsm(db, tuple_len),
+        }
+    }
+
+    /// Append the log record of a change to a page and set the page LSN. The changes to unlogged
+    /// heaps are not logged since the heaps are reset from their init forks on crash recovery.
+    fn log_page_change(
+        &self,
+        db: &DB,
+        xid: XID,
+        record: LogRecord,
+        page_view: &mut HeapPageViewMut,
+    ) -> Result<()> {
+        if db.get_storage_manager().is_unlogged(self.storage_file(db)) {
+            return Ok(());
+        }
+
+        let (_, lsn) = db.get_wal().append(xid, record)?;
+        page_view.set_lsn(lsn);
+        Ok(())
+    }
+
+    /// Get the file that stores the heap, which is logged with the changes to its pages.
+    fn storage_file(&self, db: &DB) -> RelFileRef {
+        // resolved without the storage handle, which may be locked by others while they wait for
+        // the page locks held here
+        db.get_storage_manager().relation_file(RelFileRef {
+            db: self.rel_db(),
+            rel_id: self.rel_id(),
+        })
+    }
+
+    /// Copy the page before a change if WAL consistency check is enabled for the heap.
+    fn get_consistency_check_image(
+        &self,
+        db: &DB,
+        page_view: &HeapPageViewMut,
+    ) -> Option<Box<PageBuffer>> {
+        let file_ref = RelFileRef {
+            db: self.rel_db(),
+            rel_id: self.rel_id(),
+        };
+
+        if db.get_config().wal_consistency_check.contains(&file_ref) {
+            Some(Box::new(*page_view.get_page_buffer()))
+        } else {
+            None
+        }
+    }
+
+    /// Redo the log record on the page image taken before the change and compare the result with
+    /// the live page. Both pages are compared before the new LSN is set. If the check fails, the
+    /// page is restored from the image because the change is never logged.
+    fn check_wal_consistency(
+        &self,
+        image: Option<Box<PageBuffer>>,
+        record: &LogRecord,
+        xid: XID,
+        page_view: &mut HeapPageViewMut,
+    ) -> Result<()> {
+        let image = match image {
+            Some(image) => image,
+            None => return Ok(()),
+        };
+
+        let result = (|| {
+            // redo the record as it is written to the log
+            let mut redone = image.clone();
+            let record_buf = bincode::serialize(record)?;
+            match bincode::deserialize::<LogRecord>(&record_buf)? {
+                LogRecord::Heap(heap_log) => {
+                    heap_log.redo_page(&mut HeapPageViewMut::new(&mut redone), xid)?
+                }
+                _ => return Err(Error::InvalidArgument("not a heap log record".to_owned())),
+            }
+
+            if redone[..] != page_view.get_page_buffer()[..] {
+                return Err(Error::DataCorrupted(format!(
+                    "WAL consistency check failed for {:?} on relation {}",
+                    record,
+                    RelFileRef {
+                        db: self.rel_db(),
+                        rel_id: self.rel_id(),
+                    }
+                )));
+            }
+
+            Ok(())
+        })();
+
+        if result.is_err() {
+            page_view.get_page_buffer_mut().copy_from_slice(&image[..]);
+        }
+        result
+    }
+
     fn with_page_for_tuple<F, R>(&self, db: &DB, tuple_len: usize, f: F) -> Result<R>
     where
         F: Copy + FnOnce(&mut HeapPageViewMut, usize) -> Result<(R, bool)>,
@@ -244,8 +707,10 @@ impl Heap {
 
         let smgr = db.get_storage_manager();
         let bufmgr = db.get_buffer_manager();
-        // try to use the page for the last insert
-        let mut target_page_num = self.get_insert_hint();
+        // a new page always takes the tuple, existing pages keep the reserved space free
+        let required_space = tuple_len + self.reserved_space();
+        // try to use the page for the last insert or a page from the free space map
+        let mut target_page_num = self.get_target_page(db, required_space)?;
 
         while let Some(page_num) = target_page_num {
             let page_ptr = self.with_storage(smgr, |storage| {
@@ -262,7 +727,7 @@ impl Heap {
                 }
 
                 let free_space = page_view.get_free_space();
-                let result = if free_space >= tuple_len {
+                let result = if free_space >= required_space {
                     // enough space, go with this page
                     let (result, modified) = f(&mut page_view, page_num)?;
                     dirty = dirty || modified;
@@ -272,14 +737,19 @@ impl Heap {
                     None
                 };
 
+                let free_space = page_view.get_free_space();
+
                 if dirty {
                     page.set_dirty(true);
                 }
-                Ok(result)
+                Ok((result, free_space))
             })?;
 
             bufmgr.release_page(page_ptr)?;
 
+            let (result, free_space) = result;
+            self.record_free_space(page_num, free_space);
+
             match result {
                 Some(r) => {
                     // record this page for later inserts
@@ -287,17 +757,23 @@ impl Heap {
                     return Ok(r);
                 }
                 None => {
-                    // try again with an allocated page
-                    target_page_num = None;
+                    // try again with another page or an allocated page
+                    target_page_num = match self.insert_strategy {
+                        HeapInsertStrategy::InsertHint => None,
+                        HeapInsertStrategy::Spread => self.get_page_from_fsm(db, required_space)?,
+                    };
                 }
             }
         }
 
-        // need to extend the heap
-        let page_ptr =
-            self.with_storage(smgr, |storage| bufmgr.new_page(db, storage, ForkType::Main))?;
+        // need to extend the heap. hold the extension lock until the new page is initialized
+        let (page_ptr, extension_guard) = self.with_storage(smgr, |storage| {
+            let extension_guard = db.lock_relation_extension(storage.file_ref(), ForkType::Main);
+            let page_ptr = bufmgr.new_page(db, storage, ForkType::Main)?;
+            Ok((page_ptr, extension_guard))
+        })?;
 
-        let (result, page_num) = page_ptr.with_write(move |page| {
+        let (result, page_num, free_space) = page_ptr.with_write(move |page| {
             let (_, _, page_num) = page.get_fork_and_num();
             let buffer = page.buffer_mut();
             let mut page_view = HeapPageViewMut::new(buffer);
@@ -305,519 +781,3607 @@ impl Heap {
             page_view.init_page();
 
             let (result, _) = f(&mut page_view, page_num)?;
+            let free_space = page_view.get_free_space();
             page.set_dirty(true);
-            Ok((result, page_num))
+            Ok((result, page_num, free_space))
         })?;
+        drop(extension_guard);
 
         bufmgr.release_page(page_ptr)?;
 
+        self.record_free_space(page_num, free_space);
+
         self.set_insert_hint(page_num);
 
         Ok(result)
     }
 
-    fn get_next_tuple<'a>(
-        &'a self,
+    /// Insert the rows in bulk. Each page is filled with as many rows as possible before moving
+    /// on and a single log record is written for all rows inserted into a page.
+    pub fn insert_bulk<'r, I>(
+        &self,
         db: &DB,
-        iterator: &mut HeapScanIterator<'a>,
-        dir: ScanDirection,
-    ) -> Result<bool> {
-        let smgr = db.get_storage_manager();
-        let bufmgr = db.get_buffer_manager();
+        txn: &Transaction,
+        rows: I,
+    ) -> Result<Vec<ItemPointer>>
+    where
+        I: IntoIterator<Item = &'r [u8]>,
+    {
+        self.lock_for_access(db, txn)?;
+
+        let max_tuple_size = Self::max_inline_tuple_size();
+        let reserved_space = self.reserved_space();
+        let file_ref = self.storage_file(db);
+        let rows = rows.into_iter().collect::<Vec<_>>();
+
+        // all rows are checked before any page is modified
+        for row in rows.iter() {
+            if row.len() > max_tuple_size {
+                return Err(Error::ProgramLimitExceed(format!(
+                    "tuple size {} exceeds limit {}",
+                    row.len(),
+                    max_tuple_size
+                )));
+            }
+            self.check_row(row)?;
+        }
 
-        let mut offset: usize = 0;
-        let mut remaining_tuples: usize;
-        match dir {
-            ScanDirection::Forward => {
-                if !iterator.inited {
-                    if iterator.heap_pages == 0 {
-                        // empty heap, done
-                        return Ok(false);
+        let rows = RefCell::new(rows.into_iter().peekable());
+        let item_pointers = RefCell::new(Vec::new());
+
+        loop {
+            let row_len = match rows.borrow_mut().peek() {
+                Some(row) => row.len(),
+                None => break,
+            };
+
+            let htup = self.prepare_heap_tuple_for_insert(txn.xid(), txn.command_id(), &[]);
+            let htup_len = bincode::serialized_size(&htup)? as usize + row_len;
+
+            self.with_page_for_tuple(db, htup_len, |page_view, page_num| {
+                let image = self.get_consistency_check_image(db, page_view);
+                let mut rows = rows.borrow_mut();
+                let start_offset = page_view.num_line_pointers() + 1;
+                let mut tuples = Vec::new();
+
+                while let Some(row) = rows.peek() {
+                    let htup = self.prepare_heap_tuple_for_insert(txn.xid(), txn.command_id(), row);
+                    let htup_buf = bincode::serialize(&htup)?;
+
+                    // the first tuple is placed even if it takes the reserved space of a new page
+                    let free_space = page_view.get_free_space();
+                    if free_space < htup_buf.len()
+                        || (!tuples.is_empty() && free_space < htup_buf.len() + reserved_space)
+                    {
+                        break;
                     }
 
-                    let page_num = iterator.start_page;
-                    self.with_storage(smgr, |storage| iterator.fetch_page(db, storage, page_num))?;
-                    offset = 1;
-                    iterator.inited = true;
-                } else {
-                    // continue from last tuple
-                    let item_ptr = iterator.tuple.ptr.expect("impossible");
-                    let next_off = item_ptr.next();
-                    offset = next_off.offset;
+                    // the items are appended at consecutive offsets, which is what the redo does
+                    page_view.put_item(&htup_buf, None, false)?;
+                    tuples.push(htup_buf);
+                    rows.next();
                 }
 
-                match &iterator.cur_page {
-                    None => {
-                        return Err(Error::InvalidState(
-                            "page not present for heap scan iterator".to_owned(),
-                        ))
+                let num_tuples = tuples.len();
+                let insert_log = HeapLogRecord::create_heap_multi_insert_log(
+                    file_ref,
+                    ForkType::Main,
+                    page_num,
+                    start_offset,
+                    tuples,
+                );
+                self.check_wal_consistency(image, &insert_log, txn.xid(), page_view)?;
+                self.log_page_change(db, txn.xid(), insert_log, page_view)?;
+
+                item_pointers.borrow_mut().extend(
+                    (start_offset..start_offset + num_tuples)
+                        .map(|off| ItemPointer::new(page_num, off)),
+                );
+                Ok(((), true))
+            })?;
+        }
+
+        Ok(item_pointers.into_inner())
+    }
+
+    /// Copy the tuples that may still be visible into the empty heap `dst`, which stores them in
+    /// a new file. Return the new locations of the tuples that are kept.
+    ///
+    /// The caller must make sure that the heap is not accessed concurrently.
+    pub fn vacuum_full(&self, db: &DB, dst: &Heap) -> Result<HashMap<ItemPointer, ItemPointer>> {
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let oldest_xid = db
+            .get_transaction_manager()
+            .get_vacuum_horizon(db.get_config().old_snapshot_threshold);
+        let shandle = self.with_storage(smgr, |storage| Ok(storage.clone()))?;
+        let num_pages = smgr.file_size_in_page(&shandle, ForkType::Main)?;
+        let mut item_pointer_map = HashMap::new();
+
+        // the page being filled and its tuples, which are logged when the page is appended
+        let mut buffer = [0u8; PAGE_SIZE];
+        let mut tuples = Vec::new();
+        let mut full_pages = Vec::new();
+        let mut new_page_num = 0;
+        HeapPageViewMut::new(&mut buffer).init_page();
+
+        for page_num in 0..num_pages {
+            // the old file is kept if the rewrite stops
+            db.check_shutdown()?;
+            let page_ptr = bufmgr.fetch_page(db, &shandle, ForkType::Main, page_num)?;
+
+            let result = page_ptr.with_write(|page| {
+                let page_view = HeapPageView::new(page.buffer());
+                if page_view.is_new() {
+                    return Ok(());
+                }
+
+                let num_tuples = page_view.num_line_pointers();
+                let mut moved = HashSet::new();
+
+                // the tuples of an update chain that stays on the page are moved together and
+                // keep their links, so that the index entries of the first tuple still find the
+                // others
+                for offset in 1..=num_tuples {
+                    if moved.contains(&offset) {
+                        continue;
                     }
-                    Some(page) => {
-                        remaining_tuples = HeapPageView::with_page(page, |page_view| {
-                            Ok(page_view.num_line_pointers() + 1 - offset)
-                        })?;
+
+                    let mut chain = vec![offset];
+                    while let Some(next_offset) =
+                        Self::get_heap_only_successor(&page_view, page_num, *chain.last().unwrap())?
+                    {
+                        if chain.len() >= num_tuples {
+                            break;
+                        }
+                        chain.push(next_offset);
                     }
-                }
-            }
-            ScanDirection::Backward => {
-                if !iterator.inited {
-                    if iterator.heap_pages == 0 {
-                        // empty heap, done
-                        return Ok(false);
+                    moved.extend(chain.iter().copied());
+
+                    let mut kept = Vec::new();
+                    for chain_offset in chain.iter().copied() {
+                        let htup =
+                            bincode::deserialize::<HeapTuple>(page_view.get_item(chain_offset))?;
+
+                        if !htup.is_dead(db, oldest_xid)? {
+                            kept.push((chain_offset, htup));
+                        }
                     }
 
-                    let page_num = if iterator.start_page > 0 {
-                        iterator.start_page
-                    } else {
-                        iterator.heap_pages
-                    } - 1;
+                    if kept.is_empty() {
+                        continue;
+                    }
 
-                    self.with_storage(smgr, |storage| iterator.fetch_page(db, storage, page_num))?;
-                    remaining_tuples = iterator.num_tuples;
-                    offset = if remaining_tuples > 0 {
-                        remaining_tuples
-                    } else {
-                        1
-                    };
-                    iterator.inited = true;
-                } else {
-                    // continue from last tuple
-                    let item_ptr = iterator.tuple.ptr.expect("impossible");
-                    match item_ptr.prev() {
-                        None => {
-                            remaining_tuples = 0;
+                    let required_space = kept
+                        .iter()
+                        .map(|(_, htup)| bincode::serialized_size(htup).map(|len| len as usize))
+                        .sum::<bincode::Result<usize>>()?
+                        + (kept.len() - 1) * LINE_POINTER_SIZE;
+                    if HeapPageView::new(&buffer).get_free_space() < required_space {
+                        full_pages.push(std::mem::take(&mut tuples));
+                        if full_pages.len() >= REWRITE_BATCH_PAGES {
+                            dst.append_rewritten_pages(
+                                db,
+                                oldest_xid,
+                                std::mem::take(&mut full_pages),
+                            )?;
                         }
-                        Some(prev_off) => {
-                            offset = prev_off.offset;
-                            remaining_tuples = offset;
+                        HeapPageViewMut::new(&mut buffer).init_page();
+                        new_page_num += 1;
+                    }
+
+                    let first_offset = tuples.len() + 1;
+                    let first_kept = kept[0].0;
+                    let num_kept = kept.len();
+                    for (i, (old_offset, mut htup)) in kept.into_iter().enumerate() {
+                        // the links to versions outside of the chain are dropped because those
+                        // versions are moved
+                        htup.next_version = if i + 1 < num_kept {
+                            ItemPointer::new(new_page_num, first_offset + i + 1)
+                        } else {
+                            ItemPointer::default()
+                        };
+                        if i == 0 {
+                            // the index entries of the chain point to it now
+                            htup.flags &= !HeapTupleFlags::HEAP_ONLY.bits();
                         }
+
+                        let htup_buf = bincode::serialize(&htup)?;
+                        let new_offset =
+                            HeapPageViewMut::new(&mut buffer).put_item(&htup_buf, None, false)?;
+                        tuples.push(htup_buf);
+                        item_pointer_map.insert(
+                            ItemPointer::new(page_num, old_offset),
+                            ItemPointer::new(new_page_num, new_offset),
+                        );
                     }
-                }
-            }
-        }
 
-        loop {
-            match &iterator.cur_page {
-                Some(page) => {
-                    match HeapPageViewMut::with_page(page, |page_view| {
-                        let mut remaining_tuples = remaining_tuples;
-                        let mut offset = offset;
-                        let mut dirty = false;
+                    // the index entries of removed versions at the start of the chain go to the
+                    // first version that is kept
+                    for chain_offset in chain.iter().take_while(|off| **off != first_kept) {
+                        item_pointer_map.insert(
+                            ItemPointer::new(page_num, *chain_offset),
+                            ItemPointer::new(new_page_num, first_offset),
+                        );
+                    }
+                }
 
-                        while remaining_tuples > 0 {
-                            let valid = {
-                                let item = page_view.get_item(offset);
-                                // deserialize the tuple to check visibility
-                                let mut htup = match bincode::deserialize::<HeapTuple>(item) {
-                                    Ok(htup) => htup,
-                                    _ => {
-                                        return Err(Error::DataCorrupted(
-                                            "cannot deserialize heap tuple".to_owned(),
-                                        ));
-                                    }
-                                };
+                Ok(())
+            });
 
-                                let (valid, new_flags) =
-                                    htup.is_visible(db, iterator.snapshot, iterator.xid)?;
-
-                                if new_flags != 0 {
-                                    // install the new hint bits to the page
-                                    // XXX: If we set the hint bits that some transactions are
-                                    //      committed, we should also set the page LSN to the
-                                    //      latest commit LSNs of those transactions. This is
-                                    //      to make sure that this page is written to disk
-                                    //      only after the commit log records are written.
-                                    //      Otherwise, the page may contain invalid bits if
-                                    //      the transactions are marked committed but the
-                                    //      commit log records are not written. (can this really
-                                    //      happen?)
-                                    htup.flags |= new_flags;
-                                    let htup_buf = bincode::serialize(&htup).unwrap();
-                                    page_view.set_item(offset, &htup_buf)?;
-                                    dirty = true;
-                                }
+            bufmgr.release_page(page_ptr)?;
+            result?;
+        }
 
-                                valid
-                            };
+        if !tuples.is_empty() {
+            full_pages.push(tuples);
+        }
+        if !full_pages.is_empty() {
+            dst.append_rewritten_pages(db, oldest_xid, full_pages)?;
+        }
 
-                            if valid {
-                                let item = page_view.get_item(offset);
-                                let htup_buf = unsafe {
-                                    // extend the lifetime of buf to 'a
-                                    // this is ok because we keep a pinned page in the scan iterator
-                                    // so the page buffer will be valid until the next iteration
-                                    std::mem::transmute::<&[u8], &'a [u8]>(item)
-                                };
+        Ok(item_pointer_map)
+    }
 
-                                let mut htup = match bincode::deserialize::<HeapTuple>(htup_buf) {
-                                    Ok(htup) => htup,
-                                    _ => {
-                                        return Err(Error::DataCorrupted(
-                                            "cannot deserialize heap tuple".to_owned(),
-                                        ));
-                                    }
-                                };
+    /// Append pages with the serialized tuples to the heap. The tuples are logged with the pages
+    /// so that recovery rebuilds them. The records of all pages are appended to the log at once
+    /// while the pages are locked.
+    fn append_rewritten_pages(
+        &self,
+        db: &DB,
+        vacuum_xid: XID,
+        pages: Vec<Vec<Vec<u8>>>,
+    ) -> Result<()> {
+        let bufmgr = db.get_buffer_manager();
+        let file_ref = self.storage_file(db);
+        let mut page_locks = Vec::with_capacity(pages.len());
+
+        let result = (|| {
+            // hold the extension lock until the new pages are initialized
+            let _extension_guard = self.with_storage(db.get_storage_manager(), |storage| {
+                let extension_guard =
+                    db.lock_relation_extension(storage.file_ref(), ForkType::Main);
+                for _ in 0..pages.len() {
+                    let page_ptr = bufmgr.new_page(db, storage, ForkType::Main)?;
+                    page_locks.push(page_ptr.write_owned()?);
+                }
+                Ok(extension_guard)
+            })?;
 
-                                htup.table_id = self.rel_id();
-                                htup.set_pointer(ItemPointer::new(iterator.cur_page_num, offset));
+            let mut records = Vec::with_capacity(pages.len());
+            for (page_lock, tuples) in page_locks.iter_mut().zip(pages) {
+                let (_, _, page_num) = page_lock.borrow_page_guard().get_fork_and_num();
+                page_lock.with_page_guard_mut::<Result<()>>(|page| {
+                    let mut page_view = HeapPageViewMut::new(page.buffer_mut());
+                    page_view.init_page();
+                    page_view.set_vacuum_xid(vacuum_xid);
+                    for (i, htup_buf) in tuples.iter().enumerate() {
+                        page_view.put_item(htup_buf, Some(i + 1), false)?;
+                    }
+                    Ok(())
+                })?;
+
+                records.push(HeapLogRecord::create_heap_rewrite_log(
+                    file_ref,
+                    ForkType::Main,
+                    page_num,
+                    vacuum_xid,
+                    tuples,
+                ));
+            }
 
-                                return Ok((dirty, Some(htup)));
-                            }
+            // the changes to unlogged heaps are not logged, see `log_page_change`
+            let positions = if db.get_storage_manager().is_unlogged(file_ref) {
+                None
+            } else {
+                Some(db.get_wal().append_batch(XID::default(), &records)?)
+            };
 
-                            remaining_tuples -= 1;
+            for (i, page_lock) in page_locks.iter_mut().enumerate() {
+                page_lock.with_page_guard_mut(|page| {
+                    if let Some(positions) = &positions {
+                        HeapPageViewMut::new(page.buffer_mut()).set_lsn(positions[i].1);
+                    }
+                    page.set_dirty(true);
+                });
+            }
 
-                            match dir {
-                                ScanDirection::Forward => {
-                                    offset += 1;
-                                }
-                                ScanDirection::Backward => {
-                                    offset -= 1;
-                                }
-                            }
-                        }
-                        // we've scanned all tuples on the current page, go to the next page
-                        Ok((dirty, None))
-                    })? {
-                        Some(htup) => {
-                            iterator.tuple = htup;
-                            return Ok(true);
-                        }
+            Ok(())
+        })();
 
-                        None => {
-                            let mut finished;
-                            let mut next_page;
+        for page_lock in page_locks {
+            bufmgr.release_page(page_lock.into_heads().page_ptr)?;
+        }
+        result
+    }
 
-                            match dir {
-                                // move to the next page
-                                ScanDirection::Forward => {
-                                    next_page = iterator.cur_page_num;
-                                    next_page += 1;
+    /// Begin a scan of all pages. Synchronized scans start at the page reported by another scan
+    /// of the heap.
+    fn begin_full_scan<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        sync_scan: bool,
+        filter: Option<TableScanPredicate<'a>>,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
+        self.lock_for_access(db, txn)?;
+        let smgr = db.get_storage_manager();
+        let heap_pages = self.get_size_in_page(smgr)?;
+        let (sync_scan, start_page) = if sync_scan && heap_pages > 0 {
+            let file_ref = RelFileRef {
+                db: self.rel_db(),
+                rel_id: self.rel_id(),
+            };
 
-                                    if next_page >= iterator.heap_pages {
-                                        next_page = 0;
-                                    }
+            let (sync_scan, start_page) =
+                db.get_sync_scan_registry().begin_scan(file_ref, heap_pages);
+            let start_page = match start_page {
+                Some(page_num) => page_num,
+                None if db.get_config().sync_scan_random_start => {
+                    (next_insert_random() % heap_pages as u64) as usize
+                }
+                None => 0,
+            };
 
-                                    finished = next_page == iterator.start_page;
+            (Some(sync_scan), start_page)
+        } else {
+            (None, 0)
+        };
+        let xid = txn.xid();
+        let interrupts = txn.interrupt_check();
+        let snapshot = db.get_transaction_manager().get_snapshot(txn)?;
+        let heap_it = HeapScanIterator {
+            heap: self,
+            visibility: VisibilityContext::new(snapshot, xid),
+            xid_statuses: HashMap::new(),
+            inited: false,
+            tuple: HeapTuple::new(self.rel_id(), &[]).materialize(),
+            cur_page: None,
+            cur_page_num: 0,
+            num_tuples: 0,
+            heap_pages,
+            start_page,
+            start_offset: 1,
+            max_pages: None,
+            hint_bit_writes: db.get_config().hint_bit_writes,
+            sync_scan,
+            interrupts,
+            filter,
+        };
 
-                                    if let Some(limit) = &mut iterator.max_pages {
-                                        if *limit == 0 {
-                                            finished = true;
-                                        } else {
-                                            *limit -= 1;
-                                        }
-                                    }
-                                }
-                                ScanDirection::Backward => {
-                                    finished = iterator.cur_page_num == iterator.start_page;
+        Ok(Box::new(heap_it))
+    }
 
-                                    if let Some(limit) = &mut iterator.max_pages {
-                                        if *limit == 0 {
-                                            finished = true;
-                                        } else {
-                                            *limit -= 1;
-                                        }
-                                    }
+    /// Keep the table from being dropped, truncated or rewritten while it is scanned or changed
+    /// by the transaction.
+    fn lock_for_access(&self, db: &DB, txn: &Transaction) -> Result<()> {
+        db.get_transaction_manager().lock_relation(
+            txn,
+            RelFileRef {
+                db: self.rel_db(),
+                rel_id: self.rel_id(),
+            },
+            LockMode::AccessShare,
+        )
+    }
 
-                                    next_page = if iterator.cur_page_num > 0 {
-                                        iterator.cur_page_num
-                                    } else {
-                                        iterator.heap_pages
-                                    } - 1;
-                                }
-                            }
+    /// Mark the tuple deleted by the transaction. The tuple is left untouched if another
+    /// transaction is deleting it or has updated it.
+    fn try_delete_tuple(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        item_pointer: ItemPointer,
+    ) -> Result<DeleteOutcome> {
+        let ItemPointer { page_num, offset } = item_pointer;
+        let bufmgr = db.get_buffer_manager();
 
-                            if finished {
-                                // no more pages
-                                let page = iterator.cur_page.take();
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| {
+            bufmgr.fetch_page(db, storage, ForkType::Main, page_num)
+        })?;
 
-                                if let Some(page) = page {
-                                    bufmgr.release_page(page)?;
-                                }
+        let result = HeapPageViewMut::with_page(&page_ptr, |page_view| {
+            if offset == 0 || offset > page_view.num_line_pointers() {
+                return Err(Error::InvalidArgument(format!(
+                    "invalid item pointer {:?}",
+                    item_pointer
+                )));
+            }
 
-                                iterator.tuple = HeapTuple::new(self.rel_id(), &[]).materialize();
-                                iterator.inited = false;
+            let htup_buf = {
+                let mut htup = bincode::deserialize::<HeapTuple>(page_view.get_item(offset))?;
 
-                                return Ok(false);
-                            }
+                let flags = HeapTupleFlags::from_bits_truncate(htup.flags);
+                if !flags.contains(HeapTupleFlags::MAX_XID_INVALID) && !htup.max_xid.is_invalid() {
+                    let txnmgr = db.get_transaction_manager();
 
-                            self.with_storage(smgr, |storage| {
-                                iterator.fetch_page(db, storage, next_page)
-                            })?;
+                    if htup.max_xid == txn.xid() {
+                        return Err(Error::InvalidState("tuple is already deleted".to_owned()));
+                    }
 
-                            remaining_tuples = iterator.num_tuples;
+                    if flags.contains(HeapTupleFlags::MAX_XID_COMMITTED)
+                        || txnmgr.get_transaction_status(htup.max_xid)?
+                            == TransactionStatus::Committed
+                    {
+                        return match htup.get_next_version() {
+                            Some(next_version) => Ok((false, DeleteOutcome::Updated(next_version))),
+                            None => Err(Error::InvalidState("tuple is already deleted".to_owned())),
+                        };
+                    }
 
-                            match dir {
-                                ScanDirection::Forward => {
-                                    offset = 1;
-                                }
-                                ScanDirection::Backward => {
-                                    offset = remaining_tuples;
-                                }
-                            }
-                        }
+                    if txnmgr.is_transaction_active(htup.max_xid) {
+                        return Ok((false, DeleteOutcome::BeingModified(htup.max_xid)));
                     }
                 }
-                None => {
-                    return Ok(false);
+
+                htup.mark_deleted(txn.xid());
+                bincode::serialize(&htup)?
+            };
+
+            let image = self.get_consistency_check_image(db, page_view);
+            page_view.set_item(offset, &htup_buf)?;
+
+            let delete_log = HeapLogRecord::create_heap_delete_log(
+                self.storage_file(db),
+                ForkType::Main,
+                page_num,
+                offset,
+            );
+            self.check_wal_consistency(image, &delete_log, txn.xid(), page_view)?;
+            self.log_page_change(db, txn.xid(), delete_log, page_view)?;
+
+            Ok((true, DeleteOutcome::Deleted))
+        });
+
+        bufmgr.release_page(page_ptr)?;
+
+        result
+    }
+
+    /// Delete the latest version of the tuple at `item_pointer` and return its location. If
+    /// another transaction is modifying the tuple, wait for it to end. If it commits an update,
+    /// a read committed transaction moves on to the newer version and tries again.
+    fn delete_latest_version(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        item_pointer: ItemPointer,
+    ) -> Result<ItemPointer> {
+        self.lock_for_access(db, txn)?;
+        let mut item_pointer = item_pointer;
+
+        loop {
+            match self.try_delete_tuple(db, txn, item_pointer)? {
+                DeleteOutcome::Deleted => return Ok(item_pointer),
+                DeleteOutcome::BeingModified(xid) => {
+                    // check the tuple again after the other transaction ends
+                    db.get_transaction_manager()
+                        .wait_for_xid_in_transaction(txn, xid)?;
+                }
+                DeleteOutcome::Updated(next_version) => {
+                    if txn.uses_transaction_snapshot() {
+                        // the newer version is not visible to the transaction snapshot
+                        return Err(Error::InvalidState(
+                            "could not serialize access due to concurrent update".to_owned(),
+                        ));
+                    }
+
+                    item_pointer = next_version;
                 }
             }
         }
     }
-}
 
-impl Relation for Heap {
-    fn get_relation_entry(&self) -> &RelationEntry {
-        &self.rel_entry
+    /// Write a tuple to a locked page and log the insert.
+    fn put_tuple(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        page_view: &mut HeapPageViewMut,
+        page_num: usize,
+        htup: &HeapTuple,
+        htup_buf: &[u8],
+    ) -> Result<ItemPointer> {
+        let image = self.get_consistency_check_image(db, page_view);
+        let off = page_view.put_item(htup_buf, None, false)?;
+        // create insert log
+        let insert_log = HeapLogRecord::create_heap_insert_log(
+            self.storage_file(db),
+            ForkType::Main,
+            page_num,
+            off,
+            htup.flags,
+            htup.command_id,
+            &htup.data,
+        );
+        self.check_wal_consistency(image, &insert_log, txn.xid(), page_view)?;
+        self.log_page_change(db, txn.xid(), insert_log, page_view)?;
+        Ok(ItemPointer::new(page_num, off))
     }
-}
 
-pub struct HeapScanIterator<'a> {
-    heap: &'a Heap,
-    xid: XID,
-    snapshot: &'a Snapshot,
-    inited: bool,
-    tuple: HeapTuple<'a>,
-    cur_page: Option<PinnedPagePtr>,
-    cur_page_num: usize,
-    num_tuples: usize,
-    heap_pages: usize,
-    start_page: usize,
-    max_pages: Option<usize>,
-}
+    /// Insert the new version of an updated tuple on the page of the old version if it has room,
+    /// including the space reserved by the fill factor. The new version is marked heap-only.
+    fn insert_heap_only_tuple(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        page_num: usize,
+        tuple: &[u8],
+    ) -> Result<Option<ItemPointer>> {
+        if tuple.len() > Self::max_inline_tuple_size() {
+            return Ok(None);
+        }
 
-impl<'a> HeapScanIterator<'a> {
-    fn fetch_page(&mut self, db: &DB, shandle: &StorageHandle, page_num: usize) -> Result<()> {
+        let mut htup = self.prepare_heap_tuple_for_insert(txn.xid(), txn.command_id(), tuple);
+        htup.flags |= HeapTupleFlags::HEAP_ONLY.bits();
+        let htup_buf = bincode::serialize(&htup)?;
         let bufmgr = db.get_buffer_manager();
 
-        let old_page = self.cur_page.take();
-        if let Some(page) = old_page {
-            bufmgr.release_page(page)?;
-        }
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| {
+            bufmgr.fetch_page(db, storage, ForkType::Main, page_num)
+        })?;
 
-        let page = bufmgr.fetch_page(db, shandle, ForkType::Main, page_num)?;
-        self.cur_page_num = page_num;
+        let result = HeapPageViewMut::with_page(&page_ptr, |page_view| {
+            if page_view.get_free_space() < htup_buf.len() {
+                return Ok((false, (None, page_view.get_free_space())));
+            }
 
-        self.num_tuples =
-            HeapPageView::with_page(&page, |page_view| Ok(page_view.num_line_pointers()))?;
+            let itemp = self.put_tuple(db, txn, page_view, page_num, &htup, &htup_buf)?;
+            Ok((true, (Some(itemp), page_view.get_free_space())))
+        });
 
-        self.cur_page = Some(page);
+        bufmgr.release_page(page_ptr)?;
 
-        Ok(())
+        let (itemp, free_space) = result?;
+        self.record_free_space(page_num, free_space);
+        Ok(itemp)
     }
-}
 
-impl<'a> TableScanIterator<'a> for HeapScanIterator<'a> {
-    fn next(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<TuplePtr<'a>>> {
-        if self.heap.get_next_tuple(db, self, dir)? {
-            let buffer_tuple = BufferHeapTuple {
-                tuple: self.tuple.clone(),
-                bufmgr: Some(db.get_buffer_manager()),
-                page: self.cur_page.clone(),
-            };
-            Ok(Some(Box::new(buffer_tuple)))
-        } else {
-            Ok(None)
-        }
-    }
-}
+    /// Get the offset of the newer version of the tuple at `offset` if it is a heap-only tuple,
+    /// which must be on the same page.
+    fn get_heap_only_successor<P: ItemPageReader>(
+        page_view: &P,
+        page_num: usize,
+        offset: usize,
+    ) -> Result<Option<usize>> {
+        let htup = bincode::deserialize::<HeapTuple>(page_view.get_item(offset))?;
+
+        let next_version = match htup.get_next_version() {
+            Some(next_version)
+                if next_version.page_num == page_num
+                    && next_version.offset <= page_view.num_line_pointers() =>
+            {
+                next_version
+            }
+            _ => return Ok(None),
+        };
 
-impl Table for Heap {
-    fn file_size(&self, db: &DB, fork: ForkType) -> Result<usize> {
-        let smgr = db.get_storage_manager();
+        let next_htup = bincode::deserialize::<HeapTuple>(page_view.get_item(next_version.offset))?;
+        let flags = HeapTupleFlags::from_bits_truncate(next_htup.flags);
 
-        self.with_storage(smgr, |storage| {
-            let pages = smgr.file_size_in_page(storage, fork)?;
-            Ok(pages * PAGE_SIZE)
+        Ok(if flags.contains(HeapTupleFlags::HEAP_ONLY) {
+            Some(next_version.offset)
+        } else {
+            None
         })
     }
 
-    fn insert_tuple(&self, db: &DB, txn: &Transaction, tuple: &[u8]) -> Result<ItemPointer> {
-        let htup = self.prepare_heap_tuple_for_insert(txn.xid(), tuple);
-        let htup_buf = bincode::serialize(&htup).unwrap();
-        let htup_len = htup_buf.len();
+    /// Link a deleted tuple to its newer version.
+    fn set_next_version(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        item_pointer: ItemPointer,
+        next_version: ItemPointer,
+    ) -> Result<()> {
+        let ItemPointer { page_num, offset } = item_pointer;
+        let bufmgr = db.get_buffer_manager();
 
-        let itemp = self.with_page_for_tuple(db, htup_len, |page_view, page_num| {
-            let off = page_view.put_item(&htup_buf, None, false)?;
-            // create insert log
-            let insert_log = HeapLogRecord::create_heap_insert_log(
-                RelFileRef {
-                    db: self.rel_db(),
-                    rel_id: self.rel_id(),
-                },
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| {
+            bufmgr.fetch_page(db, storage, ForkType::Main, page_num)
+        })?;
+
+        let result = HeapPageViewMut::with_page(&page_ptr, |page_view| {
+            let mut htup = bincode::deserialize::<HeapTuple>(page_view.get_item(offset))?;
+            htup.next_version = next_version;
+            let htup_buf = bincode::serialize(&htup)?;
+
+            let image = self.get_consistency_check_image(db, page_view);
+            page_view.set_item(offset, &htup_buf)?;
+
+            let set_next_version_log = HeapLogRecord::create_heap_set_next_version_log(
+                self.storage_file(db),
                 ForkType::Main,
                 page_num,
-                off,
-                htup.flags,
-                tuple,
+                offset,
+                next_version,
             );
-            let (_, lsn) = db.get_wal().append(txn.xid(), insert_log)?;
-            page_view.set_lsn(lsn);
-            Ok((ItemPointer::new(page_num, off), true))
-        })?;
-        Ok(itemp)
-    }
+            self.check_wal_consistency(image, &set_next_version_log, txn.xid(), page_view)?;
+            self.log_page_change(db, txn.xid(), set_next_version_log, page_view)?;
 
-    fn begin_scan<'a>(
+            Ok((true, ()))
+        });
+
+        bufmgr.release_page(page_ptr)?;
+
+        result
+    }
+
+    /// Check the visibility of the tuple at `item_pointer` on a pinned page and return the tuple
+    /// if it is visible. If it is not, the heap-only newer versions on the page are checked, so
+    /// an index entry of the tuple still finds the versions of updates that stay on the page. The
+    /// tuple borrows the page buffer, so the page must be kept pinned as long as the tuple is used.
+    fn fetch_tuple_on_page<'a>(
+        &self,
+        db: &DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        page_ptr: &PinnedPagePtr,
+        item_pointer: ItemPointer,
+    ) -> Result<Option<HeapTuple<'a>>> {
+        let hint_bits = db.get_config().hint_bit_writes;
+
+        let ctx = VisibilityContext::new(snapshot, xid);
+
+        HeapPageViewMut::with_page(page_ptr, |page_view| {
+            let mut item_pointer = item_pointer;
+            let mut dirty = false;
+
+            // a chain never has more versions than the page has tuples
+            for _ in 0..page_view.num_line_pointers() {
+                let (modified, htup) =
+                    self.read_visible_tuple(db, &ctx, page_view, item_pointer, hint_bits)?;
+                dirty = dirty || modified;
+
+                if htup.is_some() {
+                    return Ok((dirty, htup));
+                }
+
+                match Self::get_heap_only_successor(
+                    page_view,
+                    item_pointer.page_num,
+                    item_pointer.offset,
+                )? {
+                    Some(offset) => item_pointer.offset = offset,
+                    None => break,
+                }
+            }
+
+            Ok((dirty, None))
+        })
+    }
+
+    /// Fail if the page has been vacuumed past the snapshot, in which case tuples visible to the
+    /// snapshot may be gone. The vacuum removes the tuples deleted by the transactions before its
+    /// horizon that have committed, so the snapshot is too old if it is taken before the horizon,
+    /// or if it sees one of those transactions as in progress and that transaction has finished.
+    /// Transactions that are still running have not had their deletes removed.
+    fn check_snapshot_age<P: HeapPageReader>(
+        db: &DB,
+        snapshot: &Snapshot,
+        page_view: &P,
+    ) -> Result<()> {
+        let vacuum_xid = page_view.get_vacuum_xid();
+
+        if vacuum_xid.is_invalid() {
+            return Ok(());
+        }
+
+        let txnmgr = db.get_transaction_manager();
+        if snapshot.max_xid() < vacuum_xid
+            || snapshot
+                .in_progress_xids_before(vacuum_xid)
+                .any(|xid| !txnmgr.is_transaction_active(xid))
+        {
+            return Err(Error::SnapshotTooOld);
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the tuple at `offset` on a locked page is visible without copying its data.
+    /// Return the visibility and whether hint bits are installed on the page. Hint bits are only
+    /// installed if `hint_bits` is set.
+    fn check_visibility(
+        db: &DB,
+        ctx: &VisibilityContext,
+        page_view: &mut HeapPageViewMut,
+        offset: usize,
+        hint_bits: bool,
+    ) -> Result<(bool, bool)> {
+        Self::check_snapshot_age(db, ctx.snapshot, page_view)?;
+
+        let item = page_view.get_item(offset);
+        // deserialize the tuple to check visibility
+        let mut htup = bincode::deserialize::<HeapTuple>(item)?;
+
+        let (valid, new_flags) = htup.is_visible(db, ctx)?;
+
+        if new_flags != 0 && hint_bits {
+            htup.flags |= new_flags;
+            let htup_buf = bincode::serialize(&htup)?;
+            page_view.set_item(offset, &htup_buf)?;
+            return Ok((valid, true));
+        }
+
+        Ok((valid, false))
+    }
+
+    /// Look up the final statuses of the transactions that insert or delete the tuples on a page
+    /// with one lock on the transaction table. Running transactions are left out because their
+    /// statuses can change while the page is scanned.
+    fn prefetch_xid_statuses<P: ItemPageReader>(
+        db: &DB,
+        page_view: &P,
+    ) -> Result<HashMap<XID, TransactionStatus>> {
+        let mut xids = HashSet::new();
+
+        for offset in 1..=page_view.num_line_pointers() {
+            let htup = bincode::deserialize::<HeapTuple>(page_view.get_item(offset))?;
+            let flags = HeapTupleFlags::from_bits_truncate(htup.flags);
+
+            if !flags
+                .intersects(HeapTupleFlags::MIN_XID_COMMITTED | HeapTupleFlags::MIN_XID_INVALID)
+                && !htup.min_xid.is_invalid()
+            {
+                xids.insert(htup.min_xid);
+            }
+            if !flags
+                .intersects(HeapTupleFlags::MAX_XID_COMMITTED | HeapTupleFlags::MAX_XID_INVALID)
+                && !htup.max_xid.is_invalid()
+            {
+                xids.insert(htup.max_xid);
+            }
+        }
+
+        if xids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let xids = xids.into_iter().collect::<Vec<_>>();
+        let statuses = db
+            .get_transaction_manager()
+            .get_transaction_statuses(&xids)?;
+
+        Ok(xids
+            .into_iter()
+            .zip(statuses)
+            .filter(|(_, status)| {
+                matches!(
+                    status,
+                    TransactionStatus::Committed | TransactionStatus::Aborted
+                )
+            })
+            .collect())
+    }
+
+    /// Read the tuple at `item_pointer` from a locked page if it is visible. Hint bits are
+    /// installed on the page and the returned flag tells whether the page is modified.
+    fn read_visible_tuple<'a>(
+        &self,
+        db: &DB,
+        ctx: &VisibilityContext,
+        page_view: &mut HeapPageViewMut,
+        item_pointer: ItemPointer,
+        hint_bits: bool,
+    ) -> Result<(bool, Option<HeapTuple<'a>>)> {
+        let offset = item_pointer.offset;
+        let (valid, dirty) = Self::check_visibility(db, ctx, page_view, offset, hint_bits)?;
+
+        if valid {
+            let item = page_view.get_item(offset);
+            let htup_buf = unsafe {
+                // extend the lifetime of buf to 'a
+                // this is ok because the caller keeps the page pinned
+                // so the page buffer will be valid as long as the tuple is used
+                std::mem::transmute::<&[u8], &'a [u8]>(item)
+            };
+
+            let mut htup = bincode::deserialize::<HeapTuple>(htup_buf)?;
+
+            htup.table_id = self.rel_id();
+            htup.set_pointer(item_pointer);
+
+            Ok((dirty, Some(htup)))
+        } else {
+            Ok((dirty, None))
+        }
+    }
+
+    /// Collect at most `max` visible tuples after the current tuple of the iterator on the
+    /// current page with one lock on the page.
+    fn collect_page_tuples<'a>(
         &'a self,
         db: &DB,
-        txn: &'a mut Transaction,
-    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
-        let smgr = db.get_storage_manager();
-        let heap_pages = self.get_size_in_page(smgr)?;
-        let xid = txn.xid();
-        let snapshot = db.get_transaction_manager().get_snapshot(txn)?;
-        let heap_it = HeapScanIterator {
-            heap: &self,
-            xid,
-            snapshot,
-            inited: false,
-            tuple: HeapTuple::new(self.rel_id(), &[]).materialize(),
-            cur_page: None,
-            cur_page_num: 0,
-            num_tuples: 0,
-            heap_pages,
-            start_page: 0,
-            max_pages: None,
+        iterator: &HeapScanIterator<'a>,
+        dir: ScanDirection,
+        max: usize,
+    ) -> Result<Vec<HeapTuple<'a>>> {
+        let page = match &iterator.cur_page {
+            Some(page) => page,
+            None => return Ok(Vec::new()),
         };
 
-        Ok(Box::new(heap_it))
+        let offset = iterator.tuple.ptr.expect("impossible").offset;
+        let offsets = match dir {
+            ScanDirection::Forward => (offset + 1..=iterator.num_tuples).collect::<Vec<_>>(),
+            ScanDirection::Backward => (1..offset).rev().collect::<Vec<_>>(),
+            ScanDirection::NoMovement => Vec::new(),
+        };
+
+        HeapPageViewMut::with_page(page, |page_view| {
+            let mut tuples = Vec::new();
+            let mut dirty = false;
+
+            for offset in offsets.iter().copied() {
+                if tuples.len() >= max {
+                    break;
+                }
+
+                let item_pointer = ItemPointer::new(iterator.cur_page_num, offset);
+                let (modified, htup) = self.read_visible_tuple(
+                    db,
+                    &iterator.visibility_context(),
+                    page_view,
+                    item_pointer,
+                    iterator.hint_bit_writes,
+                )?;
+                dirty = dirty || modified;
+
+                if let Some(htup) = htup {
+                    tuples.push(htup);
+                }
+            }
+
+            Ok((dirty, tuples))
+        })
     }
 
-    fn fetch_tuple<'a>(
+    fn get_next_tuple<'a>(
         &'a self,
-        db: &'a DB,
-        xid: XID,
-        snapshot: &Snapshot,
-        item_pointer: ItemPointer,
-    ) -> Result<Option<TuplePtr<'a>>> {
-        let ItemPointer { page_num, offset } = item_pointer;
+        db: &DB,
+        iterator: &mut HeapScanIterator<'a>,
+        dir: ScanDirection,
+    ) -> Result<bool> {
+        if dir == ScanDirection::NoMovement {
+            // stay on the current tuple, if the scan has returned any
+            return Ok(iterator.inited && iterator.tuple.ptr.is_some());
+        }
 
-        self.with_storage(db.get_storage_manager(), |storage| {
-            let page_ptr =
-                db.get_buffer_manager()
-                    .fetch_page(db, storage, ForkType::Main, page_num)?;
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
 
-            let htup = HeapPageViewMut::with_page(&page_ptr, |page_view| {
-                let mut dirty = false;
-                let valid = {
-                    let item = page_view.get_item(offset);
-                    // deserialize the tuple to check visibility
-                    let mut htup = match bincode::deserialize::<HeapTuple>(item) {
-                        Ok(htup) => htup,
-                        _ => {
-                            return Err(Error::DataCorrupted(
-                                "cannot deserialize heap tuple".to_owned(),
-                            ));
-                        }
+        let mut offset: usize = 0;
+        let mut remaining_tuples: usize;
+        match dir {
+            ScanDirection::Forward => {
+                if !iterator.inited {
+                    if iterator.heap_pages == 0 {
+                        // empty heap, done
+                        return Ok(false);
+                    }
+
+                    let page_num = iterator.start_page;
+                    self.with_storage(smgr, |storage| iterator.fetch_page(db, storage, page_num))?;
+                    offset = iterator.start_offset;
+                    iterator.inited = true;
+                } else {
+                    // continue from last tuple
+                    let item_ptr = iterator.tuple.ptr.expect("impossible");
+                    let next_off = item_ptr.next();
+                    offset = next_off.offset;
+                }
+
+                match &iterator.cur_page {
+                    None => {
+                        return Err(Error::InvalidState(
+                            "page not present for heap scan iterator".to_owned(),
+                        ))
+                    }
+                    Some(page) => {
+                        remaining_tuples = HeapPageView::with_page(page, |page_view| {
+                            Ok((page_view.num_line_pointers() + 1).saturating_sub(offset))
+                        })?;
+                    }
+                }
+            }
+            ScanDirection::Backward => {
+                if !iterator.inited {
+                    if iterator.heap_pages == 0 {
+                        // empty heap, done
+                        return Ok(false);
+                    }
+
+                    let page_num = if iterator.start_page > 0 {
+                        iterator.start_page
+                    } else {
+                        iterator.heap_pages
+                    } - 1;
+
+                    self.with_storage(smgr, |storage| iterator.fetch_page(db, storage, page_num))?;
+                    remaining_tuples = iterator.num_tuples;
+                    offset = if remaining_tuples > 0 {
+                        remaining_tuples
+                    } else {
+                        1
                     };
+                    iterator.inited = true;
+                } else {
+                    // continue from last tuple
+                    let item_ptr = iterator.tuple.ptr.expect("impossible");
+                    match item_ptr.prev() {
+                        None => {
+                            remaining_tuples = 0;
+                        }
+                        Some(prev_off) => {
+                            offset = prev_off.offset;
+                            remaining_tuples = offset;
+                        }
+                    }
+                }
+            }
+            ScanDirection::NoMovement => unreachable!(),
+        }
+
+        loop {
+            if let Err(e) = iterator.interrupts.check() {
+                if let Some(page) = iterator.cur_page.take() {
+                    bufmgr.release_page(page)?;
+                }
+                iterator.inited = false;
+                return Err(e);
+            }
+
+            match &iterator.cur_page {
+                Some(page) => {
+                    match HeapPageViewMut::with_page(page, |page_view| {
+                        Self::check_snapshot_age(db, iterator.visibility.snapshot, page_view)?;
+
+                        let mut remaining_tuples = remaining_tuples;
+                        let mut offset = offset;
+                        let mut dirty = false;
+
+                        while remaining_tuples > 0 {
+                            let valid = {
+                                let item = page_view.get_item(offset);
+                                // deserialize the tuple to check visibility
+                                let mut htup = bincode::deserialize::<HeapTuple>(item)?;
+
+                                let (valid, new_flags) =
+                                    htup.is_visible(db, &iterator.visibility_context())?;
+
+                                if new_flags != 0 && iterator.hint_bit_writes {
+                                    // install the new hint bits to the page. the page
+                                    // LSN is not advanced because transactions are marked
+                                    // committed only after their commit logs are flushed
+                                    htup.flags |= new_flags;
+                                    let htup_buf = bincode::serialize(&htup)?;
+                                    page_view.set_item(offset, &htup_buf)?;
+                                    dirty = true;
+                                }
+
+                                valid
+                            };
+
+                            if valid {
+                                let item = page_view.get_item(offset);
+                                let htup_buf = unsafe {
+                                    // extend the lifetime of buf to 'a
+                                    // this is ok because we keep a pinned page in the scan iterator
+                                    // so the page buffer will be valid until the next iteration
+                                    std::mem::transmute::<&[u8], &'a [u8]>(item)
+                                };
+
+                                let mut htup = bincode::deserialize::<HeapTuple>(htup_buf)?;
+
+                                htup.table_id = self.rel_id();
+                                htup.set_pointer(ItemPointer::new(iterator.cur_page_num, offset));
+
+                                return Ok((dirty, Some(htup)));
+                            }
+
+                            remaining_tuples -= 1;
+
+                            match dir {
+                                ScanDirection::Forward => {
+                                    offset += 1;
+                                }
+                                ScanDirection::Backward => {
+                                    offset -= 1;
+                                }
+                                ScanDirection::NoMovement => unreachable!(),
+                            }
+                        }
+                        // we've scanned all tuples on the current page, go to the next page
+                        Ok((dirty, None))
+                    }) {
+                        Err(e) => {
+                            // end the scan so that the page is not leaked
+                            if let Some(page) = iterator.cur_page.take() {
+                                bufmgr.release_page(page)?;
+                            }
+                            iterator.inited = false;
+                            return Err(e);
+                        }
+
+                        Ok(Some(htup)) => {
+                            iterator.tuple = htup;
+                            return Ok(true);
+                        }
+
+                        Ok(None) => {
+                            let mut finished;
+                            let mut next_page;
+
+                            match dir {
+                                // move to the next page
+                                ScanDirection::Forward => {
+                                    next_page = iterator.cur_page_num;
+                                    next_page += 1;
+
+                                    if next_page >= iterator.heap_pages {
+                                        next_page = 0;
+                                    }
+
+                                    finished = next_page == iterator.start_page;
+
+                                    if let Some(limit) = &mut iterator.max_pages {
+                                        if *limit == 0 {
+                                            finished = true;
+                                        } else {
+                                            *limit -= 1;
+                                        }
+                                    }
+                                }
+                                ScanDirection::Backward => {
+                                    finished = iterator.cur_page_num == iterator.start_page;
+
+                                    if let Some(limit) = &mut iterator.max_pages {
+                                        if *limit == 0 {
+                                            finished = true;
+                                        } else {
+                                            *limit -= 1;
+                                        }
+                                    }
+
+                                    next_page = if iterator.cur_page_num > 0 {
+                                        iterator.cur_page_num
+                                    } else {
+                                        iterator.heap_pages
+                                    } - 1;
+                                }
+                                ScanDirection::NoMovement => unreachable!(),
+                            }
+
+                            if finished {
+                                // no more pages
+                                let page = iterator.cur_page.take();
+
+                                if let Some(page) = page {
+                                    bufmgr.release_page(page)?;
+                                }
+
+                                iterator.tuple = HeapTuple::new(self.rel_id(), &[]).materialize();
+                                iterator.inited = false;
+
+                                return Ok(false);
+                            }
+
+                            self.with_storage(smgr, |storage| {
+                                iterator.fetch_page(db, storage, next_page)
+                            })?;
+
+                            remaining_tuples = iterator.num_tuples;
+
+                            match dir {
+                                ScanDirection::Forward => {
+                                    offset = 1;
+                                }
+                                ScanDirection::Backward => {
+                                    offset = remaining_tuples;
+                                }
+                                ScanDirection::NoMovement => unreachable!(),
+                            }
+                        }
+                    }
+                }
+                None => {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+impl Relation for Heap {
+    fn get_relation_entry(&self) -> &RelationEntry {
+        &self.rel_entry
+    }
+}
+
+pub struct HeapScanIterator<'a> {
+    heap: &'a Heap,
+    visibility: VisibilityContext<'a>,
+    // final statuses of the transactions that touch the tuples on the current page
+    xid_statuses: HashMap<XID, TransactionStatus>,
+    inited: bool,
+    tuple: HeapTuple<'a>,
+    cur_page: Option<PinnedPagePtr>,
+    cur_page_num: usize,
+    num_tuples: usize,
+    heap_pages: usize,
+    start_page: usize,
+    // offset of the first tuple on the start page for forward scans
+    start_offset: usize,
+    max_pages: Option<usize>,
+    hint_bit_writes: bool,
+    // reports the current page to the synchronized scan registry
+    sync_scan: Option<SyncScan>,
+    interrupts: InterruptCheck,
+    // only the tuples matching the filter are returned
+    filter: Option<TableScanPredicate<'a>>,
+}
+
+impl<'a> HeapScanIterator<'a> {
+    fn fetch_page(&mut self, db: &DB, shandle: &StorageHandle, page_num: usize) -> Result<()> {
+        let bufmgr = db.get_buffer_manager();
+
+        let old_page = self.cur_page.take();
+        if let Some(page) = old_page {
+            bufmgr.release_page(page)?;
+        }
+
+        let page = bufmgr.fetch_page(db, shandle, ForkType::Main, page_num)?;
+        self.cur_page_num = page_num;
+
+        if let Some(sync_scan) = &self.sync_scan {
+            sync_scan.report_position(page_num);
+        }
+
+        let (num_tuples, xid_statuses) = HeapPageView::with_page(&page, |page_view| {
+            Ok((
+                page_view.num_line_pointers(),
+                Heap::prefetch_xid_statuses(db, page_view)?,
+            ))
+        })?;
+        self.num_tuples = num_tuples;
+        self.xid_statuses = xid_statuses;
+
+        self.cur_page = Some(page);
+
+        Ok(())
+    }
+}
+
+impl<'a> HeapScanIterator<'a> {
+    /// Test the tuple against the filter of the scan without pinning its page again. The scan is
+    /// ended if the filter fails so that the page is not leaked.
+    fn matches_filter(&mut self, db: &DB, htup: &HeapTuple<'a>) -> Result<bool> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return Ok(true),
+        };
+
+        match filter(&BufferHeapTuple {
+            tuple: htup.clone(),
+            bufmgr: None,
+            page: None,
+        }) {
+            Ok(matched) => Ok(matched),
+            Err(e) => {
+                if let Some(page) = self.cur_page.take() {
+                    db.get_buffer_manager().release_page(page)?;
+                }
+                self.inited = false;
+                Err(e)
+            }
+        }
+    }
+
+    fn visibility_context(&self) -> VisibilityContext<'_> {
+        VisibilityContext {
+            statuses: Some(&self.xid_statuses),
+            ..self.visibility
+        }
+    }
+}
+
+impl<'a> TableScanIterator<'a> for HeapScanIterator<'a> {
+    fn next(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<TuplePtr<'a>>> {
+        while self.heap.get_next_tuple(db, self, dir)? {
+            if self.matches_filter(db, &self.tuple.clone())? {
+                let buffer_tuple = BufferHeapTuple {
+                    tuple: self.tuple.clone(),
+                    bufmgr: Some(db.get_buffer_manager()),
+                    page: self.cur_page.clone(),
+                };
+                return Ok(Some(Box::new(buffer_tuple)));
+            }
+
+            if dir == ScanDirection::NoMovement {
+                break;
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn set_hint_bit_writes(&mut self, enabled: bool) {
+        self.hint_bit_writes = enabled;
+    }
+
+    fn mark(&mut self) -> TableScanMark {
+        TableScanMark {
+            item_pointer: if self.inited { self.tuple.ptr } else { None },
+            remaining_pages: self.max_pages,
+        }
+    }
+
+    fn restore(&mut self, db: &'a DB, mark: TableScanMark) -> Result<()> {
+        self.max_pages = mark.remaining_pages;
+
+        let item_pointer = match mark.item_pointer {
+            Some(item_pointer) => item_pointer,
+            None => {
+                // start over from the start page
+                if let Some(page) = self.cur_page.take() {
+                    db.get_buffer_manager().release_page(page)?;
+                }
+                self.tuple = HeapTuple::new(self.heap.rel_id(), &[]).materialize();
+                self.inited = false;
+                return Ok(());
+            }
+        };
+
+        if self.cur_page.is_none() || self.cur_page_num != item_pointer.page_num {
+            let heap = self.heap;
+            heap.with_storage(db.get_storage_manager(), |storage| {
+                self.fetch_page(db, storage, item_pointer.page_num)
+            })?;
+        }
+
+        let page = self.cur_page.as_ref().expect("impossible");
+        let mut htup = HeapPageView::with_page(page, |page_view| {
+            if item_pointer.offset == 0 || item_pointer.offset > page_view.num_line_pointers() {
+                return Err(Error::InvalidState(format!(
+                    "marked tuple {:?} no longer exists",
+                    item_pointer
+                )));
+            }
+
+            let htup = bincode::deserialize::<HeapTuple>(page_view.get_item(item_pointer.offset))?;
+            Ok(htup.materialize())
+        })?;
+
+        htup.table_id = self.heap.rel_id();
+        htup.set_pointer(item_pointer);
+        self.tuple = htup;
+        self.inited = true;
+        Ok(())
+    }
+
+    fn next_batch(
+        &mut self,
+        db: &'a DB,
+        dir: ScanDirection,
+        max: usize,
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        if dir == ScanDirection::NoMovement {
+            return Ok(self.next(db, dir)?.into_iter().collect());
+        }
+
+        let mut tuples: Vec<TuplePtr<'a>> = Vec::new();
+
+        while tuples.len() < max {
+            if !self.heap.get_next_tuple(db, self, dir)? {
+                break;
+            }
+
+            // take the rest of the visible tuples on the current page
+            let mut htups = vec![self.tuple.clone()];
+            htups.extend(
+                self.heap
+                    .collect_page_tuples(db, self, dir, max - tuples.len() - 1)?,
+            );
+
+            // continue from the last collected tuple
+            if let Some(htup) = htups.last() {
+                self.tuple = htup.clone();
+            }
+
+            for htup in htups {
+                if !self.matches_filter(db, &htup)? {
+                    continue;
+                }
+
+                tuples.push(Box::new(BufferHeapTuple {
+                    tuple: htup,
+                    bufmgr: Some(db.get_buffer_manager()),
+                    page: self.cur_page.clone(),
+                }));
+            }
+        }
+
+        Ok(tuples)
+    }
+}
+
+/// Scan that copies only the projected attributes of the rows out of the pages
+struct ProjectedScanIterator<'a> {
+    heap: &'a Heap,
+    inner: Box<dyn TableScanIterator<'a> + 'a>,
+    columns: Vec<usize>,
+}
+
+impl<'a> ProjectedScanIterator<'a> {
+    fn project(&self, tuple: TuplePtr<'a>) -> Result<TuplePtr<'a>> {
+        let mut htup = HeapTuple::new(self.heap.rel_id(), &[]).materialize();
+        htup.ptr = tuple.get_item_pointer();
+        htup.flags = HeapTupleFlags::HAS_ATTRS.bits();
+        htup.data = catalog::project_attrs(tuple.get_data(), &self.columns)?.into();
+
+        Ok(Box::new(BufferHeapTuple {
+            tuple: htup,
+            bufmgr: None,
+            page: None,
+        }))
+    }
+}
+
+impl<'a> TableScanIterator<'a> for ProjectedScanIterator<'a> {
+    fn next(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<TuplePtr<'a>>> {
+        match self.inner.next(db, dir)? {
+            Some(tuple) => Ok(Some(self.project(tuple)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_hint_bit_writes(&mut self, enabled: bool) {
+        self.inner.set_hint_bit_writes(enabled);
+    }
+
+    fn mark(&mut self) -> TableScanMark {
+        self.inner.mark()
+    }
+
+    fn restore(&mut self, db: &'a DB, mark: TableScanMark) -> Result<()> {
+        self.inner.restore(db, mark)
+    }
+
+    fn next_batch(
+        &mut self,
+        db: &'a DB,
+        dir: ScanDirection,
+        max: usize,
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        self.inner
+            .next_batch(db, dir, max)?
+            .into_iter()
+            .map(|tuple| self.project(tuple))
+            .collect()
+    }
+}
+
+impl Table for Heap {
+    fn file_size(&self, db: &DB, fork: ForkType) -> Result<usize> {
+        let smgr = db.get_storage_manager();
+
+        self.with_storage(smgr, |storage| {
+            let pages = smgr.file_size_in_page(storage, fork)?;
+            Ok(pages * PAGE_SIZE)
+        })
+    }
+
+    fn insert_tuple(&self, db: &DB, txn: &Transaction, tuple: &[u8]) -> Result<ItemPointer> {
+        self.check_row(tuple)?;
+
+        if tuple.len() > Self::max_inline_tuple_size() {
+            return Err(Error::ProgramLimitExceed(format!(
+                "tuple size {} exceeds limit {}",
+                tuple.len(),
+                Self::max_inline_tuple_size()
+            )));
+        }
+
+        self.lock_for_access(db, txn)?;
+
+        let htup = self.prepare_heap_tuple_for_insert(txn.xid(), txn.command_id(), tuple);
+        let htup_buf = bincode::serialize(&htup)?;
+        let htup_len = htup_buf.len();
+
+        let itemp = self.with_page_for_tuple(db, htup_len, |page_view, page_num| {
+            let itemp = self.put_tuple(db, txn, page_view, page_num, &htup, &htup_buf)?;
+            Ok((itemp, true))
+        })?;
+        Ok(itemp)
+    }
+
+    fn delete_tuple(&self, db: &DB, txn: &Transaction, item_pointer: ItemPointer) -> Result<()> {
+        self.delete_latest_version(db, txn, item_pointer)?;
+        Ok(())
+    }
+
+    fn update_tuple(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        item_pointer: ItemPointer,
+        tuple: &[u8],
+        hot_allowed: bool,
+    ) -> Result<ItemPointer> {
+        self.check_row(tuple)?;
+
+        let old_version = self.delete_latest_version(db, txn, item_pointer)?;
+        // a heap-only version is found through the index entries of the old version, which must
+        // not be reached with the old keys
+        let heap_only_version = if hot_allowed {
+            self.insert_heap_only_tuple(db, txn, old_version.page_num, tuple)?
+        } else {
+            None
+        };
+        let new_version = match heap_only_version {
+            Some(new_version) => new_version,
+            None => self.insert_tuple(db, txn, tuple)?,
+        };
+        // the link is set before the transaction ends, which is when other transactions
+        // waiting on the update read it
+        self.set_next_version(db, txn, old_version, new_version)?;
+
+        Ok(new_version)
+    }
+
+    fn truncate(&self, db: &DB, txn: &mut Transaction) -> Result<()> {
+        db.truncate_table(
+            txn,
+            RelFileRef {
+                db: self.rel_db(),
+                rel_id: self.rel_id(),
+            },
+        )
+    }
+
+    fn begin_scan<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
+        self.begin_full_scan(db, txn, false, None)
+    }
+
+    fn begin_scan_projected<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        columns: &[usize],
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
+        let num_attrs = match &self.tuple_desc {
+            Some(tuple_desc) => tuple_desc.num_attrs(),
+            None => {
+                return Err(Error::InvalidState(format!(
+                    "table {} has no tuple descriptor",
+                    self.rel_id()
+                )))
+            }
+        };
+
+        if let Some(column) = columns.iter().find(|column| **column >= num_attrs) {
+            return Err(Error::InvalidArgument(format!(
+                "column {} is out of range, the table has {} attributes",
+                column, num_attrs
+            )));
+        }
+
+        Ok(Box::new(ProjectedScanIterator {
+            heap: self,
+            inner: self.begin_full_scan(db, txn, false, None)?,
+            columns: columns.to_vec(),
+        }))
+    }
+
+    fn begin_scan_filtered<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        predicate: TableScanPredicate<'a>,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
+        self.begin_full_scan(db, txn, false, Some(predicate))
+    }
+
+    fn begin_sync_scan<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
+        self.begin_full_scan(db, txn, true, None)
+    }
+
+    fn begin_scan_from<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        start: ItemPointer,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>> {
+        self.lock_for_access(db, txn)?;
+        let smgr = db.get_storage_manager();
+        let ItemPointer { page_num, offset } = start;
+        let heap_pages = self.get_size_in_page(smgr)?;
+        let xid = txn.xid();
+        let interrupts = txn.interrupt_check();
+        let snapshot = db.get_transaction_manager().get_snapshot(txn)?;
+        let heap_it = HeapScanIterator {
+            heap: self,
+            visibility: VisibilityContext::new(snapshot, xid),
+            xid_statuses: HashMap::new(),
+            inited: false,
+            tuple: HeapTuple::new(self.rel_id(), &[]).materialize(),
+            cur_page: None,
+            cur_page_num: 0,
+            num_tuples: 0,
+            // nothing to scan if the start page is past the end of the heap
+            heap_pages: if page_num < heap_pages { heap_pages } else { 0 },
+            start_page: page_num,
+            start_offset: std::cmp::max(offset, 1),
+            // do not wrap around to the pages before the start page
+            max_pages: Some(heap_pages.saturating_sub(page_num + 1)),
+            hint_bit_writes: db.get_config().hint_bit_writes,
+            sync_scan: None,
+            interrupts,
+            filter: None,
+        };
+
+        Ok(Box::new(heap_it))
+    }
+
+    fn count_visible(&self, db: &DB, txn: &mut Transaction) -> Result<u64> {
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let xid = txn.xid();
+        let mut interrupts = txn.interrupt_check();
+        let snapshot = db.get_transaction_manager().get_snapshot(txn)?;
+        let ctx = VisibilityContext::new(snapshot, xid);
+        let hint_bits = db.get_config().hint_bit_writes;
+        let mut count = 0;
+
+        for page_num in 0..self.get_size_in_page(smgr)? {
+            interrupts.check()?;
+
+            let page_ptr = self.with_storage(smgr, |storage| {
+                bufmgr.fetch_page(db, storage, ForkType::Main, page_num)
+            })?;
+
+            let result = HeapPageViewMut::with_page(&page_ptr, |page_view| {
+                if page_view.is_new() {
+                    return Ok((false, 0));
+                }
+
+                let mut page_count = 0;
+                let mut dirty = false;
+                let statuses = Self::prefetch_xid_statuses(db, page_view)?;
+                let ctx = VisibilityContext {
+                    statuses: Some(&statuses),
+                    ..ctx
+                };
+
+                for offset in 1..=page_view.num_line_pointers() {
+                    let (valid, modified) =
+                        Self::check_visibility(db, &ctx, page_view, offset, hint_bits)?;
+                    dirty = dirty || modified;
+
+                    if valid {
+                        page_count += 1;
+                    }
+                }
+
+                Ok((dirty, page_count))
+            });
+
+            bufmgr.release_page(page_ptr)?;
+            count += result?;
+        }
+
+        Ok(count)
+    }
+
+    fn fetch_tuple<'a>(
+        &'a self,
+        db: &'a DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        item_pointer: ItemPointer,
+    ) -> Result<Option<TuplePtr<'a>>> {
+        let bufmgr = db.get_buffer_manager();
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| {
+            bufmgr.fetch_page(db, storage, ForkType::Main, item_pointer.page_num)
+        })?;
+
+        match self.fetch_tuple_on_page(db, xid, snapshot, &page_ptr, item_pointer) {
+            Ok(Some(htup)) => {
+                let buffer_tuple = BufferHeapTuple {
+                    tuple: htup,
+                    bufmgr: Some(bufmgr),
+                    page: Some(page_ptr),
+                };
+                Ok(Some(Box::new(buffer_tuple)))
+            }
+            result => {
+                bufmgr.release_page(page_ptr)?;
+                result.map(|_| None)
+            }
+        }
+    }
+
+    fn fetch_tuple_dirty<'a>(
+        &'a self,
+        db: &'a DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        item_pointer: ItemPointer,
+    ) -> Result<Option<(TuplePtr<'a>, Option<XID>)>> {
+        if !snapshot.is_dirty() {
+            return Err(Error::InvalidArgument(
+                "dirty fetch needs a dirty snapshot".to_owned(),
+            ));
+        }
+
+        let bufmgr = db.get_buffer_manager();
+        let page_ptr = self.with_storage(db.get_storage_manager(), |storage| {
+            bufmgr.fetch_page(db, storage, ForkType::Main, item_pointer.page_num)
+        })?;
+
+        let result = HeapPageView::with_page(&page_ptr, |page_view| {
+            let mut item_pointer = item_pointer;
+
+            // follow the heap-only versions like `fetch_tuple`
+            for _ in 0..page_view.num_line_pointers() {
+                let item = page_view.get_item(item_pointer.offset);
+                let htup = bincode::deserialize::<HeapTuple>(item)?;
+                let (live, in_progress_xid) = htup.is_visible_dirty(db, snapshot, xid)?;
+
+                if live {
+                    let mut htup = htup.materialize();
+                    htup.table_id = self.rel_id();
+                    htup.set_pointer(item_pointer);
+                    return Ok(Some((htup, in_progress_xid)));
+                }
+
+                match Self::get_heap_only_successor(
+                    page_view,
+                    item_pointer.page_num,
+                    item_pointer.offset,
+                )? {
+                    Some(offset) => item_pointer.offset = offset,
+                    None => break,
+                }
+            }
+
+            Ok(None)
+        });
+        bufmgr.release_page(page_ptr)?;
+
+        Ok(result?.map(|(htup, in_progress_xid)| {
+            let tuple: TuplePtr<'a> = Box::new(BufferHeapTuple {
+                tuple: htup,
+                bufmgr: None,
+                page: None,
+            });
+            (tuple, in_progress_xid)
+        }))
+    }
+
+    fn fetch_tuples<'a>(
+        &'a self,
+        db: &'a DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        item_pointers: &[ItemPointer],
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        let bufmgr = db.get_buffer_manager();
+        let mut tuples: Vec<TuplePtr<'a>> = Vec::new();
+        // keep the last page pinned so that tuples on the same page are fetched only once
+        let mut cur_page: Option<(usize, PinnedPagePtr)> = None;
+
+        for item_pointer in item_pointers.iter().copied() {
+            let page_ptr = match cur_page.take() {
+                Some((page_num, page_ptr)) if page_num == item_pointer.page_num => page_ptr,
+                last_page => {
+                    if let Some((_, page_ptr)) = last_page {
+                        bufmgr.release_page(page_ptr)?;
+                    }
+
+                    self.with_storage(db.get_storage_manager(), |storage| {
+                        bufmgr.fetch_page(db, storage, ForkType::Main, item_pointer.page_num)
+                    })?
+                }
+            };
+
+            match self.fetch_tuple_on_page(db, xid, snapshot, &page_ptr, item_pointer) {
+                Ok(Some(htup)) => {
+                    tuples.push(Box::new(BufferHeapTuple {
+                        tuple: htup,
+                        bufmgr: Some(bufmgr),
+                        page: Some(page_ptr.clone()),
+                    }));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    bufmgr.release_page(page_ptr)?;
+                    return Err(e);
+                }
+            }
+
+            cur_page = Some((item_pointer.page_num, page_ptr));
+        }
+
+        if let Some((_, page_ptr)) = cur_page {
+            bufmgr.release_page(page_ptr)?;
+        }
+
+        Ok(tuples)
+    }
+}
+
+impl RelationWithStorage for Heap {
+    fn get_storage_handle(&self) -> &Mutex<Option<StorageHandle>> {
+        &self.shandle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        heap_page::HeapPageViewMut, Heap, HeapInsertStrategy, HeapLogRecord, HeapTuple,
+        HeapTupleFlags, VisibilityContext, HEAP_INSERT_SPREAD,
+    };
+    use crate::{
+        am::index::IndexScanPredicate,
+        concurrency::{IsolationLevel, LockMode, Transaction, XID},
+        storage::{
+            consts::PAGE_SIZE, ForkType, ItemPageWriter, RelFileRef, RelationWithStorage,
+            ScanDirection, Table, TableScanPredicate,
+        },
+        test_util::get_temp_db,
+        AttrLength, DBConfig, Error, RelOptions, TupleDescriptor, DB,
+    };
+    use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Barrier};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn can_create_heap() {
+        let (db, db_dir) = get_temp_db();
+        assert!(db.create_table(0, 0).is_ok());
+
+        let mut rel_path = db_dir.path().to_path_buf();
+        rel_path.push("base");
+        rel_path.push("0");
+        rel_path.push("0_0");
+
+        assert!(rel_path.is_file());
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_insert_and_scan_heap() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let data: &[u8] = &[1u8; 100];
+        for _ in 0..100 {
+            assert!(heap.insert_tuple(&db, &txn, data).is_ok());
+        }
+
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(tuple.get_data(), data);
+                count += 1;
+            }
+            assert_eq!(count, 100);
+
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Backward).unwrap() {
+                assert_eq!(tuple.get_data(), data);
+                count += 1;
+            }
+            assert_eq!(count, 100);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_extend_heap_concurrently() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let inserted = thread::scope(|s| {
+            let threads = (0..8u8)
+                .map(|i| {
+                    let db = &db;
+                    let heap = &heap;
+                    s.spawn(move || {
+                        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                        let item_ptrs = (0..200u8)
+                            .map(|j| {
+                                let data = [i, j].repeat(50);
+                                (heap.insert_tuple(db, &txn, &data).unwrap(), data)
+                            })
+                            .collect::<Vec<_>>();
+                        db.commit_transaction(txn).unwrap();
+                        item_ptrs
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let unique = inserted
+            .iter()
+            .map(|(item_ptr, _)| *item_ptr)
+            .collect::<HashSet<_>>();
+        assert_eq!(unique.len(), 1600);
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1600);
+        let xid = txn.xid();
+        let snapshot = db.get_transaction_manager().get_snapshot(&mut txn).unwrap();
+        for (item_ptr, data) in &inserted {
+            let tuple = heap
+                .fetch_tuple(&db, xid, snapshot, *item_ptr)
+                .unwrap()
+                .unwrap();
+            assert_eq!(tuple.get_data(), &data[..]);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_synchronize_scans() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let db = DB::open(&config).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut expected = HashSet::new();
+        for _ in 0..500 {
+            expected.insert(heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap());
+        }
+        db.commit_transaction(txn).unwrap();
+        let num_pages = heap.file_size(&db, ForkType::Main).unwrap() / PAGE_SIZE;
+        assert!(num_pages > 4);
+
+        let mut txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter1 = heap.begin_sync_scan(&db, &mut txn1).unwrap();
+            let mut seen1 = HashSet::new();
+            let mut page_num = 0;
+            while page_num < 3 {
+                let tuple = iter1.next(&db, ScanDirection::Forward).unwrap().unwrap();
+                let item_ptr = tuple.get_item_pointer().unwrap();
+                page_num = item_ptr.page_num;
+                seen1.insert(item_ptr);
+            }
+
+            // the second scan joins the first one at its current page
+            let mut iter2 = heap.begin_sync_scan(&db, &mut txn2).unwrap();
+            let mut seen2 = HashSet::new();
+            let tuple = iter2.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            let item_ptr = tuple.get_item_pointer().unwrap();
+            assert!(item_ptr.page_num >= 3 && item_ptr.page_num <= 4);
+            seen2.insert(item_ptr);
+
+            let (mut done1, mut done2) = (false, false);
+            while !done1 || !done2 {
+                if !done1 {
+                    match iter1.next(&db, ScanDirection::Forward).unwrap() {
+                        Some(tuple) => assert!(seen1.insert(tuple.get_item_pointer().unwrap())),
+                        None => done1 = true,
+                    }
+                }
+                if !done2 {
+                    match iter2.next(&db, ScanDirection::Forward).unwrap() {
+                        Some(tuple) => assert!(seen2.insert(tuple.get_item_pointer().unwrap())),
+                        None => done2 = true,
+                    }
+                }
+            }
+
+            assert_eq!(seen1, expected);
+            assert_eq!(seen2, expected);
+        }
+        db.commit_transaction(txn1).unwrap();
+        db.commit_transaction(txn2).unwrap();
+        drop(heap);
+        drop(db);
+
+        // scans of a relation that is not being scanned start at a random page
+        let db = DB::open(&config.sync_scan_random_start(true)).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut start_pages = HashSet::new();
+        for _ in 0..20 {
+            // the position is cleared when the previous scan ends
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            {
+                let mut iter = heap.begin_sync_scan(&db, &mut txn).unwrap();
+                let mut seen = HashSet::new();
+                while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                    let item_ptr = tuple.get_item_pointer().unwrap();
+                    if seen.is_empty() {
+                        start_pages.insert(item_ptr.page_num);
+                    }
+                    seen.insert(item_ptr);
+                }
+                assert_eq!(seen, expected);
+            }
+            db.commit_transaction(txn).unwrap();
+        }
+        assert!(start_pages.len() > 1);
+
+        drop(heap);
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_skip_pages_added_during_scan() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..10 {
+            heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+        let old_pages = heap.file_size(&db, ForkType::Main).unwrap() / PAGE_SIZE;
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_some());
+
+            // a concurrent insert extends the heap before the scan reaches the end
+            let writer = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for _ in 0..300 {
+                heap.insert_tuple(&db, &writer, &[2u8; 100]).unwrap();
+            }
+            db.commit_transaction(writer).unwrap();
+            let num_pages = heap.file_size(&db, ForkType::Main).unwrap() / PAGE_SIZE;
+            assert!(num_pages > old_pages + 1);
+
+            // the tuples on the new pages are not in the snapshot of the scan
+            let mut count = 1;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(tuple.get_data(), &[1u8; 100]);
+                count += 1;
+            }
+            assert_eq!(count, 10);
+        }
+
+        // a new scan sees all tuples
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 310);
+        db.commit_transaction(txn).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_copy_relation() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 1).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for i in 0..200u32 {
+            item_ptrs.push(heap.insert_tuple(&db, &txn, &i.to_be_bytes()).unwrap());
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for item_ptr in item_ptrs.iter().step_by(4) {
+            heap.delete_tuple(&db, &txn, *item_ptr).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        // the copy waits for the transaction writing to the source
+        let writer = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 200..205u32 {
+            heap.insert_tuple(&db, &writer, &i.to_be_bytes()).unwrap();
+        }
+
+        thread::scope(|s| {
+            let copier = s.spawn(|| db.copy_relation(0, 1, 0, 2));
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(!copier.is_finished());
+
+            db.commit_transaction(writer).unwrap();
+            assert!(copier.join().unwrap().is_ok());
+        });
+        assert!(matches!(
+            db.copy_relation(0, 1, 0, 2),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            db.copy_relation(0, 3, 0, 4),
+            Err(Error::InvalidArgument(_))
+        ));
+        let copy = db.open_table(0, 2).unwrap().unwrap();
+
+        let scan_rows = |table: &dyn Table, txn: &mut Transaction| {
+            let mut rows = Vec::new();
+            let mut iter = table.begin_scan(&db, txn).unwrap();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                rows.push(tuple.get_data().to_vec());
+            }
+            rows
+        };
+
+        let mut txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        let rows = scan_rows(&*heap, &mut txn);
+        assert_eq!(rows.len(), 155);
+        assert_eq!(scan_rows(&*copy, &mut txn), rows);
+        db.commit_transaction(txn).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_scan_from_item_pointer() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        for i in 0..200 {
+            let mut data = vec![0u8; 100];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            heap.insert_tuple(&db, &txn, &data).unwrap();
+        }
+
+        txn.advance_command_id().unwrap();
+
+        let mut tuples = Vec::new();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                tuples.push((tuple.get_item_pointer().unwrap(), tuple.get_data().to_vec()));
+            }
+        }
+        assert_eq!(tuples.len(), 200);
+
+        for start in &[0, 1, 57, 150, 199] {
+            let mut iter = heap
+                .begin_scan_from(&db, &mut txn, tuples[*start].0)
+                .unwrap();
+
+            let mut rest = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                rest.push((tuple.get_item_pointer().unwrap(), tuple.get_data().to_vec()));
+            }
+            assert_eq!(&rest[..], &tuples[*start..]);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_restore_marked_heap_scan() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        for i in 0..200 {
+            let mut data = vec![0u8; 100];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            heap.insert_tuple(&db, &txn, &data).unwrap();
+        }
+
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            for _ in 0..10 {
+                iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            }
+            let mark = iter.mark();
+
+            // tuples inserted after the scan started are not visible after restoring
+            let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for _ in 0..50 {
+                heap.insert_tuple(&db, &txn2, &[0xffu8; 100]).unwrap();
+            }
+            db.commit_transaction(txn2).unwrap();
+
+            let mut rest = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                rest.push((tuple.get_item_pointer().unwrap(), tuple.get_data().to_vec()));
+            }
+            assert_eq!(rest.len(), 190);
+
+            iter.restore(&db, mark).unwrap();
+            let tuple = iter.next(&db, ScanDirection::NoMovement).unwrap().unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), 9);
+            drop(tuple);
+
+            let mut restored = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                restored.push((tuple.get_item_pointer().unwrap(), tuple.get_data().to_vec()));
+            }
+            assert_eq!(restored, rest);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_scan_heap_in_batches() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        for i in 0..200 {
+            let mut data = vec![0u8; 100];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            heap.insert_tuple(&db, &txn, &data).unwrap();
+        }
+
+        txn.advance_command_id().unwrap();
+
+        // not visible to the scans below
+        for _ in 0..50 {
+            heap.insert_tuple(&db, &txn, &[0u8; 100]).unwrap();
+        }
+
+        for dir in &[ScanDirection::Forward, ScanDirection::Backward] {
+            let mut single = Vec::new();
+            {
+                let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+                while let Some(tuple) = iter.next(&db, *dir).unwrap() {
+                    single.push((tuple.get_item_pointer().unwrap(), tuple.get_data().to_vec()));
+                }
+            }
+
+            let mut batched = Vec::new();
+            {
+                let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+                loop {
+                    // a short batch means the end of the scan
+                    let batch = iter.next_batch(&db, *dir, 7).unwrap();
+                    let done = batch.len() < 7;
+                    batched.extend(batch.into_iter().map(|tuple| {
+                        (tuple.get_item_pointer().unwrap(), tuple.get_data().to_vec())
+                    }));
+                    if done {
+                        break;
+                    }
+                }
+            }
+
+            assert_eq!(single.len(), 200);
+            assert_eq!(single, batched);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_refetch_current_tuple() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 0..10u32 {
+            let mut data = Vec::new();
+            data.write_u32::<LittleEndian>(i).unwrap();
+            heap.insert_tuple(&db, &txn, &data).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            assert!(iter.next(&db, ScanDirection::NoMovement).unwrap().is_none());
+
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), 0);
+
+            for _ in 0..2 {
+                let again = iter.next(&db, ScanDirection::NoMovement).unwrap().unwrap();
+                assert_eq!(again.get_data(), tuple.get_data());
+                assert_eq!(again.get_item_pointer(), tuple.get_item_pointer());
+            }
+
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(LittleEndian::read_u32(tuple.get_data()), 1);
+
+            // finish the scan to release the current page
+            drop(tuple);
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {}
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_count_visible_tuples() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptrs = (0..500)
+            .map(|_| heap.insert_tuple(&db, &txn, &[1u8; 50]).unwrap())
+            .collect::<Vec<_>>();
+        db.commit_transaction(txn).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for item_ptr in item_ptrs.iter().step_by(5) {
+            heap.delete_tuple(&db, &txn, *item_ptr).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        // not visible to other transactions
+        let uncommitted = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.insert_tuple(&db, &uncommitted, &[1u8; 50]).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 400);
+        db.commit_transaction(txn).unwrap();
+
+        db.commit_transaction(uncommitted).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 401);
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_reject_large_tuples() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let max_size = Heap::max_inline_tuple_size();
+        let data = vec![1u8; max_size + 1];
+
+        match heap.insert_tuple(&db, &txn, &data) {
+            Err(Error::ProgramLimitExceed(_)) => {}
+            _ => panic!("tuple larger than the limit is inserted"),
+        }
+
+        heap.insert_tuple(&db, &txn, &data[..max_size]).unwrap();
+        heap.insert_tuple(&db, &txn, &data[..max_size]).unwrap();
+
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(tuple.get_data(), &data[..max_size]);
+                count += 1;
+            }
+            assert_eq!(count, 2);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), 2 * PAGE_SIZE);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_insert_bulk() {
+        let (db, db_dir) = get_temp_db();
+        db.create_table(0, 0).unwrap();
+        let heap = Heap::new(0, 0, HeapInsertStrategy::InsertHint);
+
+        let rows = (0..10000u32)
+            .map(|i| {
+                let mut data = vec![0u8; 20];
+                (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+                data
+            })
+            .collect::<Vec<_>>();
+
+        let wal = db.get_wal();
+        let start_lsn = wal.current_lsn();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptrs = heap
+            .insert_bulk(&db, &txn, rows.iter().map(|row| &row[..]))
+            .unwrap();
+        assert_eq!(item_ptrs.len(), rows.len());
+        db.commit_transaction(txn).unwrap();
+
+        wal.flush(None).unwrap();
+        let num_records = wal.get_reader(start_lsn).unwrap().iter().count();
+        assert!(num_records * 50 < rows.len());
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let mut expected = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(LittleEndian::read_u32(tuple.get_data()), expected);
+                assert_eq!(tuple.get_item_pointer(), Some(item_ptrs[expected as usize]));
+                expected += 1;
+            }
+            assert_eq!(expected, 10000);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_redo_multi_insert() {
+        let (db, db_dir) = get_temp_db();
+        db.create_table(0, 0).unwrap();
+        let heap = Heap::new(0, 0, HeapInsertStrategy::InsertHint);
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+
+        let rows = (0..200u32)
+            .map(|i| vec![i as u8; 50 + i as usize])
+            .collect::<Vec<_>>();
+
+        let wal = db.get_wal();
+        let start_lsn = wal.current_lsn();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.insert_bulk(&db, &txn, rows.iter().map(|row| &row[..]))
+            .unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let num_pages = smgr.file_size_in_page(&shandle, ForkType::Main).unwrap();
+        assert!(num_pages > 1);
+
+        let read_pages = || {
+            (0..num_pages)
+                .map(|page_num| {
+                    let page_ptr = bufmgr
+                        .fetch_page(&db, &shandle, ForkType::Main, page_num)
+                        .unwrap();
+                    let page = page_ptr
+                        .with_read(|page| Ok(page.buffer().to_vec()))
+                        .unwrap();
+                    bufmgr.release_page(page_ptr).unwrap();
+                    page
+                })
+                .collect::<Vec<_>>()
+        };
+        let live_pages = read_pages();
+
+        // drop the pages and replay the log records on empty pages
+        bufmgr.invalidate_relation(shandle.file_ref()).unwrap();
+        smgr.truncate(&shandle, ForkType::Main, 0).unwrap();
+        wal.flush(None).unwrap();
+        wal.replay_logs(&db, start_lsn).unwrap();
+
+        assert_eq!(read_pages(), live_pages);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_truncate_heap() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in 0..200u8 {
+                heap.insert_tuple(&db, &txn, &[i; 100]).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+            let file_size = heap.file_size(&db, ForkType::Main).unwrap();
+
+            // the truncating transaction sees the empty table right away
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            heap.truncate(&db, &mut txn).unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+            heap.insert_tuple(&db, &txn, &[42u8; 10]).unwrap();
+            txn.advance_command_id().unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+            db.abort_transaction(txn).unwrap();
+
+            // the tuples are kept if it aborts
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 200);
+            assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), file_size);
+
+            // the tuples inserted after the truncation are kept when it commits
+            heap.truncate(&db, &mut txn).unwrap();
+            heap.insert_tuple(&db, &txn, &[42u8; 10]).unwrap();
+            db.commit_transaction(txn).unwrap();
+            assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), PAGE_SIZE);
+
+            // the old file is removed
+            let smgr = db.get_storage_manager();
+            assert!(!smgr.exists(0, 0, ForkType::Main).unwrap());
+
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+            db.commit_transaction(txn).unwrap();
+        }
+
+        // the new file is recorded in the catalog
+        let db = DB::open(&config).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_reset_unlogged_table() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_unlogged_table(0, 0).unwrap();
+            db.create_table(0, 1).unwrap();
+
+            // the changes are not logged
+            let lsn = db.get_wal().current_lsn();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in 0..200u8 {
+                heap.insert_tuple(&db, &txn, &[i; 100]).unwrap();
+            }
+            assert_eq!(db.get_wal().current_lsn(), lsn);
+            db.commit_transaction(txn).unwrap();
+            db.flush_all_buffers().unwrap();
+            assert!(heap.file_size(&db, ForkType::Main).unwrap() > PAGE_SIZE);
+
+            // crash without a checkpoint
+        }
+
+        let db = DB::open(&config).unwrap();
+        let smgr = db.get_storage_manager();
+        assert_eq!(
+            smgr.list_init_forks().unwrap(),
+            vec![RelFileRef { db: 0, rel_id: 0 }]
+        );
+
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), PAGE_SIZE);
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+        let lsn = db.get_wal().current_lsn();
+        heap.insert_tuple(&db, &txn, &[42u8; 10]).unwrap();
+        assert_eq!(db.get_wal().current_lsn(), lsn);
+        txn.advance_command_id().unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_get_total_size_of_forks() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_unlogged_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 0..200u8 {
+            heap.insert_tuple(&db, &txn, &[i; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let smgr = db.get_storage_manager();
+        let heap = Heap::new(0, 0, HeapInsertStrategy::InsertHint);
+        let main_pages = heap.get_size_in_page(smgr).unwrap();
+        // the init fork holds one page
+        assert_eq!(heap.total_size_in_pages(smgr).unwrap(), main_pages + 1);
+        assert_eq!(
+            db.relation_total_size_bytes(0, 0).unwrap(),
+            ((main_pages + 1) * PAGE_SIZE) as u64
+        );
+        assert!(db.relation_total_size_bytes(0, 1).is_err());
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_keep_relation_options_after_reopen() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let options = RelOptions::new().fillfactor(50).unlogged(true);
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table_with_options(0, 0, options.clone()).unwrap();
+            db.create_table(0, 1).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in 0..200u8 {
+                heap.insert_tuple(&db, &txn, &[i; 100]).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+            db.flush_all_buffers().unwrap();
+
+            // crash without a checkpoint
+        }
+
+        let db = DB::open(&config).unwrap();
+        assert_eq!(db.get_relation_options(0, 0), Some(options));
+        assert_eq!(db.get_relation_options(0, 1), Some(RelOptions::default()));
+
+        // the unlogged table is reset by the recovery
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let other = db.open_table(0, 1).unwrap().unwrap();
+        assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), PAGE_SIZE);
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 0..300u32 {
+            heap.insert_tuple(&db, &txn, &[i as u8; 100]).unwrap();
+            other.insert_tuple(&db, &txn, &[i as u8; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        // the pages of the table with fillfactor 50 are only half full
+        let pages = heap.file_size(&db, ForkType::Main).unwrap() / PAGE_SIZE;
+        let other_pages = other.file_size(&db, ForkType::Main).unwrap() / PAGE_SIZE;
+        assert!(pages >= 2 * other_pages - 1);
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_read_attributes_with_tuple_descriptor() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let tuple_desc = TupleDescriptor::new(vec![
+            AttrLength::Fixed(4),
+            AttrLength::Variable,
+            AttrLength::Variable,
+        ]);
+        let row = tuple_desc
+            .form_tuple(&[Some(&[0, 0, 0, 42]), None, Some(b"hello")])
+            .unwrap();
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db
+                .create_table_with_descriptor(0, 0, RelOptions::default(), tuple_desc.clone())
+                .unwrap();
+            let other = db.create_table(0, 1).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            heap.insert_tuple(&db, &txn, &row).unwrap();
+            other.insert_tuple(&db, &txn, &row).unwrap();
+
+            // rows not built with the descriptor are rejected
+            assert!(matches!(
+                heap.insert_tuple(&db, &txn, b"opaque"),
+                Err(Error::InvalidArgument(_))
+            ));
+            // a bad row in a batch is reported before any row is inserted
+            let rows = [&row[..], &row[..], b"opaque"];
+            let bulk_heap = Heap::new(0, 0, HeapInsertStrategy::InsertHint)
+                .tuple_descriptor(tuple_desc.clone());
+            assert!(matches!(
+                bulk_heap.insert_bulk(&db, &txn, rows.iter().copied()),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(tuple_desc.form_tuple(&[None, None]).is_err());
+            assert!(tuple_desc.form_tuple(&[Some(b"abc"), None, None]).is_err());
+            db.commit_transaction(txn).unwrap();
+        }
+
+        // the descriptor is kept in the catalog
+        let db = DB::open(&config).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let other = db.open_table(0, 1).unwrap().unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(tuple.get_data(), &row[..]);
+            assert_eq!(tuple.get_attr(0), Some(&[0, 0, 0, 42][..]));
+            assert_eq!(tuple.get_attr(1), None);
+            assert_eq!(tuple.get_attr(2), Some(&b"hello"[..]));
+            assert_eq!(tuple.get_attr(3), None);
+
+            let tuple = tuple.materialize();
+            assert_eq!(tuple.get_attr(2), Some(&b"hello"[..]));
+            assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_none());
+        }
+        {
+            // the rows of a table without a descriptor are opaque
+            let mut iter = other.begin_scan(&db, &mut txn).unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(tuple.get_data(), &row[..]);
+            assert_eq!(tuple.get_attr(0), None);
+            drop(tuple);
+            assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_none());
+        }
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_scan_projected_columns() {
+        let (db, db_dir) = get_temp_db();
+        let tuple_desc = TupleDescriptor::new(vec![
+            AttrLength::Fixed(4),
+            AttrLength::Variable,
+            AttrLength::Variable,
+        ]);
+        let heap = db
+            .create_table_with_descriptor(0, 0, RelOptions::default(), tuple_desc.clone())
+            .unwrap();
+        let other = db.create_table(0, 1).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let payload = [7u8; 2000];
+        for i in 0..10u32 {
+            let mut id = [0u8; 4];
+            LittleEndian::write_u32(&mut id, i);
+            let name = format!("row {}", i);
+            let row = tuple_desc
+                .form_tuple(&[Some(&id), Some(&payload), Some(name.as_bytes())])
+                .unwrap();
+            heap.insert_tuple(&db, &txn, &row).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap.begin_scan_projected(&db, &mut txn, &[2, 0]).unwrap();
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                let id = LittleEndian::read_u32(tuple.get_attr(1).unwrap());
+                assert_eq!(tuple.get_attr(0), Some(format!("row {}", id).as_bytes()));
+                assert_eq!(tuple.get_attr(2), None);
+                assert!(tuple.get_item_pointer().is_some());
+                // the wide column is left in the page
+                assert!(tuple.get_data().len() < 32);
+                count += 1;
+            }
+            assert_eq!(count, 10);
+        }
+
+        assert!(matches!(
+            heap.begin_scan_projected(&db, &mut txn, &[3]).err(),
+            Some(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            other.begin_scan_projected(&db, &mut txn, &[0]).err(),
+            Some(Error::InvalidState(_))
+        ));
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_scan_with_filter() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for i in 0..1000u32 {
+            let mut buf = [0u8; 100];
+            LittleEndian::write_u32(&mut buf, i);
+            heap.insert_tuple(&db, &txn, &buf).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let predicate = || {
+            TableScanPredicate::new(|tuple| Ok(LittleEndian::read_u32(tuple.get_data()) % 7 == 0))
+        };
+        let expected = (0..1000u32).filter(|i| i % 7 == 0).collect::<Vec<_>>();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap
+                .begin_scan_filtered(&db, &mut txn, predicate())
+                .unwrap();
+            let mut values = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                values.push(LittleEndian::read_u32(tuple.get_data()));
+            }
+            assert_eq!(values, expected);
+        }
+        {
+            let mut iter = heap
+                .begin_scan_filtered(&db, &mut txn, predicate())
+                .unwrap();
+            let mut values = Vec::new();
+            loop {
+                let tuples = iter.next_batch(&db, ScanDirection::Forward, 16).unwrap();
+                values.extend(
+                    tuples
+                        .iter()
+                        .map(|tuple| LittleEndian::read_u32(tuple.get_data())),
+                );
+
+                // batches are only short at the end of the scan
+                if tuples.len() < 16 {
+                    break;
+                }
+            }
+            assert_eq!(values, expected);
+        }
+        {
+            // errors of the predicate end the scan
+            let mut iter = heap
+                .begin_scan_filtered(
+                    &db,
+                    &mut txn,
+                    TableScanPredicate::new(|_| Err(Error::InvalidArgument("bad".to_owned()))),
+                )
+                .unwrap();
+            assert!(iter.next(&db, ScanDirection::Forward).is_err());
+        }
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_redo_truncate() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in 0..200u8 {
+                heap.insert_tuple(&db, &txn, &[i; 100]).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+            db.flush_all_buffers().unwrap();
+            assert!(heap.file_size(&db, ForkType::Main).unwrap() > PAGE_SIZE);
+
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            heap.truncate(&db, &mut txn).unwrap();
+            assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), 0);
+            heap.insert_tuple(&db, &txn, &[42u8; 10]).unwrap();
+            db.commit_transaction(txn).unwrap();
+
+            // crash without a checkpoint
+        }
+
+        let db = DB::open(&config).unwrap();
+        // write back the redone pages
+        db.create_checkpoint().unwrap();
+
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.file_size(&db, ForkType::Main).unwrap(), PAGE_SIZE);
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let tuple = iter.next(&db, ScanDirection::Forward).unwrap().unwrap();
+            assert_eq!(tuple.get_data(), &[42u8; 10]);
+            assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_none());
+        }
+
+        db.commit_transaction(txn).unwrap();
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn cannot_see_tuples_inserted_by_current_command() {
+        let (db, db_dir) = get_temp_db();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let data: &[u8] = &[1u8; 100];
+        for _ in 0..10 {
+            heap.insert_tuple(&db, &txn, data).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            // insert a new tuple for each tuple seen by the scan
+            let command_id = txn.command_id();
+            let mut writer_txn = Transaction::new(txn.xid(), IsolationLevel::ReadCommitted);
+            while writer_txn.command_id() < command_id {
+                writer_txn.advance_command_id().unwrap();
+            }
+
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(tuple.get_data(), data);
+                heap.insert_tuple(&db, &writer_txn, data).unwrap();
+                count += 1;
+            }
+            assert_eq!(count, 10);
+        }
+
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+
+            let mut count = 0;
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {
+                count += 1;
+            }
+            assert_eq!(count, 20);
+        }
+
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_update_latest_version_after_concurrent_update() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr = heap.insert_tuple(&db, &txn, b"a").unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr1 = heap.update_tuple(&db, &txn1, item_ptr, b"b", true).unwrap();
+
+        let item_ptr2 = thread::scope(|s| {
+            // both transactions update the version they have seen
+            let updater = s.spawn(|| heap.update_tuple(&db, &txn2, item_ptr, b"c", true).unwrap());
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(!updater.is_finished());
+            db.commit_transaction(txn1).unwrap();
+
+            updater.join().unwrap()
+        });
+        db.commit_transaction(txn2).unwrap();
+        assert_ne!(item_ptr2, item_ptr1);
+
+        let scan_data = |txn: &mut Transaction| {
+            let mut iter = heap.begin_scan(&db, txn).unwrap();
+            let mut data = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                data.push(tuple.get_data().to_vec());
+            }
+            data
+        };
+
+        // the second update applies to the version written by the first one
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(scan_data(&mut txn), vec![b"c".to_vec()]);
+        db.commit_transaction(txn).unwrap();
+
+        let mut txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert_eq!(scan_data(&mut txn), vec![b"c".to_vec()]);
+
+        let txn3 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.update_tuple(&db, &txn3, item_ptr2, b"d", true)
+            .unwrap();
+        db.commit_transaction(txn3).unwrap();
+
+        // the newer version is not in the snapshot of the repeatable read transaction
+        assert!(matches!(
+            heap.update_tuple(&db, &txn, item_ptr2, b"e", true),
+            Err(Error::InvalidState(_))
+        ));
+        drop(txn);
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn cannot_follow_aborted_update_after_delete() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr = heap.insert_tuple(&db, &txn, b"a").unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.update_tuple(&db, &txn1, item_ptr, b"b", true).unwrap();
+        db.abort_transaction(txn1).unwrap();
+
+        let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.delete_tuple(&db, &txn2, item_ptr).unwrap();
+
+        let txn3 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let result = thread::scope(|s| {
+            let updater = s.spawn(|| heap.update_tuple(&db, &txn3, item_ptr, b"c", true));
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(!updater.is_finished());
+            db.commit_transaction(txn2).unwrap();
+
+            updater.join().unwrap()
+        });
+        // the delete leaves no newer version to move on to
+        assert!(matches!(result, Err(Error::InvalidState(_))));
+        db.abort_transaction(txn3).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_detect_delete_deadlock() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_ptr1 = heap.insert_tuple(&db, &txn, b"a").unwrap();
+        let item_ptr2 = heap.insert_tuple(&db, &txn, b"b").unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        let txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.delete_tuple(&db, &txn1, item_ptr1).unwrap();
+        heap.delete_tuple(&db, &txn2, item_ptr2).unwrap();
+
+        thread::scope(|s| {
+            let deleter = s.spawn(|| heap.delete_tuple(&db, &txn1, item_ptr2));
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(!deleter.is_finished());
+
+            // each transaction waits for the other
+            assert!(matches!(
+                heap.delete_tuple(&db, &txn2, item_ptr1),
+                Err(Error::Deadlock(_))
+            ));
+            db.abort_transaction(txn2).unwrap();
+
+            assert!(deleter.join().unwrap().is_ok());
+        });
+        db.commit_transaction(txn1).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_handle_read_committed() {
+        let (db, db_dir) = get_temp_db();
+        let db = Arc::new(db);
+        db.create_table(0, 0).unwrap();
+
+        let barrier = Arc::new(Barrier::new(2));
+
+        let db1 = db.clone();
+        let b1 = barrier.clone();
+        let data: &[u8] = &[1u8; 100];
+        let thread1 = thread::spawn(move || {
+            let txn = db1
+                .start_transaction(IsolationLevel::ReadCommitted)
+                .unwrap();
+
+            let heap = db1.open_table(0, 0).unwrap().expect("");
+            for _ in 0..100 {
+                assert!(heap.insert_tuple(&db1, &txn, data).is_ok());
+            }
+
+            b1.wait(); // unblock scanning thread
+            b1.wait(); // wait for scanning thread to finish the first scan
+
+            db1.commit_transaction(txn).unwrap();
+
+            b1.wait(); // unblock scanning thread
+        });
+
+        let db2 = db.clone();
+        let b2 = barrier.clone();
+        let thread2 = thread::spawn(move || {
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+
+            let heap = db2.open_table(0, 0).unwrap().expect("");
+
+            b2.wait(); // wait for insert thread to insert the tuples
+
+            {
+                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+
+                let mut count = 0;
+                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
+                    assert_eq!(tuple.get_data(), data);
+                    count += 1;
+                }
+                //inserting thread has not yet committed the transaction
+                assert_eq!(count, 0); // no dirty reads
+            }
+
+            b2.wait(); // unblock inserting thread
+            b2.wait(); // wait for inserting thread to commit the transaction
+
+            {
+                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+
+                let mut count = 0;
+                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
+                    assert_eq!(tuple.get_data(), data);
+                    count += 1;
+                }
+                assert_eq!(count, 100); // inserting thread has committed the transaction
+            }
+
+            db2.commit_transaction(txn).unwrap();
+        });
+
+        thread1.join().unwrap();
+        thread2.join().unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_read_uncommitted_changes() {
+        let (db, db_dir) = get_temp_db();
+        db.create_table(0, 0).unwrap();
+        let heap = db.open_table(0, 0).unwrap().expect("");
+        let data: &[u8] = &[1u8; 100];
+
+        let count_tuples = |isolation_level| {
+            let mut txn = db.start_transaction(isolation_level).unwrap();
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let mut count = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(tuple.get_data(), data);
+                count += 1;
+            }
+            drop(iter);
+            db.commit_transaction(txn).unwrap();
+            count
+        };
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut ptrs = Vec::new();
+        for _ in 0..10 {
+            ptrs.push(heap.insert_tuple(&db, &txn, data).unwrap());
+        }
+        assert_eq!(count_tuples(IsolationLevel::ReadCommitted), 0);
+        assert_eq!(count_tuples(IsolationLevel::ReadUncommitted), 10);
+        db.commit_transaction(txn).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for ptr in &ptrs[..4] {
+            heap.delete_tuple(&db, &txn, *ptr).unwrap();
+        }
+        assert_eq!(count_tuples(IsolationLevel::ReadCommitted), 10);
+        assert_eq!(count_tuples(IsolationLevel::ReadUncommitted), 6);
+        db.abort_transaction(txn).unwrap();
+        assert_eq!(count_tuples(IsolationLevel::ReadUncommitted), 10);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_check_visibility_per_isolation_level() {
+        let (db, db_dir) = get_temp_db();
+        db.create_table(0, 0).unwrap();
+        let heap = db.open_table(0, 0).unwrap().expect("");
+        let data: &[u8] = &[1u8; 100];
+
+        let mut repeatable_read = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert_eq!(heap.count_visible(&db, &mut repeatable_read).unwrap(), 0);
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..10 {
+            heap.insert_tuple(&db, &txn, data).unwrap();
+        }
+
+        let count_tuples = |isolation_level| {
+            let mut txn = db.start_transaction(isolation_level).unwrap();
+            let count = heap.count_visible(&db, &mut txn).unwrap();
+            db.commit_transaction(txn).unwrap();
+            count
+        };
+        assert_eq!(count_tuples(IsolationLevel::ReadUncommitted), 10);
+        assert_eq!(count_tuples(IsolationLevel::ReadCommitted), 0);
+        assert_eq!(count_tuples(IsolationLevel::RepeatableRead), 0);
+
+        db.commit_transaction(txn).unwrap();
+        assert_eq!(count_tuples(IsolationLevel::ReadUncommitted), 10);
+        assert_eq!(count_tuples(IsolationLevel::ReadCommitted), 10);
+        // still uses the snapshot taken before the commit
+        assert_eq!(heap.count_visible(&db, &mut repeatable_read).unwrap(), 0);
+        db.commit_transaction(repeatable_read).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_read_uncommitted_with_hint_bits_set() {
+        let (db, db_dir) = get_temp_db();
+        let writer = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut reader = db
+            .start_transaction(IsolationLevel::ReadUncommitted)
+            .unwrap();
+        let reader_xid = reader.xid();
+        let snapshot = db
+            .get_transaction_manager()
+            .get_snapshot(&mut reader)
+            .unwrap();
+        let ctx = VisibilityContext::new(snapshot, reader_xid);
+
+        // the hint bits are set while the writer is still active, e.g. in its commit window
+        let mut tuple = HeapTuple::new(0, &[1u8; 10]);
+        tuple.min_xid = writer.xid();
+        tuple.flags = (HeapTupleFlags::MIN_XID_COMMITTED | HeapTupleFlags::MAX_XID_INVALID).bits();
+        assert!(tuple.is_visible(&db, &ctx).unwrap().0);
+
+        tuple.max_xid = writer.xid();
+        tuple.flags =
+            (HeapTupleFlags::MIN_XID_COMMITTED | HeapTupleFlags::MAX_XID_COMMITTED).bits();
+        assert!(!tuple.is_visible(&db, &ctx).unwrap().0);
+
+        db.commit_transaction(reader).unwrap();
+        db.commit_transaction(writer).unwrap();
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_handle_repeatable_read() {
+        let (db, db_dir) = get_temp_db();
+        let db = Arc::new(db);
+        db.create_table(0, 0).unwrap();
+
+        let barrier = Arc::new(Barrier::new(2));
+
+        let db1 = db.clone();
+        let b1 = barrier.clone();
+        let data: &[u8] = &[1u8; 100];
+        let thread1 = thread::spawn(move || {
+            let txn = db1
+                .start_transaction(IsolationLevel::RepeatableRead)
+                .unwrap();
+
+            let heap = db1.open_table(0, 0).unwrap().expect("");
+            for _ in 0..100 {
+                assert!(heap.insert_tuple(&db1, &txn, data).is_ok());
+            }
+
+            b1.wait(); // unblock scanning thread
+            b1.wait(); // wait for scanning thread to finish the first scan
+
+            db1.commit_transaction(txn).unwrap();
+
+            b1.wait(); // unblock scanning thread
+        });
+
+        let db2 = db.clone();
+        let b2 = barrier.clone();
+        let thread2 = thread::spawn(move || {
+            let mut txn = db
+                .start_transaction(IsolationLevel::RepeatableRead)
+                .unwrap();
+
+            let heap = db2.open_table(0, 0).unwrap().expect("");
+
+            b2.wait(); // wait for insert thread to insert the tuples
+
+            {
+                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+
+                let mut count = 0;
+                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
+                    assert_eq!(tuple.get_data(), data);
+                    count += 1;
+                }
+                // inserting thread has not yet committed the transaction
+                assert_eq!(count, 0); // no dirty reads
+            }
+
+            b2.wait(); // unblock inserting thread
+            b2.wait(); // wait for inserting thread to commit the transaction
+
+            {
+                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+
+                let mut count = 0;
+                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
+                    assert_eq!(tuple.get_data(), data);
+                    count += 1;
+                }
+                // inserting thread has committed the transaction
+                assert_eq!(count, 0); // no phantom reads
+                                      // Actually repeatable read allows phantom reads,
+                                      // but we are following PostgreSQL's implementation
+                                      // which disallows it.
+            }
+
+            db2.commit_transaction(txn).unwrap();
+        });
+
+        thread1.join().unwrap();
+        thread2.join().unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_scan_without_hint_bit_writes() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .hint_bit_writes(false);
+        let db = DB::open(&config).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let rel = RelFileRef { db: 0, rel_id: 0 };
+        let bufmgr = db.get_buffer_manager();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for _ in 0..200 {
+            item_ptrs.push(heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap());
+        }
+        db.commit_transaction(txn).unwrap();
+        bufmgr.sync_pages(&db).unwrap();
+        assert_eq!(bufmgr.dirty_page_count(rel), 0);
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let mut count = 0;
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {
+                count += 1;
+            }
+            assert_eq!(count, 200);
+        }
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 200);
+        let xid = txn.xid();
+        let snapshot = db.get_transaction_manager().get_snapshot(&mut txn).unwrap();
+        assert!(heap
+            .fetch_tuple(&db, xid, snapshot, item_ptrs[0])
+            .unwrap()
+            .is_some());
+        assert_eq!(bufmgr.dirty_page_count(rel), 0);
+
+        // the scan can still opt in to hint bit writes
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            iter.set_hint_bit_writes(true);
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {}
+        }
+        assert!(bufmgr.dirty_page_count(rel) > 0);
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_prefetch_transaction_statuses() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .hint_bit_writes(false);
+        let db = DB::open(&config).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let txnmgr = db.get_transaction_manager();
+
+        let mut xids = Vec::new();
+        for i in 0..20 {
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            xids.push(txn.xid());
+            for _ in 0..10 {
+                heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+            }
+            if i % 4 == 0 {
+                db.abort_transaction(txn).unwrap();
+            } else {
+                db.commit_transaction(txn).unwrap();
+            }
+        }
+        let running = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.insert_tuple(&db, &running, &[1u8; 100]).unwrap();
+        xids.push(running.xid());
+
+        let statuses = xids
+            .iter()
+            .map(|xid| txnmgr.get_transaction_status(*xid).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(txnmgr.get_transaction_statuses(&xids).unwrap(), statuses);
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let smgr = db.get_storage_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        let num_pages = smgr.file_size_in_page(&shandle, ForkType::Main).unwrap();
+        let lookups = txnmgr.txn_table_lookup_count();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let mut count = 0;
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {
+                count += 1;
+            }
+            assert_eq!(count, 150);
+        }
+        // one batch per page instead of one lookup per tuple
+        assert!(txnmgr.txn_table_lookup_count() - lookups <= num_pages);
+
+        let lookups = txnmgr.txn_table_lookup_count();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 150);
+        assert!(txnmgr.txn_table_lookup_count() - lookups <= num_pages);
+        db.commit_transaction(txn).unwrap();
+        db.commit_transaction(running).unwrap();
+
+        drop(heap);
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_spread_concurrent_inserts() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .heap_insert_strategy(HeapInsertStrategy::Spread);
+        let db = Arc::new(DB::open(&config).unwrap());
+        db.create_table(0, 0).unwrap();
+
+        let data: &[u8] = &[1u8; 100];
+        let threads = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                    let heap = db.open_table(0, 0).unwrap().unwrap();
+                    let pages = (0..10)
+                        .map(|_| heap.insert_tuple(&db, &txn, data).unwrap().page_num)
+                        .collect::<Vec<_>>();
+                    db.commit_transaction(txn).unwrap();
+                    pages
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut pages = HashSet::new();
+        for thread in threads {
+            pages.extend(thread.join().unwrap());
+        }
+
+        // 40 tuples fit in 2 pages
+        assert!(pages.len() >= HEAP_INSERT_SPREAD);
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_block_drop_until_scan_finishes() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..10 {
+            heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let mut reader = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        thread::scope(|s| {
+            {
+                let mut iter = heap.begin_scan(&db, &mut reader).unwrap();
+                assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_some());
+
+                s.spawn(|| {
+                    let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                    db.drop_relation(&mut txn, 0, 0).unwrap();
+                    db.commit_transaction(txn).unwrap();
+                    tx.send(()).unwrap();
+                });
+
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                assert!(rx.try_recv().is_err());
+
+                let mut count = 1;
+                while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {
+                    count += 1;
+                }
+                assert_eq!(count, 10);
+            }
 
-                    let (valid, new_flags) = htup.is_visible(db, snapshot, xid)?;
+            // the lock is held until the reader ends
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(rx.try_recv().is_err());
 
-                    if new_flags != 0 {
-                        htup.flags |= new_flags;
-                        let htup_buf = bincode::serialize(&htup).unwrap();
-                        page_view.set_item(offset, &htup_buf)?;
-                        dirty = true;
-                    }
+            db.commit_transaction(reader).unwrap();
+            rx.recv().unwrap();
+        });
 
-                    valid
-                };
+        assert!(db.open_table(0, 0).unwrap().is_none());
 
-                if valid {
-                    let item = page_view.get_item(offset);
-                    let htup_buf = unsafe {
-                        // extend the lifetime of buf to 'a
-                        // this is ok because we keep a pinned page in the scan iterator
-                        // so the page buffer will be valid until the next iteration
-                        std::mem::transmute::<&[u8], &'a [u8]>(item)
-                    };
+        db_dir.close().unwrap();
+    }
 
-                    let mut htup = match bincode::deserialize::<HeapTuple>(htup_buf) {
-                        Ok(htup) => htup,
-                        _ => {
-                            return Err(Error::DataCorrupted(
-                                "cannot deserialize heap tuple".to_owned(),
-                            ));
-                        }
-                    };
+    #[test]
+    fn can_keep_dropped_relation_on_abort() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
 
-                    htup.table_id = self.rel_id();
-                    htup.set_pointer(item_pointer);
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        db.commit_transaction(txn).unwrap();
 
-                    Ok((dirty, Some(htup)))
-                } else {
-                    Ok((dirty, None))
-                }
-            })?;
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        db.drop_relation(&mut txn, 0, 0).unwrap();
+        db.abort_transaction(txn).unwrap();
 
-            match htup {
-                Some(htup) => {
-                    let buffer_tuple = BufferHeapTuple {
-                        tuple: htup,
-                        bufmgr: Some(db.get_buffer_manager()),
-                        page: Some(page_ptr),
-                    };
-                    Ok(Some(Box::new(buffer_tuple) as TuplePtr))
-                }
-                _ => Ok(None),
-            }
-        })
-    }
-}
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+        db.commit_transaction(txn).unwrap();
 
-impl RelationWithStorage for Heap {
-    fn get_storage_handle(&self) -> &Mutex<Option<StorageHandle>> {
-        &self.shandle
-    }
-}
+        let smgr = db.get_storage_manager();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        db.drop_relation(&mut txn, 0, 0).unwrap();
+        db.commit_transaction(txn).unwrap();
+        assert!(db.open_table(0, 0).unwrap().is_none());
+        assert!(!smgr.exists(0, 0, ForkType::Main).unwrap());
 
-#[cfg(test)]
-mod tests {
-    use crate::{concurrency::IsolationLevel, storage::ScanDirection, test_util::get_temp_db};
-    use std::sync::{Arc, Barrier};
-    use std::thread;
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
 
     #[test]
-    fn can_create_heap() {
+    fn can_detect_drop_upgrade_deadlock() {
         let (db, db_dir) = get_temp_db();
-        assert!(db.create_table(0, 0).is_ok());
+        db.create_table(0, 0).unwrap();
+        let file_ref = RelFileRef { db: 0, rel_id: 0 };
 
-        let mut rel_path = db_dir.path().to_path_buf();
-        rel_path.push("base");
-        rel_path.push("0");
-        rel_path.push("0_0");
+        let mut txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        db.lock_relation(&txn1, file_ref, LockMode::AccessShare)
+            .unwrap();
+        db.lock_relation(&txn2, file_ref, LockMode::AccessShare)
+            .unwrap();
 
-        assert!(rel_path.is_file());
+        thread::scope(|s| {
+            let dropper = s.spawn(|| db.drop_relation(&mut txn1, 0, 0));
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(!dropper.is_finished());
+
+            // both transactions wait for the other to release its share lock
+            assert!(matches!(
+                db.drop_relation(&mut txn2, 0, 0),
+                Err(Error::Deadlock(_))
+            ));
+            db.abort_transaction(txn2).unwrap();
+
+            assert!(dropper.join().unwrap().is_ok());
+        });
+        db.commit_transaction(txn1).unwrap();
+        assert!(db.open_table(0, 0).unwrap().is_none());
+
+        drop(db);
         assert!(db_dir.close().is_ok());
     }
 
     #[test]
-    fn can_insert_and_scan_heap() {
+    fn can_vacuum_full() {
         let (db, db_dir) = get_temp_db();
-        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
         let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| {
+                let a = LittleEndian::read_u32(a);
+                let b = LittleEndian::read_u32(b);
+                Ok(a.cmp(&b))
+            })
+            .unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for i in 0..400 {
+            let mut data = vec![0u8; 100];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            let item_ptr = heap.insert_tuple(&db, &txn, &data).unwrap();
+            btree.insert(&db, &data[..4], item_ptr).unwrap();
+            item_ptrs.push(item_ptr);
+        }
+        db.commit_transaction(txn).unwrap();
 
-        let data: &[u8] = &[1u8; 100];
-        for _ in 0..100 {
-            assert!(heap.insert_tuple(&db, &txn, data).is_ok());
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for (i, item_ptr) in item_ptrs.iter().enumerate() {
+            if i % 4 != 0 {
+                heap.delete_tuple(&db, &txn, *item_ptr).unwrap();
+            }
         }
+        assert!(heap.delete_tuple(&db, &txn, item_ptrs[1]).is_err());
+        db.commit_transaction(txn).unwrap();
+
+        let old_size = heap.file_size(&db, ForkType::Main).unwrap();
+        db.vacuum_full(0, 0, std::slice::from_ref(&btree)).unwrap();
+
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let new_size = heap.file_size(&db, ForkType::Main).unwrap();
+        assert!(new_size * 3 <= old_size);
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
 
         {
             let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
 
             let mut count = 0;
             while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
-                assert_eq!(tuple.get_data(), data);
+                assert_eq!(LittleEndian::read_u32(tuple.get_data()) % 4, 0);
                 count += 1;
             }
             assert_eq!(count, 100);
+        }
 
-            let mut count = 0;
-            while let Some(tuple) = iter.next(&db, ScanDirection::Backward).unwrap() {
-                assert_eq!(tuple.get_data(), data);
-                count += 1;
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_| Ok(true)))
+                .unwrap();
+
+            let mut expected = 0;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(LittleEndian::read_u32(tuple.get_data()), expected);
+                expected += 4;
             }
-            assert_eq!(count, 100);
+            assert_eq!(expected, 400);
         }
 
         db.commit_transaction(txn).unwrap();
@@ -826,153 +4390,313 @@ mod tests {
     }
 
     #[test]
-    fn can_handle_read_committed() {
-        let (db, db_dir) = get_temp_db();
-        let db = Arc::new(db);
-        db.create_table(0, 0).unwrap();
-
-        let barrier = Arc::new(Barrier::new(2));
+    fn can_vacuum_full_compressed_table() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
 
-        let db1 = db.clone();
-        let b1 = barrier.clone();
-        let data: &[u8] = &[1u8; 100];
-        let thread1 = thread::spawn(move || {
-            let txn = db1
-                .start_transaction(IsolationLevel::ReadCommitted)
-                .unwrap();
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_compressed_table(0, 0).unwrap();
 
-            let heap = db1.open_table(0, 0).unwrap().expect("");
-            for _ in 0..100 {
-                assert!(heap.insert_tuple(&db1, &txn, data).is_ok());
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let mut item_ptrs = Vec::new();
+            for i in 0..200u8 {
+                item_ptrs.push(heap.insert_tuple(&db, &txn, &[i; 100]).unwrap());
             }
+            db.commit_transaction(txn).unwrap();
 
-            b1.wait(); // unblock scanning thread
-            b1.wait(); // wait for scanning thread to finish the first scan
-
-            db1.commit_transaction(txn).unwrap();
-
-            b1.wait(); // unblock scanning thread
-        });
-
-        let db2 = db.clone();
-        let b2 = barrier.clone();
-        let thread2 = thread::spawn(move || {
-            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for item_ptr in item_ptrs.iter().skip(1).step_by(2) {
+                heap.delete_tuple(&db, &txn, *item_ptr).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
 
-            let heap = db2.open_table(0, 0).unwrap().expect("");
+            let old_size = heap.file_size(&db, ForkType::Main).unwrap();
+            db.vacuum_full(0, 0, &[]).unwrap();
+            assert!(heap.file_size(&db, ForkType::Main).unwrap() < old_size);
+        }
 
-            b2.wait(); // wait for insert thread to insert the tuples
+        // the new file is compressed like the old one
+        let db = DB::open(&config).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let mut expected = 0u8;
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert_eq!(tuple.get_data(), &[expected; 100][..]);
+                expected += 2;
+            }
+            assert_eq!(expected, 200);
+        }
+        db.commit_transaction(txn).unwrap();
 
-            {
-                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
 
-                let mut count = 0;
-                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
-                    assert_eq!(tuple.get_data(), data);
-                    count += 1;
-                }
-                //inserting thread has not yet committed the transaction
-                assert_eq!(count, 0); // no dirty reads
-            }
+    #[test]
+    fn can_redo_vacuum_full() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
 
-            b2.wait(); // unblock inserting thread
-            b2.wait(); // wait for inserting thread to commit the transaction
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_ptrs = Vec::new();
+        for i in 0..600u32 {
+            item_ptrs.push(heap.insert_tuple(&db, &txn, &[i as u8; 100]).unwrap());
+        }
+        db.commit_transaction(txn).unwrap();
 
-            {
-                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for item_ptr in item_ptrs.iter().step_by(2) {
+            heap.delete_tuple(&db, &txn, *item_ptr).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
 
-                let mut count = 0;
-                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
-                    assert_eq!(tuple.get_data(), data);
-                    count += 1;
-                }
-                assert_eq!(count, 100); // inserting thread has committed the transaction
-            }
+        let wal = db.get_wal();
+        let start_lsn = wal.current_lsn();
+        db.vacuum_full(0, 0, &[]).unwrap();
+
+        let shandle = smgr
+            .open(smgr.relation_file(RelFileRef { db: 0, rel_id: 0 }))
+            .unwrap();
+        let num_pages = smgr.file_size_in_page(&shandle, ForkType::Main).unwrap();
+        // the pages are logged in more than one batch
+        assert!(num_pages > super::REWRITE_BATCH_PAGES);
+
+        let read_pages = || {
+            (0..num_pages)
+                .map(|page_num| {
+                    let page_ptr = bufmgr
+                        .fetch_page(&db, &shandle, ForkType::Main, page_num)
+                        .unwrap();
+                    let page = page_ptr
+                        .with_read(|page| Ok(page.buffer().to_vec()))
+                        .unwrap();
+                    bufmgr.release_page(page_ptr).unwrap();
+                    page
+                })
+                .collect::<Vec<_>>()
+        };
+        let live_pages = read_pages();
 
-            db2.commit_transaction(txn).unwrap();
-        });
+        // the new file is rebuilt from the log records
+        bufmgr.invalidate_relation(shandle.file_ref()).unwrap();
+        smgr.truncate(&shandle, ForkType::Main, 0).unwrap();
+        wal.flush(None).unwrap();
+        wal.replay_logs(&db, start_lsn).unwrap();
 
-        thread1.join().unwrap();
-        thread2.join().unwrap();
+        // the pages written at the commit carry their checksums
+        db.create_checkpoint().unwrap();
+        assert_eq!(read_pages(), live_pages);
 
         assert!(db_dir.close().is_ok());
     }
 
     #[test]
-    fn can_handle_repeatable_read() {
-        let (db, db_dir) = get_temp_db();
-        let db = Arc::new(db);
+    fn can_check_wal_consistency() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let file_ref = RelFileRef { db: 0, rel_id: 0 };
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .wal_consistency_check(&[file_ref]);
+        let db = DB::open(&config).unwrap();
         db.create_table(0, 0).unwrap();
+        let heap = Heap::new(0, 0, HeapInsertStrategy::InsertHint);
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut item_pointers = Vec::new();
+        for i in 0..200u32 {
+            let mut data = vec![0u8; 100];
+            (&mut data[..]).write_u32::<LittleEndian>(i).unwrap();
+            item_pointers.push(heap.insert_tuple(&db, &txn, &data).unwrap());
+        }
+        let rows = vec![vec![1u8; 50]; 200];
+        item_pointers.extend(
+            heap.insert_bulk(&db, &txn, rows.iter().map(|row| &row[..]))
+                .unwrap(),
+        );
+        db.commit_transaction(txn).unwrap();
 
-        let barrier = Arc::new(Barrier::new(2));
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for item_pointer in item_pointers.iter().step_by(3) {
+            heap.delete_tuple(&db, &txn, *item_pointer).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
 
-        let db1 = db.clone();
-        let b1 = barrier.clone();
+        // a record that does not match the change is reported
+        let mut page = [0u8; PAGE_SIZE];
+        let mut page_view = HeapPageViewMut::new(&mut page);
+        page_view.init_page();
+        let image = heap.get_consistency_check_image(&db, &page_view);
+        assert!(image.is_some());
+
+        let htup = heap.prepare_heap_tuple_for_insert(XID::default(), 0, &[1u8; 10]);
+        page_view
+            .put_item(&bincode::serialize(&htup).unwrap(), None, false)
+            .unwrap();
+        let insert_log = HeapLogRecord::create_heap_insert_log(
+            file_ref,
+            ForkType::Main,
+            0,
+            1,
+            htup.flags,
+            0,
+            &[2u8; 10],
+        );
+        let expected = image.clone().unwrap();
+        assert!(matches!(
+            heap.check_wal_consistency(image, &insert_log, XID::default(), &mut page_view),
+            Err(Error::DataCorrupted(_))
+        ));
+        // the unlogged change is undone
+        assert_eq!(page[..], expected[..]);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn cannot_read_vacuumed_page_with_old_snapshot() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .old_snapshot_threshold(2);
+        let db = DB::open(&config).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
         let data: &[u8] = &[1u8; 100];
-        let thread1 = thread::spawn(move || {
-            let txn = db1
-                .start_transaction(IsolationLevel::RepeatableRead)
-                .unwrap();
 
-            let heap = db1.open_table(0, 0).unwrap().expect("");
-            for _ in 0..100 {
-                assert!(heap.insert_tuple(&db1, &txn, data).is_ok());
-            }
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let item_pointers = (0..10)
+            .map(|_| heap.insert_tuple(&db, &txn, data).unwrap())
+            .collect::<Vec<_>>();
+        db.commit_transaction(txn).unwrap();
 
-            b1.wait(); // unblock scanning thread
-            b1.wait(); // wait for scanning thread to finish the first scan
+        let mut old_txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert_eq!(heap.count_visible(&db, &mut old_txn).unwrap(), 10);
 
-            db1.commit_transaction(txn).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for item_pointer in &item_pointers[..5] {
+            heap.delete_tuple(&db, &txn, *item_pointer).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
 
-            b1.wait(); // unblock scanning thread
-        });
+        // move the horizon past the old transaction
+        for _ in 0..3 {
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            db.commit_transaction(txn).unwrap();
+        }
 
-        let db2 = db.clone();
-        let b2 = barrier.clone();
-        let thread2 = thread::spawn(move || {
-            let mut txn = db
-                .start_transaction(IsolationLevel::RepeatableRead)
-                .unwrap();
+        db.vacuum_full(0, 0, &[]).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
 
-            let heap = db2.open_table(0, 0).unwrap().expect("");
+        assert!(matches!(
+            heap.count_visible(&db, &mut old_txn),
+            Err(Error::SnapshotTooOld)
+        ));
+        {
+            let mut iter = heap.begin_scan(&db, &mut old_txn).unwrap();
+            assert!(matches!(
+                iter.next(&db, ScanDirection::Forward),
+                Err(Error::SnapshotTooOld)
+            ));
+        }
 
-            b2.wait(); // wait for insert thread to insert the tuples
+        // new snapshots can read the page while the old transaction is still open
+        let mut txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 5);
+        db.commit_transaction(txn).unwrap();
+        db.commit_transaction(old_txn).unwrap();
 
-            {
-                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+        let mut txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 5);
+        db.commit_transaction(txn).unwrap();
 
-                let mut count = 0;
-                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
-                    assert_eq!(tuple.get_data(), data);
-                    count += 1;
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_cancel_scan_on_statement_timeout() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..2000 {
+            heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        txn.set_statement_timeout(std::time::Duration::from_millis(0));
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            let mut count = 0;
+            let result = loop {
+                match iter.next(&db, ScanDirection::Forward) {
+                    Ok(Some(_)) => count += 1,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
                 }
-                // inserting thread has not yet committed the transaction
-                assert_eq!(count, 0); // no dirty reads
-            }
+            };
 
-            b2.wait(); // unblock inserting thread
-            b2.wait(); // wait for inserting thread to commit the transaction
+            assert!(matches!(result, Err(Error::QueryCanceled(_))));
+            assert!(count > 0 && count < 2000);
+        }
+        db.commit_transaction(txn).unwrap();
 
-            {
-                let mut iter = heap.begin_scan(&db2, &mut txn).unwrap();
+        assert!(db_dir.close().is_ok());
+    }
 
-                let mut count = 0;
-                while let Some(tuple) = iter.next(&db2, ScanDirection::Forward).unwrap() {
-                    assert_eq!(tuple.get_data(), data);
-                    count += 1;
-                }
-                // inserting thread has committed the transaction
-                assert_eq!(count, 0); // no phantom reads
-                                      // Actually repeatable read allows phantom reads,
-                                      // but we are following PostgreSQL's implementation
-                                      // which disallows it.
+    #[test]
+    fn can_cancel_scan_from_another_thread() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..2000 {
+            heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let cancel_token = db.cancel_handle();
+        let barrier = Arc::new(Barrier::new(2));
+        let thread = {
+            let cancel_token = cancel_token.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                cancel_token.cancel();
+                barrier.wait();
+            })
+        };
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        txn.set_cancel_token(cancel_token.clone());
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            for _ in 0..100 {
+                assert!(iter.next(&db, ScanDirection::Forward).unwrap().is_some());
             }
 
-            db2.commit_transaction(txn).unwrap();
-        });
+            barrier.wait();
+            barrier.wait();
+            assert!(matches!(
+                iter.next(&db, ScanDirection::Forward),
+                Err(Error::QueryCanceled(_))
+            ));
+        }
+        thread.join().unwrap();
 
-        thread1.join().unwrap();
-        thread2.join().unwrap();
+        cancel_token.reset();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 2000);
+        db.commit_transaction(txn).unwrap();
 
         assert!(db_dir.close().is_ok());
     }
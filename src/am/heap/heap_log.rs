@@ -1,13 +1,17 @@
 use crate::{
-    concurrency::XID,
+    concurrency::{CommandId, XID},
     storage::{
-        DiskPageReader, DiskPageWriter, ForkType, ItemPageReader, ItemPageWriter, RelFileRef,
+        DiskPageReader, DiskPageWriter, ForkType, ItemPageReader, ItemPageWriter, ItemPointer,
+        RelFileRef,
     },
     wal::{LogPointer, LogRecord},
     Result, DB,
 };
 
-use super::{heap_page::HeapPageViewMut, HeapTuple};
+use super::{
+    heap_page::{HeapPageViewMut, HeapPageWriter},
+    HeapTuple,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -18,6 +22,7 @@ pub struct HeapInsertLog<'a> {
     page_num: usize,
     offset: u16,
     flags: u32,
+    command_id: CommandId,
     #[serde(with = "serde_bytes")]
     tuple_data: &'a [u8],
 }
@@ -27,10 +32,11 @@ impl<'a> HeapInsertLog<'a> {
         let smgr = db.get_storage_manager();
         let bufmgr = db.get_buffer_manager();
 
-        // if this log record is written, then the storage must be created and extended to at least page_num pages
-        // so we are safe to fetch the page and redo the insert there
+        // if this log record is written, then the storage must be created. the page may still be
+        // missing on disk if the heap is extended but the page is not written before a crash, in
+        // which case the insert is redone on a zero page
         let shandle = smgr.open(self.file_ref)?;
-        let page_ptr = bufmgr.fetch_page(db, &shandle, self.fork, self.page_num)?;
+        let page_ptr = bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.page_num)?;
 
         page_ptr.with_write(|page| {
             let buffer = page.buffer_mut();
@@ -41,17 +47,112 @@ impl<'a> HeapInsertLog<'a> {
                 return Ok(());
             }
 
-            if page_view.is_new() {
-                page_view.init_page();
+            self.redo_page(&mut page_view, xid)?;
+
+            page_view.set_lsn(lsn);
+            page.set_dirty(true);
+            Ok(())
+        })?;
+
+        bufmgr.release_page(page_ptr)
+    }
+
+    fn redo_page(&self, page_view: &mut HeapPageViewMut, xid: XID) -> Result<()> {
+        if page_view.is_new() {
+            page_view.init_page();
+        }
+
+        let RelFileRef { rel_id, .. } = self.file_ref;
+        let mut htup = HeapTuple::new(rel_id, self.tuple_data).materialize();
+        htup.min_xid = xid;
+        htup.flags = self.flags;
+        htup.command_id = self.command_id;
+        let htup_buf = bincode::serialize(&htup)?;
+
+        page_view.put_item(&htup_buf, Some(self.offset as usize), true)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HeapMultiInsertLog {
+    file_ref: RelFileRef,
+    fork: ForkType,
+    page_num: usize,
+    start_offset: u16,
+    // serialized heap tuples, placed at consecutive offsets from the start offset
+    tuples: Vec<Vec<u8>>,
+}
+
+impl HeapMultiInsertLog {
+    pub fn apply(self, db: &DB, _xid: XID, lsn: LogPointer) -> Result<()> {
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+
+        let shandle = smgr.open(self.file_ref)?;
+        let page_ptr = bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.page_num)?;
+
+        page_ptr.with_write(|page| {
+            let buffer = page.buffer_mut();
+            let mut page_view = HeapPageViewMut::new(buffer);
+
+            if page_view.get_lsn() >= lsn {
+                // already done
+                return Ok(());
             }
 
-            let RelFileRef { rel_id, .. } = self.file_ref;
-            let mut htup = HeapTuple::new(rel_id, self.tuple_data).materialize();
-            htup.min_xid = xid;
-            htup.flags = self.flags;
-            let htup_buf = bincode::serialize(&htup).unwrap();
+            self.redo_page(&mut page_view)?;
+
+            page_view.set_lsn(lsn);
+            page.set_dirty(true);
+            Ok(())
+        })?;
+
+        bufmgr.release_page(page_ptr)
+    }
+
+    fn redo_page(&self, page_view: &mut HeapPageViewMut) -> Result<()> {
+        if page_view.is_new() {
+            page_view.init_page();
+        }
 
-            page_view.put_item(&htup_buf, Some(self.offset as usize), true)?;
+        for (i, htup_buf) in self.tuples.iter().enumerate() {
+            let offset = self.start_offset as usize + i;
+            page_view.put_item(htup_buf, Some(offset), true)?;
+        }
+        Ok(())
+    }
+}
+
+/// A page appended to a heap rewritten by vacuum full
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HeapRewriteLog {
+    file_ref: RelFileRef,
+    fork: ForkType,
+    page_num: usize,
+    vacuum_xid: XID,
+    // serialized heap tuples, placed from the first offset
+    tuples: Vec<Vec<u8>>,
+}
+
+impl HeapRewriteLog {
+    pub fn apply(self, db: &DB, _xid: XID, lsn: LogPointer) -> Result<()> {
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+
+        let shandle = smgr.open(self.file_ref)?;
+        let page_ptr = bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.page_num)?;
+
+        page_ptr.with_write(|page| {
+            let buffer = page.buffer_mut();
+            let mut page_view = HeapPageViewMut::new(buffer);
+
+            if page_view.get_lsn() >= lsn {
+                // already done
+                return Ok(());
+            }
+
+            self.redo_page(&mut page_view)?;
 
             page_view.set_lsn(lsn);
             page.set_dirty(true);
@@ -60,18 +161,149 @@ impl<'a> HeapInsertLog<'a> {
 
         bufmgr.release_page(page_ptr)
     }
+
+    fn redo_page(&self, page_view: &mut HeapPageViewMut) -> Result<()> {
+        page_view.init_page();
+        page_view.set_vacuum_xid(self.vacuum_xid);
+
+        for (i, htup_buf) in self.tuples.iter().enumerate() {
+            page_view.put_item(htup_buf, Some(i + 1), true)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct HeapDeleteLog {
+    file_ref: RelFileRef,
+    fork: ForkType,
+    page_num: usize,
+    offset: u16,
+}
+
+impl HeapDeleteLog {
+    pub fn apply(self, db: &DB, xid: XID, lsn: LogPointer) -> Result<()> {
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+
+        let shandle = smgr.open(self.file_ref)?;
+        let page_ptr = bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.page_num)?;
+
+        page_ptr.with_write(|page| {
+            let buffer = page.buffer_mut();
+            let mut page_view = HeapPageViewMut::new(buffer);
+
+            if page_view.get_lsn() >= lsn {
+                // already done
+                return Ok(());
+            }
+
+            self.redo_page(&mut page_view, xid)?;
+
+            page_view.set_lsn(lsn);
+            page.set_dirty(true);
+            Ok(())
+        })?;
+
+        bufmgr.release_page(page_ptr)
+    }
+
+    fn redo_page(&self, page_view: &mut HeapPageViewMut, xid: XID) -> Result<()> {
+        let offset = self.offset as usize;
+        let mut htup = bincode::deserialize::<HeapTuple>(page_view.get_item(offset))?;
+        htup.mark_deleted(xid);
+        let htup_buf = bincode::serialize(&htup)?;
+
+        page_view.set_item(offset, &htup_buf)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HeapSetNextVersionLog {
+    file_ref: RelFileRef,
+    fork: ForkType,
+    page_num: usize,
+    offset: u16,
+    next_version: ItemPointer,
+}
+
+impl HeapSetNextVersionLog {
+    pub fn apply(self, db: &DB, _xid: XID, lsn: LogPointer) -> Result<()> {
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+
+        let shandle = smgr.open(self.file_ref)?;
+        let page_ptr = bufmgr.fetch_page_for_redo(db, &shandle, self.fork, self.page_num)?;
+
+        page_ptr.with_write(|page| {
+            let buffer = page.buffer_mut();
+            let mut page_view = HeapPageViewMut::new(buffer);
+
+            if page_view.get_lsn() >= lsn {
+                // already done
+                return Ok(());
+            }
+
+            self.redo_page(&mut page_view)?;
+
+            page_view.set_lsn(lsn);
+            page.set_dirty(true);
+            Ok(())
+        })?;
+
+        bufmgr.release_page(page_ptr)
+    }
+
+    fn redo_page(&self, page_view: &mut HeapPageViewMut) -> Result<()> {
+        let offset = self.offset as usize;
+        let mut htup = bincode::deserialize::<HeapTuple>(page_view.get_item(offset))?;
+        htup.next_version = self.next_version;
+        let htup_buf = bincode::serialize(&htup)?;
+
+        page_view.set_item(offset, &htup_buf)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum HeapLogRecord<'a> {
     #[serde(borrow)]
     HeapInsert(HeapInsertLog<'a>),
+    HeapDelete(HeapDeleteLog),
+    HeapMultiInsert(HeapMultiInsertLog),
+    HeapSetNextVersion(HeapSetNextVersionLog),
+    HeapRewrite(HeapRewriteLog),
 }
 
 impl<'a> HeapLogRecord<'a> {
     pub fn apply(self, db: &DB, xid: XID, lsn: LogPointer) -> Result<()> {
         match self {
             HeapLogRecord::HeapInsert(heap_insert_log) => heap_insert_log.apply(db, xid, lsn),
+            HeapLogRecord::HeapDelete(heap_delete_log) => heap_delete_log.apply(db, xid, lsn),
+            HeapLogRecord::HeapMultiInsert(heap_multi_insert_log) => {
+                heap_multi_insert_log.apply(db, xid, lsn)
+            }
+            HeapLogRecord::HeapSetNextVersion(heap_set_next_version_log) => {
+                heap_set_next_version_log.apply(db, xid, lsn)
+            }
+            HeapLogRecord::HeapRewrite(heap_rewrite_log) => heap_rewrite_log.apply(db, xid, lsn),
+        }
+    }
+
+    /// Redo the change on a page image without touching its LSN.
+    pub fn redo_page(&self, page_view: &mut HeapPageViewMut, xid: XID) -> Result<()> {
+        match self {
+            HeapLogRecord::HeapInsert(heap_insert_log) => heap_insert_log.redo_page(page_view, xid),
+            HeapLogRecord::HeapDelete(heap_delete_log) => heap_delete_log.redo_page(page_view, xid),
+            HeapLogRecord::HeapMultiInsert(heap_multi_insert_log) => {
+                heap_multi_insert_log.redo_page(page_view)
+            }
+            HeapLogRecord::HeapSetNextVersion(heap_set_next_version_log) => {
+                heap_set_next_version_log.redo_page(page_view)
+            }
+            HeapLogRecord::HeapRewrite(heap_rewrite_log) => heap_rewrite_log.redo_page(page_view),
         }
     }
 
@@ -81,16 +313,86 @@ impl<'a> HeapLogRecord<'a> {
         page_num: usize,
         offset: usize,
         flags: u32,
+        command_id: CommandId,
         tuple_data: &[u8],
-    ) -> LogRecord {
+    ) -> LogRecord<'_> {
         let heap_insert_record = HeapInsertLog {
             file_ref,
             fork,
             page_num,
             offset: offset as u16,
             flags,
+            command_id,
             tuple_data,
         };
         LogRecord::create_heap_record(HeapLogRecord::HeapInsert(heap_insert_record))
     }
+
+    pub fn create_heap_delete_log(
+        file_ref: RelFileRef,
+        fork: ForkType,
+        page_num: usize,
+        offset: usize,
+    ) -> LogRecord<'static> {
+        let heap_delete_record = HeapDeleteLog {
+            file_ref,
+            fork,
+            page_num,
+            offset: offset as u16,
+        };
+        LogRecord::create_heap_record(HeapLogRecord::HeapDelete(heap_delete_record))
+    }
+
+    pub fn create_heap_multi_insert_log(
+        file_ref: RelFileRef,
+        fork: ForkType,
+        page_num: usize,
+        start_offset: usize,
+        tuples: Vec<Vec<u8>>,
+    ) -> LogRecord<'static> {
+        let heap_multi_insert_record = HeapMultiInsertLog {
+            file_ref,
+            fork,
+            page_num,
+            start_offset: start_offset as u16,
+            tuples,
+        };
+        LogRecord::create_heap_record(HeapLogRecord::HeapMultiInsert(heap_multi_insert_record))
+    }
+
+    pub fn create_heap_set_next_version_log(
+        file_ref: RelFileRef,
+        fork: ForkType,
+        page_num: usize,
+        offset: usize,
+        next_version: ItemPointer,
+    ) -> LogRecord<'static> {
+        let heap_set_next_version_record = HeapSetNextVersionLog {
+            file_ref,
+            fork,
+            page_num,
+            offset: offset as u16,
+            next_version,
+        };
+        LogRecord::create_heap_record(HeapLogRecord::HeapSetNextVersion(
+            heap_set_next_version_record,
+        ))
+    }
+
+    pub fn create_heap_rewrite_log(
+        file_ref: RelFileRef,
+        fork: ForkType,
+        page_num: usize,
+        vacuum_xid: XID,
+        tuples: Vec<Vec<u8>>,
+    ) -> LogRecord<'static> {
+        let heap_rewrite_record = HeapRewriteLog {
+            file_ref,
+            fork,
+            page_num,
+            vacuum_xid,
+            tuples,
+        };
+        LogRecord::create_heap_record(HeapLogRecord::HeapRewrite(heap_rewrite_record))
+    }
 }
@@ -1,11 +1,37 @@
 use crate::{
+    concurrency::XID,
     storage::{
         consts::PAGE_SIZE, DiskPageReader, DiskPageWriter, ItemPageReader, ItemPageWriter,
-        PinnedPagePtr,
+        PageKind, PinnedPagePtr,
     },
     Result,
 };
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+const P_VACUUM_XID: usize = 0;
+const P_PAYLOAD: usize = P_VACUUM_XID + 4;
+
+/// Size of the heap page header before the items
+pub(super) const HEAP_PAGE_HEADER_SIZE: usize = P_PAYLOAD;
+
+pub trait HeapPageReader: DiskPageReader {
+    /// Vacuum horizon of the page. Tuples deleted by transactions before it may be removed.
+    fn get_vacuum_xid(&self) -> XID {
+        let buf = self.get_disk_page_payload();
+        XID::from((&buf[P_VACUUM_XID..]).read_u32::<LittleEndian>().unwrap())
+    }
+}
+
+pub trait HeapPageWriter: DiskPageWriter {
+    fn set_vacuum_xid(&mut self, xid: XID) {
+        let buf = self.get_disk_page_payload_mut();
+        (&mut buf[P_VACUUM_XID..])
+            .write_u32::<LittleEndian>(u64::from(xid) as u32)
+            .unwrap();
+    }
+}
+
 pub struct HeapPageView<'a> {
     buffer: &'a [u8; PAGE_SIZE],
 }
@@ -34,9 +60,11 @@ impl<'a> DiskPageReader for HeapPageView<'a> {
     }
 }
 
+impl<'a> HeapPageReader for HeapPageView<'a> {}
+
 impl<'a> ItemPageReader for HeapPageView<'a> {
     fn get_item_page_payload(&self) -> &[u8] {
-        self.get_disk_page_payload()
+        &self.get_disk_page_payload()[P_PAYLOAD..]
     }
 }
 
@@ -50,6 +78,8 @@ impl<'a> HeapPageViewMut<'a> {
     }
 
     pub fn init_page(&mut self) {
+        self.set_page_kind(PageKind::Heap);
+        self.set_vacuum_xid(XID::default());
         self.init_item_page();
     }
 
@@ -82,14 +112,18 @@ impl<'a> DiskPageWriter for HeapPageViewMut<'a> {
     }
 }
 
+impl<'a> HeapPageReader for HeapPageViewMut<'a> {}
+
+impl<'a> HeapPageWriter for HeapPageViewMut<'a> {}
+
 impl<'a> ItemPageReader for HeapPageViewMut<'a> {
     fn get_item_page_payload(&self) -> &[u8] {
-        self.get_disk_page_payload()
+        &self.get_disk_page_payload()[P_PAYLOAD..]
     }
 }
 
 impl<'a> ItemPageWriter for HeapPageViewMut<'a> {
     fn get_item_page_payload_mut(&mut self) -> &mut [u8] {
-        self.get_disk_page_payload_mut()
+        &mut self.get_disk_page_payload_mut()[P_PAYLOAD..]
     }
 }
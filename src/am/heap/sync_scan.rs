@@ -0,0 +1,90 @@
+use crate::storage::RelFileRef;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+#[derive(Default)]
+struct SyncScanState {
+    // number of running synchronized scans
+    num_scans: usize,
+    page_num: Option<usize>,
+}
+
+/// Current pages of the synchronized scans of each relation
+///
+/// A synchronized scan starts at the page last reported by another scan of the same relation and
+/// wraps around, so that concurrent scans read the same pages while they are in the buffer pool.
+/// The position of a relation is cleared when its last scan ends.
+#[derive(Default)]
+pub struct SyncScanRegistry {
+    scans: Mutex<HashMap<RelFileRef, SyncScanState>>,
+}
+
+/// A running synchronized scan which reports its position to the registry until it is dropped
+pub struct SyncScan {
+    registry: Arc<SyncScanRegistry>,
+    file_ref: RelFileRef,
+}
+
+impl SyncScanRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Begin a scan of a relation with `num_pages` pages. Also returns the page to start the scan
+    /// at if another scan of the relation has reported its position.
+    pub fn begin_scan(
+        self: &Arc<Self>,
+        file_ref: RelFileRef,
+        num_pages: usize,
+    ) -> (SyncScan, Option<usize>) {
+        let mut guard = self.scans.lock().unwrap();
+        let state = guard.entry(file_ref).or_default();
+        state.num_scans += 1;
+
+        let start_page = state.page_num.filter(|page_num| *page_num < num_pages);
+        let scan = SyncScan {
+            registry: self.clone(),
+            file_ref,
+        };
+
+        (scan, start_page)
+    }
+
+    /// Forget the position of a relation whose files are removed or rewritten.
+    pub fn forget(&self, file_ref: RelFileRef) {
+        let mut guard = self.scans.lock().unwrap();
+        if let Some(state) = guard.get_mut(&file_ref) {
+            state.page_num = None;
+        }
+    }
+}
+
+impl SyncScan {
+    pub fn report_position(&self, page_num: usize) {
+        let mut guard = self.registry.scans.lock().unwrap();
+        if let Some(state) = guard.get_mut(&self.file_ref) {
+            state.page_num = Some(page_num);
+        }
+    }
+}
+
+impl Drop for SyncScan {
+    fn drop(&mut self) {
+        // may run while unwinding from a panic with the registry locked
+        let mut guard = self
+            .registry
+            .scans
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(state) = guard.get_mut(&self.file_ref) {
+            state.num_scans -= 1;
+            if state.num_scans == 0 {
+                guard.remove(&self.file_ref);
+            }
+        }
+    }
+}
@@ -4,7 +4,7 @@ use crate::{
     Relation, Result, DB,
 };
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 pub trait IndexScanIterator<'a> {
     fn rescan(
@@ -13,7 +13,49 @@ pub trait IndexScanIterator<'a> {
         start_key: Option<&[u8]>,
         predicate: IndexScanPredicate<'a>,
     ) -> Result<()>;
+
+    /// Restart a forward scan at the first entry with a key greater than `start_key`, skipping
+    /// all duplicates of `start_key`. Entries with equal keys are returned in the order of their
+    /// item pointers, so this can be used to page through the distinct keys.
+    fn rescan_after(
+        &mut self,
+        db: &'a DB,
+        start_key: &[u8],
+        predicate: IndexScanPredicate<'a>,
+    ) -> Result<()>;
+
     fn next(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<TuplePtr<'a>>>;
+
+    /// Save the current position of the scan.
+    fn mark(&mut self) -> ScanMark;
+
+    /// Move back to a position saved by `mark`. The next call to `next` continues from the
+    /// entry after the marked one.
+    fn restore(&mut self, db: &'a DB, mark: ScanMark) -> Result<()>;
+
+    /// Get at most `max` tuples in one call. Fewer tuples are returned only at the end of the
+    /// scan.
+    fn next_batch(
+        &mut self,
+        db: &'a DB,
+        dir: ScanDirection,
+        max: usize,
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        if dir == ScanDirection::NoMovement {
+            return Ok(self.next(db, dir)?.into_iter().collect());
+        }
+
+        let mut tuples = Vec::new();
+
+        while tuples.len() < max {
+            match self.next(db, dir)? {
+                Some(tuple) => tuples.push(tuple),
+                None => break,
+            }
+        }
+
+        Ok(tuples)
+    }
 }
 
 pub trait Index: Relation + Sync + Send {
@@ -23,7 +65,19 @@ pub trait Index: Relation + Sync + Send {
     ///
     /// We try to make the index general enough and leave the decoding and comparison completely to
     /// the frontend.
-    fn insert<'a>(&'a self, db: &DB, key: &[u8], item_pointer: ItemPointer) -> Result<()>;
+    fn insert(&self, db: &DB, key: &[u8], item_pointer: ItemPointer) -> Result<()>;
+
+    /// Insert an entry for a tuple of `table` and fail with `UniqueViolation` if another live
+    /// tuple has an equal key. If the conflicting tuple is inserted or deleted by an in-progress
+    /// transaction, wait for that transaction to end and check again.
+    fn insert_unique(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        table: &dyn Table,
+        key: &[u8],
+        item_pointer: ItemPointer,
+    ) -> Result<()>;
 
     fn begin_scan<'a>(
         &'a self,
@@ -31,11 +85,50 @@ pub trait Index: Relation + Sync + Send {
         txn: &'a mut Transaction,
         table: &'a dyn Table,
     ) -> Result<Box<dyn IndexScanIterator<'a> + 'a>>;
+
+    /// Look up `key` and return the first visible tuple with an equal key.
+    fn find_one<'a>(
+        &self,
+        db: &'a DB,
+        txn: &mut Transaction,
+        table: &'a dyn Table,
+        key: &[u8],
+    ) -> Result<Option<TuplePtr<'a>>>;
+
+    /// Rebuild the index after the table is rewritten. Entries pointing to tuples in
+    /// `item_pointer_map` are moved to the new locations and other entries are dropped.
+    fn rebuild(&self, db: &DB, item_pointer_map: &HashMap<ItemPointer, ItemPointer>) -> Result<()>;
+
+    /// Get size and selectivity estimates of the index for the planner.
+    fn index_stats(&self, db: &DB) -> Result<IndexStats>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Number of pages in the main fork, including the meta page
+    pub num_pages: usize,
+    /// Number of levels from the root to the leaves, 0 for an empty index
+    pub height: usize,
+    /// Estimated number of distinct keys
+    pub distinct_keys: usize,
+}
+
+/// A saved position of an index scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanMark {
+    /// Page of the current entry, None if the scan is not positioned
+    pub page_num: Option<usize>,
+    /// Index of the current entry among the entries read from the page
+    pub item_index: usize,
+    /// Item pointer of the current entry, used to find the entry if the page has changed
+    pub item_pointer: Option<ItemPointer>,
 }
 
 pub type IndexPtr = Arc<dyn Index>;
 
-pub struct IndexScanPredicate<'a>(Box<dyn Fn(&[u8]) -> Result<bool> + 'a>);
+type PredicateFn<'a> = dyn Fn(&[u8]) -> Result<bool> + 'a;
+
+pub struct IndexScanPredicate<'a>(Box<PredicateFn<'a>>);
 
 impl<'a> IndexScanPredicate<'a> {
     pub fn new<F>(f: F) -> Self
@@ -47,7 +140,7 @@ impl<'a> IndexScanPredicate<'a> {
 }
 
 impl<'a> std::ops::Deref for IndexScanPredicate<'a> {
-    type Target = Box<dyn Fn(&[u8]) -> Result<bool> + 'a>;
+    type Target = Box<PredicateFn<'a>>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -1,5 +1,12 @@
+pub mod analyze;
 pub mod btree;
+pub mod comparators;
 pub mod heap;
 pub mod index;
+pub mod sort;
 
-pub use self::index::{Index, IndexPtr};
+pub use self::{
+    analyze::{AnalyzeColumn, ColumnStats},
+    index::{Index, IndexPtr, IndexStats, ScanMark},
+    sort::{ExternalSort, SortedEntries},
+};
@@ -0,0 +1,303 @@
+use crate::{
+    storage::{ItemPointer, TempFile, TempFileManager},
+    Result, DB,
+};
+
+use std::{
+    cmp::Ordering,
+    io::{self, prelude::*, BufReader},
+    mem, vec,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+type CompareFn<'a> = dyn Fn(&[u8], &[u8]) -> Result<Ordering> + 'a;
+type SortEntry = (Vec<u8>, ItemPointer);
+
+/// Order entries by key and then by item pointer, which is how btrees order duplicate keys.
+fn compare_entries(compare: &CompareFn, a: &SortEntry, b: &SortEntry) -> Result<Ordering> {
+    Ok(compare(&a.0, &b.0)?.then_with(|| a.1.cmp(&b.1)))
+}
+
+/// A sorted run spilled to a temporary file
+struct SortRun {
+    reader: BufReader<TempFile>,
+}
+
+impl SortRun {
+    /// Write sorted entries to a run file. An entry is the key length, the key and the item
+    /// pointer.
+    fn write(file: TempFile, entries: &[SortEntry]) -> Result<Self> {
+        let mut writer = file.into_writer();
+        for (key, item_pointer) in entries {
+            writer.write_u32::<LittleEndian>(key.len() as u32)?;
+            writer.write_all(key)?;
+            writer.write_u64::<LittleEndian>(item_pointer.page_num as u64)?;
+            writer.write_u32::<LittleEndian>(item_pointer.offset as u32)?;
+        }
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(Self {
+            reader: file.into_reader()?,
+        })
+    }
+
+    fn read_entry(&mut self) -> Result<Option<SortEntry>> {
+        let key_len = match self.reader.read_u32::<LittleEndian>() {
+            Ok(key_len) => key_len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut key = vec![0u8; key_len];
+        self.reader.read_exact(&mut key)?;
+        let page_num = self.reader.read_u64::<LittleEndian>()? as usize;
+        let offset = self.reader.read_u32::<LittleEndian>()? as usize;
+
+        Ok(Some((key, ItemPointer::new(page_num, offset))))
+    }
+}
+
+enum SortSource {
+    Memory(vec::IntoIter<SortEntry>),
+    Run(SortRun),
+}
+
+impl SortSource {
+    fn next_entry(&mut self) -> Result<Option<SortEntry>> {
+        match self {
+            SortSource::Memory(iter) => Ok(iter.next()),
+            SortSource::Run(run) => run.read_entry(),
+        }
+    }
+}
+
+/// Sort (key, item pointer) entries, e.g. to build an index from the tuples of a table
+///
+/// Entries are collected in memory until they take `maintenance_work_mem` bytes. Then they are
+/// sorted and spilled to a temporary file as a run. The runs are merged when the sorted entries
+/// are read.
+pub struct ExternalSort<'a> {
+    compare: Box<CompareFn<'a>>,
+    work_mem: usize,
+    temp_files: &'a TempFileManager,
+    entries: Vec<SortEntry>,
+    mem_used: usize,
+    runs: Vec<SortRun>,
+}
+
+impl<'a> ExternalSort<'a> {
+    pub fn new<F>(db: &'a DB, compare: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> Result<Ordering> + 'a,
+    {
+        Self {
+            compare: Box::new(compare),
+            work_mem: db.get_config().maintenance_work_mem,
+            temp_files: db.get_temp_file_manager(),
+            entries: Vec::new(),
+            mem_used: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], item_pointer: ItemPointer) -> Result<()> {
+        self.mem_used += key.len() + mem::size_of::<SortEntry>();
+        self.entries.push((key.to_vec(), item_pointer));
+
+        if self.mem_used >= self.work_mem {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of runs spilled to disk
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn sort_entries(&mut self) -> Result<()> {
+        let compare = &self.compare;
+        let mut error = None;
+        self.entries
+            .sort_by(|a, b| match compare_entries(compare, a, b) {
+                Ok(ordering) => ordering,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    Ordering::Equal
+                }
+            });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.sort_entries()?;
+
+        let run = SortRun::write(self.temp_files.create_file(None)?, &self.entries)?;
+        self.runs.push(run);
+        self.entries = Vec::new();
+        self.mem_used = 0;
+        Ok(())
+    }
+
+    /// Finish adding entries and return the entries in sorted order.
+    pub fn finish(mut self) -> Result<SortedEntries<'a>> {
+        self.sort_entries()?;
+
+        let mut sources = self.runs.drain(..).map(SortSource::Run).collect::<Vec<_>>();
+        sources.push(SortSource::Memory(mem::take(&mut self.entries).into_iter()));
+
+        let heads = sources
+            .iter_mut()
+            .map(SortSource::next_entry)
+            .collect::<Result<Vec<_>>>()?;
+        let heap = (0..heads.len()).filter(|i| heads[*i].is_some()).collect();
+
+        let mut sorted = SortedEntries {
+            compare: self.compare,
+            sources,
+            heads,
+            heap,
+        };
+        for i in (0..sorted.heap.len() / 2).rev() {
+            sorted.sift_down(i)?;
+        }
+
+        Ok(sorted)
+    }
+}
+
+/// Entries of an `ExternalSort` in sorted order, merged from the runs
+pub struct SortedEntries<'a> {
+    compare: Box<CompareFn<'a>>,
+    sources: Vec<SortSource>,
+    // next entry of each source
+    heads: Vec<Option<SortEntry>>,
+    // sources with entries left in a binary heap, the one with the smallest head first
+    heap: Vec<usize>,
+}
+
+impl SortedEntries<'_> {
+    fn less(&self, a: usize, b: usize) -> Result<bool> {
+        let head = |i: usize| self.heads[self.heap[i]].as_ref().unwrap();
+        Ok(compare_entries(&*self.compare, head(a), head(b))? == Ordering::Less)
+    }
+
+    fn sift_down(&mut self, mut i: usize) -> Result<()> {
+        loop {
+            let mut min = i;
+            for child in [2 * i + 1, 2 * i + 2] {
+                if child < self.heap.len() && self.less(child, min)? {
+                    min = child;
+                }
+            }
+
+            if min == i {
+                return Ok(());
+            }
+            self.heap.swap(i, min);
+            i = min;
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<SortEntry>> {
+        let source = match self.heap.first() {
+            Some(source) => *source,
+            None => return Ok(None),
+        };
+
+        let next = self.sources[source].next_entry()?;
+        let entry = mem::replace(&mut self.heads[source], next);
+
+        if self.heads[source].is_none() {
+            // the source is exhausted
+            self.heap.swap_remove(0);
+        }
+        self.sift_down(0)?;
+
+        Ok(entry)
+    }
+}
+
+impl Iterator for SortedEntries<'_> {
+    type Item = Result<(Vec<u8>, ItemPointer)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{am::comparators, test_util::get_temp_db, DBConfig};
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    #[test]
+    fn can_sort_more_than_work_mem() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .maintenance_work_mem(16 * 1024);
+        let db = DB::open(&config).unwrap();
+
+        let mut sort = ExternalSort::new(&db, comparators::compare_bytes);
+        let mut random = 0x2545_f491_4f6c_dd1du64;
+        let num_entries = 5000;
+        for i in 0..num_entries {
+            random ^= random << 13;
+            random ^= random >> 7;
+            random ^= random << 17;
+            let key = (random % 1000).to_be_bytes();
+            sort.add(&key, ItemPointer::new(i, i % 100)).unwrap();
+        }
+        assert!(sort.num_runs() > 1);
+
+        let entries = sort.finish().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), num_entries);
+        // equal keys are ordered by the item pointers
+        assert!(entries.windows(2).all(|w| w[0] < w[1]));
+        for (key, item_pointer) in &entries {
+            assert_eq!(item_pointer.offset, item_pointer.page_num % 100);
+            assert!(BigEndian::read_u64(key) < 1000);
+        }
+
+        // the runs are removed
+        assert_eq!(
+            std::fs::read_dir(config.get_temp_path()).unwrap().count(),
+            0
+        );
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_sort_in_memory() {
+        let (db, db_dir) = get_temp_db();
+
+        let mut sort = ExternalSort::new(&db, comparators::compare_i32);
+        for i in (0..100i32).rev() {
+            sort.add(&i.to_be_bytes(), ItemPointer::new(0, i as usize))
+                .unwrap();
+        }
+        assert_eq!(sort.num_runs(), 0);
+
+        let offsets = sort
+            .finish()
+            .unwrap()
+            .map(|entry| entry.map(|(_, item_pointer)| item_pointer.offset))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(offsets, (0..100).collect::<Vec<_>>());
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+}
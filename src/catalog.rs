@@ -0,0 +1,1017 @@
+use crate::{
+    am::{comparators, ColumnStats},
+    storage::RelFileRef,
+    Error, RelationKind, Result, OID,
+};
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::prelude::*,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde::{Deserialize, Serialize};
+
+const CATALOG_FILE_NAME: &str = "catalog";
+const CATALOG_TEMP_FILE_NAME: &str = "catalog.tmp";
+
+// catalog files start with the marker and the format version. the files written before the format
+// is versioned start with the number of entries, which is never the marker
+const CATALOG_FORMAT_MARKER: u64 = u64::MAX;
+const CATALOG_FORMAT_VERSION: u32 = 4;
+
+/// Type of the keys of an index, used to pick a built-in key comparator when the index is
+/// reopened.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    /// 4-byte big-endian signed integer
+    Int4,
+    /// 8-byte big-endian signed integer
+    Int8,
+    /// Bytes compared lexicographically
+    Bytes,
+    /// Keys made of several components, each prefixed with its 4-byte little-endian length
+    Composite(Vec<KeyType>),
+    /// 4-byte big-endian unsigned integer
+    UInt4,
+    /// 8-byte big-endian unsigned integer
+    UInt8,
+    /// UTF-8 string
+    Text,
+}
+
+impl KeyType {
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Result<Ordering> {
+        match self {
+            KeyType::Int4 => comparators::compare_i32(a, b),
+            KeyType::Int8 => comparators::compare_i64(a, b),
+            KeyType::UInt4 => comparators::compare_u32(a, b),
+            KeyType::UInt8 => comparators::compare_u64(a, b),
+            KeyType::Bytes => comparators::compare_bytes(a, b),
+            KeyType::Text => comparators::compare_str(a, b),
+            KeyType::Composite(components) => {
+                let (mut a, mut b) = (a, b);
+
+                for component in components {
+                    let (a_value, a_rest) = split_component(a)?;
+                    let (b_value, b_rest) = split_component(b)?;
+
+                    match component.compare(a_value, b_value)? {
+                        Ordering::Equal => {}
+                        ordering => return Ok(ordering),
+                    }
+
+                    a = a_rest;
+                    b = b_rest;
+                }
+
+                Ok(Ordering::Equal)
+            }
+        }
+    }
+
+    /// Whether a prefix of a key sorts right after all smaller keys, so that indexes of the type
+    /// can truncate their separator keys.
+    pub fn is_prefix_ordered(&self) -> bool {
+        matches!(self, KeyType::Bytes | KeyType::Text)
+    }
+
+    /// Get a key comparator for the index.
+    pub fn comparator(&self) -> impl Fn(&[u8], &[u8]) -> Result<Ordering> + Sync + Send + 'static {
+        let key_type = self.clone();
+        move |a: &[u8], b: &[u8]| key_type.compare(a, b)
+    }
+}
+
+fn split_component(key: &[u8]) -> Result<(&[u8], &[u8])> {
+    let invalid = || Error::InvalidArgument("invalid composite key".to_owned());
+
+    if key.len() < 4 {
+        return Err(invalid());
+    }
+    let len = LittleEndian::read_u32(key) as usize;
+    let key = &key[4..];
+
+    if key.len() < len {
+        return Err(invalid());
+    }
+    Ok(key.split_at(len))
+}
+
+/// Length of an attribute of a tuple descriptor
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttrLength {
+    Fixed(usize),
+    Variable,
+}
+
+/// Attributes of the rows of a table
+///
+/// The rows of a table with a descriptor start with the number of attributes, a null bitmap and
+/// the 2-byte little-endian end offset of each attribute, followed by the attribute values.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TupleDescriptor {
+    attrs: Vec<AttrLength>,
+}
+
+impl TupleDescriptor {
+    pub fn new(attrs: Vec<AttrLength>) -> Self {
+        Self { attrs }
+    }
+
+    pub fn num_attrs(&self) -> usize {
+        self.attrs.len()
+    }
+
+    fn header_size(&self) -> usize {
+        2 + self.attrs.len().div_ceil(8) + 2 * self.attrs.len()
+    }
+
+    /// Build a row from the attribute values, `None` for a null attribute.
+    pub fn form_tuple(&self, values: &[Option<&[u8]>]) -> Result<Vec<u8>> {
+        if values.len() != self.attrs.len() {
+            return Err(Error::InvalidArgument(format!(
+                "expected {} attributes, got {}",
+                self.attrs.len(),
+                values.len()
+            )));
+        }
+
+        for (i, (value, attr)) in values.iter().zip(&self.attrs).enumerate() {
+            match (value, attr) {
+                (Some(value), AttrLength::Fixed(len)) if value.len() != *len => {
+                    return Err(Error::InvalidArgument(format!(
+                        "attribute {} has length {}, expected {}",
+                        i,
+                        value.len(),
+                        len
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        form_attrs(values)
+    }
+
+    /// Check that a row is built by `form_tuple` with this descriptor.
+    pub(crate) fn check_tuple(&self, data: &[u8]) -> Result<()> {
+        let invalid =
+            || Error::InvalidArgument("row does not match the tuple descriptor".to_owned());
+
+        if data.len() < self.header_size()
+            || LittleEndian::read_u16(data) as usize != self.attrs.len()
+        {
+            return Err(invalid());
+        }
+
+        let mut start = 0;
+        for (i, attr) in self.attrs.iter().enumerate() {
+            let (end, is_null) = attr_bounds(data, i).ok_or_else(invalid)?;
+            let len = end.checked_sub(start).ok_or_else(invalid)?;
+
+            match attr {
+                _ if is_null && len != 0 => return Err(invalid()),
+                AttrLength::Fixed(fixed_len) if !is_null && len != *fixed_len => {
+                    return Err(invalid())
+                }
+                _ => {}
+            }
+            start = end;
+        }
+
+        if self.header_size() + start != data.len() {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+fn form_attrs(values: &[Option<&[u8]>]) -> Result<Vec<u8>> {
+    let bitmap_size = values.len().div_ceil(8);
+    let header_size = 2 + bitmap_size + 2 * values.len();
+    let mut buf = vec![0u8; header_size];
+    LittleEndian::write_u16(&mut buf, values.len() as u16);
+
+    for (i, value) in values.iter().enumerate() {
+        match value {
+            Some(value) => buf.extend_from_slice(value),
+            None => buf[2 + i / 8] |= 1 << (i % 8),
+        }
+
+        let end = buf.len() - header_size;
+        if end > u16::MAX as usize {
+            return Err(Error::ProgramLimitExceed(format!(
+                "row size {} exceeds limit {}",
+                end,
+                u16::MAX
+            )));
+        }
+        let offset_pos = 2 + bitmap_size + 2 * i;
+        LittleEndian::write_u16(&mut buf[offset_pos..], end as u16);
+    }
+
+    Ok(buf)
+}
+
+// end offset of an attribute and whether it is null
+fn attr_bounds(data: &[u8], index: usize) -> Option<(usize, bool)> {
+    let num_attrs = LittleEndian::read_u16(data.get(..2)?) as usize;
+    if index >= num_attrs {
+        return None;
+    }
+
+    let bitmap_size = num_attrs.div_ceil(8);
+    let is_null = data.get(2 + index / 8)? & (1 << (index % 8)) != 0;
+    let offset_pos = 2 + bitmap_size + 2 * index;
+    let end = LittleEndian::read_u16(data.get(offset_pos..offset_pos + 2)?) as usize;
+    Some((end, is_null))
+}
+
+/// Get an attribute of a row built by `TupleDescriptor::form_tuple`. Return `None` if the
+/// attribute is null or out of range.
+pub(crate) fn get_attr(data: &[u8], index: usize) -> Option<&[u8]> {
+    let (end, is_null) = attr_bounds(data, index)?;
+    if is_null {
+        return None;
+    }
+
+    let num_attrs = LittleEndian::read_u16(data) as usize;
+    let values = &data[2 + num_attrs.div_ceil(8) + 2 * num_attrs..];
+    let start = match index {
+        0 => 0,
+        _ => attr_bounds(data, index - 1)?.0,
+    };
+    values.get(start..end)
+}
+
+/// Build a row of the given attributes of a row built by `TupleDescriptor::form_tuple`, in the
+/// order of `columns`.
+pub(crate) fn project_attrs(data: &[u8], columns: &[usize]) -> Result<Vec<u8>> {
+    let values = columns
+        .iter()
+        .map(|column| get_attr(data, *column))
+        .collect::<Vec<_>>();
+    form_attrs(&values)
+}
+
+/// Options of a relation given when it is created
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RelOptions {
+    /// Percentage of a heap page filled by inserts, the rest is left free. `None` fills the pages
+    /// completely
+    pub fillfactor: Option<u8>,
+    /// The content is not logged and is reset after a crash
+    pub unlogged: bool,
+    /// The pages are compressed on disk
+    pub compressed: bool,
+}
+
+impl RelOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fillfactor(mut self, fillfactor: u8) -> Self {
+        self.fillfactor = Some(fillfactor);
+        self
+    }
+
+    pub fn unlogged(mut self, unlogged: bool) -> Self {
+        self.unlogged = unlogged;
+        self
+    }
+
+    pub fn compressed(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self.fillfactor {
+            Some(fillfactor) if !(10..=100).contains(&fillfactor) => Err(Error::InvalidArgument(
+                format!("fillfactor {} is not between 10 and 100", fillfactor),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    pub kind: RelationKind,
+    /// Key type of an index, `None` if the index uses a custom comparator
+    pub key_type: Option<KeyType>,
+    pub options: RelOptions,
+    /// Attributes of the rows of a table, `None` if the rows are opaque bytes
+    pub tuple_desc: Option<TupleDescriptor>,
+    /// File of the relation if it is not the one named after the relation ID, e.g. after the
+    /// relation is truncated
+    pub file_id: Option<OID>,
+    /// Statistics of the columns of a table computed by the last analyze
+    pub column_stats: Vec<ColumnStats>,
+}
+
+impl CatalogEntry {
+    pub fn new(kind: RelationKind) -> Self {
+        Self {
+            kind,
+            key_type: None,
+            options: RelOptions::default(),
+            tuple_desc: None,
+            file_id: None,
+            column_stats: Vec::new(),
+        }
+    }
+}
+
+/// Catalog entry in the files of version 3
+#[derive(Deserialize)]
+struct CatalogEntryV3 {
+    kind: RelationKind,
+    key_type: Option<KeyType>,
+    options: RelOptions,
+    tuple_desc: Option<TupleDescriptor>,
+    file_id: Option<OID>,
+}
+
+/// Catalog entry in the files of version 2
+#[derive(Deserialize)]
+struct CatalogEntryV2 {
+    kind: RelationKind,
+    key_type: Option<KeyType>,
+    options: RelOptions,
+    tuple_desc: Option<TupleDescriptor>,
+}
+
+/// Catalog entry in the files of version 1
+#[derive(Deserialize)]
+struct CatalogEntryV1 {
+    kind: RelationKind,
+    key_type: Option<KeyType>,
+    options: RelOptions,
+}
+
+/// Catalog entry in the files written before the format is versioned
+#[derive(Deserialize)]
+struct CatalogEntryV0 {
+    kind: RelationKind,
+    key_type: Option<KeyType>,
+}
+
+/// The relations of all databases. The entries of a database are stored in the catalog file in
+/// the database directory, which is replaced as a whole on every change.
+pub(crate) struct Catalog {
+    base_path: PathBuf,
+    entries: Mutex<HashMap<RelFileRef, CatalogEntry>>,
+}
+
+impl Catalog {
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let base_path = base_path.as_ref().to_path_buf();
+        let mut entries = HashMap::new();
+
+        if base_path.is_dir() {
+            for dir_entry in fs::read_dir(&base_path)? {
+                let dir_entry = dir_entry?;
+                let db = match dir_entry.file_name().to_str().map(str::parse::<OID>) {
+                    Some(Ok(db)) => db,
+                    _ => continue,
+                };
+
+                let mut path = dir_entry.path();
+                path.push(CATALOG_FILE_NAME);
+                if path.is_file() {
+                    for (rel_id, entry) in read_catalog_file(&path)? {
+                        entries.insert(RelFileRef { db, rel_id }, entry);
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            base_path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn get(&self, file_ref: RelFileRef) -> Option<CatalogEntry> {
+        let guard = self.entries.lock().unwrap();
+        guard.get(&file_ref).cloned()
+    }
+
+    pub fn insert(&self, file_ref: RelFileRef, entry: CatalogEntry) -> Result<()> {
+        let mut guard = self.entries.lock().unwrap();
+        let old_entry = guard.insert(file_ref, entry);
+
+        let result = self.write_db(&guard, file_ref.db);
+        if result.is_err() {
+            match old_entry {
+                Some(old_entry) => guard.insert(file_ref, old_entry),
+                None => guard.remove(&file_ref),
+            };
+        }
+        result
+    }
+
+    pub fn remove(&self, file_ref: RelFileRef) -> Result<()> {
+        let mut guard = self.entries.lock().unwrap();
+        let old_entry = match guard.remove(&file_ref) {
+            Some(old_entry) => old_entry,
+            None => return Ok(()),
+        };
+
+        let result = self.write_db(&guard, file_ref.db);
+        if result.is_err() {
+            guard.insert(file_ref, old_entry);
+        }
+        result
+    }
+
+    /// Replace all entries of a database.
+    pub fn rebuild_database(&self, db: OID, db_entries: Vec<(OID, CatalogEntry)>) -> Result<()> {
+        let mut guard = self.entries.lock().unwrap();
+        let mut entries = guard.clone();
+        entries.retain(|file_ref, _| file_ref.db != db);
+        entries.extend(
+            db_entries
+                .into_iter()
+                .map(|(rel_id, entry)| (RelFileRef { db, rel_id }, entry)),
+        );
+
+        self.write_db(&entries, db)?;
+        *guard = entries;
+        Ok(())
+    }
+
+    /// Return the relations of the given kind in all databases.
+    pub fn list(&self, kind: RelationKind) -> Vec<RelFileRef> {
+        let guard = self.entries.lock().unwrap();
+        let mut file_refs = guard
+            .iter()
+            .filter(|(_, entry)| entry.kind == kind)
+            .map(|(file_ref, _)| *file_ref)
+            .collect::<Vec<_>>();
+        file_refs.sort_by_key(|file_ref| (file_ref.db, file_ref.rel_id));
+        file_refs
+    }
+
+    /// Return the relations whose pages are compressed on disk.
+    pub fn list_compressed_relations(&self) -> Vec<RelFileRef> {
+        let guard = self.entries.lock().unwrap();
+        guard
+            .iter()
+            .filter(|(_, entry)| entry.options.compressed)
+            .map(|(file_ref, _)| *file_ref)
+            .collect()
+    }
+
+    /// Return the relations that are not stored in the files named after them, with the IDs of
+    /// their files.
+    pub fn list_relation_files(&self) -> Vec<(RelFileRef, OID)> {
+        let guard = self.entries.lock().unwrap();
+        guard
+            .iter()
+            .filter_map(|(file_ref, entry)| entry.file_id.map(|file_id| (*file_ref, file_id)))
+            .collect()
+    }
+
+    pub fn database_exists(&self, db: OID) -> bool {
+        self.catalog_path(db).is_file()
+    }
+
+    /// Create an empty catalog for a database.
+    pub fn create_database(&self, db: OID) -> Result<()> {
+        let guard = self.entries.lock().unwrap();
+        self.write_db(&guard, db)
+    }
+
+    /// Remove the entries of a database. The catalog file is removed with the database directory.
+    pub fn drop_database(&self, db: OID) {
+        let mut guard = self.entries.lock().unwrap();
+        guard.retain(|file_ref, _| file_ref.db != db);
+    }
+
+    fn catalog_path(&self, db: OID) -> PathBuf {
+        let mut path = self.base_path.clone();
+        path.push(db.to_string());
+        path.push(CATALOG_FILE_NAME);
+        path
+    }
+
+    fn write_db(&self, entries: &HashMap<RelFileRef, CatalogEntry>, db: OID) -> Result<()> {
+        let mut db_entries = entries
+            .iter()
+            .filter(|(file_ref, _)| file_ref.db == db)
+            .map(|(file_ref, entry)| (file_ref.rel_id, entry.clone()))
+            .collect::<Vec<_>>();
+        db_entries.sort_by_key(|(rel_id, _)| *rel_id);
+
+        let mut buffer = Vec::new();
+        buffer
+            .write_u64::<LittleEndian>(CATALOG_FORMAT_MARKER)
+            .unwrap();
+        buffer
+            .write_u32::<LittleEndian>(CATALOG_FORMAT_VERSION)
+            .unwrap();
+        bincode::serialize_into(&mut buffer, &db_entries)?;
+        let crc = crc32::checksum_ieee(&buffer);
+        buffer.write_u32::<LittleEndian>(crc).unwrap();
+
+        let mut db_path = self.base_path.clone();
+        db_path.push(db.to_string());
+        fs::create_dir_all(&db_path)?;
+
+        let mut temp_path = db_path.clone();
+        temp_path.push(CATALOG_TEMP_FILE_NAME);
+        let path = self.catalog_path(db);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+
+        fs::rename(&temp_path, &path)?;
+        File::open(&db_path)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+fn read_catalog_file(path: &Path) -> Result<Vec<(OID, CatalogEntry)>> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+
+    if buffer.len() < 4 {
+        return Err(Error::DataCorrupted(format!(
+            "catalog '{}' is corrupted",
+            path.display()
+        )));
+    }
+
+    let crc_buf = buffer.split_off(buffer.len() - 4);
+    let crc_file = (&crc_buf[..]).read_u32::<LittleEndian>().unwrap();
+
+    if crc32::checksum_ieee(&buffer) != crc_file {
+        return Err(Error::DataCorrupted(format!(
+            "catalog '{}' is corrupted (checksum does not match)",
+            path.display()
+        )));
+    }
+
+    if buffer.len() < 8 || LittleEndian::read_u64(&buffer) != CATALOG_FORMAT_MARKER {
+        let entries: Vec<(OID, CatalogEntryV0)> = bincode::deserialize(&buffer)?;
+        return Ok(entries
+            .into_iter()
+            .map(|(rel_id, entry)| {
+                let CatalogEntryV0 { kind, key_type } = entry;
+                (
+                    rel_id,
+                    CatalogEntry {
+                        kind,
+                        key_type,
+                        options: RelOptions::default(),
+                        tuple_desc: None,
+                        file_id: None,
+                        column_stats: Vec::new(),
+                    },
+                )
+            })
+            .collect());
+    }
+
+    let version = (&buffer[8..]).read_u32::<LittleEndian>()?;
+    if version == 1 {
+        let entries: Vec<(OID, CatalogEntryV1)> = bincode::deserialize(&buffer[12..])?;
+        return Ok(entries
+            .into_iter()
+            .map(|(rel_id, entry)| {
+                let CatalogEntryV1 {
+                    kind,
+                    key_type,
+                    options,
+                } = entry;
+                (
+                    rel_id,
+                    CatalogEntry {
+                        kind,
+                        key_type,
+                        options,
+                        tuple_desc: None,
+                        file_id: None,
+                        column_stats: Vec::new(),
+                    },
+                )
+            })
+            .collect());
+    }
+
+    if version == 2 {
+        let entries: Vec<(OID, CatalogEntryV2)> = bincode::deserialize(&buffer[12..])?;
+        return Ok(entries
+            .into_iter()
+            .map(|(rel_id, entry)| {
+                let CatalogEntryV2 {
+                    kind,
+                    key_type,
+                    options,
+                    tuple_desc,
+                } = entry;
+                (
+                    rel_id,
+                    CatalogEntry {
+                        kind,
+                        key_type,
+                        options,
+                        tuple_desc,
+                        file_id: None,
+                        column_stats: Vec::new(),
+                    },
+                )
+            })
+            .collect());
+    }
+
+    if version == 3 {
+        let entries: Vec<(OID, CatalogEntryV3)> = bincode::deserialize(&buffer[12..])?;
+        return Ok(entries
+            .into_iter()
+            .map(|(rel_id, entry)| {
+                let CatalogEntryV3 {
+                    kind,
+                    key_type,
+                    options,
+                    tuple_desc,
+                    file_id,
+                } = entry;
+                (
+                    rel_id,
+                    CatalogEntry {
+                        kind,
+                        key_type,
+                        options,
+                        tuple_desc,
+                        file_id,
+                        column_stats: Vec::new(),
+                    },
+                )
+            })
+            .collect());
+    }
+
+    if version != CATALOG_FORMAT_VERSION {
+        return Err(Error::InvalidState(format!(
+            "unsupported catalog version {} in '{}'",
+            version,
+            path.display()
+        )));
+    }
+
+    Ok(bincode::deserialize(&buffer[12..])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyType;
+    use crate::{
+        am::index::IndexScanPredicate, concurrency::IsolationLevel, storage::ScanDirection,
+        test_util::get_temp_db, DBConfig, Error, RelationKind, DB,
+    };
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    #[test]
+    fn cannot_open_relation_of_other_kind() {
+        let (db, db_dir) = get_temp_db();
+        db.create_table(0, 1).unwrap();
+        db.create_typed_index(0, 2, KeyType::Int4).unwrap();
+
+        assert_eq!(
+            db.get_relation_kind(0, 1).unwrap(),
+            Some(RelationKind::Table)
+        );
+        assert_eq!(
+            db.get_relation_kind(0, 2).unwrap(),
+            Some(RelationKind::Index)
+        );
+        assert_eq!(db.get_relation_kind(0, 3).unwrap(), None);
+
+        match db.open_table(0, 2) {
+            Err(Error::WrongObjectType(e)) => assert!(e.contains("not a table")),
+            _ => panic!("index is opened as a table"),
+        }
+        assert!(matches!(
+            db.open_index(0, 1, KeyType::Int4.comparator()),
+            Err(Error::WrongObjectType(_))
+        ));
+
+        // relations not in the catalog yet are told by their pages
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        db.create_table_in_transaction(&mut txn, 0, 3).unwrap();
+        db.create_index_in_transaction(&mut txn, 0, 4, KeyType::Int4.comparator())
+            .unwrap();
+        assert_eq!(
+            db.get_relation_kind(0, 3).unwrap(),
+            Some(RelationKind::Table)
+        );
+        assert_eq!(
+            db.get_relation_kind(0, 4).unwrap(),
+            Some(RelationKind::Index)
+        );
+        assert!(matches!(
+            db.open_table(0, 4),
+            Err(Error::WrongObjectType(_))
+        ));
+        assert!(db.open_table(0, 3).unwrap().is_some());
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_repair_catalog() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table(0, 1).unwrap();
+            let btree = db.create_typed_index(0, 2, KeyType::Int4).unwrap();
+            db.create_table(0, 3).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in 0..100i32 {
+                let key = i.to_be_bytes();
+                let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+                btree.insert(&db, &key, item_ptr).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+            db.create_checkpoint().unwrap();
+        }
+
+        let mut catalog_path = config.get_storage_path();
+        catalog_path.push("0");
+        catalog_path.push(super::CATALOG_FILE_NAME);
+        std::fs::remove_file(&catalog_path).unwrap();
+
+        let db = DB::open(&config).unwrap();
+        assert!(db.open_typed_index(0, 2).unwrap().is_none());
+        assert!(matches!(
+            db.repair_catalog(1),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        db.repair_catalog(0).unwrap();
+        assert!(catalog_path.is_file());
+        assert!(db.create_database(0).is_err());
+        assert!(matches!(
+            db.open_typed_index(0, 1),
+            Err(Error::WrongObjectType(_))
+        ));
+        assert!(matches!(
+            db.open_typed_index(0, 3),
+            Err(Error::WrongObjectType(_))
+        ));
+        assert!(matches!(
+            db.open_typed_index(0, 2),
+            Err(Error::InvalidState(_))
+        ));
+
+        let heap = db.open_table(0, 1).unwrap().unwrap();
+        let btree = db
+            .open_index(0, 2, KeyType::Int4.comparator())
+            .unwrap()
+            .unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            let start_key = 50i32.to_be_bytes();
+            iter.rescan(
+                &db,
+                Some(&start_key),
+                IndexScanPredicate::new(|_: &[u8]| Ok(true)),
+            )
+            .unwrap();
+
+            let mut keys = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                keys.push(BigEndian::read_i32(tuple.get_data()));
+            }
+            assert_eq!(keys, (50..100).collect::<Vec<_>>());
+        }
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        let db = DB::open(&config).unwrap();
+        assert!(db.open_typed_index(0, 1).is_err());
+        drop(db);
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_reopen_index_by_key_type() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+            let btree = db.create_typed_index(0, 1, KeyType::Int4).unwrap();
+            db.create_index(0, 2, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+                .unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in -100..100i32 {
+                let mut key = [0u8; 4];
+                BigEndian::write_i32(&mut key, i * 7 % 200);
+                let item_ptr = heap.insert_tuple(&db, &txn, &key).unwrap();
+                btree.insert(&db, &key, item_ptr).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+            db.create_checkpoint().unwrap();
+        }
+
+        let db = DB::open(&config).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let btree = db.open_typed_index(0, 1).unwrap().unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        {
+            let mut iter = btree.begin_scan(&db, &mut txn, &*heap).unwrap();
+            iter.rescan(&db, None, IndexScanPredicate::new(|_: &[u8]| Ok(true)))
+                .unwrap();
+
+            let mut keys = Vec::new();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                keys.push(BigEndian::read_i32(tuple.get_data()));
+            }
+
+            let mut expected = (-100..100i32).map(|i| i * 7 % 200).collect::<Vec<_>>();
+            expected.sort_unstable();
+            assert_eq!(keys, expected);
+        }
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db.open_typed_index(0, 3).unwrap().is_none());
+        assert!(matches!(
+            db.open_typed_index(0, 2),
+            Err(Error::InvalidState(_))
+        ));
+        assert!(matches!(
+            db.open_typed_index(0, 0),
+            Err(Error::WrongObjectType(_))
+        ));
+
+        drop(heap);
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_compare_composite_keys() {
+        let key_type = KeyType::Composite(vec![KeyType::Bytes, KeyType::Int4]);
+        let make_key = |s: &[u8], i: i32| {
+            let mut key = vec![s.len() as u8, 0, 0, 0];
+            key.extend_from_slice(s);
+            key.extend_from_slice(&[4, 0, 0, 0]);
+            key.extend_from_slice(&i.to_be_bytes());
+            key
+        };
+
+        let cmp = key_type.comparator();
+        assert_eq!(
+            cmp(&make_key(b"a", 5), &make_key(b"b", -5)).unwrap(),
+            std::cmp::Ordering::Less
+        );
+        assert_eq!(
+            cmp(&make_key(b"b", -5), &make_key(b"b", 3)).unwrap(),
+            std::cmp::Ordering::Less
+        );
+        assert!(cmp(&make_key(b"b", 3)[..6], &make_key(b"b", 3)).is_err());
+    }
+
+    #[test]
+    fn can_isolate_databases() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let db = DB::open(&config).unwrap();
+
+        db.create_database(1).unwrap();
+        db.create_database(2).unwrap();
+        assert!(matches!(
+            db.create_database(2),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        for db_oid in 1..=2 {
+            let heap = db.create_table(db_oid, 10).unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for _ in 0..db_oid * 10 {
+                heap.insert_tuple(&db, &txn, &[db_oid as u8; 100]).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+        }
+
+        let scan = |db: &DB, db_oid| {
+            let heap = db.open_table(db_oid, 10).unwrap().unwrap();
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let mut tuples = Vec::new();
+            {
+                let mut iter = heap.begin_scan(db, &mut txn).unwrap();
+                while let Some(tuple) = iter.next(db, ScanDirection::Forward).unwrap() {
+                    tuples.push(tuple.get_data().to_vec());
+                }
+            }
+            db.commit_transaction(txn).unwrap();
+            tuples
+        };
+
+        assert_eq!(scan(&db, 1), vec![vec![1u8; 100]; 10]);
+        assert_eq!(scan(&db, 2), vec![vec![2u8; 100]; 20]);
+
+        db.drop_database(1).unwrap();
+        assert!(db.open_table(1, 10).unwrap().is_none());
+        assert!(!db_dir.path().join("base").join("1").exists());
+        assert!(matches!(
+            db.drop_database(1),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(scan(&db, 2), vec![vec![2u8; 100]; 20]);
+
+        // the database can be created again
+        db.create_database(1).unwrap();
+        assert!(db.open_table(1, 10).unwrap().is_none());
+
+        drop(db);
+        let db = DB::open(&config).unwrap();
+        assert_eq!(scan(&db, 2), vec![vec![2u8; 100]; 20]);
+        assert!(db.open_table(1, 10).unwrap().is_none());
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_remove_relations_of_aborted_transaction() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let db = DB::open(&config).unwrap();
+        let rel_path = |rel_id: u64| {
+            db_dir
+                .path()
+                .join("base")
+                .join("0")
+                .join(format!("{}_0", rel_id))
+        };
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table_in_transaction(&mut txn, 0, 1).unwrap();
+        db.create_index_in_transaction(&mut txn, 0, 2, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+            .unwrap();
+        heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        assert!(rel_path(1).is_file());
+        assert!(rel_path(2).is_file());
+
+        db.abort_transaction(txn).unwrap();
+        assert!(!rel_path(1).exists());
+        assert!(!rel_path(2).exists());
+        assert!(db.open_table(0, 1).unwrap().is_none());
+
+        // dropped transactions are cleaned up at the next checkpoint
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        db.create_table_in_transaction(&mut txn, 0, 3).unwrap();
+        drop(txn);
+        assert!(rel_path(3).is_file());
+        db.create_checkpoint().unwrap();
+        assert!(!rel_path(3).exists());
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table_in_transaction(&mut txn, 0, 4).unwrap();
+        heap.insert_tuple(&db, &txn, &[4u8; 100]).unwrap();
+        db.commit_transaction(txn).unwrap();
+
+        drop(heap);
+        drop(db);
+        let db = DB::open(&config).unwrap();
+        assert!(rel_path(4).is_file());
+        assert!(db.open_table(0, 4).unwrap().is_some());
+        assert!(matches!(
+            db.open_typed_index(0, 4),
+            Err(Error::WrongObjectType(_))
+        ));
+        assert!(db.open_typed_index(0, 2).unwrap().is_none());
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{
+    concurrency::{xid_table::XidTable, XID},
+    wal::{is_invalid_lsn, LogPointer},
+    Result,
+};
+
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Record the commit LSNs of transactions
+///
+/// Entries after the last checkpoint are restored by replaying the commit log records. The
+/// entries of transactions before the vacuum horizon are removed on checkpoints.
+pub struct CommitLsnTable {
+    table: XidTable,
+}
+
+impl CommitLsnTable {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            table: XidTable::open(path, "commit LSN table", 8)?,
+        })
+    }
+
+    pub fn get_commit_lsn(&mut self, xid: XID) -> Result<Option<LogPointer>> {
+        let lsn = LittleEndian::read_u64(self.table.get_entry(xid)?);
+
+        Ok(if is_invalid_lsn(lsn) { None } else { Some(lsn) })
+    }
+
+    pub fn set_commit_lsn(&mut self, xid: XID, lsn: LogPointer) -> Result<()> {
+        LittleEndian::write_u64(self.table.get_entry_mut(xid)?, lsn);
+        Ok(())
+    }
+
+    /// Remove the commit LSNs of the transactions before `horizon`.
+    pub fn truncate(&mut self, horizon: XID) -> Result<()> {
+        self.table.truncate(horizon)
+    }
+
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.table.checkpoint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::xid_table::{TABLE_CACHE_CAPACITY, XID_TABLE_PAGE_SIZE};
+
+    const LSNS_PER_PAGE: usize = XID_TABLE_PAGE_SIZE / 8;
+
+    #[test]
+    fn can_get_set_commit_lsn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("commit_lsn");
+
+        {
+            let mut table = CommitLsnTable::open(&path).unwrap();
+            // span more pages than the cache holds
+            for i in 1..(LSNS_PER_PAGE * TABLE_CACHE_CAPACITY + 100) as u32 {
+                if i % 3 == 0 {
+                    table.set_commit_lsn(XID::from(i), i as u64 * 10).unwrap();
+                }
+            }
+            table.checkpoint().unwrap();
+        }
+
+        let mut table = CommitLsnTable::open(&path).unwrap();
+        for i in 1..(LSNS_PER_PAGE * TABLE_CACHE_CAPACITY + 100) as u32 {
+            let expected = if i % 3 == 0 {
+                Some(i as u64 * 10)
+            } else {
+                None
+            };
+            assert_eq!(table.get_commit_lsn(XID::from(i)).unwrap(), expected);
+        }
+        assert_eq!(table.get_commit_lsn(XID::from(1_000_000)).unwrap(), None);
+
+        dir.close().unwrap();
+    }
+}
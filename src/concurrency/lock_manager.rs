@@ -0,0 +1,219 @@
+use crate::{
+    concurrency::{InterruptCheck, XID},
+    storage::{ForkType, RelFileRef},
+    Error, Result,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+
+// how often a waiting transaction checks for cancellation
+const LOCK_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    /// Taken by scans and writes. Conflicts only with `AccessExclusive`.
+    AccessShare,
+    /// Taken by DDL that replaces or removes the relation files. Conflicts with all modes.
+    AccessExclusive,
+}
+
+#[derive(Default)]
+struct RelationLock {
+    share_holders: HashSet<XID>,
+    exclusive_holder: Option<XID>,
+}
+
+impl RelationLock {
+    fn can_grant(&self, xid: XID, mode: LockMode) -> bool {
+        let exclusive_ok = self.exclusive_holder.is_none_or(|holder| holder == xid);
+
+        match mode {
+            LockMode::AccessShare => exclusive_ok,
+            LockMode::AccessExclusive => {
+                exclusive_ok && self.share_holders.iter().all(|holder| *holder == xid)
+            }
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.share_holders.is_empty() && self.exclusive_holder.is_none()
+    }
+
+    /// Get the holders that keep the lock from being granted to the transaction.
+    fn conflicting_holders(&self, xid: XID, mode: LockMode) -> HashSet<XID> {
+        let share_holders = match mode {
+            LockMode::AccessShare => None,
+            LockMode::AccessExclusive => Some(self.share_holders.iter().copied()),
+        };
+
+        self.exclusive_holder
+            .into_iter()
+            .chain(share_holders.into_iter().flatten())
+            .filter(|holder| *holder != xid)
+            .collect()
+    }
+}
+
+/// Relation-level locks held by transactions until they end
+#[derive(Default)]
+pub struct LockManager {
+    locks: Mutex<HashMap<RelFileRef, RelationLock>>,
+    lock_released: Condvar,
+    // forks being extended, not tied to transactions
+    extensions: Mutex<HashSet<(RelFileRef, ForkType)>>,
+    extension_released: Condvar,
+    // transactions waiting for locks or for other transactions to end, with the XIDs they wait
+    // for. a waiting transaction is recorded under all of its current XIDs
+    waits: Mutex<HashMap<XID, HashSet<XID>>>,
+}
+
+/// Extension lock of a relation fork, released when dropped
+pub struct ExtensionLockGuard<'a> {
+    lock_manager: &'a LockManager,
+    key: (RelFileRef, ForkType),
+}
+
+impl Drop for ExtensionLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut guard = self
+            .lock_manager
+            .extensions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        guard.remove(&self.key);
+        self.lock_manager.extension_released.notify_all();
+    }
+}
+
+impl LockManager {
+    /// Acquire a lock on the relation for the transaction `xid`, waiting until the conflicting
+    /// locks are released or the wait is interrupted. `current_xids` are the XIDs of the
+    /// transaction that other transactions may wait for.
+    pub(crate) fn lock(
+        &self,
+        xid: XID,
+        current_xids: &HashSet<XID>,
+        file_ref: RelFileRef,
+        mode: LockMode,
+        interrupts: &mut InterruptCheck,
+    ) -> Result<()> {
+        let mut guard = self.locks.lock().unwrap();
+
+        let result = loop {
+            let lock = guard.entry(file_ref).or_default();
+
+            if lock.can_grant(xid, mode) {
+                match mode {
+                    LockMode::AccessShare => {
+                        lock.share_holders.insert(xid);
+                    }
+                    LockMode::AccessExclusive => {
+                        lock.exclusive_holder = Some(xid);
+                    }
+                }
+
+                break Ok(());
+            }
+
+            // the holders change while waiting, e.g. when a share lock is upgraded
+            if let Err(e) = self
+                .begin_wait(current_xids, lock.conflicting_holders(xid, mode))
+                .and_then(|_| interrupts.check())
+            {
+                break Err(e);
+            }
+            guard = self
+                .lock_released
+                .wait_timeout(guard, LOCK_WAIT_INTERVAL)
+                .unwrap()
+                .0;
+        };
+
+        self.end_wait(current_xids);
+        result
+    }
+
+    /// Record that the transaction with `waiter_xids` waits for the transactions `xids`. Fail
+    /// with `Error::Deadlock` if they wait for the transaction in turn.
+    pub(crate) fn begin_wait(&self, waiter_xids: &HashSet<XID>, xids: HashSet<XID>) -> Result<()> {
+        let mut guard = self.waits.lock().unwrap();
+
+        // follow the waits from the transactions waited for
+        let mut visited = HashSet::new();
+        let mut stack = xids.iter().copied().collect::<Vec<_>>();
+        while let Some(xid) = stack.pop() {
+            if waiter_xids.contains(&xid) {
+                for waiter_xid in waiter_xids {
+                    guard.remove(waiter_xid);
+                }
+
+                return Err(Error::Deadlock(format!(
+                    "transaction {} waits for a transaction that waits for it",
+                    waiter_xids.iter().min().unwrap()
+                )));
+            }
+
+            if visited.insert(xid) {
+                if let Some(next_xids) = guard.get(&xid) {
+                    stack.extend(next_xids.iter().copied());
+                }
+            }
+        }
+
+        for waiter_xid in waiter_xids {
+            guard.insert(*waiter_xid, xids.clone());
+        }
+        Ok(())
+    }
+
+    /// Remove the waits of a transaction after it stops waiting.
+    pub(crate) fn end_wait(&self, waiter_xids: &HashSet<XID>) {
+        // this may run while a panic unwinds
+        let mut guard = self.waits.lock().unwrap_or_else(PoisonError::into_inner);
+        for waiter_xid in waiter_xids {
+            guard.remove(waiter_xid);
+        }
+    }
+
+    /// Acquire the extension lock of a relation fork. It is held while a page is added to the
+    /// fork and initialized so that no one else sees the page before that.
+    pub(crate) fn lock_extension(
+        &self,
+        file_ref: RelFileRef,
+        fork: ForkType,
+    ) -> ExtensionLockGuard<'_> {
+        let key = (file_ref, fork);
+        let mut guard = self.extensions.lock().unwrap();
+
+        while guard.contains(&key) {
+            guard = self.extension_released.wait(guard).unwrap();
+        }
+        guard.insert(key);
+
+        ExtensionLockGuard {
+            lock_manager: self,
+            key,
+        }
+    }
+
+    /// Release all locks held by the transaction.
+    pub fn release_all(&self, xid: XID) {
+        // this may run while a panic unwinds
+        let mut guard = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+
+        guard.retain(|_, lock| {
+            lock.share_holders.remove(&xid);
+            if lock.exclusive_holder == Some(xid) {
+                lock.exclusive_holder = None;
+            }
+
+            !lock.is_free()
+        });
+
+        self.lock_released.notify_all();
+    }
+}
@@ -1,28 +1,44 @@
+mod commit_lsn_table;
+mod lock_manager;
 mod state_manager;
+mod subtrans_table;
 mod transaction_log;
 mod transaction_manager;
 mod transaction_table;
+mod xid_table;
 
 pub use self::{
+    commit_lsn_table::CommitLsnTable,
+    lock_manager::{ExtensionLockGuard, LockManager, LockMode},
     state_manager::StateManager,
+    subtrans_table::SubtransTable,
     transaction_log::TransactionLogRecord,
     transaction_manager::TransactionManager,
     transaction_table::{TransactionStatus, TransactionTable},
 };
 
-use std::{cmp::Ordering, collections::HashSet, fmt, num::Wrapping};
+use crate::{
+    storage::{RelFileRef, TruncatedRelation},
+    CatalogEntry, Error, Result,
+};
+
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fmt,
+    num::Wrapping,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct XID(u32);
 
-impl Default for XID {
-    fn default() -> Self {
-        Self(0)
-    }
-}
-
 impl PartialOrd for XID {
     fn partial_cmp(&self, other: &XID) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -52,9 +68,9 @@ impl From<u32> for XID {
     }
 }
 
-impl Into<u64> for XID {
-    fn into(self) -> u64 {
-        self.0 as u64
+impl From<XID> for u64 {
+    fn from(val: XID) -> Self {
+        val.0 as u64
     }
 }
 
@@ -102,10 +118,93 @@ pub enum IsolationLevel {
 
 impl IsolationLevel {}
 
+pub type CommandId = u32;
+
+/// Number of calls between two clock reads when checking a statement deadline
+const DEADLINE_CHECK_INTERVAL: u32 = 16;
+
+/// A token to cancel the operations of the transactions it is attached to from another thread.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Allow operations to run again after a cancellation.
+    pub fn reset(&self) {
+        self.0.store(false, AtomicOrdering::SeqCst);
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.0.load(AtomicOrdering::SeqCst)
+    }
+}
+
+/// Deadline and cancel token of a statement, checked periodically by long-running scans.
+pub(crate) struct InterruptCheck {
+    deadline: Option<Instant>,
+    cancel_token: Option<CancelToken>,
+    shutdown_token: Option<CancelToken>,
+    calls: u32,
+}
+
+impl InterruptCheck {
+    pub fn check(&mut self) -> Result<()> {
+        if let Some(shutdown_token) = &self.shutdown_token {
+            if shutdown_token.is_canceled() {
+                return Err(Error::Interrupted);
+            }
+        }
+
+        if let Some(cancel_token) = &self.cancel_token {
+            if cancel_token.is_canceled() {
+                return Err(Error::QueryCanceled(
+                    "canceling statement due to user request".to_owned(),
+                ));
+            }
+        }
+
+        if let Some(deadline) = self.deadline {
+            self.calls = self.calls.wrapping_add(1);
+
+            if self.calls.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(Error::QueryCanceled(
+                    "canceling statement due to statement timeout".to_owned(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub struct Transaction {
     xid: XID,
     isolation_level: IsolationLevel,
+    command_id: CommandId,
     current_snapshot: Option<Snapshot>,
+    statement_timeout: Option<Duration>,
+    cancel_token: Option<CancelToken>,
+    // set when the database is shutting down
+    shutdown_token: Option<CancelToken>,
+    // relations created by the transaction, added to the catalog on commit
+    pending_relations: Vec<(RelFileRef, CatalogEntry)>,
+    // relations truncated by the transaction, whose old files are removed on commit
+    pending_truncations: Vec<TruncatedRelation>,
+    // relations dropped by the transaction, removed on commit
+    pending_drops: Vec<RelFileRef>,
+    // enclosing transactions of a subtransaction, the top-level one first
+    ancestor_xids: Vec<XID>,
+    // subtransactions committed into the transaction, which end with it
+    committed_subxids: Vec<XID>,
+    // aborts the transaction if it is dropped before it finishes
+    txnmgr: Weak<TransactionManager>,
     // state: TransactionState,
 }
 
@@ -114,15 +213,133 @@ impl Transaction {
         Self {
             xid,
             isolation_level,
+            command_id: 0,
             current_snapshot: None,
+            statement_timeout: None,
+            cancel_token: None,
+            shutdown_token: None,
+            pending_relations: Vec::new(),
+            pending_truncations: Vec::new(),
+            pending_drops: Vec::new(),
+            ancestor_xids: Vec::new(),
+            committed_subxids: Vec::new(),
+            txnmgr: Weak::new(),
             // state: TransactionState::InProgress,
         }
     }
 
+    pub(self) fn set_transaction_manager(&mut self, txnmgr: &Arc<TransactionManager>) {
+        self.txnmgr = Arc::downgrade(txnmgr);
+    }
+
+    /// The transaction is finished and no longer needs to be aborted on drop.
+    pub(self) fn set_finished(&mut self) {
+        self.txnmgr = Weak::new();
+    }
+
     pub fn xid(&self) -> XID {
         self.xid
     }
 
+    pub fn command_id(&self) -> CommandId {
+        self.command_id
+    }
+
+    /// Get the XID of the enclosing transaction if this is a subtransaction.
+    pub fn parent_xid(&self) -> Option<XID> {
+        self.ancestor_xids.last().copied()
+    }
+
+    /// Get the XIDs whose writes are made by this transaction: its own XID, the XIDs of the
+    /// enclosing transactions and those of the committed subtransactions.
+    pub(crate) fn current_xids(&self) -> HashSet<XID> {
+        std::iter::once(self.xid)
+            .chain(self.ancestor_xids.iter().copied())
+            .chain(self.committed_subxids.iter().copied())
+            .collect()
+    }
+
+    /// Start a new command in the transaction. Tuples inserted by the previous commands become
+    /// visible to the new command.
+    pub fn advance_command_id(&mut self) -> Result<()> {
+        if self.command_id == CommandId::MAX {
+            return Err(Error::ProgramLimitExceed(
+                "too many commands in a transaction".to_owned(),
+            ));
+        }
+
+        self.command_id += 1;
+        Ok(())
+    }
+
+    /// Cancel scans that run longer than `timeout`. The time is measured from the start of
+    /// each scan.
+    pub fn set_statement_timeout(&mut self, timeout: Duration) {
+        self.statement_timeout = Some(timeout);
+    }
+
+    /// Cancel the scans of the transaction when the token is canceled.
+    pub fn set_cancel_token(&mut self, cancel_token: CancelToken) {
+        self.cancel_token = Some(cancel_token);
+    }
+
+    /// Interrupt the scans of the transaction when the database is shutting down.
+    pub(crate) fn set_shutdown_token(&mut self, shutdown_token: CancelToken) {
+        self.shutdown_token = Some(shutdown_token);
+    }
+
+    /// Record a relation created by the transaction. The relation is removed if the transaction
+    /// aborts.
+    pub(crate) fn add_pending_relation(&mut self, file_ref: RelFileRef, entry: CatalogEntry) {
+        self.pending_relations.push((file_ref, entry));
+    }
+
+    /// Whether the transaction has created relation files, which are removed if it aborts.
+    pub(crate) fn has_pending_relations(&self) -> bool {
+        !self.pending_relations.is_empty() || !self.pending_truncations.is_empty()
+    }
+
+    pub(crate) fn take_pending_relations(&mut self) -> Vec<(RelFileRef, CatalogEntry)> {
+        std::mem::take(&mut self.pending_relations)
+    }
+
+    /// Record a relation truncated by the transaction. The new file of the relation is removed
+    /// if the transaction aborts, and the old one if it commits.
+    pub(crate) fn add_pending_truncation(&mut self, truncation: TruncatedRelation) {
+        self.pending_truncations.push(truncation);
+    }
+
+    pub(crate) fn has_pending_truncations(&self) -> bool {
+        !self.pending_truncations.is_empty()
+    }
+
+    pub(crate) fn take_pending_truncations(&mut self) -> Vec<TruncatedRelation> {
+        std::mem::take(&mut self.pending_truncations)
+    }
+
+    /// Record a relation dropped by the transaction. The relation is removed when the
+    /// transaction commits and kept if it aborts.
+    pub(crate) fn add_pending_drop(&mut self, file_ref: RelFileRef) {
+        if !self.pending_drops.contains(&file_ref) {
+            self.pending_drops.push(file_ref);
+        }
+    }
+
+    pub(crate) fn take_pending_drops(&mut self) -> Vec<RelFileRef> {
+        std::mem::take(&mut self.pending_drops)
+    }
+
+    pub(crate) fn interrupt_check(&self) -> InterruptCheck {
+        InterruptCheck {
+            deadline: self
+                .statement_timeout
+                .map(|timeout| Instant::now() + timeout),
+            cancel_token: self.cancel_token.clone(),
+            shutdown_token: self.shutdown_token.clone(),
+            calls: 0,
+        }
+    }
+
     // pub fn state(&self) -> TransactionState {
     //     self.state
     // }
@@ -130,6 +347,10 @@ impl Transaction {
     // pub fn set_state(&mut self, state: TransactionState) {
     //     self.state = state;
     // }
+    pub fn isolation_level(&self) -> IsolationLevel {
+        self.isolation_level
+    }
+
     pub fn uses_transaction_snapshot(&self) -> bool {
         self.isolation_level >= IsolationLevel::RepeatableRead
     }
@@ -139,6 +360,20 @@ impl Transaction {
     }
 }
 
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(txnmgr) = self.txnmgr.upgrade() {
+            // nothing can be done about the error here
+            let subxids = std::mem::take(&mut self.committed_subxids);
+            let truncations = self.take_pending_truncations();
+            let _ = txnmgr.abort_transaction(self.xid, &subxids, &truncations);
+
+            let relations = self.take_pending_relations();
+            txnmgr.add_unused_files(relations.into_iter().map(|(file_ref, _)| file_ref));
+        }
+    }
+}
+
 pub struct Snapshot {
     // first active transaction
     min_xid: XID,
@@ -146,6 +381,14 @@ pub struct Snapshot {
     max_xid: XID,
     // active XIDs at the time of snapshot
     xips: HashSet<XID>,
+    // command of the current transaction that takes the snapshot
+    command_id: CommandId,
+    // XIDs of the current transaction and its enclosing and committed subtransactions
+    current_xids: HashSet<XID>,
+    // see the latest version of the tuples, including the changes of in-progress transactions
+    dirty: bool,
+    // isolation level of the transaction that takes the snapshot
+    isolation_level: IsolationLevel,
 }
 
 impl Snapshot {
@@ -162,6 +405,39 @@ impl Snapshot {
 
         self.xips.contains(&xid)
     }
+
+    pub fn command_id(&self) -> CommandId {
+        self.command_id
+    }
+
+    /// Are the writes of the XID made by the transaction that takes the snapshot
+    pub fn is_current_xid(&self, xid: XID) -> bool {
+        self.current_xids.contains(&xid)
+    }
+
+    pub fn min_xid(&self) -> XID {
+        self.min_xid
+    }
+
+    /// First XID that is unassigned when the snapshot is taken
+    pub fn max_xid(&self) -> XID {
+        self.max_xid
+    }
+
+    /// Get the XIDs before `xid` that are in progress according to the snapshot.
+    pub fn in_progress_xids_before(&self, xid: XID) -> impl Iterator<Item = XID> + '_ {
+        self.xips.iter().copied().filter(move |xip| *xip < xid)
+    }
+
+    /// Is this a dirty snapshot, which reports the tuples inserted or deleted by in-progress
+    /// transactions along with their XIDs instead of deciding their visibility
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn isolation_level(&self) -> IsolationLevel {
+        self.isolation_level
+    }
 }
 
 impl fmt::Display for Snapshot {
@@ -1,28 +1,28 @@
-use crate::{concurrency::XID, wal::WalLogRecord, Result, DB, OID};
+use crate::{concurrency::XID, wal::WalLogRecord, Error, Result, DB, OID};
 
 use std::sync::Mutex;
 
 const OID_PREALLOC_COUNT: usize = 8192;
 const NORMAL_OID_START: OID = 16384;
 
+#[derive(Default)]
 struct OidAllocator {
     next_oid: OID,
     oid_count: usize,
-}
-
-impl Default for OidAllocator {
-    fn default() -> Self {
-        Self {
-            next_oid: 0,
-            oid_count: 0,
-        }
-    }
+    // the OID after the last preallocated range, which is durable in the log
+    logged_oid: OID,
 }
 
 pub struct StateManager {
     oid_allocator: Mutex<OidAllocator>,
 }
 
+impl Default for StateManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StateManager {
     pub fn new() -> Self {
         let oid_allocator = Default::default();
@@ -41,10 +41,9 @@ impl StateManager {
 
         if guard.oid_count == 0 {
             // get some more
-            let wal = db.get_wal();
-            let next_oid_log =
-                WalLogRecord::create_next_oid_log(guard.next_oid + OID_PREALLOC_COUNT as OID);
-            wal.append(XID::default(), next_oid_log)?;
+            let logged_oid = guard.next_oid + OID_PREALLOC_COUNT as OID;
+            Self::log_next_oid(db, logged_oid)?;
+            guard.logged_oid = logged_oid;
             guard.oid_count = OID_PREALLOC_COUNT;
         }
 
@@ -55,15 +54,115 @@ impl StateManager {
         Ok(next_oid)
     }
 
+    /// Allocate `count` contiguous OIDs. Return the first and the last OID of the range.
+    pub fn alloc_oid_range(&self, db: &DB, count: usize) -> Result<(OID, OID)> {
+        if count == 0 {
+            return Err(Error::InvalidArgument(
+                "cannot allocate an empty OID range".to_owned(),
+            ));
+        }
+
+        let mut guard = self.oid_allocator.lock().unwrap();
+
+        if guard.next_oid < NORMAL_OID_START {
+            guard.next_oid = NORMAL_OID_START;
+            guard.oid_count = 0;
+        }
+
+        if guard.oid_count < count {
+            // the preallocated OIDs are the head of the range, extend it to cover the whole range
+            let logged_oid = guard.next_oid + count as OID;
+            Self::log_next_oid(db, logged_oid)?;
+            guard.logged_oid = logged_oid;
+            guard.oid_count = count;
+        }
+
+        let first_oid = guard.next_oid;
+        guard.next_oid += count as OID;
+        guard.oid_count -= count;
+
+        Ok((first_oid, first_oid + count as OID - 1))
+    }
+
+    /// Log the end of a preallocated range. The log is flushed before any OID in the range is
+    /// handed out so that the OIDs are never reissued after a crash.
+    fn log_next_oid(db: &DB, next_oid: OID) -> Result<()> {
+        let wal = db.get_wal();
+        let next_oid_log = WalLogRecord::create_next_oid_log(next_oid);
+        let (_, lsn) = wal.append(XID::default(), next_oid_log)?;
+        wal.flush(Some(lsn))
+    }
+
+    /// Return the high-water mark of the allocated OIDs that is recorded in the log. It may be
+    /// larger than the next OID to be handed out.
     pub fn max_allocated_oid(&self) -> OID {
         let guard = self.oid_allocator.lock().unwrap();
 
-        guard.next_oid + guard.oid_count as OID
+        std::cmp::max(guard.logged_oid, guard.next_oid)
     }
 
     pub fn set_next_oid(&self, oid: OID) {
         let mut guard = self.oid_allocator.lock().unwrap();
         guard.next_oid = oid;
         guard.oid_count = 0;
+        guard.logged_oid = oid;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::get_temp_db, DBConfig, DB};
+
+    #[test]
+    fn can_allocate_oid_range() {
+        let (db, db_dir) = get_temp_db();
+
+        let first_oid = db.get_next_oid().unwrap();
+        let (start1, end1) = db.alloc_oid_range(100).unwrap();
+        let (start2, end2) = db.alloc_oid_range(10000).unwrap();
+
+        assert_eq!(start1, first_oid + 1);
+        assert_eq!(end1 - start1 + 1, 100);
+        assert_eq!(end2 - start2 + 1, 10000);
+        assert!(end1 < start2);
+
+        assert_eq!(db.get_next_oid().unwrap(), first_oid + 1 + 10100);
+        assert!(db.alloc_oid_range(0).is_err());
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn cannot_reissue_oids_after_crash() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        let mut oids = Vec::new();
+        {
+            let db = DB::open(&config).unwrap();
+            db.create_checkpoint().unwrap();
+            for _ in 0..3 {
+                oids.push(db.get_next_oid().unwrap());
+            }
+            // crash without a checkpoint or any other log flush
+        }
+
+        {
+            let db = DB::open(&config).unwrap();
+            let oid = db.get_next_oid().unwrap();
+            assert!(oids.iter().all(|old_oid| *old_oid < oid));
+
+            oids.push(oid);
+            db.create_checkpoint().unwrap();
+            assert!(db.get_state_manager().max_allocated_oid() > oid);
+        }
+
+        {
+            let db = DB::open(&config).unwrap();
+            let oid = db.get_next_oid().unwrap();
+            assert!(oids.iter().all(|old_oid| *old_oid < oid));
+        }
+
+        assert!(db_dir.close().is_ok());
     }
 }
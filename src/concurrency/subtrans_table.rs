@@ -0,0 +1,82 @@
+use crate::{
+    concurrency::{xid_table::XidTable, XID},
+    Result,
+};
+
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Record the parents of committed subtransactions
+///
+/// A subtransaction whose parent is lost in a crash is treated as aborted, unless the commit log
+/// of its top-level transaction is replayed.
+pub struct SubtransTable {
+    table: XidTable,
+}
+
+impl SubtransTable {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            table: XidTable::open(path, "subtransaction table", 4)?,
+        })
+    }
+
+    pub fn get_parent(&mut self, xid: XID) -> Result<Option<XID>> {
+        let parent = XID::from(LittleEndian::read_u32(self.table.get_entry(xid)?));
+
+        Ok(if parent.is_invalid() {
+            None
+        } else {
+            Some(parent)
+        })
+    }
+
+    pub fn set_parent(&mut self, xid: XID, parent: XID) -> Result<()> {
+        let parent: u64 = parent.into();
+        LittleEndian::write_u32(self.table.get_entry_mut(xid)?, parent as u32);
+        Ok(())
+    }
+
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.table.checkpoint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::xid_table::{TABLE_CACHE_CAPACITY, XID_TABLE_PAGE_SIZE};
+
+    const PARENTS_PER_PAGE: usize = XID_TABLE_PAGE_SIZE / 4;
+
+    #[test]
+    fn can_get_set_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("subtrans");
+
+        {
+            let mut table = SubtransTable::open(&path).unwrap();
+            // span more pages than the cache holds
+            for i in 1..(PARENTS_PER_PAGE * TABLE_CACHE_CAPACITY + 100) as u32 {
+                if i % 3 == 0 {
+                    table.set_parent(XID::from(i), XID::from(i - 1)).unwrap();
+                }
+            }
+            table.checkpoint().unwrap();
+        }
+
+        let mut table = SubtransTable::open(&path).unwrap();
+        for i in 1..(PARENTS_PER_PAGE * TABLE_CACHE_CAPACITY + 100) as u32 {
+            let expected = if i % 3 == 0 {
+                Some(XID::from(i - 1))
+            } else {
+                None
+            };
+            assert_eq!(table.get_parent(XID::from(i)).unwrap(), expected);
+        }
+        assert_eq!(table.get_parent(XID::from(1_000_000)).unwrap(), None);
+
+        dir.close().unwrap();
+    }
+}
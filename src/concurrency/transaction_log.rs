@@ -1,5 +1,6 @@
 use crate::{
     concurrency::XID,
+    storage::TruncatedRelation,
     wal::{LogPointer, LogRecord},
     Result, DB,
 };
@@ -11,6 +12,10 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TxnCommitLog {
     pub(super) commit_time: SystemTime,
+    /// Relations truncated by the transaction with their old and new files
+    pub(super) truncated_relations: Vec<TruncatedRelation>,
+    /// Subtransactions committed into the transaction
+    pub(super) committed_subxids: Vec<XID>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -30,8 +35,16 @@ impl TransactionLogRecord {
             .redo_txn_log(db, xid, lsn, self)
     }
 
-    pub fn create_transaction_commit_log<'a>(commit_time: SystemTime) -> LogRecord<'a> {
-        let txn_commit_record = TxnCommitLog { commit_time };
+    pub fn create_transaction_commit_log<'a>(
+        commit_time: SystemTime,
+        truncated_relations: Vec<TruncatedRelation>,
+        committed_subxids: Vec<XID>,
+    ) -> LogRecord<'a> {
+        let txn_commit_record = TxnCommitLog {
+            commit_time,
+            truncated_relations,
+            committed_subxids,
+        };
         LogRecord::create_transaction_record(TransactionLogRecord::Commit(txn_commit_record))
     }
 
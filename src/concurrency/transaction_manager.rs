@@ -1,42 +1,78 @@
 use crate::{
     concurrency::{
-        IsolationLevel, Snapshot, Transaction, TransactionLogRecord, TransactionStatus,
+        CommitLsnTable, ExtensionLockGuard, InterruptCheck, IsolationLevel, LockManager, LockMode,
+        Snapshot, SubtransTable, Transaction, TransactionLogRecord, TransactionStatus,
         TransactionTable, XID,
     },
+    metrics::Counter,
+    storage::{ForkType, RelFileRef, RelationFileMap, TempFileManager, TruncatedRelation},
     wal::LogPointer,
     Error, Result, DB,
 };
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::DirBuilder,
     path::{Path, PathBuf},
-    sync::Mutex,
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+// how often a transaction waiting for another one checks for cancellation
+const TRANSACTION_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+struct XidWait {
+    ended: Mutex<bool>,
+    ended_cond: Condvar,
+}
+
+#[derive(Default)]
 struct SnapshotData {
     active_xids: HashSet<XID>,
     latest_completed_xid: XID,
 }
 
-impl Default for SnapshotData {
-    fn default() -> Self {
-        Self {
-            active_xids: HashSet::new(),
-            latest_completed_xid: XID::default(),
-        }
-    }
-}
-
 pub struct TransactionManager {
     next_xid: Mutex<XID>,
     txn_table: Mutex<TransactionTable>,
     snapshot_data: Mutex<SnapshotData>,
+    // waits for the end of running transactions, signaled and removed when they end
+    xid_waits: Mutex<HashMap<XID, Arc<XidWait>>>,
+    // held by committing transactions from writing the commit log until the status is updated
+    commit_lock: RwLock<()>,
+    num_active_transactions: AtomicUsize,
+    max_active_transactions: usize,
+    commits: Counter,
+    aborts: Counter,
+    // files of the relations created by aborted transactions and the files replaced by
+    // truncations that are not removed yet
+    unused_files: Mutex<Vec<RelFileRef>>,
+    // files of the relations, restored when a truncating transaction aborts
+    relation_files: Arc<RelationFileMap>,
+    commit_lsn_table: Mutex<CommitLsnTable>,
+    subtrans_table: Mutex<SubtransTable>,
+    lock_manager: LockManager,
+    // temporary files are removed when their transactions end
+    temp_files: Arc<TempFileManager>,
+    txn_table_flush_interval: Option<Duration>,
+    // None if the transaction table is flushed by the next commit or abort
+    last_txn_table_flush: Mutex<Option<Instant>>,
+    #[cfg(test)]
+    txn_table_lookups: AtomicUsize,
 }
 
 impl TransactionManager {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_active_transactions: usize,
+        txn_table_flush_interval: Option<Duration>,
+        temp_files: Arc<TempFileManager>,
+        relation_files: Arc<RelationFileMap>,
+    ) -> Result<Self> {
         if !path.as_ref().exists() {
             DirBuilder::new().recursive(true).create(&path)?;
         } else if !path.as_ref().is_dir() {
@@ -46,7 +82,9 @@ impl TransactionManager {
             )));
         }
 
-        let txn_table = TransactionTable::open(Self::get_txn_table_path(path))?;
+        let txn_table = TransactionTable::open(Self::get_txn_table_path(&path))?;
+        let commit_lsn_table = CommitLsnTable::open(Self::get_commit_lsn_table_path(&path))?;
+        let subtrans_table = SubtransTable::open(Self::get_subtrans_table_path(&path))?;
 
         let snapshot_data = Default::default();
 
@@ -54,6 +92,22 @@ impl TransactionManager {
             next_xid: Mutex::new(XID::default().inc()),
             txn_table: Mutex::new(txn_table),
             snapshot_data: Mutex::new(snapshot_data),
+            xid_waits: Mutex::new(HashMap::new()),
+            commit_lock: RwLock::new(()),
+            num_active_transactions: AtomicUsize::new(0),
+            max_active_transactions,
+            commits: Default::default(),
+            aborts: Default::default(),
+            unused_files: Mutex::new(Vec::new()),
+            relation_files,
+            commit_lsn_table: Mutex::new(commit_lsn_table),
+            subtrans_table: Mutex::new(subtrans_table),
+            lock_manager: Default::default(),
+            temp_files,
+            txn_table_flush_interval,
+            last_txn_table_flush: Mutex::new(Some(Instant::now())),
+            #[cfg(test)]
+            txn_table_lookups: AtomicUsize::new(0),
         };
 
         Ok(txnmgr)
@@ -63,52 +117,295 @@ impl TransactionManager {
         let guard = self.next_xid.lock().unwrap();
 
         {
-            let mut snapshot_guard = self.snapshot_data.lock().unwrap();
+            let mut snapshot_guard = self.lock_snapshot_data();
             snapshot_guard.latest_completed_xid = (*guard).dec();
         }
 
-        let mut table_guard = self.txn_table.lock().unwrap();
+        let mut table_guard = self.lock_txn_table();
         table_guard.init_state(*guard);
     }
 
     pub fn start_transaction(
-        &self,
+        self: &Arc<Self>,
         db: &DB,
         isolation_level: IsolationLevel,
     ) -> Result<Transaction> {
-        let xid = self.get_next_xid(db)?;
+        let xid = self.assign_xid(db)?;
+
+        let mut txn = Transaction::new(xid, isolation_level);
+        txn.set_transaction_manager(self);
+        Ok(txn)
+    }
+
+    /// Start a subtransaction of `parent` with its own XID. The writes of the subtransaction are
+    /// undone if it aborts, or end with the parent if it is committed into the parent.
+    pub fn start_subtransaction(
+        self: &Arc<Self>,
+        db: &DB,
+        parent: &Transaction,
+    ) -> Result<Transaction> {
+        let xid = self.assign_xid(db)?;
+
+        let mut txn = Transaction::new(xid, parent.isolation_level);
+        txn.command_id = parent.command_id;
+        txn.statement_timeout = parent.statement_timeout;
+        txn.cancel_token = parent.cancel_token.clone();
+        txn.shutdown_token = parent.shutdown_token.clone();
+        txn.ancestor_xids = parent.ancestor_xids.clone();
+        txn.ancestor_xids.push(parent.xid);
+        txn.set_transaction_manager(self);
+        Ok(txn)
+    }
+
+    /// Commit a subtransaction into its parent. The subtransaction stays active until the
+    /// top-level transaction ends and is only committed if the top-level transaction commits.
+    pub fn commit_subtransaction(
+        &self,
+        parent: &mut Transaction,
+        mut txn: Transaction,
+    ) -> Result<()> {
+        if txn.parent_xid() != Some(parent.xid) {
+            return Err(Error::InvalidArgument(format!(
+                "transaction {} is not a subtransaction of {}",
+                txn.xid, parent.xid
+            )));
+        }
+
+        // the parent is recorded first so that a subcommitted transaction always has one
+        self.subtrans_table
+            .lock()
+            .unwrap()
+            .set_parent(txn.xid, parent.xid)?;
+        {
+            let mut guard = self.lock_txn_table();
+            guard.set_transaction_status(txn.xid, TransactionStatus::SubCommitted)?;
+        }
+
+        parent.committed_subxids.push(txn.xid);
+        parent.committed_subxids.append(&mut txn.committed_subxids);
+        parent.pending_relations.append(&mut txn.pending_relations);
+        parent
+            .pending_truncations
+            .append(&mut txn.pending_truncations);
+        for file_ref in txn.take_pending_drops() {
+            parent.add_pending_drop(file_ref);
+        }
+        parent.command_id = std::cmp::max(parent.command_id, txn.command_id);
+
+        txn.set_finished();
+        Ok(())
+    }
+
+    /// Reserve a slot for a new transaction and allocate its XID.
+    fn assign_xid(&self, db: &DB) -> Result<XID> {
+        // reserve a slot before allocating the XID
+        if self
+            .num_active_transactions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < self.max_active_transactions {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_err()
+        {
+            return Err(Error::InvalidState(
+                "too many active transactions".to_owned(),
+            ));
+        }
+
+        let xid = match self.get_next_xid(db) {
+            Ok(xid) => xid,
+            Err(e) => {
+                self.num_active_transactions.fetch_sub(1, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
 
         {
-            let mut guard = self.snapshot_data.lock().unwrap();
+            let mut guard = self.lock_snapshot_data();
             guard.active_xids.insert(xid);
         }
 
-        Ok(Transaction::new(xid, isolation_level))
+        Ok(xid)
     }
 
-    pub fn commit_transaction(&self, db: &DB, txn: Transaction) -> Result<()> {
+    /// Commit a transaction and switch the relations truncated by it to their new files in the
+    /// catalog. If anything fails after the commit log is flushed, the error is returned but the
+    /// transaction stays committed and the rest is redone by recovery.
+    pub fn commit_transaction(&self, db: &DB, mut txn: Transaction) -> Result<()> {
+        if txn.parent_xid().is_some() {
+            return Err(Error::InvalidArgument(
+                "subtransactions are committed into their parents".to_owned(),
+            ));
+        }
+
         let xid = txn.xid();
+        let subxids = std::mem::take(&mut txn.committed_subxids);
         let wal = db.get_wal();
         let commit_time = SystemTime::now();
+        let truncated_relations = txn.take_pending_truncations();
 
-        // write txn commit log
-        let txn_commit_log = TransactionLogRecord::create_transaction_commit_log(commit_time);
-        let (_, lsn) = wal.append(xid, txn_commit_log)?;
+        let result = {
+            // a checkpoint must not start between the commit log and the status update, or the
+            // commit would be neither in the checkpointed transaction table nor replayed. the
+            // same goes for the new files of the truncated relations
+            let _commit_guard = self.commit_lock.read().unwrap();
 
-        // flush the log
-        wal.flush(Some(lsn))?;
+            // write txn commit log
+            let txn_commit_log = TransactionLogRecord::create_transaction_commit_log(
+                commit_time,
+                truncated_relations.clone(),
+                subxids.clone(),
+            );
+            let (_, lsn) = wal.append(xid, txn_commit_log)?;
+
+            // flush the log
+            wal.flush(Some(lsn))?;
+
+            // the transaction is committed once the log is flushed, so it must not be aborted
+            // when it is dropped even if the rest fails
+            txn.set_finished();
+
+            (|| {
+                // update status
+                {
+                    let mut guard = self.lock_txn_table();
+                    guard.set_transaction_status(xid, TransactionStatus::Committed)?;
+                }
+                {
+                    let mut guard = self.commit_lsn_table.lock().unwrap();
+                    guard.set_commit_lsn(xid, lsn)?;
+                    for subxid in subxids.iter().copied() {
+                        guard.set_commit_lsn(subxid, lsn)?;
+                    }
+                }
+
+                truncated_relations
+                    .iter()
+                    .try_for_each(|truncation| db.finish_truncation(truncation))
+            })()
+        };
+
+        for subxid in subxids {
+            self.mark_transaction_end(subxid);
+        }
+        self.mark_transaction_end(xid);
+        self.commits.inc();
+
+        self.flush_txn_table_on_interval();
+        result
+    }
+
+    /// Abort a transaction that is dropped before it commits. No log is needed because a
+    /// transaction without a commit log is never considered committed by recovery. The
+    /// subtransactions committed into it are aborted with it through their parents. The relations
+    /// truncated by the transaction go back to their old files.
+    pub(super) fn abort_transaction(
+        &self,
+        xid: XID,
+        subxids: &[XID],
+        truncations: &[TruncatedRelation],
+    ) -> Result<()> {
+        // the relations are still locked by the transaction
+        for truncation in truncations.iter().rev() {
+            self.relation_files
+                .set(truncation.file_ref, truncation.old_file_id);
+        }
+        self.add_unused_files(truncations.iter().map(|truncation| RelFileRef {
+            db: truncation.file_ref.db,
+            rel_id: truncation.new_file_id,
+        }));
 
-        // update status
         {
-            let mut guard = self.txn_table.lock().unwrap();
-            guard.set_transaction_status(xid, TransactionStatus::Committed)?;
+            // this may run while a panic unwinds
+            let mut guard = self
+                .txn_table
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            guard.set_transaction_status(xid, TransactionStatus::Aborted)?;
         }
 
+        for subxid in subxids.iter().copied() {
+            self.mark_transaction_end(subxid);
+        }
         self.mark_transaction_end(xid);
-
+        self.aborts.inc();
+        self.flush_txn_table_on_interval();
         Ok(())
     }
 
+    /// Write the dirty transaction status pages if the flush interval has passed since the last
+    /// write. The statuses are written after their commit logs are flushed. The transaction has
+    /// already ended, so an error is only logged and the pages are written by the next flush or
+    /// checkpoint.
+    fn flush_txn_table_on_interval(&self) {
+        let interval = match self.txn_table_flush_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        {
+            let mut guard = self
+                .last_txn_table_flush
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if matches!(*guard, Some(last_flush) if last_flush.elapsed() < interval) {
+                return;
+            }
+            *guard = Some(Instant::now());
+        }
+
+        // this may run while a panic unwinds
+        let result = self
+            .subtrans_table
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .checkpoint()
+            .and_then(|_| {
+                self.txn_table
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .checkpoint()
+            });
+        if let Err(e) = result {
+            log::warn!("cannot flush the transaction table: {}", e);
+        }
+    }
+
+    /// Make the next commit or abort flush the transaction table as if the flush interval has
+    /// passed.
+    #[cfg(test)]
+    fn expire_txn_table_flush_interval(&self) {
+        *self.last_txn_table_flush.lock().unwrap() = None;
+    }
+
+    pub(crate) fn add_unused_files<I>(&self, relations: I)
+    where
+        I: IntoIterator<Item = RelFileRef>,
+    {
+        let mut guard = self
+            .unused_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        guard.extend(relations);
+    }
+
+    /// Take the relation files that are no longer used to remove them.
+    pub(crate) fn take_unused_files(&self) -> Vec<RelFileRef> {
+        let mut guard = self
+            .unused_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        std::mem::take(&mut *guard)
+    }
+
+    /// Return the number of transactions committed and aborted since startup.
+    pub fn get_commits_and_aborts(&self) -> (u64, u64) {
+        (self.commits.get(), self.aborts.get())
+    }
+
     pub fn get_snapshot<'a>(&self, txn: &'a mut Transaction) -> Result<&'a Snapshot> {
         let snapshot = txn.current_snapshot.take();
         match snapshot {
@@ -120,6 +417,9 @@ impl TransactionManager {
             Some(snapshot) => {
                 if txn.uses_transaction_snapshot() {
                     // for repeatable read, always use the first snapshot
+                    let mut snapshot = snapshot;
+                    snapshot.command_id = txn.command_id();
+                    snapshot.current_xids = txn.current_xids();
                     txn.current_snapshot = Some(snapshot);
                 } else {
                     let snapshot = self.record_snapshot(txn)?;
@@ -135,11 +435,12 @@ impl TransactionManager {
     }
 
     fn record_snapshot(&self, txn: &Transaction) -> Result<Snapshot> {
-        let guard = self.snapshot_data.lock().unwrap();
+        let guard = self.lock_snapshot_data();
 
         let max_xid = guard.latest_completed_xid.inc();
         let mut min_xid = max_xid;
         let mut xips = HashSet::new();
+        let current_xids = txn.current_xids();
 
         for xid in guard.active_xids.iter().copied() {
             if xid.is_invalid() {
@@ -154,7 +455,7 @@ impl TransactionManager {
                 min_xid = xid;
             }
 
-            if xid == txn.xid() {
+            if current_xids.contains(&xid) {
                 continue;
             }
 
@@ -165,16 +466,124 @@ impl TransactionManager {
             min_xid,
             max_xid,
             xips,
+            command_id: txn.command_id(),
+            current_xids,
+            dirty: false,
+            isolation_level: txn.isolation_level,
         };
         Ok(snapshot)
     }
 
+    /// Get a dirty snapshot for the transaction, used by uniqueness checks to see the tuples of
+    /// in-progress transactions. The status of the other transactions is checked when the
+    /// tuples are read instead of being recorded in the snapshot.
+    pub fn get_dirty_snapshot(&self, txn: &Transaction) -> Snapshot {
+        let next_xid = self.read_next_id();
+
+        Snapshot {
+            // pages vacuumed before the snapshot are fine because only the latest versions of
+            // the tuples are read
+            min_xid: next_xid,
+            max_xid: next_xid,
+            xips: HashSet::new(),
+            command_id: txn.command_id(),
+            current_xids: txn.current_xids(),
+            dirty: true,
+            isolation_level: txn.isolation_level,
+        }
+    }
+
+    /// Get the oldest XID that may still be running. Tuples deleted by committed transactions
+    /// older than this are not visible to anyone.
+    pub fn get_oldest_active_xid(&self) -> XID {
+        let guard = self.lock_snapshot_data();
+
+        let mut oldest_xid = guard.latest_completed_xid.inc();
+        for xid in guard.active_xids.iter().copied() {
+            if xid < oldest_xid {
+                oldest_xid = xid;
+            }
+        }
+
+        oldest_xid
+    }
+
+    /// Get the XIDs of the running transactions.
+    pub fn get_active_xids(&self) -> Vec<XID> {
+        let guard = self.lock_snapshot_data();
+        guard.active_xids.iter().copied().collect()
+    }
+
+    pub fn is_transaction_active(&self, xid: XID) -> bool {
+        let guard = self.lock_snapshot_data();
+        guard.active_xids.contains(&xid)
+    }
+
+    /// Wait until the transaction `xid` commits or aborts and return its final status.
+    pub fn wait_for_xid(&self, xid: XID) -> Result<TransactionStatus> {
+        self.wait_for_xid_with(xid, None)
+    }
+
+    /// Wait for the transaction `xid` on behalf of `txn`. The wait can be canceled like a scan
+    /// of `txn`, and fails with `Error::Deadlock` if `xid` waits for `txn`.
+    pub fn wait_for_xid_in_transaction(
+        &self,
+        txn: &Transaction,
+        xid: XID,
+    ) -> Result<TransactionStatus> {
+        let current_xids = txn.current_xids();
+        self.lock_manager
+            .begin_wait(&current_xids, std::iter::once(xid).collect())?;
+
+        let result = self.wait_for_xid_with(xid, Some(&mut txn.interrupt_check()));
+        self.lock_manager.end_wait(&current_xids);
+        result
+    }
+
+    fn wait_for_xid_with(
+        &self,
+        xid: XID,
+        mut interrupts: Option<&mut InterruptCheck>,
+    ) -> Result<TransactionStatus> {
+        let wait = {
+            // the wait is registered while the transaction is known to be active, so the end of
+            // the transaction cannot be missed
+            let guard = self.lock_snapshot_data();
+            if !guard.active_xids.contains(&xid) {
+                None
+            } else {
+                let mut waits = self.xid_waits.lock().unwrap();
+                Some(waits.entry(xid).or_default().clone())
+            }
+        };
+
+        if let Some(wait) = wait {
+            let mut ended = wait.ended.lock().unwrap();
+
+            while !*ended {
+                ended = match interrupts.as_mut() {
+                    Some(interrupts) => {
+                        interrupts.check()?;
+                        wait.ended_cond
+                            .wait_timeout(ended, TRANSACTION_WAIT_INTERVAL)
+                            .unwrap()
+                            .0
+                    }
+                    None => wait.ended_cond.wait(ended).unwrap(),
+                };
+            }
+        }
+
+        // the status is final once the transaction is not active
+        self.get_transaction_status(xid)
+    }
+
     fn get_next_xid(&self, db: &DB) -> Result<XID> {
         let mut guard = self.next_xid.lock().unwrap();
         let xid = *guard;
 
         {
-            let mut table_guard = self.txn_table.lock().unwrap();
+            let mut table_guard = self.lock_txn_table();
             table_guard.extend(db, xid)?;
         }
 
@@ -182,6 +591,22 @@ impl TransactionManager {
         Ok(xid)
     }
 
+    /// Get the XID before which deleted tuples can be removed. With `old_snapshot_threshold`, the
+    /// transactions more than that many XIDs behind no longer hold back the horizon, and their
+    /// snapshots may become too old to read the vacuumed pages.
+    pub fn get_vacuum_horizon(&self, old_snapshot_threshold: Option<u32>) -> XID {
+        let oldest_xid = self.get_oldest_active_xid();
+
+        match old_snapshot_threshold {
+            Some(threshold) => {
+                let next_xid = u64::from(self.read_next_id()) as u32;
+                let limit = XID::from(next_xid.saturating_sub(threshold).max(1));
+                std::cmp::max(oldest_xid, limit)
+            }
+            None => oldest_xid,
+        }
+    }
+
     pub fn read_next_id(&self) -> XID {
         let guard = self.next_xid.lock().unwrap();
         *guard
@@ -200,15 +625,133 @@ impl TransactionManager {
         }
     }
 
+    /// Get the status of a transaction. A committed subtransaction is `SubCommitted` while its
+    /// top-level transaction runs and then takes the status of the top-level transaction.
     pub fn get_transaction_status(&self, xid: XID) -> Result<TransactionStatus> {
-        let mut guard = self.txn_table.lock().unwrap();
+        let status = self
+            .lock_txn_table_for_lookup()
+            .get_transaction_status(xid)?;
+        if status != TransactionStatus::SubCommitted {
+            return Ok(status);
+        }
+
+        let mut xid = xid;
+        loop {
+            xid = match self.subtrans_table.lock().unwrap().get_parent(xid)? {
+                Some(parent) => parent,
+                // the parent is lost in a crash, so the top-level transaction never committed
+                None => return Ok(TransactionStatus::Aborted),
+            };
+
+            match self
+                .lock_txn_table_for_lookup()
+                .get_transaction_status(xid)?
+            {
+                TransactionStatus::SubCommitted => {}
+                TransactionStatus::InProgress => return Ok(TransactionStatus::SubCommitted),
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Get the statuses of a batch of transactions with one lock on the transaction table.
+    /// Committed subtransactions are resolved as in `get_transaction_status`.
+    pub fn get_transaction_statuses(&self, xids: &[XID]) -> Result<Vec<TransactionStatus>> {
+        let mut statuses = self
+            .lock_txn_table_for_lookup()
+            .get_transaction_statuses(xids)?;
+
+        for (xid, status) in xids.iter().zip(statuses.iter_mut()) {
+            if *status == TransactionStatus::SubCommitted {
+                *status = self.get_transaction_status(*xid)?;
+            }
+        }
 
-        guard.get_transaction_status(xid)
+        Ok(statuses)
+    }
+
+    fn lock_txn_table_for_lookup(&self) -> MutexGuard<'_, TransactionTable> {
+        #[cfg(test)]
+        self.txn_table_lookups.fetch_add(1, Ordering::Relaxed);
+
+        self.lock_txn_table()
+    }
+
+    // the transaction table and the snapshot data are changed in single steps, so they are
+    // still consistent if a thread panics while holding their locks
+    fn lock_txn_table(&self) -> MutexGuard<'_, TransactionTable> {
+        self.txn_table
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_snapshot_data(&self) -> MutexGuard<'_, SnapshotData> {
+        self.snapshot_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Return the number of times the transaction table is locked to look up statuses.
+    #[cfg(test)]
+    pub(crate) fn txn_table_lookup_count(&self) -> usize {
+        self.txn_table_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Run `f` while no transaction is in the middle of committing.
+    pub fn with_commits_blocked<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let _guard = self.commit_lock.write().unwrap();
+        f()
+    }
+
+    /// Get the LSN of the commit log of a transaction, None if the transaction is not committed
+    /// or was before the vacuum horizon at a checkpoint.
+    pub fn get_commit_lsn(&self, xid: XID) -> Result<Option<LogPointer>> {
+        let mut guard = self.commit_lsn_table.lock().unwrap();
+        guard.get_commit_lsn(xid)
+    }
+
+    /// Lock a relation until the transaction ends. The wait for conflicting locks can be
+    /// canceled like a scan of the transaction.
+    pub fn lock_relation(
+        &self,
+        txn: &Transaction,
+        file_ref: RelFileRef,
+        mode: LockMode,
+    ) -> Result<()> {
+        self.lock_manager.lock(
+            txn.xid(),
+            &txn.current_xids(),
+            file_ref,
+            mode,
+            &mut txn.interrupt_check(),
+        )
+    }
+
+    pub fn lock_relation_extension(
+        &self,
+        file_ref: RelFileRef,
+        fork: ForkType,
+    ) -> ExtensionLockGuard<'_> {
+        self.lock_manager.lock_extension(file_ref, fork)
     }
 
     pub fn checkpoint(&self) -> Result<()> {
-        let mut guard = self.txn_table.lock().unwrap();
-        guard.checkpoint()
+        // parents are written before the statuses that refer to them
+        self.subtrans_table.lock().unwrap().checkpoint()?;
+
+        {
+            let mut guard = self.lock_txn_table();
+            guard.checkpoint()?;
+        }
+
+        // commit LSNs are not looked up for transactions before the horizon
+        let horizon = self.get_vacuum_horizon(None);
+        let mut guard = self.commit_lsn_table.lock().unwrap();
+        guard.checkpoint()?;
+        guard.truncate(horizon)
     }
 
     pub fn redo_txn_log(
@@ -220,10 +763,20 @@ impl TransactionManager {
     ) -> Result<()> {
         match redo {
             TransactionLogRecord::ZeroPage(zero_page_log) => {
-                let mut guard = self.txn_table.lock().unwrap();
+                let mut guard = self.lock_txn_table();
                 guard.redo_zero_page(zero_page_log.page_num)
             }
             TransactionLogRecord::Commit(commit_log) => {
+                for truncation in commit_log.truncated_relations.iter() {
+                    db.finish_truncation(truncation)?;
+                }
+
+                // the subtransactions commit with the top-level transaction so their parents
+                // are no longer needed
+                for subxid in commit_log.committed_subxids {
+                    self.redo_commit(db, subxid, lsn, commit_log.commit_time)?;
+                }
+
                 self.redo_commit(db, xid, lsn, commit_log.commit_time)
             }
         }
@@ -238,9 +791,13 @@ impl TransactionManager {
     ) -> Result<()> {
         // update status
         {
-            let mut guard = self.txn_table.lock().unwrap();
+            let mut guard = self.lock_txn_table();
             guard.set_transaction_status(xid, TransactionStatus::Committed)?;
         }
+        self.commit_lsn_table
+            .lock()
+            .unwrap()
+            .set_commit_lsn(xid, lsn)?;
 
         db.get_wal().flush(Some(lsn))?;
         Ok(())
@@ -252,13 +809,397 @@ impl TransactionManager {
         dir
     }
 
+    fn get_commit_lsn_table_path<P: AsRef<Path>>(path: P) -> PathBuf {
+        let mut dir = path.as_ref().to_path_buf();
+        dir.push("commit_lsn");
+        dir
+    }
+
+    fn get_subtrans_table_path<P: AsRef<Path>>(path: P) -> PathBuf {
+        let mut dir = path.as_ref().to_path_buf();
+        dir.push("subtrans");
+        dir
+    }
+
     fn mark_transaction_end(&self, xid: XID) {
-        let mut guard = self.snapshot_data.lock().unwrap();
+        self.temp_files.remove_transaction_files(xid);
+
+        let mut guard = self
+            .snapshot_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
 
         guard.active_xids.remove(&xid); // XXX: sanity check
+        self.lock_manager.release_all(xid);
 
         if guard.latest_completed_xid < xid {
             guard.latest_completed_xid = xid;
         }
+
+        let wait = self
+            .xid_waits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&xid);
+        if let Some(wait) = wait {
+            *wait.ended.lock().unwrap_or_else(PoisonError::into_inner) = true;
+            wait.ended_cond.notify_all();
+        }
+
+        self.num_active_transactions.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn num_active_transactions(&self) -> usize {
+        self.num_active_transactions.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        concurrency::{IsolationLevel, TransactionStatus},
+        test_util::get_temp_db,
+        DBConfig, Error, DB,
+    };
+
+    #[test]
+    fn can_recover_from_poisoned_transaction_locks() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let txnmgr = db.get_transaction_manager();
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _txn_table = txnmgr.txn_table.lock().unwrap();
+            let _snapshot_data = txnmgr.snapshot_data.lock().unwrap();
+            panic!("panic while holding the locks")
+        }));
+        assert!(result.is_err());
+        assert!(txnmgr.txn_table.is_poisoned());
+        assert!(txnmgr.snapshot_data.is_poisoned());
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let xid = txn.xid();
+        heap.insert_tuple(&db, &txn, &[1u8; 10]).unwrap();
+        db.commit_transaction(txn).unwrap();
+        assert_eq!(
+            txnmgr.get_transaction_status(xid).unwrap(),
+            TransactionStatus::Committed
+        );
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_wait_for_xid() {
+        use std::{thread, time::Duration};
+
+        let (db, db_dir) = get_temp_db();
+        let txnmgr = db.get_transaction_manager();
+
+        for commit in [true, false] {
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let xid = txn.xid();
+
+            let status = thread::scope(|s| {
+                let waiter = s.spawn(|| txnmgr.wait_for_xid(xid).unwrap());
+
+                thread::sleep(Duration::from_millis(30));
+                assert!(!waiter.is_finished());
+
+                if commit {
+                    db.commit_transaction(txn).unwrap();
+                } else {
+                    db.abort_transaction(txn).unwrap();
+                }
+                waiter.join().unwrap()
+            });
+
+            let expected = if commit {
+                TransactionStatus::Committed
+            } else {
+                TransactionStatus::Aborted
+            };
+            assert_eq!(status, expected);
+            // the transaction has ended, so there is nothing to wait for
+            assert_eq!(txnmgr.wait_for_xid(xid).unwrap(), expected);
+        }
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_flush_txn_table_on_interval() {
+        use crate::concurrency::TransactionTable;
+        use std::time::Duration;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let txn_table_path =
+            super::TransactionManager::get_txn_table_path(config.get_transaction_path());
+        let read_status = |xid| {
+            let mut table = TransactionTable::open(&txn_table_path).unwrap();
+            table.get_transaction_status(xid).unwrap()
+        };
+
+        let xid = {
+            let db = DB::open(&config).unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let xid = txn.xid();
+            db.commit_transaction(txn).unwrap();
+            xid
+        };
+        // only written by checkpoints
+        assert_eq!(read_status(xid), TransactionStatus::InProgress);
+
+        let db = DB::open(&config.txn_table_flush_interval(Duration::from_secs(3600))).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let xid = txn.xid();
+        let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let xid2 = txn2.xid();
+        db.commit_transaction(txn).unwrap();
+        // not written before the interval passes
+        assert_eq!(read_status(xid), TransactionStatus::InProgress);
+
+        db.get_transaction_manager()
+            .expire_txn_table_flush_interval();
+        db.abort_transaction(txn2).unwrap();
+        assert_eq!(read_status(xid), TransactionStatus::Committed);
+        assert_eq!(read_status(xid2), TransactionStatus::Aborted);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_abort_committed_subtransaction_with_parent() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let data: &[u8] = &[1u8; 100];
+
+        {
+            let db = DB::open(&config).unwrap();
+            let txnmgr = db.get_transaction_manager();
+            let heap = db.create_table(0, 0).unwrap();
+
+            let mut parent = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let sub = db.start_subtransaction(&parent).unwrap();
+            let subxid = sub.xid();
+            assert_eq!(sub.parent_xid(), Some(parent.xid()));
+            heap.insert_tuple(&db, &sub, data).unwrap();
+            db.commit_subtransaction(&mut parent, sub).unwrap();
+            assert_eq!(
+                txnmgr.get_transaction_status(subxid).unwrap(),
+                TransactionStatus::SubCommitted
+            );
+
+            // visible to the parent but not to others
+            parent.advance_command_id().unwrap();
+            assert_eq!(heap.count_visible(&db, &mut parent).unwrap(), 1);
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+            db.commit_transaction(txn).unwrap();
+
+            db.abort_transaction(parent).unwrap();
+            assert_eq!(
+                txnmgr.get_transaction_status(subxid).unwrap(),
+                TransactionStatus::Aborted
+            );
+            assert!(!txnmgr.is_transaction_active(subxid));
+            assert_eq!(txnmgr.num_active_transactions(), 0);
+
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+            db.commit_transaction(txn).unwrap();
+
+            // nested subtransactions commit with the top-level transaction
+            let mut parent = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let mut sub = db.start_subtransaction(&parent).unwrap();
+            let nested = db.start_subtransaction(&sub).unwrap();
+            heap.insert_tuple(&db, &nested, data).unwrap();
+            assert!(db.commit_subtransaction(&mut parent, nested).is_err());
+
+            let nested = db.start_subtransaction(&sub).unwrap();
+            let nested_xid = nested.xid();
+            heap.insert_tuple(&db, &nested, data).unwrap();
+            db.commit_subtransaction(&mut sub, nested).unwrap();
+            db.commit_subtransaction(&mut parent, sub).unwrap();
+            db.commit_transaction(parent).unwrap();
+            assert_eq!(
+                txnmgr.get_transaction_status(nested_xid).unwrap(),
+                TransactionStatus::Committed
+            );
+
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+            db.commit_transaction(txn).unwrap();
+        }
+
+        // the commit of the subtransactions is replayed with the top-level transaction
+        let db = DB::open(&config).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_limit_active_transactions() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .max_active_transactions(3);
+        let db = DB::open(&config).unwrap();
+
+        let mut txns = (0..3)
+            .map(|_| db.start_transaction(IsolationLevel::ReadCommitted).unwrap())
+            .collect::<Vec<_>>();
+
+        match db.start_transaction(IsolationLevel::ReadCommitted) {
+            Err(Error::InvalidState(_)) => {}
+            _ => panic!("transaction limit is not enforced"),
+        }
+
+        db.commit_transaction(txns.pop().unwrap()).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        txns.push(txn);
+        assert_eq!(db.get_transaction_manager().num_active_transactions(), 3);
+
+        for txn in txns {
+            db.commit_transaction(txn).unwrap();
+        }
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_start_transaction_with_isolation_level() {
+        let (db, db_dir) = get_temp_db();
+
+        let txn = db
+            .start_transaction(IsolationLevel::RepeatableRead)
+            .unwrap();
+        assert!(txn.uses_transaction_snapshot());
+        assert!(!txn.is_serializable());
+        db.commit_transaction(txn).unwrap();
+
+        let txn = db.start_default_transaction().unwrap();
+        assert!(!txn.uses_transaction_snapshot());
+        db.commit_transaction(txn).unwrap();
+        drop(db);
+
+        let config = DBConfig::new()
+            .root_path(db_dir.path())
+            .default_isolation_level(IsolationLevel::RepeatableRead);
+        let db = DB::open(&config).unwrap();
+        let txn = db.start_default_transaction().unwrap();
+        assert!(txn.uses_transaction_snapshot());
+        db.commit_transaction(txn).unwrap();
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_reuse_snapshot_in_serializable_transaction() {
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let data: &[u8] = &[1u8; 100];
+
+        let mut txn = db.start_transaction(IsolationLevel::Serializable).unwrap();
+        assert!(txn.is_serializable());
+        assert!(txn.uses_transaction_snapshot());
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+
+        let writer = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        heap.insert_tuple(&db, &writer, data).unwrap();
+        db.commit_transaction(writer).unwrap();
+
+        // the next statement still sees the first snapshot
+        txn.advance_command_id().unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 0);
+        db.commit_transaction(txn).unwrap();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 1);
+        db.commit_transaction(txn).unwrap();
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_abort_dropped_transaction() {
+        let (db, db_dir) = get_temp_db();
+        let txnmgr = db.get_transaction_manager();
+
+        let committed = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let committed_xid = committed.xid();
+        db.commit_transaction(committed).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let xid = txn.xid();
+        assert!(txnmgr.is_transaction_active(xid));
+        assert_eq!(txnmgr.num_active_transactions(), 1);
+
+        drop(txn);
+        assert!(!txnmgr.is_transaction_active(xid));
+        assert_eq!(txnmgr.num_active_transactions(), 0);
+        assert_eq!(
+            txnmgr.get_transaction_status(xid).unwrap(),
+            TransactionStatus::Aborted
+        );
+        assert_eq!(
+            txnmgr.get_transaction_status(committed_xid).unwrap(),
+            TransactionStatus::Committed
+        );
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_get_commit_lsn() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let mut commits = Vec::new();
+
+        let aborted_xid = {
+            let db = DB::open(&config).unwrap();
+
+            for i in 0..10 {
+                let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                let xid = txn.xid();
+                db.commit_transaction(txn).unwrap();
+                commits.push((
+                    xid,
+                    db.get_transaction_manager().get_commit_lsn(xid).unwrap(),
+                ));
+
+                if i == 4 {
+                    db.create_checkpoint().unwrap();
+                }
+            }
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            txn.xid()
+        };
+
+        assert!(commits.iter().all(|(_, lsn)| lsn.is_some()));
+        assert!(commits.windows(2).all(|w| w[0].1 < w[1].1));
+
+        // commits after the checkpoint are restored by recovery
+        let db = DB::open(&config).unwrap();
+        let txnmgr = db.get_transaction_manager();
+        for (xid, lsn) in &commits {
+            assert_eq!(txnmgr.get_commit_lsn(*xid).unwrap(), *lsn);
+        }
+        assert_eq!(txnmgr.get_commit_lsn(aborted_xid).unwrap(), None);
+
+        drop(db);
+        assert!(db_dir.close().is_ok());
     }
 }
@@ -35,7 +35,8 @@ pub enum TransactionStatus {
     InProgress = 0,
     Committed = 1,
     Aborted = 2,
-    Error = 3,
+    /// Committed into the parent transaction, which decides the final status
+    SubCommitted = 3,
 }
 
 impl From<u8> for TransactionStatus {
@@ -44,7 +45,7 @@ impl From<u8> for TransactionStatus {
             0 => Self::InProgress,
             1 => Self::Committed,
             2 => Self::Aborted,
-            _ => Self::Error,
+            _ => Self::SubCommitted,
         }
     }
 }
@@ -99,7 +100,7 @@ impl TransactionTable {
 
         let metadata = file.metadata()?;
 
-        if metadata.len() as usize % TRANSACTION_PAGE_SIZE != 0 {
+        if !(metadata.len() as usize).is_multiple_of(TRANSACTION_PAGE_SIZE) {
             return Err(Error::DataCorrupted(
                 "the last page of the transaction table is torn".to_owned(),
             ));
@@ -242,6 +243,42 @@ impl TransactionTable {
         Ok(status)
     }
 
+    /// Get the statuses of the transactions, fetching each page of the table once.
+    pub fn get_transaction_statuses(&mut self, xids: &[XID]) -> Result<Vec<TransactionStatus>> {
+        let mut order = (0..xids.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| transaction_to_page_num(xids[*i]));
+
+        let mut statuses = vec![TransactionStatus::InProgress; xids.len()];
+        let mut page: Option<TransactionPage> = None;
+
+        for i in order {
+            let page_num = transaction_to_page_num(xids[i]);
+            let cur_page = match page.take() {
+                Some(cur_page) if cur_page.page_num == page_num => cur_page,
+                old_page => {
+                    if let Some(old_page) = old_page {
+                        self.put_page(old_page);
+                    }
+                    self.fetch_page(page_num)?
+                }
+            };
+
+            let index = transaction_to_page_index(xids[i]);
+            let bytepos = index / TXNS_PER_BYTE;
+            let byteoff = index % TXNS_PER_BYTE;
+            statuses[i] = TransactionStatus::from(
+                (cur_page.buffer[bytepos] >> (byteoff * BITS_PER_TXN)) & 3u8,
+            );
+            page = Some(cur_page);
+        }
+
+        if let Some(page) = page {
+            self.put_page(page);
+        }
+
+        Ok(statuses)
+    }
+
     pub fn set_transaction_status(&mut self, xid: XID, status: TransactionStatus) -> Result<()> {
         let page_num = transaction_to_page_num(xid);
         let mut page = self.fetch_page(page_num)?;
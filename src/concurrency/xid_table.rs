@@ -0,0 +1,246 @@
+use crate::{concurrency::XID, Error, Result};
+
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{prelude::*, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use lru::LruCache;
+
+pub(super) const XID_TABLE_PAGE_SIZE: usize = 4096;
+pub(super) const TABLE_CACHE_CAPACITY: usize = 128;
+const PAGES_PER_SEGMENT: usize = 32;
+
+struct XidTablePage {
+    buffer: [u8; XID_TABLE_PAGE_SIZE],
+    dirty: bool,
+}
+
+/// A table of fixed-size entries keyed by XID
+///
+/// The pages are stored in segment files under a directory and cached in an LRU. They are only
+/// written on checkpoints and when evicted. Pages past the end of the segments read as zeros.
+/// Segments that only hold XIDs before a horizon can be removed with `truncate`.
+pub struct XidTable {
+    dir: PathBuf,
+    name: &'static str,
+    entry_size: usize,
+    lru: LruCache<usize, XidTablePage>,
+}
+
+impl XidTable {
+    pub fn open<P: AsRef<Path>>(path: P, name: &'static str, entry_size: usize) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            DirBuilder::new().recursive(true).create(path)?;
+        } else if !path.is_dir() {
+            return Err(Error::WrongObjectType(format!(
+                "{} exists but is not a directory",
+                name
+            )));
+        }
+
+        Ok(Self {
+            dir: path.to_path_buf(),
+            name,
+            entry_size,
+            lru: LruCache::new(TABLE_CACHE_CAPACITY),
+        })
+    }
+
+    fn entries_per_page(&self) -> usize {
+        XID_TABLE_PAGE_SIZE / self.entry_size
+    }
+
+    fn transaction_to_page_num(&self, xid: XID) -> usize {
+        let xid: u64 = xid.into();
+        xid as usize / self.entries_per_page()
+    }
+
+    fn transaction_to_page_offset(&self, xid: XID) -> usize {
+        let xid: u64 = xid.into();
+        (xid as usize % self.entries_per_page()) * self.entry_size
+    }
+
+    fn get_segment_path(&self, segno: usize) -> PathBuf {
+        self.dir.join(format!("{:04X}", segno))
+    }
+
+    fn read_page(&self, page_num: usize, page: &mut XidTablePage) -> Result<()> {
+        let path = self.get_segment_path(page_num / PAGES_PER_SEGMENT);
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let start = (page_num % PAGES_PER_SEGMENT) * XID_TABLE_PAGE_SIZE;
+        if start < file_len {
+            let len = std::cmp::min(file_len - start, XID_TABLE_PAGE_SIZE);
+            file.seek(SeekFrom::Start(start as u64))?;
+            file.read_exact(&mut page.buffer[..len])?;
+        }
+
+        Ok(())
+    }
+
+    fn write_page(&self, page_num: usize, page: &XidTablePage, sync: bool) -> Result<()> {
+        let error = || {
+            Error::FileAccess(format!(
+                "could not write page {} of the {}",
+                page_num, self.name
+            ))
+        };
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.get_segment_path(page_num / PAGES_PER_SEGMENT))
+            .map_err(|_| error())?;
+        file.seek(SeekFrom::Start(
+            ((page_num % PAGES_PER_SEGMENT) * XID_TABLE_PAGE_SIZE) as u64,
+        ))?;
+        file.write_all(&page.buffer).map_err(|_| error())?;
+
+        if sync {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn fetch_page(&mut self, page_num: usize) -> Result<&mut XidTablePage> {
+        if !self.lru.contains(&page_num) {
+            if self.lru.len() >= TABLE_CACHE_CAPACITY {
+                if let Some((victim, page)) = self.lru.pop_lru() {
+                    if page.dirty {
+                        self.write_page(victim, &page, false)?;
+                    }
+                }
+            }
+
+            let mut page = XidTablePage {
+                buffer: [0u8; XID_TABLE_PAGE_SIZE],
+                dirty: false,
+            };
+            self.read_page(page_num, &mut page)?;
+
+            self.lru.put(page_num, page);
+        }
+
+        Ok(self.lru.get_mut(&page_num).unwrap())
+    }
+
+    /// Get the entry of a transaction.
+    pub fn get_entry(&mut self, xid: XID) -> Result<&[u8]> {
+        let offset = self.transaction_to_page_offset(xid);
+        let entry_size = self.entry_size;
+        let page = self.fetch_page(self.transaction_to_page_num(xid))?;
+        Ok(&page.buffer[offset..offset + entry_size])
+    }
+
+    /// Get the entry of a transaction to update it.
+    pub fn get_entry_mut(&mut self, xid: XID) -> Result<&mut [u8]> {
+        let offset = self.transaction_to_page_offset(xid);
+        let entry_size = self.entry_size;
+        let page = self.fetch_page(self.transaction_to_page_num(xid))?;
+        page.dirty = true;
+        Ok(&mut page.buffer[offset..offset + entry_size])
+    }
+
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let mut dirty_pages = self
+            .lru
+            .iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(page_num, _)| *page_num)
+            .collect::<Vec<_>>();
+        dirty_pages.sort_unstable();
+
+        let mut iter = dirty_pages.iter().peekable();
+        while let Some(page_num) = iter.next() {
+            // sync each segment after its last page is written
+            let sync = iter.peek().is_none_or(|next_page_num| {
+                *next_page_num / PAGES_PER_SEGMENT != page_num / PAGES_PER_SEGMENT
+            });
+            self.write_page(*page_num, self.lru.peek(page_num).unwrap(), sync)?;
+            self.lru.peek_mut(page_num).unwrap().dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the segments that only hold the entries of XIDs before `horizon`. These entries
+    /// read as zeros afterwards.
+    pub fn truncate(&mut self, horizon: XID) -> Result<()> {
+        let cutoff_segno = self.transaction_to_page_num(horizon) / PAGES_PER_SEGMENT;
+
+        let victims = self
+            .lru
+            .iter()
+            .map(|(page_num, _)| *page_num)
+            .filter(|page_num| page_num / PAGES_PER_SEGMENT < cutoff_segno)
+            .collect::<Vec<_>>();
+        for page_num in victims {
+            self.lru.pop(&page_num);
+        }
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let segno = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| usize::from_str_radix(name, 16).ok());
+
+            match segno {
+                Some(segno) if segno < cutoff_segno => fs::remove_file(path)?,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_truncate_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("xid_table");
+        let entries_per_segment = (XID_TABLE_PAGE_SIZE / 4 * PAGES_PER_SEGMENT) as u32;
+
+        let mut table = XidTable::open(&path, "test table", 4).unwrap();
+        for i in 1..entries_per_segment * 3 {
+            table.get_entry_mut(XID::from(i)).unwrap()[0] = 1;
+        }
+        table.checkpoint().unwrap();
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 3);
+
+        // only the segments entirely before the horizon are removed
+        table
+            .truncate(XID::from(entries_per_segment * 2 + 1))
+            .unwrap();
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 1);
+
+        let mut table = XidTable::open(&path, "test table", 4).unwrap();
+        assert_eq!(table.get_entry(XID::from(1)).unwrap()[0], 0);
+        assert_eq!(
+            table
+                .get_entry(XID::from(entries_per_segment * 2 - 1))
+                .unwrap()[0],
+            0
+        );
+        assert_eq!(
+            table.get_entry(XID::from(entries_per_segment * 2)).unwrap()[0],
+            1
+        );
+
+        dir.close().unwrap();
+    }
+}
@@ -1,21 +1,63 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::wal::WalConfig;
+use crate::{
+    am::heap::HeapInsertStrategy,
+    concurrency::{CancelToken, IsolationLevel},
+    storage::RelFileRef,
+    wal::{WalConfig, WalSyncMethod},
+    Error, Result,
+};
 
 const DEFAULT_ROOT_PATH: &str = "suziQ";
 
+#[derive(Clone)]
 pub struct DBConfig {
     pub cache_capacity: usize,
+    pub buffer_alloc_retries: usize,
     pub root_path: PathBuf,
     pub wal_config: WalConfig,
+    pub max_active_transactions: usize,
+    /// Maximum number of relation files kept open at the same time
+    pub max_open_files: usize,
+    pub default_isolation_level: IsolationLevel,
+    /// Number of XIDs after which a snapshot no longer holds back vacuum
+    pub old_snapshot_threshold: Option<u32>,
+    pub heap_insert_strategy: HeapInsertStrategy,
+    /// Write the hint bits set by visibility checks to the pages
+    pub hint_bit_writes: bool,
+    /// Start synchronized scans at a random page when no other scan of the relation is running
+    pub sync_scan_random_start: bool,
+    /// Relations whose WAL records are checked against the pages when they are written
+    pub wal_consistency_check: Vec<RelFileRef>,
+    /// Minimum time between writes of the dirty transaction status pages outside of checkpoints
+    pub txn_table_flush_interval: Option<Duration>,
+    /// Memory in bytes used by a sort before it spills to temporary files
+    pub maintenance_work_mem: usize,
+    /// Token that requests a shutdown, if it should be set before the database is opened
+    pub shutdown_token: Option<CancelToken>,
 }
 
 impl Default for DBConfig {
     fn default() -> Self {
         Self {
             cache_capacity: 4096,
+            buffer_alloc_retries: 10,
             root_path: PathBuf::from(DEFAULT_ROOT_PATH),
             wal_config: WalConfig::new(),
+            max_active_transactions: 1024,
+            max_open_files: 1024,
+            default_isolation_level: IsolationLevel::ReadCommitted,
+            old_snapshot_threshold: None,
+            heap_insert_strategy: HeapInsertStrategy::InsertHint,
+            hint_bit_writes: true,
+            sync_scan_random_start: false,
+            wal_consistency_check: Vec::new(),
+            txn_table_flush_interval: None,
+            maintenance_work_mem: 64 * 1024 * 1024,
+            shutdown_token: None,
         }
     }
 }
@@ -35,11 +77,116 @@ impl DBConfig {
         self
     }
 
+    pub fn buffer_alloc_retries(mut self, buffer_alloc_retries: usize) -> Self {
+        self.buffer_alloc_retries = buffer_alloc_retries;
+        self
+    }
+
     pub fn wal_segment_capacity(mut self, segment_capacity: usize) -> Self {
         self.wal_config.segment_capacity = segment_capacity;
         self
     }
 
+    /// Choose how the WAL is forced to disk when it is flushed.
+    pub fn wal_sync_method(mut self, sync_method: WalSyncMethod) -> Self {
+        self.wal_config.sync_method = sync_method;
+        self
+    }
+
+    pub fn max_active_transactions(mut self, max_active_transactions: usize) -> Self {
+        self.max_active_transactions = max_active_transactions;
+        self
+    }
+
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    pub fn default_isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.default_isolation_level = isolation_level;
+        self
+    }
+
+    pub fn old_snapshot_threshold(mut self, old_snapshot_threshold: u32) -> Self {
+        self.old_snapshot_threshold = Some(old_snapshot_threshold);
+        self
+    }
+
+    pub fn heap_insert_strategy(mut self, heap_insert_strategy: HeapInsertStrategy) -> Self {
+        self.heap_insert_strategy = heap_insert_strategy;
+        self
+    }
+
+    /// Check the transaction status of tuples without installing hint bits, so that reads never
+    /// dirty pages. Visibility checks then look up the status again on every read.
+    pub fn hint_bit_writes(mut self, hint_bit_writes: bool) -> Self {
+        self.hint_bit_writes = hint_bit_writes;
+        self
+    }
+
+    /// Spread the synchronized scans of relations that are not being scanned over the pages
+    /// instead of starting them at the first page.
+    pub fn sync_scan_random_start(mut self, sync_scan_random_start: bool) -> Self {
+        self.sync_scan_random_start = sync_scan_random_start;
+        self
+    }
+
+    /// Redo every WAL record written for the relations on a copy of the page before the change
+    /// and compare the result with the live page. This is slow and meant for debugging.
+    pub fn wal_consistency_check(mut self, rels: &[RelFileRef]) -> Self {
+        self.wal_consistency_check = rels.to_vec();
+        self
+    }
+
+    /// Write the dirty pages of the transaction table when a transaction ends if the interval has
+    /// passed since the last write, instead of only on checkpoints.
+    pub fn txn_table_flush_interval(mut self, interval: Duration) -> Self {
+        self.txn_table_flush_interval = Some(interval);
+        self
+    }
+
+    pub fn maintenance_work_mem(mut self, maintenance_work_mem: usize) -> Self {
+        self.maintenance_work_mem = maintenance_work_mem;
+        self
+    }
+
+    /// Use a token to request the shutdown, e.g. from a signal handler. Unlike
+    /// `DB::request_shutdown`, it can interrupt the recovery while the database is opened.
+    pub fn shutdown_token(mut self, shutdown_token: CancelToken) -> Self {
+        self.shutdown_token = Some(shutdown_token);
+        self
+    }
+
+    /// Check that the configuration can be used to open a database.
+    pub fn validate(&self) -> Result<()> {
+        if self.cache_capacity == 0 {
+            return Err(Error::InvalidConfig(
+                "cache capacity must be positive".to_owned(),
+            ));
+        }
+
+        if self.max_active_transactions == 0 {
+            return Err(Error::InvalidConfig(
+                "max active transactions must be positive".to_owned(),
+            ));
+        }
+
+        if self.max_open_files == 0 {
+            return Err(Error::InvalidConfig(
+                "max open files must be positive".to_owned(),
+            ));
+        }
+
+        if self.maintenance_work_mem == 0 {
+            return Err(Error::InvalidConfig(
+                "maintenance work memory must be positive".to_owned(),
+            ));
+        }
+
+        self.wal_config.validate()
+    }
+
     pub fn get_storage_path(&self) -> PathBuf {
         let mut path = self.root_path.clone();
         path.push("base");
@@ -58,9 +205,54 @@ impl DBConfig {
         path
     }
 
+    /// Directory of the temporary files, e.g. the runs of external sorts.
+    pub fn get_temp_path(&self) -> PathBuf {
+        let mut path = self.root_path.clone();
+        path.push("temp");
+        path
+    }
+
     pub fn get_master_record_path(&self) -> PathBuf {
         let mut path = self.root_path.clone();
         path.push("master_record");
         path
     }
+
+    pub fn get_control_file_path(&self) -> PathBuf {
+        let mut path = self.root_path.clone();
+        path.push("control");
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DBConfig;
+    use crate::{Error, DB};
+
+    fn assert_invalid(config: DBConfig) {
+        match DB::open(&config) {
+            Err(Error::InvalidConfig(_)) => {}
+            _ => panic!("invalid config is accepted"),
+        }
+    }
+
+    #[test]
+    fn can_reject_invalid_config() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        assert!(config.validate().is_ok());
+
+        assert_invalid(config.clone().cache_capacity(0));
+        assert_invalid(config.clone().max_active_transactions(0));
+        assert_invalid(config.clone().max_open_files(0));
+        assert_invalid(config.clone().maintenance_work_mem(0));
+        assert_invalid(config.clone().wal_segment_capacity(0));
+        assert_invalid(config.clone().wal_segment_capacity(4096));
+        assert_invalid(config.clone().wal_segment_capacity(3 * 4096));
+
+        assert!(DB::open(&config.wal_segment_capacity(4 * 8192)).is_ok());
+
+        assert!(db_dir.close().is_ok());
+    }
 }
@@ -0,0 +1,200 @@
+use crate::{storage::consts::PAGE_SIZE, DBConfig, Error, Result};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::prelude::*,
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde::{Deserialize, Serialize};
+
+const CONTROL_TEMP_FILE_NAME: &str = "control.tmp";
+
+/// Version of the on-disk format, bumped on incompatible changes to any of the files
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+/// Format and layout parameters the data directory is created with
+///
+/// The control file is written when the data directory is created and checked every time it is
+/// opened, so that a directory written by an incompatible build is rejected instead of misread.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ControlFile {
+    pub format_version: u32,
+    pub page_size: u64,
+    pub wal_segment_capacity: u64,
+}
+
+impl ControlFile {
+    fn from_config(config: &DBConfig) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u64,
+            wal_segment_capacity: config.wal_config.segment_capacity as u64,
+        }
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        if !path.is_file() {
+            return Err(Error::WrongObjectType(format!(
+                "'{}' exists but is not a regular file",
+                path.display()
+            )));
+        }
+
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+
+        if buffer.len() < 4 {
+            return Err(Error::DataCorrupted("control file is corrupted".to_owned()));
+        }
+
+        let crc_buf = buffer.split_off(buffer.len() - 4);
+        let crc_file = (&crc_buf[..]).read_u32::<LittleEndian>().unwrap();
+
+        if crc32::checksum_ieee(&buffer) != crc_file {
+            return Err(Error::DataCorrupted(
+                "control file is corrupted (checksum does not match)".to_owned(),
+            ));
+        }
+
+        Ok(Some(bincode::deserialize(&buffer)?))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut buffer = bincode::serialize(self)?;
+        let crc = crc32::checksum_ieee(&buffer);
+        buffer.write_u32::<LittleEndian>(crc).unwrap();
+
+        // replace the file atomically so that a crash never leaves a garbled control file
+        let path = path.as_ref();
+        let dir_path = path.parent().unwrap_or_else(|| Path::new("."));
+        let temp_path = dir_path.join(CONTROL_TEMP_FILE_NAME);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+
+        fs::rename(&temp_path, path)?;
+        File::open(dir_path)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Check the control file of the data directory against the build and the configuration,
+    /// creating it if the directory is new.
+    pub fn check_or_create(config: &DBConfig) -> Result<()> {
+        let path = config.get_control_file_path();
+        let expected = Self::from_config(config);
+
+        let control = match Self::read(&path)? {
+            Some(control) => control,
+            None => {
+                // files of an older build that did not write the control file
+                let existing = [config.get_master_record_path(), config.get_storage_path()]
+                    .iter()
+                    .find(|path| path.exists())
+                    .cloned();
+                if let Some(existing) = existing {
+                    return Err(Error::InvalidState(format!(
+                        "data directory has no control file but '{}' exists",
+                        existing.display()
+                    )));
+                }
+
+                fs::create_dir_all(&config.root_path)?;
+                return expected.write(&path);
+            }
+        };
+
+        if control.format_version != expected.format_version {
+            return Err(Error::InvalidState(format!(
+                "data directory has format version {}, but this build supports version {}",
+                control.format_version, expected.format_version
+            )));
+        }
+
+        if control.page_size != expected.page_size {
+            return Err(Error::InvalidState(format!(
+                "data directory is created with page size {}, but this build uses {}",
+                control.page_size, expected.page_size
+            )));
+        }
+
+        if control.wal_segment_capacity != expected.wal_segment_capacity {
+            return Err(Error::InvalidState(format!(
+                "data directory is created with WAL segment capacity {}, but {} is configured",
+                control.wal_segment_capacity, expected.wal_segment_capacity
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DB;
+
+    #[test]
+    fn can_reject_mismatched_data_directory() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        drop(DB::open(&config).unwrap());
+        let path = config.get_control_file_path();
+        let control = ControlFile::read(&path).unwrap().unwrap();
+        assert_eq!(control, ControlFile::from_config(&config));
+        assert!(DB::open(&config).is_ok());
+
+        let wal_config = config.clone().wal_segment_capacity(2 * 1024 * 1024);
+        match DB::open(&wal_config) {
+            Err(Error::InvalidState(e)) => assert!(e.contains("WAL segment capacity")),
+            _ => panic!("mismatched WAL segment capacity is accepted"),
+        }
+
+        ControlFile {
+            format_version: FORMAT_VERSION + 1,
+            ..control
+        }
+        .write(&path)
+        .unwrap();
+        match DB::open(&config) {
+            Err(Error::InvalidState(e)) => assert!(e.contains("format version")),
+            _ => panic!("mismatched format version is accepted"),
+        }
+
+        // a garbled control file is not mistaken for a new directory
+        fs::write(&path, b"garbage").unwrap();
+        assert!(matches!(DB::open(&config), Err(Error::DataCorrupted(_))));
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn cannot_open_data_directory_without_control_file() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        drop(DB::open(&config).unwrap());
+        fs::remove_file(config.get_control_file_path()).unwrap();
+        match DB::open(&config) {
+            Err(Error::InvalidState(e)) => assert!(e.contains("no control file")),
+            _ => panic!("data directory without control file is accepted"),
+        }
+
+        db_dir.close().unwrap();
+    }
+}
@@ -6,37 +6,87 @@ use std::{
 };
 
 use crate::{
-    am::{btree::BTree, heap::Heap, Index, IndexPtr},
-    concurrency::{IsolationLevel, StateManager, Transaction, TransactionManager},
-    storage::{BufferManager, ForkType, RelationWithStorage, StorageManager, TablePtr},
-    wal::{CheckpointManager, DBState, Wal},
+    am::{
+        analyze::{compute_column_stats, sample_rows},
+        btree::BTree,
+        heap::{Heap, SyncScanRegistry},
+        AnalyzeColumn, ColumnStats, Index, IndexPtr,
+    },
+    catalog::Catalog,
+    concurrency::{
+        CancelToken, ExtensionLockGuard, IsolationLevel, LockMode, StateManager, Transaction,
+        TransactionManager, TransactionStatus,
+    },
+    control_file::ControlFile,
+    metrics::Counter,
+    storage::{
+        consts::PAGE_SIZE, BufferManager, DiskPageReader, DiskPageView, ForkType, PageKind,
+        RelFileRef, RelationWithStorage, ResidentPageInfo, StorageManager, TablePtr,
+        TempFileManager, TruncatedRelation,
+    },
+    wal::{CheckpointManager, DBState, LogPointer, Wal},
     Result,
 };
 
 pub struct DB {
+    config: DBConfig,
     bufmgr: BufferManager,
     smgr: StorageManager,
-    txnmgr: TransactionManager,
+    txnmgr: Arc<TransactionManager>,
     wal: Wal,
     ckptmgr: Mutex<CheckpointManager>,
     statemgr: StateManager,
+    catalog: Catalog,
+    checkpoints: Counter,
+    sync_scans: Arc<SyncScanRegistry>,
+    temp_files: Arc<TempFileManager>,
+    shutdown_token: CancelToken,
+    // cancels the operations of the transactions without their own cancel tokens
+    cancel_token: CancelToken,
 }
 
 impl DB {
     pub fn open(config: &DBConfig) -> Result<Self> {
-        let smgr = StorageManager::new(config.get_storage_path());
-        let bufmgr = BufferManager::new(config.cache_capacity);
-        let txnmgr = TransactionManager::open(config.get_transaction_path())?;
+        config.validate()?;
+        ControlFile::check_or_create(config)?;
+
+        let smgr = StorageManager::new(config.get_storage_path(), config.max_open_files);
+        let bufmgr = BufferManager::new(config.cache_capacity, config.buffer_alloc_retries);
+        let temp_files = Arc::new(TempFileManager::open(config.get_temp_path())?);
+        let txnmgr = Arc::new(TransactionManager::open(
+            config.get_transaction_path(),
+            config.max_active_transactions,
+            config.txn_table_flush_interval,
+            temp_files.clone(),
+            smgr.get_relation_file_map().clone(),
+        )?);
         let wal = Wal::open(config.get_wal_path(), &config.wal_config)?;
         let ckptmgr = CheckpointManager::open(config.get_master_record_path())?;
         let statemgr = StateManager::new();
+        let catalog = Catalog::open(config.get_storage_path())?;
+        for (file_ref, file_id) in catalog.list_relation_files() {
+            smgr.get_relation_file_map().set(file_ref, file_id);
+        }
+        for file_ref in catalog.list_compressed_relations() {
+            smgr.set_compressed(smgr.relation_file(file_ref));
+        }
+        for file_ref in smgr.list_init_forks()? {
+            smgr.set_unlogged(file_ref);
+        }
         let db = Self {
+            config: config.clone(),
             bufmgr,
             smgr,
             txnmgr,
             wal,
             ckptmgr: Mutex::new(ckptmgr),
             statemgr,
+            catalog,
+            checkpoints: Default::default(),
+            sync_scans: Arc::new(SyncScanRegistry::new()),
+            temp_files,
+            shutdown_token: config.shutdown_token.clone().unwrap_or_default(),
+            cancel_token: CancelToken::new(),
         };
 
         db.startup()?;
@@ -44,6 +94,47 @@ impl DB {
         Ok(db)
     }
 
+    pub fn get_config(&self) -> &DBConfig {
+        &self.config
+    }
+
+    pub fn get_temp_file_manager(&self) -> &TempFileManager {
+        &self.temp_files
+    }
+
+    /// Ask the recovery, scans and vacuums in progress to stop with `Error::Interrupted` so the
+    /// database can be shut down.
+    pub fn request_shutdown(&self) {
+        self.shutdown_token.cancel();
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.shutdown_token.is_canceled()
+    }
+
+    /// Return `Error::Interrupted` if a shutdown is requested.
+    pub(crate) fn check_shutdown(&self) -> Result<()> {
+        if self.is_shutdown_requested() {
+            Err(Error::Interrupted)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return `Error::Interrupted` if a shutdown is requested, or `Error::QueryCanceled` if the
+    /// token from `cancel_handle` is canceled.
+    pub(crate) fn check_interrupts(&self) -> Result<()> {
+        self.check_shutdown()?;
+
+        if self.cancel_token.is_canceled() {
+            return Err(Error::QueryCanceled(
+                "canceling statement due to user request".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn get_storage_manager(&self) -> &StorageManager {
         &self.smgr
     }
@@ -64,6 +155,10 @@ impl DB {
         &self.statemgr
     }
 
+    pub fn get_sync_scan_registry(&self) -> &Arc<SyncScanRegistry> {
+        &self.sync_scans
+    }
+
     pub fn startup(&self) -> Result<()> {
         let mut guard = self.ckptmgr.lock().unwrap();
 
@@ -93,6 +188,8 @@ impl DB {
             guard.set_db_state(DBState::InCrashRecovery)?;
 
             self.wal.replay_logs(self, redo_pos)?;
+            self.reset_unlogged_relations()?;
+            self.repair_index_siblings()?;
         }
 
         self.txnmgr.init_state();
@@ -100,36 +197,426 @@ impl DB {
         Ok(())
     }
 
+    /// Create a database namespace with an empty catalog.
+    pub fn create_database(&self, db: OID) -> Result<()> {
+        if self.catalog.database_exists(db) {
+            return Err(Error::InvalidArgument(format!(
+                "database {} already exists",
+                db
+            )));
+        }
+
+        self.smgr.create_database(db)?;
+        self.catalog.create_database(db)
+    }
+
+    /// Drop a database with all its relations. The relations must not be used by other
+    /// transactions and handles to them should not be used afterwards.
+    pub fn drop_database(&self, db: OID) -> Result<()> {
+        if !self.catalog.database_exists(db) {
+            return Err(Error::InvalidArgument(format!(
+                "database {} does not exist",
+                db
+            )));
+        }
+
+        self.bufmgr.invalidate_database(db)?;
+        self.smgr.drop_database(db)?;
+        self.catalog.drop_database(db);
+
+        // make sure that the log records for the dropped relations are never replayed
+        self.create_checkpoint()
+    }
+
     pub fn create_table(&self, db: OID, rel_id: OID) -> Result<TablePtr> {
-        let heap = Arc::new(Heap::new(rel_id, db));
+        self.create_table_with_options(db, rel_id, RelOptions::default())
+    }
+
+    /// Create a table with the options. The options are recorded in the catalog and applied
+    /// again when the table is opened with `open_table`.
+    pub fn create_table_with_options(
+        &self,
+        db: OID,
+        rel_id: OID,
+        options: RelOptions,
+    ) -> Result<TablePtr> {
+        let mut entry = CatalogEntry::new(RelationKind::Table);
+        entry.options = options;
+        self.create_table_with_entry(db, rel_id, entry)
+    }
+
+    /// Create a table whose rows are built with `TupleDescriptor::form_tuple`. The descriptor is
+    /// recorded in the catalog so the attributes of the rows can be read with `Tuple::get_attr`.
+    pub fn create_table_with_descriptor(
+        &self,
+        db: OID,
+        rel_id: OID,
+        options: RelOptions,
+        tuple_desc: TupleDescriptor,
+    ) -> Result<TablePtr> {
+        let mut entry = CatalogEntry::new(RelationKind::Table);
+        entry.options = options;
+        entry.tuple_desc = Some(tuple_desc);
+        self.create_table_with_entry(db, rel_id, entry)
+    }
+
+    fn create_table_with_entry(
+        &self,
+        db: OID,
+        rel_id: OID,
+        entry: CatalogEntry,
+    ) -> Result<TablePtr> {
+        let options = &entry.options;
+        options.validate()?;
+
+        let heap = Arc::new(self.new_heap(db, rel_id, &entry));
+        if options.compressed {
+            heap.create_compressed_storage(&self.smgr)?;
+        } else {
+            heap.create_storage(&self.smgr)?;
+        }
+        if options.unlogged {
+            heap.create_init_fork(&self.smgr)?;
+        }
+
+        self.catalog.insert(RelFileRef { db, rel_id }, entry)?;
+        Ok(heap)
+    }
+
+    fn new_heap(&self, db: OID, rel_id: OID, entry: &CatalogEntry) -> Heap {
+        let heap = Heap::new(rel_id, db, self.config.heap_insert_strategy)
+            .fillfactor(entry.options.fillfactor.unwrap_or(100));
+
+        match &entry.tuple_desc {
+            Some(tuple_desc) => heap.tuple_descriptor(tuple_desc.clone()),
+            None => heap,
+        }
+    }
+
+    /// Create a table as part of a transaction. The table is added to the catalog when the
+    /// transaction commits and its files are removed if the transaction aborts.
+    pub fn create_table_in_transaction(
+        &self,
+        txn: &mut Transaction,
+        db: OID,
+        rel_id: OID,
+    ) -> Result<TablePtr> {
+        let heap = Arc::new(Heap::new(rel_id, db, self.config.heap_insert_strategy));
         heap.create_storage(&self.smgr)?;
+        txn.add_pending_relation(
+            RelFileRef { db, rel_id },
+            CatalogEntry::new(RelationKind::Table),
+        );
         Ok(heap)
     }
 
+    /// Create a table whose pages are compressed on disk. Whether a relation is compressed is
+    /// recorded in the catalog so the table is opened with `open_table` as usual.
+    pub fn create_compressed_table(&self, db: OID, rel_id: OID) -> Result<TablePtr> {
+        self.create_table_with_options(db, rel_id, RelOptions::new().compressed(true))
+    }
+
+    /// Create an unlogged table. Its content is lost on crash recovery, when the table is reset
+    /// to the empty state kept in its init fork.
+    pub fn create_unlogged_table(&self, db: OID, rel_id: OID) -> Result<TablePtr> {
+        self.create_table_with_options(db, rel_id, RelOptions::new().unlogged(true))
+    }
+
     pub fn open_table(&self, db: OID, rel_id: OID) -> Result<Option<TablePtr>> {
-        if self.smgr.exists(db, rel_id, ForkType::Main)? {
-            let heap = Arc::new(Heap::new(rel_id, db));
+        if let Some(kind) = self.get_relation_kind(db, rel_id)? {
+            if kind != RelationKind::Table {
+                return Err(Error::WrongObjectType(format!(
+                    "relation {} is not a table",
+                    rel_id
+                )));
+            }
+
+            let entry = self
+                .catalog
+                .get(RelFileRef { db, rel_id })
+                .unwrap_or_else(|| CatalogEntry::new(RelationKind::Table));
+            let heap = Arc::new(self.new_heap(db, rel_id, &entry));
             Ok(Some(heap))
         } else {
             Ok(None)
         }
     }
 
+    /// Get the options a relation is created with, `None` if the relation is not in the catalog.
+    pub fn get_relation_options(&self, db: OID, rel_id: OID) -> Option<RelOptions> {
+        self.catalog
+            .get(RelFileRef { db, rel_id })
+            .map(|entry| entry.options)
+    }
+
+    /// Rewrite a table to remove the dead tuples and rebuild its indexes to point to the new
+    /// tuple locations. The vacuum waits for the scans of the table and the indexes to finish
+    /// and blocks new scans until it is done. Table handles opened before the vacuum should be
+    /// reopened.
+    pub fn vacuum_full(&self, db: OID, rel_id: OID, indexes: &[IndexPtr]) -> Result<()> {
+        if !self.relation_exists(RelFileRef { db, rel_id })? {
+            return Err(Error::InvalidArgument(format!(
+                "table {} does not exist",
+                rel_id
+            )));
+        }
+        let file_ref = RelFileRef { db, rel_id };
+        let heap = Heap::new(rel_id, db, self.config.heap_insert_strategy);
+
+        // the locks are released when the transaction ends
+        let mut txn = self.start_transaction(IsolationLevel::ReadCommitted)?;
+        self.lock_relation(&txn, file_ref, LockMode::AccessExclusive)?;
+        for index in indexes {
+            self.lock_relation(
+                &txn,
+                RelFileRef {
+                    db: index.rel_db(),
+                    rel_id: index.rel_id(),
+                },
+                LockMode::AccessExclusive,
+            )?;
+        }
+
+        // the tuples are copied into a new file which replaces the old one when the transaction
+        // commits
+        let new_file = self.create_table_file(file_ref)?;
+        let new_heap = Heap::new(
+            new_file.rel_id,
+            new_file.db,
+            self.config.heap_insert_strategy,
+        );
+        let item_pointer_map = match heap.vacuum_full(self, &new_heap) {
+            Ok(item_pointer_map) => item_pointer_map,
+            Err(e) => {
+                self.txnmgr.add_unused_files(std::iter::once(new_file));
+                return Err(e);
+            }
+        };
+        self.replace_table_file(&mut txn, file_ref, new_file);
+        self.sync_scans.forget(file_ref);
+
+        for index in indexes {
+            index.rebuild(self, &item_pointer_map)?;
+        }
+
+        self.commit_transaction(txn)
+    }
+
+    /// Copy a relation with its pages into a new relation, which is faster than copying the
+    /// tuples. The tuples keep their XIDs so the copy shows the same rows as the source under
+    /// the same snapshot. The copy waits for the transactions using the source and blocks them
+    /// until it is done, so that no change is partially copied.
+    pub fn copy_relation(
+        &self,
+        src_db: OID,
+        src_rel: OID,
+        dst_db: OID,
+        dst_rel: OID,
+    ) -> Result<()> {
+        let kind = match self.get_relation_kind(src_db, src_rel)? {
+            Some(kind) => kind,
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "relation {} does not exist",
+                    src_rel
+                )))
+            }
+        };
+        if self.get_relation_kind(dst_db, dst_rel)?.is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "relation {} already exists",
+                dst_rel
+            )));
+        }
+
+        let src = RelFileRef {
+            db: src_db,
+            rel_id: src_rel,
+        };
+        let dst = RelFileRef {
+            db: dst_db,
+            rel_id: dst_rel,
+        };
+
+        // the files are copied from the disk, so no page may change until they are copied.
+        // the lock is released when the transaction ends
+        let txn = self.start_transaction(IsolationLevel::ReadCommitted)?;
+        self.lock_relation(&txn, src, LockMode::AccessExclusive)?;
+
+        // flush the pages of the source. the copy is not logged so the checkpoint also makes
+        // sure that no log record before it is replayed on the copy
+        self.create_checkpoint()?;
+        self.smgr.copy_relation(self.smgr.relation_file(src), dst)?;
+
+        let mut entry = self
+            .catalog
+            .get(src)
+            .unwrap_or_else(|| CatalogEntry::new(kind));
+        entry.file_id = None;
+        self.catalog.insert(dst, entry)?;
+
+        self.commit_transaction(txn)
+    }
+
+    /// Drop a relation after the scans of other transactions on it finish. The relation stays
+    /// locked until the transaction ends, and it is removed with its files when the transaction
+    /// commits. The relation is kept if the transaction aborts.
+    pub fn drop_relation(&self, txn: &mut Transaction, db: OID, rel_id: OID) -> Result<()> {
+        let file_ref = RelFileRef { db, rel_id };
+        if !self.relation_exists(file_ref)? {
+            return Err(Error::InvalidArgument(format!(
+                "relation {} does not exist",
+                rel_id
+            )));
+        }
+
+        self.lock_relation(txn, file_ref, LockMode::AccessExclusive)?;
+        txn.add_pending_drop(file_ref);
+        Ok(())
+    }
+
+    /// Remove a relation dropped by a committed transaction. Its file is removed at the next
+    /// checkpoint.
+    fn finish_drop(&self, file_ref: RelFileRef) -> Result<()> {
+        let file = self.smgr.relation_file(file_ref);
+        self.catalog.remove(file_ref)?;
+        self.smgr
+            .get_relation_file_map()
+            .set(file_ref, file_ref.rel_id);
+        self.sync_scans.forget(file_ref);
+        self.txnmgr.add_unused_files(std::iter::once(file));
+        Ok(())
+    }
+
+    /// Lock a relation until the transaction ends. Scans take `AccessShare` locks on the
+    /// relations they read.
+    pub fn lock_relation(
+        &self,
+        txn: &Transaction,
+        file_ref: RelFileRef,
+        mode: LockMode,
+    ) -> Result<()> {
+        self.txnmgr.lock_relation(txn, file_ref, mode)
+    }
+
+    /// Lock the extension of a relation fork. Adding a page and initializing it should be done
+    /// under the lock.
+    pub fn lock_relation_extension(
+        &self,
+        file_ref: RelFileRef,
+        fork: ForkType,
+    ) -> ExtensionLockGuard<'_> {
+        self.txnmgr.lock_relation_extension(file_ref, fork)
+    }
+
+    /// Sample a table and compute the statistics of the columns for the planner.
+    pub fn analyze(&self, db: OID, rel_id: OID, columns: &[AnalyzeColumn]) -> Result<()> {
+        let table = match self.open_table(db, rel_id)? {
+            Some(table) => table,
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "table {} does not exist",
+                    rel_id
+                )))
+            }
+        };
+
+        let (rows, total_rows) = sample_rows(self, &*table)?;
+        let stats = columns
+            .iter()
+            .map(|column| compute_column_stats(&rows, total_rows, column))
+            .collect::<Result<Vec<_>>>()?;
+
+        let file_ref = RelFileRef { db, rel_id };
+        let mut entry = self
+            .catalog
+            .get(file_ref)
+            .unwrap_or_else(|| CatalogEntry::new(RelationKind::Table));
+        entry.column_stats = stats;
+        self.catalog.insert(file_ref, entry)
+    }
+
+    /// Get the statistics of a column computed by the last `analyze` of the table.
+    pub fn get_column_stats(&self, db: OID, rel_id: OID, column: usize) -> Option<ColumnStats> {
+        self.catalog
+            .get(RelFileRef { db, rel_id })
+            .and_then(|entry| entry.column_stats.get(column).cloned())
+    }
+
+    /// Create an index with a custom key comparator. The same comparator must be supplied to
+    /// `open_index` when the index is reopened.
     pub fn create_index<F>(&self, db: OID, rel_id: OID, key_comparator: F) -> Result<IndexPtr>
+    where
+        F: Fn(&[u8], &[u8]) -> Result<std::cmp::Ordering> + Sync + Send + 'static,
+    {
+        self.create_index_with_entry(
+            BTree::new(rel_id, db, key_comparator),
+            CatalogEntry::new(RelationKind::Index),
+        )
+    }
+
+    /// Create an index with the built-in comparator of the key type. The key type is recorded in
+    /// the catalog so the index can be reopened with `open_typed_index`.
+    pub fn create_typed_index(&self, db: OID, rel_id: OID, key_type: KeyType) -> Result<IndexPtr> {
+        let btree = BTree::new(rel_id, db, key_type.comparator())
+            .suffix_truncation(key_type.is_prefix_ordered())
+            .prefix_compression(key_type.is_prefix_ordered());
+        let entry = CatalogEntry {
+            key_type: Some(key_type),
+            ..CatalogEntry::new(RelationKind::Index)
+        };
+
+        self.create_index_with_entry(btree, entry)
+    }
+
+    /// Create an index as part of a transaction like `create_table_in_transaction`.
+    pub fn create_index_in_transaction<F>(
+        &self,
+        txn: &mut Transaction,
+        db: OID,
+        rel_id: OID,
+        key_comparator: F,
+    ) -> Result<IndexPtr>
     where
         F: Fn(&[u8], &[u8]) -> Result<std::cmp::Ordering> + Sync + Send + 'static,
     {
         let btree = Arc::new(BTree::new(rel_id, db, key_comparator));
         btree.create_storage(&self.smgr)?;
+        txn.add_pending_relation(
+            RelFileRef { db, rel_id },
+            CatalogEntry::new(RelationKind::Index),
+        );
         btree.build_empty(self)?;
         Ok(btree)
     }
 
+    fn create_index_with_entry<F>(&self, btree: BTree<F>, entry: CatalogEntry) -> Result<IndexPtr>
+    where
+        F: Fn(&[u8], &[u8]) -> Result<std::cmp::Ordering> + Sync + Send + 'static,
+    {
+        let file_ref = RelFileRef {
+            db: btree.rel_db(),
+            rel_id: btree.rel_id(),
+        };
+        let btree = Arc::new(btree);
+        btree.create_storage(&self.smgr)?;
+        btree.build_empty(self)?;
+        self.catalog.insert(file_ref, entry)?;
+        Ok(btree)
+    }
+
     pub fn open_index<F>(&self, db: OID, rel_id: OID, key_comparator: F) -> Result<Option<IndexPtr>>
     where
         F: Fn(&[u8], &[u8]) -> Result<std::cmp::Ordering> + Sync + Send + 'static,
     {
-        if self.smgr.exists(db, rel_id, ForkType::Main)? {
+        if let Some(kind) = self.get_relation_kind(db, rel_id)? {
+            if kind != RelationKind::Index {
+                return Err(Error::WrongObjectType(format!(
+                    "relation {} is not an index",
+                    rel_id
+                )));
+            }
+
             let index = Arc::new(BTree::new(rel_id, db, key_comparator));
             Ok(Some(index))
         } else {
@@ -137,21 +624,449 @@ impl DB {
         }
     }
 
+    /// Open an index created by `create_typed_index` with the comparator of its key type.
+    pub fn open_typed_index(&self, db: OID, rel_id: OID) -> Result<Option<IndexPtr>> {
+        let entry = match self.catalog.get(RelFileRef { db, rel_id }) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        match entry {
+            CatalogEntry {
+                kind: RelationKind::Index,
+                key_type: Some(key_type),
+                ..
+            } => {
+                let btree = BTree::new(rel_id, db, key_type.comparator())
+                    .suffix_truncation(key_type.is_prefix_ordered())
+                    .prefix_compression(key_type.is_prefix_ordered());
+                Ok(Some(Arc::new(btree)))
+            }
+            CatalogEntry {
+                kind: RelationKind::Index,
+                key_type: None,
+                ..
+            } => Err(Error::InvalidState(format!(
+                "index {} has a custom key comparator",
+                rel_id
+            ))),
+            _ => Err(Error::WrongObjectType(format!(
+                "relation {} is not an index",
+                rel_id
+            ))),
+        }
+    }
+
+    /// Block until the WAL is flushed up to `lsn`, which must have been written already.
+    pub fn wait_for_flush(&self, lsn: LogPointer) -> Result<()> {
+        self.wal.wait_for_flush(lsn)
+    }
+
     pub fn start_transaction(&self, isolation_level: IsolationLevel) -> Result<Transaction> {
-        self.txnmgr.start_transaction(self, isolation_level)
+        let mut txn = self.txnmgr.start_transaction(self, isolation_level)?;
+        txn.set_shutdown_token(self.shutdown_token.clone());
+        txn.set_cancel_token(self.cancel_token.clone());
+        Ok(txn)
+    }
+
+    /// Start a transaction with the default isolation level in the config.
+    pub fn start_default_transaction(&self) -> Result<Transaction> {
+        self.start_transaction(self.config.default_isolation_level)
+    }
+
+    /// Start a subtransaction of `parent`. It is aborted by dropping it or committed into the
+    /// parent with `commit_subtransaction`.
+    pub fn start_subtransaction(&self, parent: &Transaction) -> Result<Transaction> {
+        self.txnmgr.start_subtransaction(self, parent)
+    }
+
+    pub fn commit_subtransaction(&self, parent: &mut Transaction, txn: Transaction) -> Result<()> {
+        self.txnmgr.commit_subtransaction(parent, txn)
     }
 
-    pub fn commit_transaction(&self, txn: Transaction) -> Result<()> {
-        self.txnmgr.commit_transaction(self, txn)
+    /// Get the token that cancels the scans and index rebuilds in progress when it is canceled
+    /// from another thread. Transactions use it unless they are given their own token with
+    /// `Transaction::set_cancel_token`. Reset it once the canceled operations have stopped.
+    pub fn cancel_handle(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn commit_transaction(&self, mut txn: Transaction) -> Result<()> {
+        let xid = txn.xid();
+        let relations = txn.take_pending_relations();
+        let drops = txn.take_pending_drops();
+        let has_truncations = txn.has_pending_truncations();
+        let result = self.txnmgr.commit_transaction(self, txn);
+
+        // the commit may fail after the transaction is committed. the transaction is only
+        // aborted if its commit log is not flushed
+        if result.is_err() && self.txnmgr.get_transaction_status(xid)? == TransactionStatus::Aborted
+        {
+            self.txnmgr
+                .add_unused_files(relations.into_iter().map(|(file_ref, _)| file_ref));
+            return result;
+        }
+
+        for (file_ref, mut entry) in relations {
+            // the relation may be truncated after it is created
+            let file = self.smgr.relation_file(file_ref);
+            if file != file_ref {
+                entry.file_id = Some(file.rel_id);
+            }
+            self.catalog.insert(file_ref, entry)?;
+        }
+        for file_ref in drops.iter().copied() {
+            self.finish_drop(file_ref)?;
+        }
+        result?;
+
+        if has_truncations || !drops.is_empty() {
+            // remove the old files of the truncated relations and the files of the dropped
+            // relations. the checkpoint also makes sure that the log records for them are never
+            // replayed
+            self.create_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Abort a transaction. This is the same as dropping the transaction except that the
+    /// relation files created by the transaction are removed immediately instead of at the next
+    /// checkpoint.
+    pub fn abort_transaction(&self, txn: Transaction) -> Result<()> {
+        let has_relations = txn.has_pending_relations();
+        drop(txn);
+
+        if has_relations {
+            self.create_checkpoint()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rebuild the catalog of a database from the relation files, e.g. after the catalog file is
+    /// lost. Relations whose first page is a btree page become indexes and the others become
+    /// tables. The key types of the indexes are lost, so they must be opened with `open_index`.
+    pub fn repair_catalog(&self, db: OID) -> Result<()> {
+        let mut entries = Vec::new();
+
+        for file_id in self.smgr.list_relations(db)? {
+            let file = RelFileRef {
+                db,
+                rel_id: file_id,
+            };
+            // the page maps are the only record of compression left without the catalog
+            let compressed = self.smgr.has_page_map(file, ForkType::Main);
+            if compressed {
+                self.smgr.set_compressed(file);
+            }
+            let mut entry = CatalogEntry::new(self.read_relation_kind(file)?);
+            entry.options.compressed = compressed;
+
+            // files of truncated relations are not named after the relations
+            let rel_id = match self.smgr.get_relation_file_map().find_relation(file) {
+                Some(file_ref) => {
+                    entry.file_id = Some(file_id);
+                    file_ref.rel_id
+                }
+                None => file_id,
+            };
+            entries.push((rel_id, entry));
+        }
+
+        self.catalog.rebuild_database(db, entries)
+    }
+
+    /// Get the size of all forks of a relation in bytes. Compressed pages are counted at their
+    /// full size.
+    pub fn relation_total_size_bytes(&self, db: OID, rel_id: OID) -> Result<u64> {
+        let file_ref = RelFileRef { db, rel_id };
+        if !self.relation_exists(file_ref)? {
+            return Err(Error::InvalidArgument(format!(
+                "relation {} does not exist",
+                rel_id
+            )));
+        }
+
+        let shandle = self.smgr.open(self.smgr.relation_file(file_ref))?;
+        let num_pages = self.smgr.total_size_in_pages(&shandle)?;
+        Ok(num_pages as u64 * PAGE_SIZE as u64)
+    }
+
+    /// Get the kind of a relation from the catalog, or from its first page if it is not in the
+    /// catalog, e.g. when it is created by a running transaction. Return None if the relation
+    /// does not exist.
+    pub fn get_relation_kind(&self, db: OID, rel_id: OID) -> Result<Option<RelationKind>> {
+        let file_ref = RelFileRef { db, rel_id };
+        if !self.relation_exists(file_ref)? {
+            return Ok(None);
+        }
+
+        match self.catalog.get(file_ref) {
+            Some(entry) => Ok(Some(entry.kind)),
+            None => self
+                .read_relation_kind(self.smgr.relation_file(file_ref))
+                .map(Some),
+        }
+    }
+
+    fn relation_exists(&self, file_ref: RelFileRef) -> Result<bool> {
+        let file = self.smgr.relation_file(file_ref);
+        self.smgr.exists(file.db, file.rel_id, ForkType::Main)
+    }
+
+    /// Tell the kind of a relation by the first page of its file. Relations whose first page is
+    /// a btree page are indexes and the others are tables.
+    fn read_relation_kind(&self, file: RelFileRef) -> Result<RelationKind> {
+        let shandle = self.smgr.open(file)?;
+        if self.smgr.file_size_in_page(&shandle, ForkType::Main)? == 0 {
+            return Ok(RelationKind::Table);
+        }
+
+        let page_ptr = self.bufmgr.fetch_page(self, &shandle, ForkType::Main, 0)?;
+        let page_kind = page_ptr.with_read(|page| Ok(DiskPageView::new(page.buffer()).page_kind()));
+        self.bufmgr.release_page(page_ptr)?;
+
+        Ok(if page_kind? == PageKind::BTree {
+            RelationKind::Index
+        } else {
+            RelationKind::Table
+        })
+    }
+
+    /// Copy the init fork over the main fork of every unlogged relation after crash recovery. The
+    /// pages redone for the relations are discarded.
+    fn reset_unlogged_relations(&self) -> Result<()> {
+        for file_ref in self.smgr.list_init_forks()? {
+            self.bufmgr.invalidate_relation(file_ref)?;
+            let shandle = self.smgr.open(file_ref)?;
+            self.smgr.reset_from_init_fork(&shandle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fix the back links of the btree pages that are not logged by splits.
+    fn repair_index_siblings(&self) -> Result<()> {
+        for RelFileRef { db, rel_id } in self.catalog.list(RelationKind::Index) {
+            if !self.smgr.exists(db, rel_id, ForkType::Main)? {
+                continue;
+            }
+
+            // the keys are not compared
+            let btree = BTree::new(rel_id, db, |_: &[u8], _: &[u8]| {
+                Ok(std::cmp::Ordering::Equal)
+            });
+            btree.repair_siblings(self)?;
+        }
+
+        Ok(())
+    }
+
+    /// Truncate a relation fork to `nr_pages` without logging, discarding the cached pages past
+    /// the new end. This is used when the truncation is already logged, so it does nothing if the
+    /// relation no longer exists or the fork is already shorter.
+    pub(crate) fn truncate_relation(
+        &self,
+        file_ref: RelFileRef,
+        fork: ForkType,
+        nr_pages: usize,
+    ) -> Result<()> {
+        if !self.smgr.exists(file_ref.db, file_ref.rel_id, fork)? {
+            return Ok(());
+        }
+
+        let shandle = self.smgr.open(file_ref)?;
+        self.bufmgr
+            .invalidate_truncated_pages(file_ref, fork, nr_pages)?;
+        self.smgr.truncate(&shandle, fork, nr_pages)
+    }
+
+    /// Give a table a new empty file in a transaction. The transaction sees the empty table right
+    /// away and other transactions cannot access the table until it ends. The old file is removed
+    /// when the transaction commits, and the table goes back to it if the transaction aborts.
+    pub(crate) fn truncate_table(&self, txn: &mut Transaction, file_ref: RelFileRef) -> Result<()> {
+        self.lock_relation(txn, file_ref, LockMode::AccessExclusive)?;
+
+        let new_file = self.create_table_file(file_ref)?;
+        self.replace_table_file(txn, file_ref, new_file);
+        Ok(())
+    }
+
+    /// Create a new empty file for a table with the storage options of the table.
+    fn create_table_file(&self, file_ref: RelFileRef) -> Result<RelFileRef> {
+        let new_file = self.alloc_relation_file(file_ref.db)?;
+        let options = self
+            .catalog
+            .get(file_ref)
+            .map(|entry| entry.options)
+            .unwrap_or_default();
+
+        let heap = Heap::new(
+            new_file.rel_id,
+            new_file.db,
+            self.config.heap_insert_strategy,
+        );
+        let result = if options.compressed {
+            heap.create_compressed_storage(&self.smgr)
+        } else {
+            heap.create_storage(&self.smgr)
+        }
+        .and_then(|_| {
+            if options.unlogged {
+                heap.create_init_fork(&self.smgr)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            self.txnmgr.add_unused_files(std::iter::once(new_file));
+            return Err(e);
+        }
+
+        Ok(new_file)
+    }
+
+    /// Switch a table to a new file. The switch is undone if the transaction aborts.
+    fn replace_table_file(
+        &self,
+        txn: &mut Transaction,
+        file_ref: RelFileRef,
+        new_file: RelFileRef,
+    ) {
+        let old_file = self.smgr.relation_file(file_ref);
+        self.smgr
+            .get_relation_file_map()
+            .set(file_ref, new_file.rel_id);
+        txn.add_pending_truncation(TruncatedRelation {
+            file_ref,
+            old_file_id: old_file.rel_id,
+            new_file_id: new_file.rel_id,
+        });
+    }
+
+    /// Pick an unused file for a relation in the database.
+    fn alloc_relation_file(&self, db: OID) -> Result<RelFileRef> {
+        loop {
+            let file = RelFileRef {
+                db,
+                rel_id: self.statemgr.get_next_oid(self)?,
+            };
+
+            // the IDs of the relations are given by the users, so the OID may be taken
+            if !self.smgr.exists(db, file.rel_id, ForkType::Main)?
+                && self.catalog.get(file).is_none()
+                && self.smgr.relation_file(file) == file
+            {
+                return Ok(file);
+            }
+        }
+    }
+
+    /// Switch a relation truncated by a committed transaction to its new file. The old file is
+    /// removed at the next checkpoint.
+    pub(crate) fn finish_truncation(&self, truncation: &TruncatedRelation) -> Result<()> {
+        let TruncatedRelation {
+            file_ref,
+            old_file_id,
+            new_file_id,
+        } = *truncation;
+
+        self.smgr.get_relation_file_map().set(file_ref, new_file_id);
+
+        // only tables are truncated. the entry of a table created by the transaction is
+        // replaced when it is added to the catalog after the commit
+        let mut entry = self
+            .catalog
+            .get(file_ref)
+            .unwrap_or_else(|| CatalogEntry::new(RelationKind::Table));
+        entry.file_id = Some(new_file_id).filter(|file_id| *file_id != file_ref.rel_id);
+        self.catalog.insert(file_ref, entry)?;
+
+        self.txnmgr.add_unused_files(std::iter::once(RelFileRef {
+            db: file_ref.db,
+            rel_id: old_file_id,
+        }));
+        Ok(())
+    }
+
+    /// Remove the relation files that are no longer used.
+    fn remove_unused_files(&self) -> Result<()> {
+        let relations = self.txnmgr.take_unused_files();
+
+        for (i, file_ref) in relations.iter().enumerate() {
+            let result = self
+                .bufmgr
+                .invalidate_relation(*file_ref)
+                .and_then(|_| self.smgr.unlink(*file_ref));
+
+            if let Err(e) = result {
+                // try again at the next checkpoint
+                self.txnmgr.add_unused_files(relations[i..].iter().copied());
+                return Err(e);
+            }
+        }
+
+        Ok(())
     }
 
     pub fn create_checkpoint(&self) -> Result<()> {
         let mut guard = self.ckptmgr.lock().unwrap();
 
-        guard.create_checkpoint(self)
+        // the checkpoint makes sure that the log records for the removed relations are never
+        // replayed
+        self.remove_unused_files()?;
+
+        guard.create_checkpoint(self)?;
+        self.checkpoints.inc();
+        Ok(())
+    }
+
+    /// Collect the counters of the database.
+    pub fn metrics(&self) -> DbMetrics {
+        let (buffer_hits, buffer_misses) = self.bufmgr.get_hits_and_misses();
+        let (wal_records, wal_bytes) = self.wal.get_write_stats();
+        let (commits, aborts) = self.txnmgr.get_commits_and_aborts();
+
+        DbMetrics {
+            buffer_hits,
+            buffer_misses,
+            wal_records,
+            wal_bytes,
+            commits,
+            aborts,
+            checkpoints: self.checkpoints.get(),
+        }
+    }
+
+    /// List the pages in the buffer pool, e.g. to see which relations occupy the cache.
+    pub fn buffer_contents(&self) -> Vec<ResidentPageInfo> {
+        self.bufmgr.list_resident_pages()
+    }
+
+    /// Write all dirty pages back and sync the relation files without creating a checkpoint.
+    pub fn flush_all_buffers(&self) -> Result<()> {
+        self.bufmgr.sync_pages(self)?;
+        self.smgr.sync_all()
     }
 
     pub fn get_next_oid(&self) -> Result<OID> {
         self.statemgr.get_next_oid(self)
     }
+
+    /// Allocate `count` contiguous OIDs and return the inclusive range.
+    pub fn alloc_oid_range(&self, count: usize) -> Result<(OID, OID)> {
+        self.statemgr.alloc_oid_range(self, count)
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        // catch the pages that are fetched but never released in tests
+        if let Err(e) = self.bufmgr.check_pin_leaks() {
+            if cfg!(test) && !std::thread::panicking() {
+                panic!("{}", e);
+            }
+            log::warn!("{}", e);
+        }
+    }
 }
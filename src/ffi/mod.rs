@@ -6,7 +6,16 @@ use crate::{
 };
 
 use libc::{c_char, c_int, c_uint, c_ulonglong};
-use std::{cell::RefCell, ffi::CStr, path::PathBuf, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::CStr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, RwLock,
+    },
+};
 
 #[no_mangle]
 pub extern "C" fn sq_init() {
@@ -15,7 +24,7 @@ pub extern "C" fn sq_init() {
 
 // error handling code borrowed from https://michael-f-bryan.github.io/rust-ffi-guide/errors/return_types.html
 thread_local! {
-    static LAST_ERROR: RefCell<Option<Box<Error>>> = RefCell::new(None);
+    static LAST_ERROR: RefCell<Option<Box<Error>>> = const { RefCell::new(None) };
 }
 
 fn update_last_error(err: Error) {
@@ -28,6 +37,130 @@ fn take_last_error() -> Option<Box<Error>> {
     LAST_ERROR.with(|prev| prev.borrow_mut().take())
 }
 
+// error reported by a frontend callback with `sq_set_callback_error`
+thread_local! {
+    static CALLBACK_ERROR: RefCell<Option<Error>> = const { RefCell::new(None) };
+}
+
+/// Report an error from a key comparator or predicate callback. The callback must still return
+/// an out-of-range value for the error to be raised. `code` selects the kind of the error: 1 for
+/// data corrupted, 2 for invalid state, 3 for query canceled and other values for invalid
+/// argument.
+#[no_mangle]
+pub unsafe extern "C" fn sq_set_callback_error(code: c_int, message: *const c_char) {
+    let message = if message.is_null() {
+        "callback failed".to_owned()
+    } else {
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    };
+
+    let err = match code {
+        1 => Error::DataCorrupted(message),
+        2 => Error::InvalidState(message),
+        3 => Error::QueryCanceled(message),
+        _ => Error::InvalidArgument(message),
+    };
+
+    CALLBACK_ERROR.with(|prev| {
+        *prev.borrow_mut() = Some(err);
+    });
+}
+
+/// Take the error reported by the callback or make a generic one if there is none.
+fn take_callback_error(default_message: &str) -> Error {
+    CALLBACK_ERROR
+        .with(|prev| prev.borrow_mut().take())
+        .unwrap_or_else(|| Error::InvalidArgument(default_message.to_owned()))
+}
+
+type KeyComparatorFunc = extern "C" fn(*const u8, c_uint, *const u8, c_uint) -> c_int;
+
+fn call_key_comparator(
+    key_comparator_func: KeyComparatorFunc,
+    a: &[u8],
+    b: &[u8],
+) -> Result<std::cmp::Ordering> {
+    let result = key_comparator_func(a.as_ptr(), a.len() as c_uint, b.as_ptr(), b.len() as c_uint);
+
+    match result {
+        -1 => Ok(std::cmp::Ordering::Less),
+        0 => Ok(std::cmp::Ordering::Equal),
+        1 => Ok(std::cmp::Ordering::Greater),
+        _ => Err(take_callback_error("cannot compare index keys")),
+    }
+}
+
+fn make_key_comparator(
+    key_comparator_func: *const (),
+) -> impl Fn(&[u8], &[u8]) -> Result<std::cmp::Ordering> + Sync + Send + 'static {
+    let key_comparator_func: KeyComparatorFunc =
+        unsafe { std::mem::transmute(key_comparator_func) };
+
+    move |a: &[u8], b: &[u8]| call_key_comparator(key_comparator_func, a, b)
+}
+
+struct RegisteredComparator {
+    func: KeyComparatorFunc,
+    ref_count: usize,
+}
+
+// comparators registered by the frontend, keyed by token
+static COMPARATORS: RwLock<BTreeMap<c_ulonglong, RegisteredComparator>> =
+    RwLock::new(BTreeMap::new());
+static NEXT_COMPARATOR_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+/// Register a key comparator and return a token to create or open indexes with. Registering the
+/// same function again returns the same token and increases its reference count.
+#[no_mangle]
+pub extern "C" fn sq_register_comparator(key_comparator_func: *const ()) -> c_ulonglong {
+    assert!(!key_comparator_func.is_null());
+    let func: KeyComparatorFunc = unsafe { std::mem::transmute(key_comparator_func) };
+    let mut guard = COMPARATORS.write().unwrap();
+
+    for (token, comparator) in guard.iter_mut() {
+        if comparator.func as usize == func as usize {
+            comparator.ref_count += 1;
+            return *token;
+        }
+    }
+
+    let token = NEXT_COMPARATOR_TOKEN.fetch_add(1, AtomicOrdering::Relaxed);
+    guard.insert(token, RegisteredComparator { func, ref_count: 1 });
+    token
+}
+
+/// Drop a reference to a registered comparator. Once all references are dropped, indexes using
+/// the comparator fail with an error instead of calling it, so the frontend can unload the
+/// code. This waits for the comparisons in progress and must not be called from a comparator.
+#[no_mangle]
+pub extern "C" fn sq_unregister_comparator(token: c_ulonglong) {
+    let mut guard = COMPARATORS.write().unwrap();
+
+    if let Some(comparator) = guard.get_mut(&token) {
+        comparator.ref_count -= 1;
+        if comparator.ref_count == 0 {
+            guard.remove(&token);
+        }
+    }
+}
+
+fn make_registered_key_comparator(
+    token: c_ulonglong,
+) -> impl Fn(&[u8], &[u8]) -> Result<std::cmp::Ordering> + Sync + Send + 'static {
+    move |a: &[u8], b: &[u8]| {
+        // hold the lock during the call so that the comparator is not unregistered while in use
+        let guard = COMPARATORS.read().unwrap();
+
+        match guard.get(&token) {
+            Some(comparator) => call_key_comparator(comparator.func, a, b),
+            None => Err(Error::InvalidState(format!(
+                "key comparator {} is not registered",
+                token
+            ))),
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn sq_last_error_length() -> c_int {
     LAST_ERROR.with(|prev| match *prev.borrow() {
@@ -94,8 +227,9 @@ pub extern "C" fn sq_free_db(db: *const DB) {
     }
 }
 
-fn sq_get_isolation_level(isolation_level: c_int) -> Result<IsolationLevel> {
+fn sq_get_isolation_level(db: &DB, isolation_level: c_int) -> Result<IsolationLevel> {
     match isolation_level {
+        -1 => Ok(db.get_config().default_isolation_level),
         0 => Ok(IsolationLevel::ReadUncommitted),
         1 => Ok(IsolationLevel::ReadCommitted),
         2 => Ok(IsolationLevel::RepeatableRead),
@@ -111,7 +245,7 @@ pub extern "C" fn sq_start_transaction(db: *const DB, isolation_level: c_int) ->
         &*db
     };
 
-    let isolation_level = match sq_get_isolation_level(isolation_level) {
+    let isolation_level = match sq_get_isolation_level(db, isolation_level) {
         Ok(iso_level) => iso_level,
         Err(e) => {
             update_last_error(e);
@@ -152,6 +286,21 @@ pub extern "C" fn sq_commit_transaction(db: *const DB, txn: *mut Transaction) {
     }
 }
 
+#[no_mangle]
+pub extern "C" fn sq_transaction_advance_command_id(txn: *mut Transaction) {
+    let txn = unsafe {
+        assert!(!txn.is_null());
+        &mut *txn
+    };
+
+    match txn.advance_command_id() {
+        Ok(_) => {}
+        Err(e) => {
+            update_last_error(e);
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn sq_create_table(db: *const DB, db_oid: OID, rel_oid: OID) -> *const TablePtr {
     let db = unsafe {
@@ -297,6 +446,69 @@ pub extern "C" fn sq_table_begin_scan<'a>(
     Box::into_raw(Box::new(iterator))
 }
 
+#[no_mangle]
+pub extern "C" fn sq_table_begin_scan_from<'a>(
+    table: *const TablePtr,
+    db: *const DB,
+    txn: *mut Transaction,
+    start: *const ItemPointer,
+) -> *mut Box<dyn TableScanIterator<'a> + 'a> {
+    let db = unsafe {
+        assert!(!db.is_null());
+        &*db
+    };
+    let table: &TablePtr = unsafe {
+        assert!(!table.is_null());
+        &*table
+    };
+    let txn: &mut Transaction = unsafe {
+        assert!(!txn.is_null());
+        &mut *txn
+    };
+    let start: &ItemPointer = unsafe {
+        assert!(!start.is_null());
+        &*start
+    };
+
+    let iterator = match table.begin_scan_from(db, txn, *start) {
+        Ok(iterator) => iterator,
+        Err(e) => {
+            update_last_error(e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    Box::into_raw(Box::new(iterator))
+}
+
+#[no_mangle]
+pub extern "C" fn sq_table_count(
+    table: *const TablePtr,
+    db: *const DB,
+    txn: *mut Transaction,
+) -> u64 {
+    let db = unsafe {
+        assert!(!db.is_null());
+        &*db
+    };
+    let table: &TablePtr = unsafe {
+        assert!(!table.is_null());
+        &*table
+    };
+    let txn: &mut Transaction = unsafe {
+        assert!(!txn.is_null());
+        &mut *txn
+    };
+
+    match table.count_visible(db, txn) {
+        Ok(count) => count,
+        Err(e) => {
+            update_last_error(e);
+            0
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn sq_free_table_scan_iterator<'a>(
     iterator: *mut Box<dyn TableScanIterator<'a> + 'a>,
@@ -310,10 +522,10 @@ pub extern "C" fn sq_free_table_scan_iterator<'a>(
 }
 
 fn get_scan_direction(dir: c_int) -> ScanDirection {
-    if dir == 0 {
-        ScanDirection::Forward
-    } else {
-        ScanDirection::Backward
+    match dir {
+        0 => ScanDirection::Forward,
+        2 => ScanDirection::NoMovement,
+        _ => ScanDirection::Backward,
     }
 }
 
@@ -408,6 +620,21 @@ pub extern "C" fn sq_create_checkpoint(db: *const DB) {
     };
 }
 
+#[no_mangle]
+pub extern "C" fn sq_flush_buffers(db: *const DB) {
+    let db = unsafe {
+        assert!(!db.is_null());
+        &*db
+    };
+
+    match db.flush_all_buffers() {
+        Ok(_) => {}
+        Err(e) => {
+            update_last_error(e);
+        }
+    };
+}
+
 #[no_mangle]
 pub extern "C" fn sq_get_next_oid(db: *const DB) -> OID {
     let db = unsafe {
@@ -424,6 +651,8 @@ pub extern "C" fn sq_get_next_oid(db: *const DB) -> OID {
     }
 }
 
+/// Create an index with a raw comparator function. The function must stay valid for as long as
+/// the index is used; use `sq_create_index_with_comparator` if the code can be unloaded.
 #[no_mangle]
 pub extern "C" fn sq_create_index(
     db: *const DB,
@@ -436,22 +665,7 @@ pub extern "C" fn sq_create_index(
         &*db
     };
 
-    let key_comparator_func: extern "C" fn(*const u8, c_uint, *const u8, c_uint) -> c_int =
-        unsafe { std::mem::transmute(key_comparator_func) };
-
-    let key_comparator = move |a: &[u8], b: &[u8]| {
-        let result =
-            key_comparator_func(a.as_ptr(), a.len() as c_uint, b.as_ptr(), b.len() as c_uint);
-
-        match result {
-            -1 => Ok(std::cmp::Ordering::Less),
-            0 => Ok(std::cmp::Ordering::Equal),
-            1 => Ok(std::cmp::Ordering::Greater),
-            _ => Err(Error::InvalidArgument(
-                "cannot compare index keys".to_owned(),
-            )),
-        }
-    };
+    let key_comparator = make_key_comparator(key_comparator_func);
 
     let index = match db.create_index(db_oid, rel_oid, key_comparator) {
         Ok(index) => index,
@@ -476,23 +690,63 @@ pub extern "C" fn sq_open_index(
         &*db
     };
 
-    let key_comparator_func: extern "C" fn(*const u8, c_uint, *const u8, c_uint) -> c_int =
-        unsafe { std::mem::transmute(key_comparator_func) };
+    let key_comparator = make_key_comparator(key_comparator_func);
+
+    let index = match db.open_index(db_oid, rel_oid, key_comparator) {
+        Ok(Some(index)) => index,
+        Ok(None) => {
+            return std::ptr::null();
+        }
+        Err(e) => {
+            update_last_error(e);
+            return std::ptr::null();
+        }
+    };
 
-    let key_comparator = move |a: &[u8], b: &[u8]| {
-        let result =
-            key_comparator_func(a.as_ptr(), a.len() as c_uint, b.as_ptr(), b.len() as c_uint);
+    Box::into_raw(Box::new(index))
+}
 
-        match result {
-            -1 => Ok(std::cmp::Ordering::Less),
-            0 => Ok(std::cmp::Ordering::Equal),
-            1 => Ok(std::cmp::Ordering::Greater),
-            _ => Err(Error::InvalidArgument(
-                "cannot compare index keys".to_owned(),
-            )),
+/// Create an index with a comparator registered by `sq_register_comparator`.
+#[no_mangle]
+pub extern "C" fn sq_create_index_with_comparator(
+    db: *const DB,
+    db_oid: OID,
+    rel_oid: OID,
+    comparator_token: c_ulonglong,
+) -> *const IndexPtr {
+    let db = unsafe {
+        assert!(!db.is_null());
+        &*db
+    };
+
+    let key_comparator = make_registered_key_comparator(comparator_token);
+
+    let index = match db.create_index(db_oid, rel_oid, key_comparator) {
+        Ok(index) => index,
+        Err(e) => {
+            update_last_error(e);
+            return std::ptr::null();
         }
     };
 
+    Box::into_raw(Box::new(index))
+}
+
+/// Open an index with a comparator registered by `sq_register_comparator`.
+#[no_mangle]
+pub extern "C" fn sq_open_index_with_comparator(
+    db: *const DB,
+    db_oid: OID,
+    rel_oid: OID,
+    comparator_token: c_ulonglong,
+) -> *const IndexPtr {
+    let db = unsafe {
+        assert!(!db.is_null());
+        &*db
+    };
+
+    let key_comparator = make_registered_key_comparator(comparator_token);
+
     let index = match db.open_index(db_oid, rel_oid, key_comparator) {
         Ok(Some(index)) => index,
         Ok(None) => {
@@ -586,6 +840,48 @@ pub extern "C" fn sq_index_begin_scan<'a>(
     Box::into_raw(Box::new(iterator))
 }
 
+#[no_mangle]
+pub extern "C" fn sq_index_find_one<'a>(
+    index: *const IndexPtr,
+    db: *const DB,
+    txn: *mut Transaction,
+    table: *const TablePtr,
+    key: *const u8,
+    length: c_int,
+) -> *const Box<dyn Tuple + 'a> {
+    let db = unsafe {
+        assert!(!db.is_null());
+        &*db
+    };
+    let index: &IndexPtr = unsafe {
+        assert!(!index.is_null());
+        &*index
+    };
+    let txn: &mut Transaction = unsafe {
+        assert!(!txn.is_null());
+        &mut *txn
+    };
+    let table: &TablePtr = unsafe {
+        assert!(!table.is_null());
+        &*table
+    };
+
+    let key = unsafe { std::slice::from_raw_parts(key, length as usize) };
+
+    let tuple = match index.find_one(db, txn, &**table, key) {
+        Ok(Some(tuple)) => tuple.materialize(),
+        Ok(None) => {
+            return std::ptr::null();
+        }
+        Err(e) => {
+            update_last_error(e);
+            return std::ptr::null();
+        }
+    };
+
+    Box::into_raw(Box::new(tuple))
+}
+
 #[no_mangle]
 pub extern "C" fn sq_free_index_scan_iterator<'a>(
     iterator: *mut Box<dyn IndexScanIterator<'a> + 'a>,
@@ -633,9 +929,7 @@ pub extern "C" fn sq_index_rescan<'a>(
         match result {
             0 => Ok(false),
             1 => Ok(true),
-            _ => Err(Error::InvalidArgument(
-                "cannot match keys with predicates".to_owned(),
-            )),
+            _ => Err(take_callback_error("cannot match keys with predicates")),
         }
     });
 
@@ -675,3 +969,120 @@ pub extern "C" fn sq_index_scan_next<'a>(
 
     Box::into_raw(Box::new(tuple))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    extern "C" fn failing_comparator(_: *const u8, _: c_uint, _: *const u8, _: c_uint) -> c_int {
+        let message = CString::new("collation \"xx\" is not supported").unwrap();
+        unsafe { sq_set_callback_error(2, message.as_ptr()) };
+        -2
+    }
+
+    #[test]
+    fn can_report_callback_error() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let root_path = CString::new(db_dir.path().to_str().unwrap()).unwrap();
+        let db = sq_create_db(root_path.as_ptr());
+        assert!(!db.is_null());
+
+        let index = sq_create_index(db, 0, 1, failing_comparator as *const ());
+        assert!(!index.is_null());
+
+        let item_pointer = ItemPointer::new(0, 1);
+        for key in [[1u8; 4], [2u8; 4]].iter() {
+            sq_index_insert(
+                index,
+                db,
+                std::ptr::null(),
+                key.as_ptr(),
+                key.len() as c_int,
+                &item_pointer,
+            );
+        }
+
+        let length = sq_last_error_length();
+        assert!(length > 0);
+        let mut buffer = vec![0 as c_char; length as usize];
+        let written = unsafe { sq_last_error_message(buffer.as_mut_ptr(), length) };
+        assert_eq!(written, length - 1);
+
+        let message = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+        assert_eq!(
+            message.to_str().unwrap(),
+            "Invalid state: collation \"xx\" is not supported"
+        );
+
+        // the error is consumed by the comparison
+        assert!(matches!(
+            take_callback_error("cannot compare index keys"),
+            Error::InvalidArgument(_)
+        ));
+
+        sq_free_index(index);
+        sq_free_db(db);
+        db_dir.close().unwrap();
+    }
+
+    extern "C" fn compare_first_byte(a: *const u8, _: c_uint, b: *const u8, _: c_uint) -> c_int {
+        let (a, b) = unsafe { (*a, *b) };
+        a.cmp(&b) as c_int
+    }
+
+    #[test]
+    fn can_reject_unregistered_comparator() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let root_path = CString::new(db_dir.path().to_str().unwrap()).unwrap();
+        let db = sq_create_db(root_path.as_ptr());
+        assert!(!db.is_null());
+
+        let token = sq_register_comparator(compare_first_byte as *const ());
+        assert_eq!(
+            sq_register_comparator(compare_first_byte as *const ()),
+            token
+        );
+
+        let index = sq_create_index_with_comparator(db, 0, 1, token);
+        assert!(!index.is_null());
+
+        let item_pointer = ItemPointer::new(0, 1);
+        let insert = |key: u8| {
+            let key = [key; 4];
+            sq_index_insert(
+                index,
+                db,
+                std::ptr::null(),
+                key.as_ptr(),
+                key.len() as c_int,
+                &item_pointer,
+            );
+        };
+
+        insert(1);
+        insert(2);
+        assert_eq!(sq_last_error_length(), 0);
+
+        // still referenced once
+        sq_unregister_comparator(token);
+        insert(3);
+        assert_eq!(sq_last_error_length(), 0);
+
+        sq_unregister_comparator(token);
+        insert(4);
+        let error = take_last_error().unwrap();
+        assert!(matches!(*error, Error::InvalidState(_)));
+        assert!(error.to_string().contains("is not registered"));
+
+        // a new registration gets a new token
+        assert_ne!(
+            sq_register_comparator(compare_first_byte as *const ()),
+            token
+        );
+
+        sq_free_index(index);
+        sq_free_db(db);
+        db_dir.close().unwrap();
+    }
+}
@@ -3,16 +3,21 @@ pub mod concurrency;
 pub mod storage;
 pub mod wal;
 
+mod catalog;
 mod config;
+mod control_file;
 mod db;
 mod ffi;
+mod metrics;
 mod relation;
 mod result;
 mod test_util;
 
 pub use self::{
+    catalog::{AttrLength, CatalogEntry, KeyType, RelOptions, TupleDescriptor},
     config::DBConfig,
     db::DB,
+    metrics::DbMetrics,
     relation::{Relation, RelationEntry, RelationKind},
     result::{Error, Result},
 };
@@ -0,0 +1,101 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A monotonically increasing counter.
+#[derive(Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A snapshot of the counters of a database.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DbMetrics {
+    /// Pages found in the buffer pool
+    pub buffer_hits: u64,
+    /// Pages read from disk into the buffer pool
+    pub buffer_misses: u64,
+    pub wal_records: u64,
+    pub wal_bytes: u64,
+    pub commits: u64,
+    pub aborts: u64,
+    pub checkpoints: u64,
+}
+
+impl fmt::Display for DbMetrics {
+    /// Render the counters in the Prometheus text format.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counters = [
+            (
+                "buffer_hits",
+                "Pages found in the buffer pool",
+                self.buffer_hits,
+            ),
+            ("buffer_misses", "Pages read from disk", self.buffer_misses),
+            ("wal_records", "WAL records written", self.wal_records),
+            ("wal_bytes", "Bytes of WAL records written", self.wal_bytes),
+            ("commits", "Transactions committed", self.commits),
+            ("aborts", "Transactions aborted", self.aborts),
+            ("checkpoints", "Checkpoints created", self.checkpoints),
+        ];
+
+        for (name, help, value) in counters.iter() {
+            writeln!(f, "# HELP suziq_{}_total {}", name, help)?;
+            writeln!(f, "# TYPE suziq_{}_total counter", name)?;
+            writeln!(f, "suziq_{}_total {}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{concurrency::IsolationLevel, test_util::get_temp_db};
+
+    #[test]
+    fn can_collect_metrics() {
+        let (db, db_dir) = get_temp_db();
+        let before = db.metrics();
+
+        let heap = db.create_table(0, 0).unwrap();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..10 {
+            heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        drop(txn);
+
+        db.create_checkpoint().unwrap();
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.commits, before.commits + 1);
+        assert_eq!(metrics.aborts, before.aborts + 1);
+        assert_eq!(metrics.checkpoints, before.checkpoints + 1);
+        // 10 inserts, a commit and a checkpoint
+        assert!(metrics.wal_records >= before.wal_records + 12);
+        assert!(metrics.wal_bytes >= before.wal_bytes + 10 * 100);
+        assert!(metrics.buffer_hits > before.buffer_hits);
+
+        let text = metrics.to_string();
+        assert!(text.contains("# TYPE suziq_commits_total counter\n"));
+        assert!(text.contains(&format!("suziq_commits_total {}\n", metrics.commits)));
+
+        db_dir.close().unwrap();
+    }
+}
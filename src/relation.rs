@@ -1,6 +1,8 @@
 use crate::OID;
 
-#[derive(Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RelationKind {
     Table,
     Index,
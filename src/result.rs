@@ -15,7 +15,19 @@ pub enum Error {
     ProgramLimitExceed(String),
     InvalidState(String),
     InvalidArgument(String),
+    InvalidConfig(String),
     OutOfMemory,
+    /// No page can be evicted from the buffer pool because all of them are pinned
+    BufferPoolFull(String),
+    Serialization(Box<dyn StdError + Send + Sync>),
+    SnapshotTooOld,
+    QueryCanceled(String),
+    /// A unique index already has a live entry with the key
+    UniqueViolation(String),
+    /// A long operation stopped because a shutdown is requested
+    Interrupted,
+    /// Waiting for a lock or a transaction would never end because of a cycle of waits
+    Deadlock(String),
 }
 
 impl From<io::Error> for Error {
@@ -24,7 +36,21 @@ impl From<io::Error> for Error {
     }
 }
 
-impl StdError for Error {}
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Serialization(ref e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::result::Result<(), fmt::Error> {
@@ -37,8 +63,16 @@ impl Display for Error {
             ProgramLimitExceed(ref e) => write!(f, "Program limit exceed: {}", e),
             InvalidState(ref e) => write!(f, "Invalid state: {}", e),
             InvalidArgument(ref e) => write!(f, "Invalid argument: {}", e),
+            InvalidConfig(ref e) => write!(f, "Invalid config: {}", e),
             Io(ref e) => write!(f, "IO error: {}", e),
             OutOfMemory => write!(f, "Out of memory."),
+            BufferPoolFull(ref e) => write!(f, "Buffer pool full: {}", e),
+            Serialization(ref e) => write!(f, "Serialization error: {}", e),
+            SnapshotTooOld => write!(f, "Snapshot too old"),
+            QueryCanceled(ref e) => write!(f, "Query canceled: {}", e),
+            UniqueViolation(ref e) => write!(f, "Unique violation: {}", e),
+            Interrupted => write!(f, "Interrupted by shutdown request"),
+            Deadlock(ref e) => write!(f, "Deadlock detected: {}", e),
         }
     }
 }
@@ -1,19 +1,54 @@
 use crate::{
-    storage::{page_cache::PageCache, ForkType, PinnedPagePtr, StorageHandle},
-    Result, DB,
+    metrics::Counter,
+    storage::{page_cache::PageCache, ForkType, PinnedPagePtr, RelFileRef, StorageHandle},
+    Error, Result, DB, OID,
 };
 
-use std::sync::Mutex;
+#[cfg(test)]
+use std::collections::HashMap;
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+const ALLOC_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+const ALLOC_RETRY_MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+/// State of a page in the buffer pool
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResidentPageInfo {
+    pub rel: RelFileRef,
+    pub fork: ForkType,
+    pub page_num: usize,
+    pub pin_count: i32,
+    pub dirty: bool,
+    /// Number of times the page is fetched since it is loaded
+    pub usage_count: u32,
+}
 
 pub struct BufferManager {
     page_cache: Mutex<PageCache>,
+    // number of times to retry when all pages are pinned
+    alloc_retries: usize,
+    hits: Counter,
+    misses: Counter,
+    #[cfg(test)]
+    fetch_counts: Mutex<HashMap<RelFileRef, usize>>,
 }
 
 impl BufferManager {
-    pub fn new(cache_capacity: usize) -> Self {
+    pub fn new(cache_capacity: usize, alloc_retries: usize) -> Self {
         let page_cache = Mutex::new(PageCache::new(cache_capacity));
 
-        Self { page_cache }
+        Self {
+            page_cache,
+            alloc_retries,
+            hits: Default::default(),
+            misses: Default::default(),
+            #[cfg(test)]
+            fetch_counts: Mutex::new(HashMap::new()),
+        }
     }
 
     pub fn new_page(
@@ -22,10 +57,13 @@ impl BufferManager {
         shandle: &StorageHandle,
         fork: ForkType,
     ) -> Result<PinnedPagePtr> {
-        self.page_cache
-            .lock()
-            .unwrap()
-            .new_page(db, shandle, shandle.file_ref(), fork)
+        // extend the file outside of the retry loop so that a retried allocation does not add
+        // another page. if the allocation fails, the page is left empty in the file
+        let page_num = db.get_storage_manager().zero_extend(shandle, fork)?;
+
+        self.with_alloc_retry(|page_cache| {
+            page_cache.new_page(db, shandle.file_ref(), fork, page_num)
+        })
     }
 
     pub fn fetch_page(
@@ -35,25 +73,202 @@ impl BufferManager {
         fork: ForkType,
         page_num: usize,
     ) -> Result<PinnedPagePtr> {
+        #[cfg(test)]
+        {
+            let mut guard = self.fetch_counts.lock().unwrap();
+            *guard.entry(shandle.file_ref()).or_insert(0) += 1;
+        }
+
+        self.fetch_page_counted(db, shandle, fork, page_num, false)
+    }
+
+    /// Return the number of page fetches served from the cache and from disk.
+    pub fn get_hits_and_misses(&self) -> (u64, u64) {
+        (self.hits.get(), self.misses.get())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fetch_count(&self, rel: RelFileRef) -> usize {
+        let guard = self.fetch_counts.lock().unwrap();
+        guard.get(&rel).copied().unwrap_or(0)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cached_page_count(&self) -> usize {
+        let guard = self.lock_page_cache();
+        guard.cached_page_count()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn dirty_page_count(&self, rel: RelFileRef) -> usize {
+        let guard = self.lock_page_cache();
+        guard.dirty_page_count(rel)
+    }
+
+    /// Fetch a page for redo. The page may not exist on disk if the relation is extended but the
+    /// page is not written before a crash, in which case a zero page is returned.
+    pub fn fetch_page_for_redo(
+        &self,
+        db: &DB,
+        shandle: &StorageHandle,
+        fork: ForkType,
+        page_num: usize,
+    ) -> Result<PinnedPagePtr> {
+        self.fetch_page_counted(db, shandle, fork, page_num, true)
+    }
+
+    fn fetch_page_counted(
+        &self,
+        db: &DB,
+        shandle: &StorageHandle,
+        fork: ForkType,
+        page_num: usize,
+        zero_past_eof: bool,
+    ) -> Result<PinnedPagePtr> {
+        let rel = shandle.file_ref();
+
+        self.with_alloc_retry(|page_cache| {
+            let cached = page_cache.is_cached(rel, fork, page_num);
+            let page_ptr =
+                page_cache.fetch_page(db, shandle, rel, fork, page_num, zero_past_eof)?;
+
+            if cached {
+                self.hits.inc();
+            } else {
+                self.misses.inc();
+            }
+
+            Ok(page_ptr)
+        })
+    }
+
+    /// Run `f` on the page cache. If no page can be evicted because all pages are pinned, wait
+    /// for other threads to release some pages and try again with backoff.
+    fn with_alloc_retry<F>(&self, mut f: F) -> Result<PinnedPagePtr>
+    where
+        F: FnMut(&mut PageCache) -> Result<PinnedPagePtr>,
+    {
+        let mut backoff = ALLOC_RETRY_INITIAL_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            let result = f(&mut self.lock_page_cache());
+
+            match result {
+                Err(Error::BufferPoolFull(_)) if retries < self.alloc_retries => {
+                    retries += 1;
+                    thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, ALLOC_RETRY_MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // the pages recover from poisoning on their own, see `PagePtr::lock_write`
+    fn lock_page_cache(&self) -> MutexGuard<'_, PageCache> {
         self.page_cache
             .lock()
-            .unwrap()
-            .fetch_page(db, shandle, shandle.file_ref(), fork, page_num)
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
     pub fn release_page(&self, page_ptr: PinnedPagePtr) -> Result<()> {
-        self.page_cache.lock().unwrap().release_page(page_ptr)
+        self.lock_page_cache().release_page(page_ptr)
+    }
+
+    /// Protect the page from being evicted, e.g. for hot pages like the btree meta page.
+    pub fn mark_resident(&self, rel: RelFileRef, fork: ForkType, page_num: usize) {
+        self.page_cache
+            .lock()
+            .unwrap()
+            .mark_resident(rel, fork, page_num)
+    }
+
+    pub fn unmark_resident(&self, rel: RelFileRef, fork: ForkType, page_num: usize) {
+        self.page_cache
+            .lock()
+            .unwrap()
+            .unmark_resident(rel, fork, page_num)
+    }
+
+    pub fn is_cached(&self, rel: RelFileRef, fork: ForkType, page_num: usize) -> bool {
+        self.page_cache
+            .lock()
+            .unwrap()
+            .is_cached(rel, fork, page_num)
+    }
+
+    /// Grow or shrink the buffer pool. Shrinking fails if more pages than the new capacity are
+    /// pinned.
+    pub fn resize(&self, db: &DB, new_capacity: usize) -> Result<()> {
+        self.lock_page_cache().resize(db, new_capacity)
+    }
+
+    /// Discard the cached pages of a relation whose storage is replaced or truncated.
+    pub fn invalidate_relation(&self, rel: RelFileRef) -> Result<()> {
+        self.lock_page_cache().invalidate_relation(rel)
+    }
+
+    /// Discard the cached pages of a relation fork that are past the truncated end.
+    pub fn invalidate_truncated_pages(
+        &self,
+        rel: RelFileRef,
+        fork: ForkType,
+        nr_pages: usize,
+    ) -> Result<()> {
+        self.page_cache
+            .lock()
+            .unwrap()
+            .invalidate_truncated_pages(rel, fork, nr_pages)
+    }
+
+    /// Discard the cached pages of all relations in a database that is dropped.
+    pub fn invalidate_database(&self, db: OID) -> Result<()> {
+        self.lock_page_cache().invalidate_database(db)
+    }
+
+    /// List the pages in the buffer pool for diagnostics. The pages are not pinned so the listing
+    /// may be stale once it is returned.
+    pub fn list_resident_pages(&self) -> Vec<ResidentPageInfo> {
+        self.lock_page_cache().get_resident_pages()
+    }
+
+    /// Check that every fetched page has been released. Return an error naming the pages that
+    /// are still pinned.
+    pub fn check_pin_leaks(&self) -> Result<()> {
+        let pinned_pages = self.lock_page_cache().get_pinned_pages();
+
+        if pinned_pages.is_empty() {
+            return Ok(());
+        }
+
+        let pages = pinned_pages
+            .iter()
+            .map(|(rel, fork, page_num, pin_count)| {
+                format!("({}, {:?}, page {}) x{}", rel, fork, page_num, pin_count)
+            })
+            .collect::<Vec<_>>();
+
+        Err(Error::InvalidState(format!(
+            "leaked page pins: {}",
+            pages.join(", ")
+        )))
     }
 
     pub fn sync_pages(&self, db: &DB) -> Result<()> {
         let dirty_pages = {
             // get dirty pages with lock on page cache, then release the lock and proceed to write the pages
-            let mut guard = self.page_cache.lock().unwrap();
+            let mut guard = self.lock_page_cache();
             guard.get_dirty_pages()
         };
 
         for page_ptr in dirty_pages {
-            page_ptr.with_write(|mut page| PageCache::flush_page(db, &mut page))?;
+            page_ptr.with_write(|page| {
+                if page.is_dirty() {
+                    PageCache::flush_page(db, page)?;
+                }
+                Ok(())
+            })?;
             self.release_page(page_ptr)?;
         }
         Ok(())
@@ -63,7 +278,7 @@ impl BufferManager {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{storage::RelFileRef, test_util::get_temp_db};
+    use crate::test_util::get_temp_db;
 
     #[test]
     fn can_allocate_page() {
@@ -77,11 +292,670 @@ mod tests {
             Some(0)
         );
 
-        assert!(bufmgr.new_page(&db, &shandle, ForkType::Main).is_ok());
+        let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
         assert_eq!(
             smgr.file_size_in_page(&shandle, ForkType::Main).ok(),
             Some(1)
         );
+        bufmgr.release_page(page_ptr).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_extend_relation_concurrently() {
+        use std::collections::HashSet;
+
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let page_nums = std::thread::scope(|s| {
+            let threads = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        let bufmgr = db.get_buffer_manager();
+                        (0..50)
+                            .map(|_| {
+                                let page_ptr =
+                                    bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+                                let (_, _, page_num) = page_ptr
+                                    .with_read(|page| Ok(page.get_fork_and_num()))
+                                    .unwrap();
+                                bufmgr.release_page(page_ptr).unwrap();
+                                page_num
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let unique = page_nums.iter().copied().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), 400);
+        assert_eq!(unique, (0..400).collect::<HashSet<_>>());
+        assert_eq!(
+            smgr.file_size_in_page(&shandle, ForkType::Main).ok(),
+            Some(400)
+        );
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_retry_allocation_when_all_pages_are_pinned() {
+        use std::sync::{Arc, Barrier};
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new()
+            .root_path(db_dir.path())
+            .cache_capacity(4)
+            .buffer_alloc_retries(20);
+        let db = Arc::new(DB::open(&config).unwrap());
+        let smgr = db.get_storage_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let bufmgr = db.get_buffer_manager();
+        for _ in 0..5 {
+            let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+
+        // fail immediately without retries
+        let no_retry_bufmgr = BufferManager::new(4, 0);
+        let page_ptrs = (0..4)
+            .map(|i| {
+                no_retry_bufmgr
+                    .fetch_page(&db, &shandle, ForkType::Main, i)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        match no_retry_bufmgr.fetch_page(&db, &shandle, ForkType::Main, 4) {
+            Err(Error::BufferPoolFull(e)) => assert!(e.contains("4 of 4 pages are pinned")),
+            _ => panic!("allocation should fail"),
+        }
+        for page_ptr in page_ptrs {
+            no_retry_bufmgr.release_page(page_ptr).unwrap();
+        }
+
+        let barrier = Arc::new(Barrier::new(2));
+        let thread = {
+            let db = db.clone();
+            let barrier = barrier.clone();
+            let shandle = shandle.clone();
+            std::thread::spawn(move || {
+                let bufmgr = db.get_buffer_manager();
+                let page_ptrs = (0..4)
+                    .map(|i| bufmgr.fetch_page(&db, &shandle, ForkType::Main, i).unwrap())
+                    .collect::<Vec<_>>();
+                barrier.wait();
+
+                std::thread::sleep(Duration::from_millis(50));
+                for page_ptr in page_ptrs {
+                    bufmgr.release_page(page_ptr).unwrap();
+                }
+            })
+        };
+
+        barrier.wait();
+        let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 4).unwrap();
+        bufmgr.release_page(page_ptr).unwrap();
+        thread.join().unwrap();
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_fetch_right_pages_while_evicting() {
+        use crate::storage::consts::PAGE_SIZE;
+        use byteorder::{ByteOrder, LittleEndian};
+
+        const NUM_PAGES: usize = 64;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new()
+            .root_path(db_dir.path())
+            .cache_capacity(8)
+            .buffer_alloc_retries(20);
+        let db = DB::open(&config).unwrap();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        // each page records its own page number at the end
+        let write_marker = |page_ptr: &PinnedPagePtr, page_num: usize| {
+            page_ptr
+                .with_write(|page| {
+                    let buffer = page.buffer_mut();
+                    LittleEndian::write_u64(&mut buffer[PAGE_SIZE - 8..], page_num as u64);
+                    page.set_dirty(true);
+                    Ok(())
+                })
+                .unwrap();
+        };
+        for page_num in 0..NUM_PAGES {
+            let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+            write_marker(&page_ptr, page_num);
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+
+        std::thread::scope(|s| {
+            for i in 0..8u64 {
+                let (db, shandle) = (&db, &shandle);
+                s.spawn(move || {
+                    let mut state = i * 2 + 1;
+                    for _ in 0..500 {
+                        // xorshift64
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        let page_num = state as usize % NUM_PAGES;
+
+                        let page_ptr = bufmgr
+                            .fetch_page(db, shandle, ForkType::Main, page_num)
+                            .unwrap();
+                        let (tag, marker) = page_ptr
+                            .with_read(|page| {
+                                let marker =
+                                    LittleEndian::read_u64(&page.buffer()[PAGE_SIZE - 8..]);
+                                Ok((page.get_fork_and_num(), marker))
+                            })
+                            .unwrap();
+                        assert_eq!(tag, (shandle.file_ref(), ForkType::Main, page_num));
+                        assert_eq!(marker, page_num as u64);
+
+                        // dirty some pages so that evictions write them back
+                        if state % 4 == 0 {
+                            write_marker(&page_ptr, page_num);
+                        }
+                        bufmgr.release_page(page_ptr).unwrap();
+                    }
+                });
+            }
+        });
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_keep_page_hash_within_pool() {
+        const CACHE_CAPACITY: usize = 4;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new()
+            .root_path(db_dir.path())
+            .cache_capacity(CACHE_CAPACITY);
+        let db = DB::open(&config).unwrap();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        for _ in 0..32 {
+            let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+            assert!(bufmgr.cached_page_count() <= CACHE_CAPACITY);
+        }
+
+        for i in 0..256 {
+            let page_num = (i * 7) % 32;
+            let page_ptr = bufmgr
+                .fetch_page(&db, &shandle, ForkType::Main, page_num)
+                .unwrap();
+            let tag = page_ptr
+                .with_read(|page| Ok(page.get_fork_and_num()))
+                .unwrap();
+            assert_eq!(tag, (shandle.file_ref(), ForkType::Main, page_num));
+            bufmgr.release_page(page_ptr).unwrap();
+            assert!(bufmgr.cached_page_count() <= CACHE_CAPACITY);
+        }
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_hold_owning_page_lock() {
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let mut page_lock = bufmgr
+            .new_page(&db, &shandle, ForkType::Main)
+            .unwrap()
+            .write_owned()
+            .unwrap();
+        for i in 0..4 {
+            page_lock.with_page_guard_mut(|page_guard| {
+                page_guard.buffer_mut()[i] = i as u8 + 1;
+                page_guard.set_dirty(true);
+            });
+        }
+        assert_eq!(page_lock.borrow_page_guard().buffer()[..4], [1, 2, 3, 4]);
+        bufmgr
+            .release_page(page_lock.into_heads().page_ptr)
+            .unwrap();
+
+        let page_lock = bufmgr
+            .fetch_page(&db, &shandle, ForkType::Main, 0)
+            .unwrap()
+            .read_owned()
+            .unwrap();
+        let page_guard = page_lock.borrow_page_guard();
+        assert_eq!(page_guard.buffer()[..4], [1, 2, 3, 4]);
+        assert!(page_guard.is_dirty());
+        bufmgr
+            .release_page(page_lock.into_heads().page_ptr)
+            .unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_reuse_page_cached_before_allocation() {
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let rel = RelFileRef { db: 0, rel_id: 5 };
+        let shandle = smgr.open(rel).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        // a reader caches the page after the file is extended but before the extending thread
+        // allocates its buffer
+        let page_num = smgr.zero_extend(&shandle, ForkType::Main).unwrap();
+        let page_ptr = bufmgr
+            .fetch_page(&db, &shandle, ForkType::Main, page_num)
+            .unwrap();
+        bufmgr.release_page(page_ptr).unwrap();
+
+        let page_ptr = bufmgr
+            .page_cache
+            .lock()
+            .unwrap()
+            .new_page(&db, rel, ForkType::Main, page_num)
+            .unwrap();
+        let pages = bufmgr
+            .list_resident_pages()
+            .into_iter()
+            .filter(|page| page.rel == rel)
+            .collect::<Vec<_>>();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].pin_count, 1);
+        bufmgr.release_page(page_ptr).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_list_resident_pages() {
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let rel = RelFileRef { db: 0, rel_id: 5 };
+        let shandle = smgr.open(rel).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        for _ in 0..2 {
+            let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+
+        let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 1).unwrap();
+        page_ptr
+            .with_write(|page| {
+                page.set_dirty(true);
+                Ok(())
+            })
+            .unwrap();
+        bufmgr.release_page(page_ptr).unwrap();
+
+        let pinned_page = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 0).unwrap();
+        let pages = db
+            .buffer_contents()
+            .into_iter()
+            .filter(|page| page.rel == rel)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pages,
+            vec![
+                ResidentPageInfo {
+                    rel,
+                    fork: ForkType::Main,
+                    page_num: 0,
+                    pin_count: 1,
+                    dirty: false,
+                    usage_count: 2,
+                },
+                ResidentPageInfo {
+                    rel,
+                    fork: ForkType::Main,
+                    page_num: 1,
+                    pin_count: 0,
+                    dirty: true,
+                    usage_count: 2,
+                },
+            ]
+        );
+        bufmgr.release_page(pinned_page).unwrap();
+
+        // written back pages stay cached
+        db.flush_all_buffers().unwrap();
+        let pages = bufmgr
+            .list_resident_pages()
+            .into_iter()
+            .filter(|page| page.rel == rel)
+            .collect::<Vec<_>>();
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|page| !page.dirty && page.pin_count == 0));
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_detect_pin_leaks() {
+        use crate::{concurrency::IsolationLevel, storage::ScanDirection};
+        use std::panic::{self, AssertUnwindSafe};
+
+        let (db, db_dir) = get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+        let bufmgr = db.get_buffer_manager();
+
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        for _ in 0..100 {
+            heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        }
+        txn.advance_command_id().unwrap();
+
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {}
+        }
+        db.commit_transaction(txn).unwrap();
+        assert!(bufmgr.check_pin_leaks().is_ok());
+
+        let shandle = db
+            .get_storage_manager()
+            .open(RelFileRef { db: 0, rel_id: 0 })
+            .unwrap();
+        let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 1).unwrap();
+        std::mem::forget(page_ptr);
+
+        match bufmgr.check_pin_leaks() {
+            Err(Error::InvalidState(e)) => assert!(e.contains("(0, 0), Main, page 1")),
+            _ => panic!("pin leak is not detected"),
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| drop(db)));
+        assert!(result.is_err());
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_recover_from_poisoned_page_cache() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _guard = bufmgr.page_cache.lock().unwrap();
+            panic!("panic while holding the page cache")
+        }));
+        assert!(result.is_err());
+        assert!(bufmgr.page_cache.is_poisoned());
+
+        let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+        bufmgr.release_page(page_ptr).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_recover_from_poisoned_page_lock() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+        page_ptr
+            .with_write(|page| {
+                page.buffer_mut()[0] = 1;
+                Ok(())
+            })
+            .unwrap();
+
+        // panic while holding the write lock but before touching the buffer
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            page_ptr
+                .with_write::<_, ()>(|page| {
+                    page.set_dirty(true);
+                    panic!("panic before modifying the page")
+                })
+                .unwrap();
+        }));
+        assert!(result.is_err());
+        assert!(page_ptr.is_poisoned());
+
+        assert_eq!(page_ptr.with_read(|page| Ok(page.buffer()[0])).unwrap(), 1);
+        assert!(!page_ptr.is_poisoned());
+        page_ptr
+            .with_write(|page| {
+                page.buffer_mut()[0] = 2;
+                Ok(())
+            })
+            .unwrap();
+
+        // panic in the middle of modifying the buffer
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            page_ptr
+                .with_write::<_, ()>(|page| {
+                    page.buffer_mut()[0] = 3;
+                    panic!("panic while modifying the page")
+                })
+                .unwrap();
+        }));
+        assert!(result.is_err());
+
+        assert!(matches!(
+            page_ptr.with_read(|page| Ok(page.buffer()[0])),
+            Err(Error::InvalidState(_))
+        ));
+        assert!(matches!(
+            page_ptr.with_write(|_| Ok(())),
+            Err(Error::InvalidState(_))
+        ));
+        // the page can still be released
+        bufmgr.release_page(page_ptr).unwrap();
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_resize_buffer_pool() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new()
+            .root_path(db_dir.path())
+            .cache_capacity(4);
+        let db = DB::open(&config).unwrap();
+        let smgr = db.get_storage_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let bufmgr = db.get_buffer_manager();
+        let rel = shandle.file_ref();
+        let num_cached = || {
+            (0..8)
+                .filter(|i| bufmgr.is_cached(rel, ForkType::Main, *i))
+                .count()
+        };
+
+        for _ in 0..8 {
+            let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+        assert_eq!(num_cached(), 4);
+
+        bufmgr.resize(&db, 8).unwrap();
+        for i in 0..8 {
+            let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, i).unwrap();
+            page_ptr
+                .with_write(|page| {
+                    page.buffer_mut()[0] = i as u8 + 1;
+                    page.set_dirty(true);
+                    Ok(())
+                })
+                .unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+        assert_eq!(num_cached(), 8);
+
+        let page_ptrs = (5..8)
+            .map(|i| bufmgr.fetch_page(&db, &shandle, ForkType::Main, i).unwrap())
+            .collect::<Vec<_>>();
+        assert!(bufmgr.resize(&db, 2).is_err());
+        assert_eq!(num_cached(), 8);
+
+        // only the pinned pages are left and the evicted pages are written back
+        bufmgr.resize(&db, 3).unwrap();
+        assert_eq!(num_cached(), 3);
+        for (i, page_ptr) in (5..8).zip(page_ptrs) {
+            assert!(bufmgr.is_cached(rel, ForkType::Main, i));
+            assert_eq!(
+                page_ptr.with_read(|page| Ok(page.buffer()[0])).unwrap(),
+                i as u8 + 1
+            );
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+
+        for i in 0..8 {
+            let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, i).unwrap();
+            assert_eq!(
+                page_ptr.with_read(|page| Ok(page.buffer()[0])).unwrap(),
+                i as u8 + 1
+            );
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+        assert_eq!(num_cached(), 3);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_verify_page_checksum() {
+        use crate::storage::{DiskPageReader, DiskPageView, PAGE_SIZE};
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new()
+            .root_path(db_dir.path())
+            .cache_capacity(2);
+        let db = DB::open(&config).unwrap();
+        let smgr = db.get_storage_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+
+        let bufmgr = db.get_buffer_manager();
+        for i in 0..3 {
+            let page_ptr = bufmgr.new_page(&db, &shandle, ForkType::Main).unwrap();
+            page_ptr
+                .with_write(|page| {
+                    page.buffer_mut()[100] = i as u8 + 1;
+                    page.set_dirty(true);
+                    Ok(())
+                })
+                .unwrap();
+            bufmgr.release_page(page_ptr).unwrap();
+        }
+        // page 0 is evicted and written with its checksum
+        assert!(!bufmgr.is_cached(shandle.file_ref(), ForkType::Main, 0));
+
+        let mut buffer = [0u8; PAGE_SIZE];
+        smgr.read(&shandle, ForkType::Main, 0, &mut buffer).unwrap();
+        assert_eq!(buffer[100], 1);
+        assert!(DiskPageView::new(&buffer).verify_checksum());
+
+        buffer[200] ^= 0xff;
+        assert!(!DiskPageView::new(&buffer).verify_checksum());
+        smgr.write(&shandle, ForkType::Main, 0, &buffer).unwrap();
+
+        assert!(matches!(
+            bufmgr.fetch_page(&db, &shandle, ForkType::Main, 0),
+            Err(Error::DataCorrupted(_))
+        ));
+        assert!(!bufmgr.is_cached(shandle.file_ref(), ForkType::Main, 0));
+
+        // other pages can still be read
+        let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 1).unwrap();
+        assert_eq!(
+            page_ptr.with_read(|page| Ok(page.buffer()[100])).unwrap(),
+            2
+        );
+        bufmgr.release_page(page_ptr).unwrap();
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_reject_page_of_other_layout_version() {
+        use crate::storage::{
+            DiskPageReader, DiskPageView, DiskPageViewMut, DiskPageWriter, PageKind,
+            PAGE_LAYOUT_VERSION, PAGE_SIZE, P_VERSION,
+        };
+
+        let (db, db_dir) = get_temp_db();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        smgr.create(&shandle, ForkType::Main, false).unwrap();
+        smgr.zero_extend(&shandle, ForkType::Main).unwrap();
+        smgr.zero_extend(&shandle, ForkType::Main).unwrap();
+
+        let mut buffer = [0u8; PAGE_SIZE];
+        let mut page_view = DiskPageViewMut::new(&mut buffer);
+        page_view.set_page_kind(PageKind::Heap);
+        page_view.set_checksum();
+        smgr.write(&shandle, ForkType::Main, 0, &buffer).unwrap();
+
+        // a page written by a build with another layout
+        buffer[P_VERSION] = PAGE_LAYOUT_VERSION + 1;
+        DiskPageViewMut::new(&mut buffer).set_checksum();
+        smgr.write(&shandle, ForkType::Main, 1, &buffer).unwrap();
+
+        let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 0).unwrap();
+        assert_eq!(
+            page_ptr
+                .with_read(|page| Ok(DiskPageView::new(page.buffer()).get_page_version()))
+                .unwrap(),
+            PAGE_LAYOUT_VERSION
+        );
+        bufmgr.release_page(page_ptr).unwrap();
+
+        match bufmgr.fetch_page(&db, &shandle, ForkType::Main, 1) {
+            Err(Error::InvalidState(e)) => assert!(e.contains("layout version")),
+            _ => panic!("page of another layout version is accepted"),
+        }
+        assert!(!bufmgr.is_cached(shandle.file_ref(), ForkType::Main, 1));
 
         db_dir.close().unwrap();
     }
@@ -0,0 +1,312 @@
+use crate::{
+    storage::{consts::PAGE_SIZE, PageBuffer},
+    Error, Result,
+};
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{prelude::*, SeekFrom},
+    path::Path,
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const MAP_ENTRY_SIZE: usize = 12;
+
+/// Compression level of the pages, zstd's default.
+const COMPRESSION_LEVEL: i32 = 0;
+
+/// Compress a page with zstd.
+pub fn compress_page(page: &PageBuffer) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::compress(page, COMPRESSION_LEVEL)?)
+}
+
+pub fn decompress_page(data: &[u8], page: &mut PageBuffer) -> Result<()> {
+    match zstd::bulk::decompress_to_buffer(data, &mut page[..]) {
+        Ok(len) if len == PAGE_SIZE => Ok(()),
+        _ => Err(Error::DataCorrupted("invalid compressed page".to_owned())),
+    }
+}
+
+/// Location of a compressed page in the data file. A page with zero length is read as zeros.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Extent {
+    offset: u64,
+    len: u32,
+}
+
+/// Map from the logical page numbers of a compressed fork to the extents in the data file.
+///
+/// Pages are never overwritten in place unless their extent has not been synced yet, so the
+/// extents referenced by the map on disk stay valid until the map is synced again. The extents
+/// given up by the pages are reused for other pages once the map on disk no longer references
+/// them.
+pub struct PageMap {
+    map_file: File,
+    extents: Vec<Extent>,
+    // extents as of the last sync
+    synced_extents: Vec<Extent>,
+    // unused extents of the data file, sorted by offset
+    free_extents: Vec<Extent>,
+    // extents given up since the last sync, which are still referenced by the map on disk
+    released_extents: Vec<Extent>,
+}
+
+impl PageMap {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let map_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        Ok(Self {
+            map_file,
+            extents: Vec::new(),
+            synced_extents: Vec::new(),
+            free_extents: Vec::new(),
+            released_extents: Vec::new(),
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut map_file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut buf = Vec::new();
+        map_file.read_to_end(&mut buf)?;
+
+        let extents = buf
+            .chunks_exact(MAP_ENTRY_SIZE)
+            .map(|entry| Extent {
+                offset: LittleEndian::read_u64(&entry[..8]),
+                len: LittleEndian::read_u32(&entry[8..]),
+            })
+            .collect::<Vec<_>>();
+
+        // the gaps between the pages are free
+        let mut used_extents = extents
+            .iter()
+            .filter(|extent| extent.len > 0)
+            .copied()
+            .collect::<Vec<_>>();
+        used_extents.sort_by_key(|extent| extent.offset);
+
+        let mut page_map = Self {
+            map_file,
+            synced_extents: extents.clone(),
+            extents,
+            free_extents: Vec::new(),
+            released_extents: Vec::new(),
+        };
+
+        let mut end = 0;
+        for extent in used_extents {
+            while end < extent.offset {
+                let len = (extent.offset - end).min(PAGE_SIZE as u64);
+                page_map.free_extent(Extent {
+                    offset: end,
+                    len: len as u32,
+                });
+                end += len;
+            }
+            end = end.max(extent.offset + extent.len as u64);
+        }
+
+        Ok(page_map)
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.extents.len()
+    }
+
+    /// Read a page from the data file. Return false if the page is past the end of the fork.
+    pub fn read_page(
+        &self,
+        file: &mut File,
+        page_num: usize,
+        page: &mut PageBuffer,
+    ) -> Result<bool> {
+        let extent = match self.extents.get(page_num) {
+            Some(extent) => *extent,
+            None => return Ok(false),
+        };
+
+        if extent.len == 0 {
+            for b in page.iter_mut() {
+                *b = 0;
+            }
+        } else if extent.len as usize == PAGE_SIZE {
+            file.seek(SeekFrom::Start(extent.offset))?;
+            file.read_exact(page)?;
+        } else {
+            let mut data = vec![0u8; extent.len as usize];
+            file.seek(SeekFrom::Start(extent.offset))?;
+            file.read_exact(&mut data)?;
+            decompress_page(&data, page)?;
+        }
+
+        Ok(true)
+    }
+
+    pub fn write_page(
+        &mut self,
+        file: &mut File,
+        page_num: usize,
+        page: &PageBuffer,
+    ) -> Result<()> {
+        let compressed = compress_page(page)?;
+        // store the page uncompressed if it does not get smaller
+        let data = if compressed.len() < PAGE_SIZE {
+            &compressed[..]
+        } else {
+            &page[..]
+        };
+
+        if page_num >= self.extents.len() {
+            self.extents.resize(page_num + 1, Extent::default());
+        }
+
+        let extent = self.extents[page_num];
+        let synced = self.synced_extents.get(page_num) == Some(&extent);
+        let offset = if extent.len as usize >= data.len() && !synced {
+            if extent.len as usize > data.len() {
+                self.free_extent(Extent {
+                    offset: extent.offset + data.len() as u64,
+                    len: extent.len - data.len() as u32,
+                });
+            }
+            extent.offset
+        } else {
+            self.release_extent(page_num, extent);
+            match self.allocate_extent(data.len()) {
+                Some(offset) => offset,
+                None => file.seek(SeekFrom::End(0))?,
+            }
+        };
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+
+        let extent = Extent {
+            offset,
+            len: data.len() as u32,
+        };
+        let mut entry = [0u8; MAP_ENTRY_SIZE];
+        LittleEndian::write_u64(&mut entry[..8], extent.offset);
+        LittleEndian::write_u32(&mut entry[8..], extent.len);
+        self.map_file
+            .seek(SeekFrom::Start((page_num * MAP_ENTRY_SIZE) as u64))?;
+        self.map_file.write_all(&entry)?;
+
+        self.extents[page_num] = extent;
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, nr_pages: usize) -> Result<()> {
+        if self.extents.len() >= nr_pages {
+            self.map_file.set_len((nr_pages * MAP_ENTRY_SIZE) as u64)?;
+            for page_num in nr_pages..self.extents.len() {
+                self.release_extent(page_num, self.extents[page_num]);
+            }
+            self.extents.truncate(nr_pages);
+        }
+
+        Ok(())
+    }
+
+    /// Sync the map after the data file is synced.
+    pub fn sync(&mut self) -> Result<()> {
+        self.map_file.sync_data()?;
+        self.synced_extents = self.extents.clone();
+
+        let released = std::mem::take(&mut self.released_extents);
+        for extent in released {
+            self.free_extent(extent);
+        }
+        Ok(())
+    }
+
+    /// Give up the extent of a page. An extent that is not synced is free right away, otherwise
+    /// it is freed after the next sync.
+    fn release_extent(&mut self, page_num: usize, extent: Extent) {
+        if extent.len == 0 {
+            return;
+        }
+
+        if self.synced_extents.get(page_num) == Some(&extent) {
+            self.released_extents.push(extent);
+        } else {
+            self.free_extent(extent);
+        }
+    }
+
+    /// Add an extent to the free extents and merge it with its neighbours.
+    fn free_extent(&mut self, mut extent: Extent) {
+        let pos = self
+            .free_extents
+            .partition_point(|free| free.offset < extent.offset);
+
+        // a page never needs more than PAGE_SIZE bytes, so larger extents are not merged
+        if pos < self.free_extents.len() {
+            let next = self.free_extents[pos];
+            if extent.offset + extent.len as u64 == next.offset
+                && (extent.len + next.len) as usize <= PAGE_SIZE
+            {
+                extent.len += next.len;
+                self.free_extents.remove(pos);
+            }
+        }
+        if pos > 0 {
+            let prev = &mut self.free_extents[pos - 1];
+            if prev.offset + prev.len as u64 == extent.offset
+                && (prev.len + extent.len) as usize <= PAGE_SIZE
+            {
+                prev.len += extent.len;
+                return;
+            }
+        }
+
+        self.free_extents.insert(pos, extent);
+    }
+
+    /// Take `len` bytes from the first free extent that is large enough and return the offset.
+    fn allocate_extent(&mut self, len: usize) -> Option<u64> {
+        let pos = self
+            .free_extents
+            .iter()
+            .position(|free| free.len as usize >= len)?;
+        let free = &mut self.free_extents[pos];
+        let offset = free.offset;
+
+        if free.len as usize == len {
+            self.free_extents.remove(pos);
+        } else {
+            free.offset += len as u64;
+            free.len -= len as u32;
+        }
+
+        Some(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_compress_page() {
+        let mut page = [0u8; PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate().take(1000) {
+            *b = (i % 7) as u8;
+        }
+        page[2000..2300].copy_from_slice(&[0xab; 300]);
+
+        let data = compress_page(&page).unwrap();
+        assert!(data.len() < PAGE_SIZE / 2);
+
+        let mut output = [1u8; PAGE_SIZE];
+        decompress_page(&data, &mut output).unwrap();
+        assert_eq!(&page[..], &output[..]);
+
+        assert!(decompress_page(&data[..data.len() - 1], &mut output).is_err());
+    }
+}
@@ -1,27 +1,43 @@
 pub mod consts;
+pub mod temp_files;
 
 mod buffer_manager;
+mod compression;
 mod page_cache;
 mod storage_manager;
 mod table;
 
-use crate::{wal::LogPointer, Error, Relation, Result, OID};
+use crate::{
+    concurrency::XID,
+    wal::{LogPointer, WalLogRecord},
+    Error, Relation, Result, DB, OID,
+};
 
 use std::{
     fmt,
+    fs::File,
     ops::Deref,
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    path::Path,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
 use self::consts::PAGE_SIZE;
 
 pub use self::{
-    buffer_manager::BufferManager,
-    storage_manager::{ForkType, StorageHandle, StorageManager},
-    table::{ScanDirection, Table, TablePtr, TableScanIterator, Tuple, TuplePtr},
+    buffer_manager::{BufferManager, ResidentPageInfo},
+    storage_manager::{
+        ForkType, RelationFileMap, StorageHandle, StorageManager, TruncatedRelation,
+    },
+    table::{
+        ScanDirection, Table, TablePtr, TableScanIterator, TableScanMark, TableScanPredicate,
+        Tuple, TuplePtr,
+    },
+    temp_files::{TempFile, TempFileManager},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::{crc32, Hasher32};
+use ouroboros::self_referencing;
 
 use serde::{Deserialize, Serialize};
 
@@ -43,26 +59,17 @@ pub struct Page {
     file_ref: RelFileRef,
     fork: ForkType,
     page_num: usize,
-    slot: usize,
     buffer: PageBuffer,
-    pin_count: i32,
     dirty: bool,
+    // set when the buffer is borrowed mutably by the current write lock holder
+    buffer_borrowed: bool,
 }
 
 impl Page {
-    pub fn pin(&mut self) -> i32 {
-        self.pin_count += 1;
-        self.pin_count
-    }
-
-    pub fn unpin(&mut self) -> i32 {
-        self.pin_count -= 1;
-        self.pin_count
-    }
-
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
+
     pub fn set_dirty(&mut self, dirty: bool) {
         self.dirty = dirty;
     }
@@ -71,33 +78,63 @@ impl Page {
         (self.file_ref, self.fork, self.page_num)
     }
 
-    pub fn set_fork_and_num(&mut self, file_ref: RelFileRef, fork: ForkType, page_num: usize) {
+    fn set_fork_and_num(&mut self, file_ref: RelFileRef, fork: ForkType, page_num: usize) {
         self.file_ref = file_ref;
         self.fork = fork;
         self.page_num = page_num;
     }
 
-    pub fn slot(&self) -> usize {
-        self.slot
-    }
-
     pub fn buffer(&self) -> &PageBuffer {
         &self.buffer
     }
 
     pub fn buffer_mut(&mut self) -> &mut PageBuffer {
+        self.buffer_borrowed = true;
         &mut self.buffer
     }
 }
 
+/// State of a cached page that the page cache reads and changes without the page lock, so that
+/// pinning a page never waits for the holder of its lock
+struct PageHeader {
+    file_ref: RelFileRef,
+    fork: ForkType,
+    page_num: usize,
+    slot: usize,
+    pin_count: i32,
+    // number of times the page is fetched since it is loaded into the slot
+    usage_count: u32,
+}
+
+impl PageHeader {
+    fn pin(&mut self) -> i32 {
+        self.pin_count += 1;
+        self.pin_count
+    }
+
+    fn unpin(&mut self) -> i32 {
+        self.pin_count -= 1;
+        self.pin_count
+    }
+
+    fn get_fork_and_num(&self) -> (RelFileRef, ForkType, usize) {
+        (self.file_ref, self.fork, self.page_num)
+    }
+}
+
+struct PageSlot {
+    page: RwLock<Page>,
+    header: Mutex<PageHeader>,
+}
+
 #[derive(Clone)]
-pub struct PagePtr(Arc<RwLock<Page>>);
+pub struct PagePtr(Arc<PageSlot>);
 
 impl Deref for PagePtr {
     type Target = RwLock<Page>;
 
     fn deref(&self) -> &RwLock<Page> {
-        &self.0
+        &self.0.page
     }
 }
 
@@ -106,45 +143,126 @@ pub type PageWriteGuard<'a> = RwLockWriteGuard<'a, Page>;
 
 impl PagePtr {
     pub fn new(file_ref: RelFileRef, fork: ForkType, page_num: usize, slot: usize) -> Self {
-        Self(Arc::new(RwLock::new(Page {
-            file_ref,
-            fork,
-            page_num,
-            slot,
-            buffer: [0u8; PAGE_SIZE],
-            pin_count: 0,
-            dirty: false,
-        })))
+        Self(Arc::new(PageSlot {
+            page: RwLock::new(Page {
+                file_ref,
+                fork,
+                page_num,
+                buffer: [0u8; PAGE_SIZE],
+                dirty: false,
+                buffer_borrowed: false,
+            }),
+            header: Mutex::new(PageHeader {
+                file_ref,
+                fork,
+                page_num,
+                slot,
+                pin_count: 0,
+                usage_count: 0,
+            }),
+        }))
+    }
+
+    /// Take a read lock on the page. A lock poisoned by a thread that panicked before touching
+    /// the buffer is recovered, otherwise the page can no longer be trusted.
+    pub fn lock_read(&self) -> Result<PageReadGuard<'_>> {
+        match self.0.page.read() {
+            Ok(guard) => Ok(guard),
+            Err(poisoned) => {
+                let guard = poisoned.into_inner();
+                Self::check_poisoned_page(&guard)?;
+                self.0.page.clear_poison();
+                Ok(guard)
+            }
+        }
+    }
+
+    /// Take a write lock on the page, recovering from poisoning like `lock_read`.
+    pub fn lock_write(&self) -> Result<PageWriteGuard<'_>> {
+        let mut guard = match self.0.page.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                let guard = poisoned.into_inner();
+                Self::check_poisoned_page(&guard)?;
+                self.0.page.clear_poison();
+                guard
+            }
+        };
+
+        // the previous holder released the lock normally so its changes are complete
+        guard.buffer_borrowed = false;
+        Ok(guard)
+    }
+
+    fn check_poisoned_page(page: &Page) -> Result<()> {
+        if page.buffer_borrowed {
+            Err(Error::InvalidState(format!(
+                "page {} of relation {} was being modified by a panicked thread",
+                page.page_num, page.file_ref
+            )))
+        } else {
+            Ok(())
+        }
     }
 
     pub fn with_read<F, R>(&self, f: F) -> Result<R>
     where
         F: Fn(&Page) -> Result<R>,
     {
-        let guard = self.0.read().unwrap();
-        f(&*guard)
+        let guard = self.lock_read()?;
+        f(&guard)
     }
 
     pub fn with_write<F, R>(&self, f: F) -> Result<R>
     where
         F: FnOnce(&mut Page) -> Result<R>,
     {
-        let mut guard = self.0.write().unwrap();
-        f(&mut *guard)
+        let mut guard = self.lock_write()?;
+        f(&mut guard)
+    }
+
+    /// Access the page header (pin count, tag, etc.) without taking the page lock.
+    pub(self) fn with_header<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut PageHeader) -> R,
+    {
+        let mut guard = self
+            .0
+            .header
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut guard)
+    }
+
+    /// Assign the unpinned page to another tag.
+    pub(self) fn set_fork_and_num(
+        &self,
+        page: &mut Page,
+        file_ref: RelFileRef,
+        fork: ForkType,
+        page_num: usize,
+    ) {
+        page.set_fork_and_num(file_ref, fork, page_num);
+        self.with_header(|header| {
+            header.file_ref = file_ref;
+            header.fork = fork;
+            header.page_num = page_num;
+            header.usage_count = 0;
+        });
     }
 
     pub(self) fn pin(self) -> Result<(i32, PinnedPagePtr)> {
-        let pin_count = self.with_write(|page| Ok(page.pin()))?;
+        let pin_count = self.with_header(|header| header.pin());
         Ok((pin_count, PinnedPagePtr(self)))
     }
 
-    pub(self) fn pin_if<F>(self, f: F) -> Result<Option<(i32, PinnedPagePtr)>>
-    where
-        F: FnOnce(&Page) -> bool,
-    {
-        let pin_count =
-            self.with_write(|page| Ok(if f(&page) { Some(page.pin()) } else { None }))?;
-        Ok(pin_count.map(|pin_count| (pin_count, PinnedPagePtr(self))))
+    /// Pin the page for a fetch and count the use of the page.
+    pub(self) fn pin_for_use(self) -> Result<(i32, PinnedPagePtr)> {
+        let pin_count = self.with_header(|header| {
+            header.usage_count = header.usage_count.saturating_add(1);
+            header.pin()
+        });
+        Ok((pin_count, PinnedPagePtr(self)))
     }
 }
 
@@ -155,19 +273,27 @@ pub trait RelationWithStorage: Relation {
         self.with_storage(smgr, |storage| smgr.create(storage, ForkType::Main, false))
     }
 
+    fn create_compressed_storage(&self, smgr: &StorageManager) -> Result<()> {
+        self.with_storage(smgr, |storage| {
+            smgr.create_compressed(storage, ForkType::Main)
+        })
+    }
+
     fn with_storage<F, R>(&self, smgr: &StorageManager, f: F) -> Result<R>
     where
         F: FnOnce(&StorageHandle) -> Result<R>,
     {
+        // the file changes when the storage of the relation is replaced
+        let file_ref = smgr.relation_file(RelFileRef {
+            db: self.rel_db(),
+            rel_id: self.rel_id(),
+        });
         let mut guard = self.get_storage_handle().lock().unwrap();
 
         match &*guard {
-            Some(shandle) => f(shandle),
-            None => {
-                let shandle = smgr.open(RelFileRef {
-                    db: self.rel_db(),
-                    rel_id: self.rel_id(),
-                })?;
+            Some(shandle) if shandle.file_ref() == file_ref => f(shandle),
+            _ => {
+                let shandle = smgr.open(file_ref)?;
                 *guard = Some(shandle.clone());
                 f(&shandle)
             }
@@ -179,6 +305,37 @@ pub trait RelationWithStorage: Relation {
             smgr.file_size_in_page(storage, ForkType::Main)
         })
     }
+
+    /// Get the number of pages in all forks, e.g. including the init fork of an unlogged relation.
+    fn total_size_in_pages(&self, smgr: &StorageManager) -> Result<usize> {
+        self.with_storage(smgr, |storage| smgr.total_size_in_pages(storage))
+    }
+
+    /// Truncate the main fork to `nr_pages`. The truncation is logged and flushed before the file
+    /// is shrunk so that pages redone after a crash are truncated again. None of the truncated
+    /// pages can be pinned.
+    fn truncate_storage(&self, db: &DB, xid: XID, nr_pages: usize) -> Result<()> {
+        let smgr = db.get_storage_manager();
+
+        self.with_storage(smgr, |storage| {
+            let file_ref = storage.file_ref();
+            // unlogged relations are reset on crash recovery
+            if !smgr.is_unlogged(file_ref) {
+                let wal = db.get_wal();
+                let truncate_log =
+                    WalLogRecord::create_truncate_log(file_ref, ForkType::Main, nr_pages);
+                let (_, lsn) = wal.append(xid, truncate_log)?;
+                wal.flush(Some(lsn))?;
+            }
+
+            db.get_buffer_manager().invalidate_truncated_pages(
+                file_ref,
+                ForkType::Main,
+                nr_pages,
+            )?;
+            smgr.truncate(storage, ForkType::Main, nr_pages)
+        })
+    }
 }
 
 pub struct PinnedPagePtr(PagePtr);
@@ -191,6 +348,54 @@ impl Deref for PinnedPagePtr {
     }
 }
 
+impl PinnedPagePtr {
+    /// Take a read lock on the page that is held until the returned lock is dropped. The page is
+    /// unpinned if the lock cannot be taken.
+    pub fn read_owned(self) -> Result<OwningPageReadLock> {
+        OwningPageReadLockTryBuilder {
+            page_ptr: self,
+            page_guard_builder: |page_ptr| page_ptr.lock_read(),
+        }
+        .try_build_or_recover()
+        .map_err(|(e, heads)| {
+            heads.page_ptr.with_header(|header| header.unpin());
+            e
+        })
+    }
+
+    /// Take a write lock on the page that is held until the returned lock is dropped. The page is
+    /// unpinned if the lock cannot be taken.
+    pub fn write_owned(self) -> Result<OwningPageWriteLock> {
+        OwningPageWriteLockTryBuilder {
+            page_ptr: self,
+            page_guard_builder: |page_ptr| page_ptr.lock_write(),
+        }
+        .try_build_or_recover()
+        .map_err(|(e, heads)| {
+            heads.page_ptr.with_header(|header| header.unpin());
+            e
+        })
+    }
+}
+
+/// Own a pinned page and a read lock on that page
+#[self_referencing(pub_extras)]
+pub struct OwningPageReadLock {
+    pub page_ptr: PinnedPagePtr,
+    #[borrows(page_ptr)]
+    #[covariant]
+    pub page_guard: PageReadGuard<'this>,
+}
+
+/// Own a pinned page and a write lock on that page
+#[self_referencing(pub_extras)]
+pub struct OwningPageWriteLock {
+    pub page_ptr: PinnedPagePtr,
+    #[borrows(page_ptr)]
+    #[covariant]
+    pub page_guard: PageWriteGuard<'this>,
+}
+
 impl Clone for PinnedPagePtr {
     fn clone(&self) -> Self {
         let page_ptr = self.0.clone();
@@ -199,7 +404,9 @@ impl Clone for PinnedPagePtr {
     }
 }
 
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Debug, Default, Copy, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
 pub struct ItemPointer {
     pub page_num: usize,
     pub offset: usize,
@@ -230,7 +437,59 @@ impl ItemPointer {
 }
 
 const P_LSN: usize = 0;
-const P_PAYLOAD: usize = P_LSN + 8;
+const P_CHECKSUM: usize = P_LSN + 8;
+const P_KIND: usize = P_CHECKSUM + 4;
+const P_VERSION: usize = P_KIND + 1;
+// two bytes reserved after the version
+const P_PAYLOAD: usize = P_KIND + 4;
+
+/// Version of the page layout written by `set_page_kind`
+///
+/// 2: heap pages have a 4-byte header with the vacuum horizon before the items
+/// 3: heap tuples end with the location of their newer version
+pub const PAGE_LAYOUT_VERSION: u8 = 3;
+
+const PAGE_KIND_HEAP: u8 = 0x68;
+const PAGE_KIND_BTREE: u8 = 0x62;
+
+/// Kind of a page, set by the access method when the page is initialized
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageKind {
+    /// The page is never initialized, e.g. a zero page from extending the file
+    Uninitialized,
+    Heap,
+    BTree,
+    Unknown(u8),
+}
+
+#[cfg(test)]
+thread_local! {
+    static DIR_SYNC_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Sync a directory so that the entries of the files created or removed in it are durable.
+pub(crate) fn sync_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    File::open(path)?.sync_all()?;
+
+    #[cfg(test)]
+    DIR_SYNC_COUNT.with(|count| count.set(count.get() + 1));
+
+    Ok(())
+}
+
+/// Number of directories synced by the current thread.
+#[cfg(test)]
+pub(crate) fn dir_sync_count() -> usize {
+    DIR_SYNC_COUNT.with(|count| count.get())
+}
+
+/// Checksum of the page buffer without the checksum field.
+fn page_checksum(buf: &[u8; PAGE_SIZE]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(&buf[..P_CHECKSUM]);
+    digest.write(&buf[P_PAYLOAD..]);
+    digest.sum32()
+}
 
 pub trait DiskPageReader {
     fn get_page_buffer(&self) -> &[u8; PAGE_SIZE];
@@ -243,6 +502,42 @@ pub trait DiskPageReader {
         let buf = self.get_page_buffer();
         (&buf[P_LSN..]).read_u64::<LittleEndian>().unwrap() as LogPointer
     }
+
+    fn get_checksum(&self) -> u32 {
+        let buf = self.get_page_buffer();
+        (&buf[P_CHECKSUM..]).read_u32::<LittleEndian>().unwrap()
+    }
+
+    fn page_kind(&self) -> PageKind {
+        match self.get_page_buffer()[P_KIND] {
+            0 => PageKind::Uninitialized,
+            PAGE_KIND_HEAP => PageKind::Heap,
+            PAGE_KIND_BTREE => PageKind::BTree,
+            kind => PageKind::Unknown(kind),
+        }
+    }
+
+    fn get_page_version(&self) -> u8 {
+        self.get_page_buffer()[P_VERSION]
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        page_checksum(self.get_page_buffer())
+    }
+
+    /// Check the checksum stored in the page. Pages that are never written are all zeros and
+    /// have no checksum.
+    fn verify_checksum(&self) -> bool {
+        self.get_checksum() == self.compute_checksum()
+            || self.get_page_buffer().iter().all(|b| *b == 0)
+    }
+
+    /// Check that an initialized page has the current layout version. Pages that are never
+    /// initialized by an access method have no version.
+    fn verify_page_version(&self) -> bool {
+        self.page_kind() == PageKind::Uninitialized
+            || self.get_page_version() == PAGE_LAYOUT_VERSION
+    }
 }
 
 pub trait DiskPageWriter {
@@ -254,7 +549,29 @@ pub trait DiskPageWriter {
 
     fn set_lsn(&mut self, lsn: LogPointer) {
         (&mut self.get_page_buffer_mut()[P_LSN..])
-            .write_u64::<LittleEndian>(lsn as u64)
+            .write_u64::<LittleEndian>(lsn)
+            .unwrap();
+    }
+
+    /// Mark the page as initialized by an access method with the current layout version.
+    fn set_page_kind(&mut self, kind: PageKind) {
+        let kind = match kind {
+            PageKind::Uninitialized => 0,
+            PageKind::Heap => PAGE_KIND_HEAP,
+            PageKind::BTree => PAGE_KIND_BTREE,
+            PageKind::Unknown(kind) => kind,
+        };
+
+        let buf = self.get_page_buffer_mut();
+        buf[P_KIND] = kind;
+        buf[P_VERSION] = PAGE_LAYOUT_VERSION;
+    }
+
+    /// Store the checksum of the page. This is done right before the page is written to disk.
+    fn set_checksum(&mut self) {
+        let checksum = page_checksum(self.get_page_buffer_mut());
+        (&mut self.get_page_buffer_mut()[P_CHECKSUM..])
+            .write_u32::<LittleEndian>(checksum)
             .unwrap();
     }
 }
@@ -291,6 +608,12 @@ pub struct DiskPageViewMut<'a> {
     buffer: &'a mut [u8; PAGE_SIZE],
 }
 
+impl<'a> DiskPageViewMut<'a> {
+    pub fn new(buffer: &'a mut [u8; PAGE_SIZE]) -> Self {
+        Self { buffer }
+    }
+}
+
 impl<'a> DiskPageReader for DiskPageViewMut<'a> {
     fn get_page_buffer(&self) -> &[u8; PAGE_SIZE] {
         self.buffer
@@ -313,7 +636,10 @@ pub struct LinePointer {
     len: u16,
 }
 
-const LINE_POINTER_SIZE: usize = 4;
+pub(crate) const LINE_POINTER_SIZE: usize = 4;
+
+/// Size of the largest item that fits in an empty item page
+pub(crate) const MAX_ITEM_SIZE: usize = PAGE_SIZE - P_PAYLOAD - P_POINTERS - LINE_POINTER_SIZE;
 
 /// Item-based interface for pages
 ///
@@ -338,11 +664,7 @@ pub trait ItemPageReader {
     fn get_free_space(&self) -> usize {
         let size = self.get_upper() as usize - self.get_lower() as usize;
 
-        if size < LINE_POINTER_SIZE {
-            0
-        } else {
-            size - LINE_POINTER_SIZE
-        }
+        size.saturating_sub(LINE_POINTER_SIZE)
     }
 
     fn num_line_pointers(&self) -> usize {
@@ -466,7 +788,7 @@ pub trait ItemPageWriter: ItemPageReader {
             lower += LINE_POINTER_SIZE as u16;
         }
 
-        (&mut self.get_item_page_payload_mut()[upper as usize..upper as usize + item.len()])
+        self.get_item_page_payload_mut()[upper as usize..upper as usize + item.len()]
             .copy_from_slice(item);
 
         self.set_lower(lower);
@@ -483,7 +805,7 @@ pub trait ItemPageWriter: ItemPageReader {
                 "tuple size does not match".to_owned(),
             ));
         }
-        (&mut self.get_item_page_payload_mut()[off as usize..off as usize + len as usize])
+        self.get_item_page_payload_mut()[off as usize..off as usize + len as usize]
             .copy_from_slice(item);
 
         Ok(())
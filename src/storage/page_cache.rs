@@ -1,22 +1,35 @@
 use crate::{
     storage::{
-        DiskPageReader, DiskPageView, ForkType, Page, PagePtr, PinnedPagePtr, RelFileRef,
-        StorageHandle, PAGE_SIZE,
+        DiskPageReader, DiskPageView, DiskPageViewMut, DiskPageWriter, ForkType, Page, PagePtr,
+        PinnedPagePtr, RelFileRef, ResidentPageInfo, StorageHandle, PAGE_LAYOUT_VERSION,
     },
-    Error, Result, DB,
+    Error, Result, DB, OID,
 };
 
 use lru::LruCache;
-use std::{collections::HashMap, vec::Vec};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::TryLockError,
+    vec::Vec,
+};
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
 struct PageTag(RelFileRef, ForkType, usize);
 
+/// Cache of the pages in a fixed pool of slots
+///
+/// `page_hash` maps a tag to the slot of the page only while the page in the slot holds the tag,
+/// so the entry is changed together with the tag of the page. `lru` holds the unpinned pages
+/// that can be evicted.
 pub struct PageCache {
     lru: LruCache<PageTag, usize>,
     page_hash: HashMap<PageTag, usize>,
     page_pool: Vec<PagePtr>,
     cache_capacity: usize,
+    // pages that are never chosen as eviction victims
+    resident_pages: HashSet<PageTag>,
+    // slots of invalidated pages that can be reused
+    free_slots: Vec<usize>,
 }
 
 impl PageCache {
@@ -26,6 +39,8 @@ impl PageCache {
             page_hash: HashMap::new(),
             page_pool: Vec::new(),
             cache_capacity,
+            resident_pages: HashSet::new(),
+            free_slots: Vec::new(),
         }
     }
 
@@ -39,7 +54,33 @@ impl PageCache {
     ) -> Result<PagePtr> {
         let tag = PageTag(rel, fork, page_num);
 
-        if self.page_pool.len() < self.cache_capacity {
+        // every tag has at most one slot in the page hash
+        if let Some(slot) = self.page_hash.get(&tag) {
+            return Err(Error::InvalidState(format!(
+                "page {} of relation {} is already cached in slot {}",
+                page_num, rel, slot
+            )));
+        }
+
+        if let Some(slot) = self.free_slots.pop() {
+            let page_ptr = self.page_pool[slot].clone();
+            let old_tag = Self::get_tag(&page_ptr);
+            if self.page_hash.get(&old_tag) == Some(&slot) {
+                self.free_slots.push(slot);
+                return Err(Error::InvalidState(format!(
+                    "free slot {} is still cached for page {} of relation {}",
+                    slot, old_tag.2, old_tag.0
+                )));
+            }
+
+            page_ptr.with_write(|page| {
+                page_ptr.set_fork_and_num(page, tag.0, tag.1, tag.2);
+                Ok(())
+            })?;
+            self.page_hash.insert(tag, slot);
+
+            Ok(page_ptr)
+        } else if self.page_pool.len() < self.cache_capacity {
             let slot = self.page_pool.len();
             let page_ptr = PagePtr::new(rel, fork, page_num, slot);
             self.page_pool.push(page_ptr.clone());
@@ -47,42 +88,89 @@ impl PageCache {
 
             Ok(page_ptr)
         } else {
-            match self.evict() {
-                Some(page_ptr) => {
-                    page_ptr.with_write(|mut page| {
+            match self.lru.pop_lru() {
+                Some((victim_tag, victim)) => {
+                    let page_ptr = self.page_pool[victim].clone();
+                    // the victim must be cached under the tag it is evicted with, or the entry
+                    // of its actual tag would be left behind
+                    if Self::get_tag(&page_ptr) != victim_tag
+                        || self.page_hash.get(&victim_tag) != Some(&victim)
+                    {
+                        // keep the slot evictable instead of leaking it
+                        self.lru.put(victim_tag, victim);
+                        return Err(Error::InvalidState(format!(
+                            "evicted slot {} is not cached for page {} of relation {}",
+                            victim, victim_tag.2, victim_tag.0
+                        )));
+                    }
+
+                    let result = page_ptr.with_write(|page| {
                         if page.is_dirty() {
-                            Self::flush_page(db, &mut page)?;
+                            Self::flush_page(db, page)?;
                         }
 
-                        page.set_fork_and_num(tag.0, tag.1, tag.2);
-                        self.page_hash.insert(tag, page.slot());
+                        page_ptr.set_fork_and_num(page, tag.0, tag.1, tag.2);
                         Ok(())
-                    })?;
+                    });
+
+                    if let Err(e) = result {
+                        // the victim keeps its tag and stays cached so that its changes are
+                        // written later
+                        self.lru.put(victim_tag, victim);
+                        return Err(e);
+                    }
 
-                    Ok(page_ptr.clone())
+                    self.page_hash.remove(&victim_tag);
+                    self.page_hash.insert(tag, victim);
+
+                    Ok(page_ptr)
                 }
-                None => Err(Error::OutOfMemory),
+                None => Err(Error::BufferPoolFull(format!(
+                    "no page to evict, {} of {} pages are pinned",
+                    self.get_pinned_pages().len(),
+                    self.cache_capacity
+                ))),
             }
         }
     }
 
+    fn get_tag(page_ptr: &PagePtr) -> PageTag {
+        page_ptr.with_header(|header| {
+            let (rel, fork, page_num) = header.get_fork_and_num();
+            PageTag(rel, fork, page_num)
+        })
+    }
+
+    /// Allocate a buffer for a page just appended to the relation with `zero_extend`.
     pub fn new_page(
         &mut self,
         db: &DB,
-        shandle: &StorageHandle,
         rel: RelFileRef,
         fork: ForkType,
+        page_num: usize,
     ) -> Result<PinnedPagePtr> {
-        let smgr = db.get_storage_manager();
-        let page_num = smgr.file_size_in_page(shandle, fork)?;
-        let temp_buf = [0u8; PAGE_SIZE];
-        smgr.write(shandle, fork, page_num, &temp_buf)?;
-        let page_ptr = self.alloc_page(db, rel, fork, page_num)?;
+        let tag = PageTag(rel, fork, page_num);
 
-        let (_, pinned_page) = page_ptr.pin()?;
+        // the file is extended before the cache is locked, so a concurrent reader may have cached
+        // the new page already. it is still a zero page as only the extending thread initializes
+        // it
+        if let Some(slot) = self.page_hash.get(&tag) {
+            let (pin_count, pinned_page) = self.page_pool[*slot].clone().pin_for_use()?;
+
+            if pin_count == 1 {
+                self.lru.pop(&tag);
+            }
+
+            return Ok(pinned_page);
+        }
+
+        let page_ptr = self.alloc_page(db, rel, fork, page_num)?;
+        let (_, pinned_page) = page_ptr.pin_for_use()?;
         Ok(pinned_page)
     }
 
+    /// Get a page from the cache or read it from disk. If `zero_past_eof` is set, pages past the
+    /// end of file are read as zero pages.
     pub fn fetch_page(
         &mut self,
         db: &DB,
@@ -90,14 +178,19 @@ impl PageCache {
         rel: RelFileRef,
         fork: ForkType,
         page_num: usize,
+        zero_past_eof: bool,
     ) -> Result<PinnedPagePtr> {
         let tag = PageTag(rel, fork, page_num);
 
         match self.page_hash.get(&tag) {
             Some(slot) => {
                 let page_ptr = self.page_pool[*slot].clone();
+                debug_assert!(
+                    page_ptr.with_header(|header| header.get_fork_and_num())
+                        == (tag.0, tag.1, tag.2)
+                );
 
-                let (pin_count, pinned_page) = page_ptr.pin()?;
+                let (pin_count, pinned_page) = page_ptr.pin_for_use()?;
 
                 if pin_count == 1 {
                     self.lru.pop(&tag);
@@ -108,9 +201,42 @@ impl PageCache {
             None => {
                 let page_ptr = self.alloc_page(db, rel, fork, page_num)?;
                 let smgr = db.get_storage_manager();
-                page_ptr
-                    .with_write(|page| smgr.read(shandle, fork, page_num, page.buffer_mut()))?;
-                let (_, pinned_page) = page_ptr.pin()?;
+                let result = page_ptr.with_write(|page| {
+                    if zero_past_eof {
+                        smgr.read_or_zero(shandle, fork, page_num, page.buffer_mut())?;
+                    } else {
+                        smgr.read(shandle, fork, page_num, page.buffer_mut())?;
+                    }
+
+                    let page_view = DiskPageView::new(page.buffer());
+                    if !page_view.verify_checksum() {
+                        return Err(Error::DataCorrupted(format!(
+                            "page {} of relation {} has an invalid checksum",
+                            page_num, rel
+                        )));
+                    }
+
+                    if !page_view.verify_page_version() {
+                        return Err(Error::InvalidState(format!(
+                            "page {} of relation {} has layout version {}, but this build uses {}",
+                            page_num,
+                            rel,
+                            page_view.get_page_version(),
+                            PAGE_LAYOUT_VERSION
+                        )));
+                    }
+
+                    Ok(())
+                });
+
+                if let Err(e) = result {
+                    if let Some(slot) = self.page_hash.remove(&tag) {
+                        self.free_slots.push(slot);
+                    }
+                    return Err(e);
+                }
+
+                let (_, pinned_page) = page_ptr.pin_for_use()?;
 
                 Ok(pinned_page)
             }
@@ -118,51 +244,259 @@ impl PageCache {
     }
 
     pub fn release_page(&mut self, page_ptr: PinnedPagePtr) -> Result<()> {
-        page_ptr.with_write(|page| {
-            let pin_count = page.unpin();
-            let (file_ref, fork, page_num) = page.get_fork_and_num();
-            let slot = page.slot();
+        page_ptr.with_header(|header| {
+            let pin_count = header.unpin();
+            let (file_ref, fork, page_num) = header.get_fork_and_num();
+            let slot = header.slot;
 
-            if pin_count == 0 {
-                self.lru.put(PageTag(file_ref, fork, page_num), slot);
+            let tag = PageTag(file_ref, fork, page_num);
+            if pin_count == 0 && !self.resident_pages.contains(&tag) {
+                self.lru.put(tag, slot);
             }
 
             Ok(())
         })
     }
 
+    /// Pin the pages that may be dirty. A page locked by others is pinned without checking the
+    /// dirty flag, as the lock holder may wait for the page cache.
     pub fn get_dirty_pages(&mut self) -> Vec<PinnedPagePtr> {
         let lru = &mut self.lru;
         self.page_pool
             .iter()
-            .filter_map(|page_ptr| {
-                page_ptr
-                    .clone()
-                    .pin_if(Page::is_dirty)
-                    .unwrap()
-                    .map(|(pin_count, pinned_page)| {
-                        if pin_count == 1 {
-                            let (rel, fork, num) = pinned_page
-                                .with_read(|page| Ok(page.get_fork_and_num()))
-                                .unwrap();
-                            let tag = PageTag(rel, fork, num);
-                            lru.pop(&tag);
-                        }
+            .filter(|page_ptr| match page_ptr.try_read() {
+                Ok(page) => page.is_dirty(),
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().is_dirty(),
+                Err(TryLockError::WouldBlock) => true,
+            })
+            .map(|page_ptr| {
+                let (pin_count, pinned_page) = page_ptr.clone().pin().unwrap();
+                if pin_count == 1 {
+                    lru.pop(&Self::get_tag(&pinned_page));
+                }
 
-                        pinned_page
-                    })
+                pinned_page
             })
             .collect()
     }
-    fn evict(&mut self) -> Option<PagePtr> {
-        match self.lru.pop_lru() {
-            Some((tag, victim)) => {
-                let page_ptr = self.page_pool[victim].clone();
-                self.page_hash.remove(&tag);
-                Some(page_ptr)
+    /// Keep the page in the cache once it is loaded.
+    pub fn mark_resident(&mut self, rel: RelFileRef, fork: ForkType, page_num: usize) {
+        let tag = PageTag(rel, fork, page_num);
+        self.lru.pop(&tag);
+        self.resident_pages.insert(tag);
+    }
+
+    /// Allow the page to be evicted again after it is released next time.
+    pub fn unmark_resident(&mut self, rel: RelFileRef, fork: ForkType, page_num: usize) {
+        self.resident_pages.remove(&PageTag(rel, fork, page_num));
+    }
+
+    pub fn is_cached(&self, rel: RelFileRef, fork: ForkType, page_num: usize) -> bool {
+        self.page_hash.contains_key(&PageTag(rel, fork, page_num))
+    }
+
+    /// Drop all cached pages of a relation without writing them back. None of the pages can be
+    /// pinned.
+    pub fn invalidate_relation(&mut self, rel: RelFileRef) -> Result<()> {
+        self.invalidate_pages(|tag| tag.0 == rel)
+    }
+
+    /// Drop all cached pages of the relations in a database without writing them back.
+    pub fn invalidate_database(&mut self, db: OID) -> Result<()> {
+        self.invalidate_pages(|tag| tag.0.db == db)
+    }
+
+    /// Drop the cached pages of a relation fork past `nr_pages` without writing them back.
+    pub fn invalidate_truncated_pages(
+        &mut self,
+        rel: RelFileRef,
+        fork: ForkType,
+        nr_pages: usize,
+    ) -> Result<()> {
+        self.invalidate_pages(|tag| tag.0 == rel && tag.1 == fork && tag.2 >= nr_pages)
+    }
+
+    fn invalidate_pages<F>(&mut self, matches: F) -> Result<()>
+    where
+        F: Fn(&PageTag) -> bool,
+    {
+        let tags = self
+            .page_hash
+            .keys()
+            .filter(|tag| matches(tag))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for tag in tags {
+            let slot = self.page_hash[&tag];
+            if self.page_pool[slot].with_header(|header| header.pin_count) > 0 {
+                return Err(Error::InvalidState(format!(
+                    "page {} of relation {} is still pinned",
+                    tag.2, tag.0
+                )));
             }
-            None => None,
+
+            self.page_pool[slot].with_write(|page| {
+                page.set_dirty(false);
+                Ok(())
+            })?;
+
+            self.lru.pop(&tag);
+            self.page_hash.remove(&tag);
+            self.free_slots.push(slot);
         }
+
+        self.resident_pages.retain(|tag| !matches(tag));
+
+        Ok(())
+    }
+
+    /// Return the cached pages that are pinned and their pin counts.
+    pub fn get_pinned_pages(&self) -> Vec<(RelFileRef, ForkType, usize, i32)> {
+        let mut pinned_pages = self
+            .page_hash
+            .iter()
+            .filter_map(|(tag, slot)| {
+                let pin_count = self.page_pool[*slot].with_header(|header| header.pin_count);
+
+                if pin_count > 0 {
+                    Some((tag.0, tag.1, tag.2, pin_count))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        pinned_pages.sort_by_key(|(rel, _, page_num, _)| (rel.db, rel.rel_id, *page_num));
+        pinned_pages
+    }
+
+    /// Return the state of every cached page without pinning them.
+    pub fn get_resident_pages(&self) -> Vec<ResidentPageInfo> {
+        let mut pages = self
+            .page_hash
+            .iter()
+            .map(|(tag, slot)| {
+                let page_ptr = &self.page_pool[*slot];
+                let (pin_count, usage_count) =
+                    page_ptr.with_header(|header| (header.pin_count, header.usage_count));
+
+                ResidentPageInfo {
+                    rel: tag.0,
+                    fork: tag.1,
+                    page_num: tag.2,
+                    pin_count,
+                    dirty: page_ptr
+                        .with_read(|page| Ok(page.is_dirty()))
+                        .unwrap_or(true),
+                    usage_count,
+                }
+            })
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|page| {
+            (
+                page.rel.db,
+                page.rel.rel_id,
+                page.fork as usize,
+                page.page_num,
+            )
+        });
+        pages
+    }
+
+    #[cfg(test)]
+    pub fn cached_page_count(&self) -> usize {
+        self.page_hash.len()
+    }
+
+    #[cfg(test)]
+    pub fn dirty_page_count(&self, rel: RelFileRef) -> usize {
+        self.page_hash
+            .iter()
+            .filter(|(tag, slot)| {
+                tag.0 == rel
+                    && self.page_pool[**slot]
+                        .with_read(|page| Ok(page.is_dirty()))
+                        .unwrap()
+            })
+            .count()
+    }
+
+    /// Change the number of pages in the cache. Pages in the slots that are dropped are written
+    /// back and evicted, pinned pages are moved to the remaining slots.
+    pub fn resize(&mut self, db: &DB, new_capacity: usize) -> Result<()> {
+        if new_capacity == 0 {
+            return Err(Error::InvalidArgument(
+                "cache capacity must be positive".to_owned(),
+            ));
+        }
+
+        let num_pinned = self.get_pinned_pages().len();
+        if num_pinned > new_capacity {
+            return Err(Error::InvalidState(format!(
+                "cannot shrink the cache to {} pages, {} pages are pinned",
+                new_capacity, num_pinned
+            )));
+        }
+
+        for slot in (new_capacity..self.page_pool.len()).rev() {
+            if self.free_slots.contains(&slot) {
+                continue;
+            }
+
+            let page_ptr = self.page_pool[slot].clone();
+            if page_ptr.with_header(|header| header.pin_count) == 0 {
+                self.evict_slot(db, slot)?;
+                continue;
+            }
+
+            // move the pinned page to a free slot or the slot of an unpinned page
+            let target = (0..new_capacity)
+                .find(|target| {
+                    self.free_slots.contains(target)
+                        || self.page_pool[*target].with_header(|header| header.pin_count) == 0
+                })
+                .ok_or_else(|| {
+                    Error::InvalidState(format!("no slot to move page in slot {} to", slot))
+                })?;
+
+            if !self.free_slots.contains(&target) {
+                self.evict_slot(db, target)?;
+            }
+
+            self.page_pool.swap(slot, target);
+            let tag = page_ptr.with_header(|header| {
+                header.slot = target;
+                let (rel, fork, page_num) = header.get_fork_and_num();
+                PageTag(rel, fork, page_num)
+            });
+            self.page_hash.insert(tag, target);
+            self.free_slots.retain(|free_slot| *free_slot != target);
+        }
+
+        self.page_pool.truncate(new_capacity);
+        self.free_slots.retain(|slot| *slot < new_capacity);
+        self.cache_capacity = new_capacity;
+        self.lru.resize(new_capacity);
+
+        Ok(())
+    }
+
+    /// Write back and drop the unpinned page in the slot.
+    fn evict_slot(&mut self, db: &DB, slot: usize) -> Result<()> {
+        let tag = self.page_pool[slot].with_write(|page| {
+            if page.is_dirty() {
+                Self::flush_page(db, page)?;
+            }
+
+            let (rel, fork, page_num) = page.get_fork_and_num();
+            Ok(PageTag(rel, fork, page_num))
+        })?;
+
+        self.lru.pop(&tag);
+        self.page_hash.remove(&tag);
+        self.free_slots.push(slot);
+
+        Ok(())
     }
 
     pub fn flush_page(db: &DB, page: &mut Page) -> Result<()> {
@@ -174,7 +508,8 @@ impl PageCache {
         let smgr = db.get_storage_manager();
         let (rel, fork, num) = page.get_fork_and_num();
         let shandle = { smgr.open(rel) }?;
-        smgr.write(&shandle, fork, num, buffer)?;
+        DiskPageViewMut::new(page.buffer_mut()).set_checksum();
+        smgr.write(&shandle, fork, num, page.buffer())?;
 
         page.set_dirty(false);
         Ok(())
@@ -1,28 +1,45 @@
-use crate::storage::*;
+use crate::storage::{compression::PageMap, *};
 use crate::*;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{DirBuilder, File, OpenOptions},
     io::{self, prelude::*, SeekFrom},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
 };
 
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ForkType {
     Main = 0,
-    Last = 1,
+    /// The empty state of an unlogged relation, copied over the main fork by crash recovery
+    Init = 1,
+    Last = 2,
 }
 
 const MAX_FORKS: usize = ForkType::Last as usize;
+const ALL_FORKS: [ForkType; MAX_FORKS] = [ForkType::Main, ForkType::Init];
+
+/// An open fork. Compressed forks have a page map which locates the pages in the file.
+pub struct ForkFile {
+    file: File,
+    page_map: Option<PageMap>,
+}
 
 pub struct StorageHandleInner {
     file_ref: RelFileRef,
-    forks: [Mutex<Option<File>>; MAX_FORKS],
+    forks: [Mutex<Option<ForkFile>>; MAX_FORKS],
+    // number of times a fork is truncated, so that cached page numbers can be checked
+    truncations: AtomicUsize,
+    // serialize the extensions of the relation so that each extender gets a distinct page
+    extension_lock: Mutex<()>,
 }
 
 #[derive(Clone)]
@@ -32,12 +49,18 @@ impl StorageHandle {
     pub fn new(file_ref: RelFileRef) -> Self {
         Self(Arc::new(StorageHandleInner {
             file_ref,
-            forks: [Mutex::new(None); MAX_FORKS],
+            forks: [Mutex::new(None), Mutex::new(None)],
+            truncations: AtomicUsize::new(0),
+            extension_lock: Mutex::new(()),
         }))
     }
     pub fn file_ref(&self) -> RelFileRef {
         self.0.file_ref
     }
+
+    pub fn truncation_count(&self) -> usize {
+        self.0.truncations.load(Ordering::Acquire)
+    }
 }
 
 impl Deref for StorageHandle {
@@ -48,23 +71,127 @@ impl Deref for StorageHandle {
     }
 }
 
+/// A relation whose file is replaced with a new empty one by a truncation
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TruncatedRelation {
+    pub file_ref: RelFileRef,
+    pub old_file_id: OID,
+    pub new_file_id: OID,
+}
+
+/// Files of the relations whose storage is replaced, e.g. by a truncation. The other relations
+/// are stored in the files named after their IDs.
+#[derive(Default)]
+pub struct RelationFileMap(RwLock<HashMap<RelFileRef, OID>>);
+
+impl RelationFileMap {
+    /// Get the file that stores the relation.
+    pub fn get(&self, file_ref: RelFileRef) -> RelFileRef {
+        let guard = self.0.read().unwrap();
+        match guard.get(&file_ref) {
+            Some(file_id) => RelFileRef {
+                db: file_ref.db,
+                rel_id: *file_id,
+            },
+            None => file_ref,
+        }
+    }
+
+    pub fn set(&self, file_ref: RelFileRef, file_id: OID) {
+        // this may run while a panic unwinds
+        let mut guard = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if file_id == file_ref.rel_id {
+            guard.remove(&file_ref);
+        } else {
+            guard.insert(file_ref, file_id);
+        }
+    }
+
+    /// Get the relation stored in the file if the relation is not named after it.
+    pub fn find_relation(&self, file: RelFileRef) -> Option<RelFileRef> {
+        let guard = self.0.read().unwrap();
+        guard
+            .iter()
+            .find(|(file_ref, file_id)| file_ref.db == file.db && **file_id == file.rel_id)
+            .map(|(file_ref, _)| *file_ref)
+    }
+
+    pub fn remove_database(&self, db: OID) {
+        let mut guard = self.0.write().unwrap();
+        guard.retain(|file_ref, _| file_ref.db != db);
+    }
+}
+
 pub struct StorageManager {
     base_path: PathBuf,
     dir_builder: Mutex<DirBuilder>,
     shandles: Mutex<HashMap<RelFileRef, StorageHandle>>,
+    max_open_files: usize,
+    // open forks in LRU order, the least recently used ones are closed when there are too many
+    open_files: Mutex<LruCache<(RelFileRef, ForkType), StorageHandle>>,
+    relation_files: Arc<RelationFileMap>,
+    // files whose main fork is compressed, as recorded in the catalog
+    compressed_files: Mutex<HashSet<RelFileRef>>,
+    // files with an init fork, whose changes are not logged
+    unlogged_files: Mutex<HashSet<RelFileRef>>,
+    #[cfg(test)]
+    sync_counts: Mutex<HashMap<RelFileRef, usize>>,
 }
 
 impl StorageManager {
-    pub fn new<P: AsRef<Path>>(base_path: P) -> Self {
+    pub fn new<P: AsRef<Path>>(base_path: P, max_open_files: usize) -> Self {
         let dir_builder = Mutex::new(DirBuilder::new());
         dir_builder.lock().unwrap().recursive(true);
         Self {
             base_path: base_path.as_ref().to_path_buf(),
             dir_builder,
             shandles: Mutex::new(HashMap::new()),
+            max_open_files,
+            open_files: Mutex::new(LruCache::unbounded()),
+            relation_files: Default::default(),
+            compressed_files: Mutex::new(HashSet::new()),
+            unlogged_files: Mutex::new(HashSet::new()),
+            #[cfg(test)]
+            sync_counts: Mutex::new(HashMap::new()),
         }
     }
 
+    pub fn get_relation_file_map(&self) -> &Arc<RelationFileMap> {
+        &self.relation_files
+    }
+
+    /// Get the file that stores the relation, which is given to `open` to access the relation.
+    pub fn relation_file(&self, file_ref: RelFileRef) -> RelFileRef {
+        self.relation_files.get(file_ref)
+    }
+
+    /// Mark the main fork of a file as compressed before it is opened. The catalog records which
+    /// relations are compressed and the marks are set from it when the database is opened.
+    pub fn set_compressed(&self, file_ref: RelFileRef) {
+        self.compressed_files.lock().unwrap().insert(file_ref);
+    }
+
+    /// Mark a file as unlogged. Files are marked when their init forks are created and from the
+    /// init forks found when the database is opened.
+    pub fn set_unlogged(&self, file_ref: RelFileRef) {
+        self.unlogged_files.lock().unwrap().insert(file_ref);
+    }
+
+    /// Check whether a file has an init fork. The changes to such files are not logged because
+    /// they are reset from their init forks on crash recovery.
+    pub fn is_unlogged(&self, file_ref: RelFileRef) -> bool {
+        self.unlogged_files.lock().unwrap().contains(&file_ref)
+    }
+
+    fn is_compressed(&self, file_ref: RelFileRef, fork: ForkType) -> bool {
+        fork == ForkType::Main && self.compressed_files.lock().unwrap().contains(&file_ref)
+    }
+
+    /// Check whether a fork has a page map on disk, which is only used to rebuild the catalog.
+    pub fn has_page_map(&self, file_ref: RelFileRef, fork: ForkType) -> bool {
+        self.map_path(file_ref, fork).is_file()
+    }
+
     pub fn open(&self, file_ref: RelFileRef) -> Result<StorageHandle> {
         let mut guard = self.shandles.lock().unwrap();
         let handle = guard
@@ -74,18 +201,49 @@ impl StorageManager {
     }
 
     pub fn create(&self, shandle: &StorageHandle, fork: ForkType, redo: bool) -> Result<()> {
+        if fork == ForkType::Init {
+            self.set_unlogged(shandle.file_ref());
+        }
+
+        let victims = self.create_fork(shandle, fork, redo, false)?;
+        self.close_victims(victims)
+    }
+
+    /// Create a fork whose pages are compressed on disk.
+    pub fn create_compressed(&self, shandle: &StorageHandle, fork: ForkType) -> Result<()> {
+        if fork != ForkType::Main {
+            return Err(Error::InvalidArgument(format!(
+                "cannot compress fork {:?} of relation {}",
+                fork,
+                shandle.file_ref()
+            )));
+        }
+
+        self.set_compressed(shandle.file_ref());
+        let victims = self.create_fork(shandle, fork, false, true)?;
+        self.close_victims(victims)
+    }
+
+    fn create_fork(
+        &self,
+        shandle: &StorageHandle,
+        fork: ForkType,
+        redo: bool,
+        compressed: bool,
+    ) -> Result<Vec<(RelFileRef, ForkType, StorageHandle)>> {
         let mut guard = shandle.forks[fork as usize].lock().unwrap();
         let RelFileRef { db, rel_id } = shandle.file_ref();
         match &*guard {
-            Some(_) => Ok(()),
+            Some(_) => Ok(Vec::new()),
             None => {
                 self.ensure_database_path(db)?;
                 let rel_path = self.rel_path(RelFileRef { db, rel_id }, fork);
+                let created = !rel_path.exists();
 
-                let file = if rel_path.exists() {
+                let file = if !created {
                     if rel_path.is_file() {
                         if redo {
-                            File::open(rel_path)?
+                            File::open(&rel_path)?
                         } else {
                             return Err(Error::FileAccess(format!(
                                 "cannot create file '{}'",
@@ -103,11 +261,25 @@ impl StorageManager {
                         .read(true)
                         .write(true)
                         .create(true)
-                        .open(rel_path)?
+                        .truncate(false)
+                        .open(&rel_path)?
                 };
 
-                *guard = Some(file);
-                Ok(())
+                let map_path = self.map_path(shandle.file_ref(), fork);
+                let page_map = if compressed {
+                    Some(PageMap::create(map_path)?)
+                } else if self.is_compressed(shandle.file_ref(), fork) {
+                    Some(PageMap::open(map_path)?)
+                } else {
+                    None
+                };
+
+                if created || compressed {
+                    sync_dir(self.db_path(db))?;
+                }
+
+                *guard = Some(ForkFile { file, page_map });
+                Ok(self.track_open_fork(shandle, fork))
             }
         }
     }
@@ -134,15 +306,216 @@ impl StorageManager {
 
         match &*guard {
             None => {}
-            Some(_) => *guard = None,
+            Some(_) => {
+                *guard = None;
+                self.open_files
+                    .lock()
+                    .unwrap()
+                    .pop(&(shandle.file_ref(), fork));
+            }
         }
 
         Ok(())
     }
 
+    /// Close a relation and remove its files. The caller must make sure that the relation is no
+    /// longer used.
+    pub fn unlink(&self, file_ref: RelFileRef) -> Result<()> {
+        let shandle = self.open(file_ref)?;
+        self.close(shandle)?;
+
+        let mut removed = false;
+        for fork in ALL_FORKS.iter() {
+            for path in [
+                self.rel_path(file_ref, *fork),
+                self.map_path(file_ref, *fork),
+            ]
+            .iter()
+            {
+                if path.is_file() {
+                    std::fs::remove_file(path)?;
+                    removed = true;
+                }
+            }
+        }
+
+        if removed {
+            sync_dir(self.db_path(file_ref.db))?;
+        }
+        self.compressed_files.lock().unwrap().remove(&file_ref);
+        self.unlogged_files.lock().unwrap().remove(&file_ref);
+        Ok(())
+    }
+
+    /// Return the IDs of the relations in a database that have a main fork.
+    pub fn list_relations(&self, db: OID) -> Result<Vec<OID>> {
+        let db_path = self.db_path(db);
+        if !db_path.is_dir() {
+            return Err(Error::InvalidArgument(format!(
+                "database {} does not exist",
+                db
+            )));
+        }
+
+        let mut rel_ids = Vec::new();
+        for rel_entry in std::fs::read_dir(db_path)? {
+            let file_name = rel_entry?.file_name();
+            let rel_id = match file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(&format!("_{}", ForkType::Main as usize)))
+                .map(str::parse::<OID>)
+            {
+                Some(Ok(rel_id)) => rel_id,
+                _ => continue,
+            };
+
+            rel_ids.push(rel_id);
+        }
+
+        rel_ids.sort_unstable();
+        Ok(rel_ids)
+    }
+
+    /// Return the relations that have an init fork, i.e. the unlogged relations.
+    pub fn list_init_forks(&self) -> Result<Vec<RelFileRef>> {
+        let mut file_refs = Vec::new();
+
+        if !self.base_path.is_dir() {
+            return Ok(file_refs);
+        }
+
+        for db_entry in std::fs::read_dir(&self.base_path)? {
+            let db_entry = db_entry?;
+            let db = match db_entry.file_name().to_str().map(str::parse::<OID>) {
+                Some(Ok(db)) if db_entry.path().is_dir() => db,
+                _ => continue,
+            };
+
+            for rel_entry in std::fs::read_dir(db_entry.path())? {
+                let file_name = rel_entry?.file_name();
+                let rel_id = match file_name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(&format!("_{}", ForkType::Init as usize)))
+                    .map(str::parse::<OID>)
+                {
+                    Some(Ok(rel_id)) => rel_id,
+                    _ => continue,
+                };
+
+                file_refs.push(RelFileRef { db, rel_id });
+            }
+        }
+
+        file_refs.sort_by_key(|file_ref| (file_ref.db, file_ref.rel_id));
+        Ok(file_refs)
+    }
+
+    /// Replace the main fork of an unlogged relation with a copy of its init fork.
+    pub fn reset_from_init_fork(&self, shandle: &StorageHandle) -> Result<()> {
+        let init_path = self.rel_path(shandle.file_ref(), ForkType::Init);
+
+        self.rewrite(shandle, ForkType::Main, |file| {
+            let mut init_file = File::open(&init_path)?;
+            io::copy(&mut init_file, file)?;
+            Ok(())
+        })
+    }
+
+    /// Copy the files of all forks of a relation to a new relation and sync them. The pages of
+    /// the source must be flushed before the copy.
+    pub fn copy_relation(&self, src: RelFileRef, dst: RelFileRef) -> Result<()> {
+        self.ensure_database_path(dst.db)?;
+
+        let mut copied = Vec::new();
+        let result = (|| {
+            for fork in ALL_FORKS.iter() {
+                for (src_path, dst_path) in [
+                    (self.rel_path(src, *fork), self.rel_path(dst, *fork)),
+                    (self.map_path(src, *fork), self.map_path(dst, *fork)),
+                ]
+                .iter()
+                {
+                    if !src_path.is_file() {
+                        continue;
+                    }
+
+                    if dst_path.exists() {
+                        return Err(Error::FileAccess(format!(
+                            "cannot create file '{}'",
+                            dst_path.as_path().display()
+                        )));
+                    }
+
+                    std::fs::copy(src_path, dst_path)?;
+                    copied.push(dst_path.clone());
+                    File::open(dst_path)?.sync_all()?;
+                }
+            }
+
+            Ok(())
+        })();
+
+        if result.is_err() {
+            for path in copied {
+                let _ = std::fs::remove_file(path);
+            }
+        } else {
+            if self.is_compressed(src, ForkType::Main) {
+                self.set_compressed(dst);
+            }
+            if self.is_unlogged(src) {
+                self.set_unlogged(dst);
+            }
+        }
+
+        result
+    }
+
+    /// Create the directory of a database.
+    pub fn create_database(&self, db: OID) -> Result<()> {
+        self.ensure_database_path(db)
+    }
+
+    /// Close all relations in a database and remove the directory of the database. The caller
+    /// must make sure that the relations are no longer used.
+    pub fn drop_database(&self, db: OID) -> Result<()> {
+        let mut guard = self.shandles.lock().unwrap();
+        let file_refs = guard
+            .keys()
+            .filter(|file_ref| file_ref.db == db)
+            .copied()
+            .collect::<Vec<_>>();
+
+        for file_ref in file_refs {
+            if let Some(shandle) = guard.remove(&file_ref) {
+                for fork in ALL_FORKS.iter() {
+                    self.close_fork(&shandle, *fork)?;
+                }
+            }
+        }
+
+        let db_path = self.db_path(db);
+        if db_path.is_dir() {
+            std::fs::remove_dir_all(db_path)?;
+        }
+        self.relation_files.remove_database(db);
+        self.compressed_files
+            .lock()
+            .unwrap()
+            .retain(|file_ref| file_ref.db != db);
+        self.unlogged_files
+            .lock()
+            .unwrap()
+            .retain(|file_ref| file_ref.db != db);
+
+        Ok(())
+    }
+
     pub fn close(&self, shandle: StorageHandle) -> Result<()> {
         let mut guard = self.shandles.lock().unwrap();
-        self.close_fork(&shandle, ForkType::Main)?;
+        for fork in ALL_FORKS.iter() {
+            self.close_fork(&shandle, *fork)?;
+        }
 
         let file_ref = shandle.file_ref();
         guard.remove(&file_ref);
@@ -157,7 +530,19 @@ impl StorageManager {
         page_num: usize,
         buffer: &mut PageBuffer,
     ) -> Result<()> {
-        self.with_fork(shandle, fork, |file| {
+        self.with_fork(shandle, fork, |ForkFile { file, page_map }| {
+            if let Some(page_map) = page_map {
+                return if page_map.read_page(file, page_num, buffer)? {
+                    Ok(())
+                } else {
+                    Err(Error::DataCorrupted(format!(
+                        "could not read page {} of relation {}: unexpected EOF",
+                        page_num,
+                        shandle.file_ref()
+                    )))
+                };
+            }
+
             file.seek(SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))?;
             match file.read_exact(buffer) {
                 Err(e) => {
@@ -180,6 +565,51 @@ impl StorageManager {
         })
     }
 
+    /// Read a page and treat the part past EOF as zeros. This is used by redo where a page may
+    /// be allocated but never written before a crash.
+    pub fn read_or_zero(
+        &self,
+        shandle: &StorageHandle,
+        fork: ForkType,
+        page_num: usize,
+        buffer: &mut PageBuffer,
+    ) -> Result<()> {
+        self.with_fork(shandle, fork, |ForkFile { file, page_map }| {
+            if let Some(page_map) = page_map {
+                if !page_map.read_page(file, page_num, buffer)? {
+                    for i in buffer.iter_mut() {
+                        *i = 0;
+                    }
+                }
+                return Ok(());
+            }
+
+            file.seek(SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))?;
+
+            let mut len = 0;
+            while len < buffer.len() {
+                match file.read(&mut buffer[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => {
+                        return Err(Error::FileAccess(format!(
+                            "could not read page {} of relation {}",
+                            page_num,
+                            shandle.file_ref()
+                        )))
+                    }
+                }
+            }
+
+            for i in buffer[len..].iter_mut() {
+                *i = 0;
+            }
+
+            Ok(())
+        })
+    }
+
     pub fn write(
         &self,
         shandle: &StorageHandle,
@@ -187,7 +617,11 @@ impl StorageManager {
         page_num: usize,
         buffer: &PageBuffer,
     ) -> Result<()> {
-        self.with_fork(shandle, fork, |file| {
+        self.with_fork(shandle, fork, |ForkFile { file, page_map }| {
+            if let Some(page_map) = page_map {
+                return page_map.write_page(file, page_num, buffer);
+            }
+
             file.seek(SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))?;
             match file.write_all(buffer) {
                 Err(_) => Err(Error::FileAccess(format!(
@@ -200,16 +634,49 @@ impl StorageManager {
         })
     }
 
+    /// Append a zero page to the fork and return its page number.
+    pub fn zero_extend(&self, shandle: &StorageHandle, fork: ForkType) -> Result<usize> {
+        let _guard = shandle.extension_lock.lock().unwrap();
+        let page_num = self.file_size_in_page(shandle, fork)?;
+        self.write(shandle, fork, page_num, &[0u8; PAGE_SIZE])?;
+        Ok(page_num)
+    }
+
     pub fn file_size_in_page(&self, shandle: &StorageHandle, fork: ForkType) -> Result<usize> {
-        self.with_fork(shandle, fork, |file| {
+        self.with_fork(shandle, fork, |ForkFile { file, page_map }| {
+            if let Some(page_map) = page_map {
+                return Ok(page_map.num_pages());
+            }
+
             let metadata = file.metadata()?;
 
             Ok(metadata.len() as usize / PAGE_SIZE)
         })
     }
 
+    /// Get the number of pages in all existing forks of the relation.
+    pub fn total_size_in_pages(&self, shandle: &StorageHandle) -> Result<usize> {
+        let RelFileRef { db, rel_id } = shandle.file_ref();
+        let mut total = 0;
+
+        for fork in ALL_FORKS.iter() {
+            if self.exists(db, rel_id, *fork)? {
+                total += self.file_size_in_page(shandle, *fork)?;
+            }
+        }
+
+        Ok(total)
+    }
+
     pub fn truncate(&self, shandle: &StorageHandle, fork: ForkType, nr_pages: usize) -> Result<()> {
-        self.with_fork(shandle, fork, |file| {
+        shandle.truncations.fetch_add(1, Ordering::AcqRel);
+
+        self.with_fork(shandle, fork, |ForkFile { file, page_map }| {
+            if let Some(page_map) = page_map {
+                // the space of the truncated pages is not reclaimed
+                return page_map.truncate(nr_pages);
+            }
+
             let metadata = file.metadata()?;
             let cur_pages = metadata.len() as usize / PAGE_SIZE;
 
@@ -221,18 +688,118 @@ impl StorageManager {
         })
     }
 
+    /// Replace the content of a fork with the pages written by `f`. The pages are written to a new
+    /// file which then atomically replaces the old one.
+    pub fn rewrite<F>(&self, shandle: &StorageHandle, fork: ForkType, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut File) -> Result<()>,
+    {
+        if self.is_compressed(shandle.file_ref(), fork) {
+            return Err(Error::InvalidState(format!(
+                "cannot rewrite compressed relation {}",
+                shandle.file_ref()
+            )));
+        }
+
+        let rel_path = self.rel_path(shandle.file_ref(), fork);
+        let mut temp_path = rel_path.clone().into_os_string();
+        temp_path.push(".tmp");
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        if let Err(e) = f(&mut file).and_then(|_| Ok(file.sync_all()?)) {
+            drop(file);
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        drop(file);
+
+        {
+            let mut guard = shandle.forks[fork as usize].lock().unwrap();
+            std::fs::rename(&temp_path, &rel_path)?;
+            // the old file is unlinked by the rename, reopen the fork on the next access
+            *guard = None;
+            self.open_files
+                .lock()
+                .unwrap()
+                .pop(&(shandle.file_ref(), fork));
+        }
+
+        sync_dir(self.db_path(shandle.file_ref().db))?;
+
+        Ok(())
+    }
+
     pub fn sync(&self, shandle: &StorageHandle, fork: ForkType) -> Result<()> {
-        self.with_fork(shandle, fork, |file| Ok(file.sync_data()?))
+        self.with_fork(shandle, fork, |fork_file| {
+            self.sync_file(shandle, fork_file)
+        })
+    }
+
+    /// Sync all open relation files to disk.
+    pub fn sync_all(&self) -> Result<()> {
+        let shandles = {
+            let guard = self.shandles.lock().unwrap();
+            guard.values().cloned().collect::<Vec<_>>()
+        };
+
+        for shandle in shandles {
+            for fork in shandle.forks.iter() {
+                let mut guard = fork.lock().unwrap();
+
+                if let Some(fork_file) = &mut *guard {
+                    self.sync_file(&shandle, fork_file)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync_file(&self, _shandle: &StorageHandle, fork_file: &mut ForkFile) -> Result<()> {
+        fork_file.file.sync_data()?;
+
+        // the page map may only point to synced pages
+        if let Some(page_map) = &mut fork_file.page_map {
+            page_map.sync()?;
+        }
+
+        #[cfg(test)]
+        {
+            let mut guard = self.sync_counts.lock().unwrap();
+            *guard.entry(_shandle.file_ref()).or_insert(0) += 1;
+        }
+
+        Ok(())
     }
+
+    #[cfg(test)]
+    pub(crate) fn sync_count(&self, file_ref: RelFileRef) -> usize {
+        let guard = self.sync_counts.lock().unwrap();
+        guard.get(&file_ref).copied().unwrap_or(0)
+    }
+    #[cfg(test)]
+    pub(crate) fn num_open_files(&self) -> usize {
+        let guard = self.shandles.lock().unwrap();
+        guard
+            .values()
+            .flat_map(|shandle| shandle.forks.iter())
+            .filter(|fork| fork.lock().unwrap().is_some())
+            .count()
+    }
+
     fn with_fork<F, R>(&self, shandle: &StorageHandle, fork: ForkType, f: F) -> Result<R>
     where
-        F: FnOnce(&mut File) -> Result<R>,
+        F: FnOnce(&mut ForkFile) -> Result<R>,
     {
-        let mut guard = shandle.forks[fork as usize].lock().unwrap();
+        let (result, victims) = {
+            let mut guard = shandle.forks[fork as usize].lock().unwrap();
 
-        match &mut *guard {
-            Some(file) => f(file),
-            guard_ref @ None => {
+            if guard.is_none() {
                 let rel_path = self.rel_path(shandle.file_ref, fork);
                 let file = OpenOptions::new()
                     .read(true)
@@ -240,14 +807,78 @@ impl StorageManager {
                     .create(false)
                     .open(rel_path)?;
 
-                *guard_ref = Some(file);
+                let page_map = if self.is_compressed(shandle.file_ref, fork) {
+                    Some(PageMap::open(self.map_path(shandle.file_ref, fork))?)
+                } else {
+                    None
+                };
+
+                *guard = Some(ForkFile { file, page_map });
+            }
+
+            let victims = self.track_open_fork(shandle, fork);
+
+            match guard.as_mut() {
+                Some(fork_file) => (f(fork_file), victims),
+                _ => unreachable!(),
+            }
+        };
+
+        self.close_victims(victims)?;
+        result
+    }
+
+    /// Mark an open fork as the most recently used one and return the forks that should be
+    /// closed to keep the number of open files under the limit. The caller must hold the lock of
+    /// the fork.
+    fn track_open_fork(
+        &self,
+        shandle: &StorageHandle,
+        fork: ForkType,
+    ) -> Vec<(RelFileRef, ForkType, StorageHandle)> {
+        let mut guard = self.open_files.lock().unwrap();
+        let key = (shandle.file_ref(), fork);
+
+        if guard.get(&key).is_none() {
+            guard.put(key, shandle.clone());
+        }
+
+        let mut victims = Vec::new();
+        while guard.len() > self.max_open_files {
+            match guard.pop_lru() {
+                Some(((file_ref, fork), shandle)) => victims.push((file_ref, fork, shandle)),
+                None => break,
+            }
+        }
+
+        victims
+    }
+
+    /// Close forks evicted from the open file cache. This must be called without holding any fork
+    /// lock.
+    fn close_victims(&self, victims: Vec<(RelFileRef, ForkType, StorageHandle)>) -> Result<()> {
+        for (file_ref, fork, shandle) in victims {
+            let mut guard = shandle.forks[fork as usize].lock().unwrap();
+
+            // the fork may have been used again since it was evicted
+            if self.open_files.lock().unwrap().contains(&(file_ref, fork)) {
+                continue;
+            }
 
-                match guard_ref.as_mut() {
-                    Some(file) => f(file),
-                    _ => unreachable!(),
+            if let Some(mut fork_file) = guard.take() {
+                // sync_all() only syncs open files so the writes must be synced before closing
+                if let Err(e) = self.sync_file(&shandle, &mut fork_file) {
+                    *guard = Some(fork_file);
+                    self.open_files
+                        .lock()
+                        .unwrap()
+                        .put((file_ref, fork), shandle.clone());
+                    return Err(e);
                 }
             }
         }
+
+        Ok(())
     }
 
     fn ensure_database_path(&self, db: OID) -> Result<()> {
@@ -282,6 +913,12 @@ impl StorageManager {
         path.push(format!("{}_{}", rel_id, fork as usize));
         path
     }
+
+    fn map_path(&self, file_ref: RelFileRef, fork: ForkType) -> PathBuf {
+        let mut path = self.rel_path(file_ref, fork);
+        path.set_extension("map");
+        path
+    }
 }
 
 #[cfg(test)]
@@ -318,6 +955,36 @@ mod tests {
         assert!(db_dir.close().is_ok());
     }
 
+    #[test]
+    fn can_read_past_eof_as_zeros() {
+        let (smgr, db_dir) = get_temp_smgr();
+        let shandle = smgr.open(RelFileRef { db: 0, rel_id: 0 }).unwrap();
+        assert!(smgr.create(&shandle, ForkType::Main, false).is_ok());
+
+        let wbuf = [1u8; PAGE_SIZE];
+        let mut rbuf = [1u8; PAGE_SIZE];
+
+        assert!(smgr.write(&shandle, ForkType::Main, 0, &wbuf).is_ok());
+        assert!(smgr.read(&shandle, ForkType::Main, 2, &mut rbuf).is_err());
+        assert!(smgr
+            .read_or_zero(&shandle, ForkType::Main, 2, &mut rbuf)
+            .is_ok());
+        assert!(rbuf.iter().all(|b| *b == 0));
+
+        // partial page at the end of file
+        smgr.with_fork(&shandle, ForkType::Main, |fork_file| {
+            fork_file.file.set_len(PAGE_SIZE as u64 + 100)?;
+            Ok(())
+        })
+        .unwrap();
+        assert!(smgr
+            .read_or_zero(&shandle, ForkType::Main, 1, &mut rbuf)
+            .is_ok());
+        assert!(rbuf.iter().all(|b| *b == 0));
+
+        assert!(db_dir.close().is_ok());
+    }
+
     #[test]
     fn can_truncate() {
         let (smgr, db_dir) = get_temp_smgr();
@@ -347,4 +1014,147 @@ mod tests {
 
         assert!(db_dir.close().is_ok());
     }
+
+    #[test]
+    fn can_limit_open_files() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let smgr = StorageManager::new(db_dir.path(), 4);
+        let shandles = (0..32)
+            .map(|rel_id| smgr.open(RelFileRef { db: 0, rel_id }).unwrap())
+            .collect::<Vec<_>>();
+
+        for (i, shandle) in shandles.iter().enumerate() {
+            let wbuf = [i as u8; PAGE_SIZE];
+
+            assert!(smgr.create(shandle, ForkType::Main, false).is_ok());
+            assert!(smgr.write(shandle, ForkType::Main, 0, &wbuf).is_ok());
+            assert!(smgr.num_open_files() <= 4);
+        }
+
+        // closed files are synced and reopened on demand
+        for (i, shandle) in shandles.iter().enumerate() {
+            let mut rbuf = [0u8; PAGE_SIZE];
+
+            assert!(smgr.read(shandle, ForkType::Main, 0, &mut rbuf).is_ok());
+            assert!(rbuf.iter().all(|b| *b == i as u8));
+            assert!(smgr.num_open_files() <= 4);
+        }
+        assert!(smgr.sync_count(shandles[0].file_ref()) > 0);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_compress_pages() {
+        let (smgr, db_dir) = get_temp_smgr();
+        let file_ref = RelFileRef { db: 0, rel_id: 0 };
+        let shandle = smgr.open(file_ref).unwrap();
+        assert!(smgr.create_compressed(&shandle, ForkType::Main).is_ok());
+
+        let page = |i: usize| {
+            let mut buf = [0u8; PAGE_SIZE];
+            for (j, b) in buf.iter_mut().enumerate().take(100 * (i + 1)) {
+                *b = (j / 10 + i) as u8;
+            }
+            buf
+        };
+
+        for i in 0..16 {
+            assert!(smgr.write(&shandle, ForkType::Main, i, &page(i)).is_ok());
+        }
+        // overwrite a page before and after syncing
+        assert!(smgr.write(&shandle, ForkType::Main, 3, &page(3)).is_ok());
+        assert!(smgr.sync(&shandle, ForkType::Main).is_ok());
+        assert!(smgr.write(&shandle, ForkType::Main, 3, &page(3)).is_ok());
+
+        let check_pages = |smgr: &StorageManager, shandle: &StorageHandle, num_pages: usize| {
+            assert_eq!(
+                smgr.file_size_in_page(shandle, ForkType::Main).ok(),
+                Some(num_pages)
+            );
+
+            let mut rbuf = [0u8; PAGE_SIZE];
+            for i in 0..num_pages {
+                assert!(smgr.read(shandle, ForkType::Main, i, &mut rbuf).is_ok());
+                assert_eq!(&rbuf[..], &page(i)[..]);
+            }
+            assert!(smgr
+                .read(shandle, ForkType::Main, num_pages, &mut rbuf)
+                .is_err());
+        };
+        check_pages(&smgr, &shandle, 16);
+
+        let mut rel_path = db_dir.path().to_path_buf();
+        rel_path.push("0");
+        rel_path.push("0_0");
+        assert!(std::fs::metadata(&rel_path).unwrap().len() < 16 * PAGE_SIZE as u64);
+
+        assert!(smgr.truncate(&shandle, ForkType::Main, 8).is_ok());
+        check_pages(&smgr, &shandle, 8);
+
+        // the pages are found again after reopening the relation
+        let smgr = StorageManager::new(db_dir.path(), 1);
+        smgr.set_compressed(file_ref);
+        let shandle = smgr.open(file_ref).unwrap();
+        check_pages(&smgr, &shandle, 8);
+
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_reuse_compressed_extents() {
+        let (smgr, db_dir) = get_temp_smgr();
+        let file_ref = RelFileRef { db: 0, rel_id: 0 };
+        let shandle = smgr.open(file_ref).unwrap();
+        assert!(smgr.create_compressed(&shandle, ForkType::Main).is_ok());
+
+        let mut rel_path = db_dir.path().to_path_buf();
+        rel_path.push("0");
+        rel_path.push("0_0");
+
+        let page = |i: usize| {
+            let mut buf = [0u8; PAGE_SIZE];
+            for (j, b) in buf.iter_mut().enumerate().take(200 + i % 50) {
+                *b = (j * 7 + i) as u8;
+            }
+            buf
+        };
+
+        for i in 0..8 {
+            assert!(smgr.write(&shandle, ForkType::Main, i, &page(i)).is_ok());
+        }
+        assert!(smgr.sync(&shandle, ForkType::Main).is_ok());
+        let file_len = std::fs::metadata(&rel_path).unwrap().len();
+
+        // the extents given up by the overwritten pages are reused after each sync
+        for round in 1..50 {
+            for i in 0..8 {
+                let page_num = (i + round) % 8;
+                assert!(smgr
+                    .write(&shandle, ForkType::Main, page_num, &page(i + round))
+                    .is_ok());
+            }
+            assert!(smgr.sync(&shandle, ForkType::Main).is_ok());
+        }
+        assert!(std::fs::metadata(&rel_path).unwrap().len() <= file_len * 3);
+
+        // so are the extents of the truncated pages
+        assert!(smgr.truncate(&shandle, ForkType::Main, 4).is_ok());
+        assert!(smgr.sync(&shandle, ForkType::Main).is_ok());
+        let file_len = std::fs::metadata(&rel_path).unwrap().len();
+        for i in 4..8 {
+            assert!(smgr.write(&shandle, ForkType::Main, i, &page(0)).is_ok());
+        }
+        assert_eq!(std::fs::metadata(&rel_path).unwrap().len(), file_len);
+
+        let mut rbuf = [0u8; PAGE_SIZE];
+        for i in 0..8 {
+            assert!(smgr.read(&shandle, ForkType::Main, i, &mut rbuf).is_ok());
+            if i >= 4 {
+                assert_eq!(&rbuf[..], &page(0)[..]);
+            }
+        }
+
+        assert!(db_dir.close().is_ok());
+    }
 }
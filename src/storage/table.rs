@@ -10,11 +10,17 @@ use std::sync::Arc;
 pub enum ScanDirection {
     Forward,
     Backward,
+    /// Return the current tuple again without stepping
+    NoMovement,
 }
 
 pub trait Tuple {
     fn get_data(&self) -> &[u8];
     fn get_item_pointer(&self) -> Option<ItemPointer>;
+    /// Get an attribute of a row of a table with a tuple descriptor without decoding the other
+    /// attributes. Return `None` if the attribute is null or out of range, or if the table has no
+    /// tuple descriptor.
+    fn get_attr(&self, index: usize) -> Option<&[u8]>;
     /// Materialize the tuple so that it does not depend on any underlying resource
     fn materialize<'ret>(self: Box<Self>) -> Box<dyn Tuple + 'ret>;
 }
@@ -23,6 +29,71 @@ pub type TuplePtr<'a> = Box<dyn Tuple + 'a>;
 
 pub trait TableScanIterator<'a> {
     fn next(&mut self, db: &'a DB, dir: ScanDirection) -> Result<Option<TuplePtr<'a>>>;
+
+    /// Enable or disable writing hint bits to the pages read by this scan. The default is taken
+    /// from `DBConfig::hint_bit_writes`.
+    fn set_hint_bit_writes(&mut self, enabled: bool);
+
+    /// Save the current position of the scan.
+    fn mark(&mut self) -> TableScanMark;
+
+    /// Move back to a position saved by `mark`. The scan keeps its original snapshot.
+    fn restore(&mut self, db: &'a DB, mark: TableScanMark) -> Result<()>;
+
+    /// Get at most `max` tuples in one call. Fewer tuples are returned only at the end of the
+    /// scan. The tuples keep their pages pinned until they are dropped or materialized.
+    fn next_batch(
+        &mut self,
+        db: &'a DB,
+        dir: ScanDirection,
+        max: usize,
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        if dir == ScanDirection::NoMovement {
+            return Ok(self.next(db, dir)?.into_iter().collect());
+        }
+
+        let mut tuples = Vec::new();
+
+        while tuples.len() < max {
+            match self.next(db, dir)? {
+                Some(tuple) => tuples.push(tuple),
+                None => break,
+            }
+        }
+
+        Ok(tuples)
+    }
+}
+
+type PredicateFn<'a> = dyn Fn(&dyn Tuple) -> Result<bool> + 'a;
+
+/// Filter of a table scan, evaluated on each visible tuple before it is returned
+pub struct TableScanPredicate<'a>(Box<PredicateFn<'a>>);
+
+impl<'a> TableScanPredicate<'a> {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&dyn Tuple) -> Result<bool> + 'a,
+    {
+        Self(Box::new(f))
+    }
+}
+
+impl<'a> std::ops::Deref for TableScanPredicate<'a> {
+    type Target = Box<PredicateFn<'a>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A saved position of a table scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableScanMark {
+    /// Current tuple of the scan, None if the scan has not started or has ended
+    pub item_pointer: Option<ItemPointer>,
+    /// Pages left to scan if the number of pages is limited
+    pub remaining_pages: Option<usize>,
 }
 
 pub trait Table: Relation + Sync + Send {
@@ -30,12 +101,76 @@ pub trait Table: Relation + Sync + Send {
 
     fn insert_tuple(&self, db: &DB, txn: &Transaction, tuple: &[u8]) -> Result<ItemPointer>;
 
+    fn delete_tuple(&self, db: &DB, txn: &Transaction, item_pointer: ItemPointer) -> Result<()>;
+
+    /// Replace the tuple with a new version and return the location of the new version. If the
+    /// tuple has been updated by a committed transaction, a read committed transaction updates
+    /// the latest version instead.
+    ///
+    /// `hot_allowed` tells that no indexed column is changed. The new version may then be put on
+    /// the page of the old version, where it is still found through the index entries of the old
+    /// version, and the index entries only need to be added if the new version is on another
+    /// page. Otherwise the caller adds the entries of the new version to all indexes.
+    fn update_tuple(
+        &self,
+        db: &DB,
+        txn: &Transaction,
+        item_pointer: ItemPointer,
+        tuple: &[u8],
+        hot_allowed: bool,
+    ) -> Result<ItemPointer>;
+
+    /// Remove all tuples by giving the table new empty storage, which the transaction sees right
+    /// away. Other transactions cannot access the table until the transaction ends. The old
+    /// storage is removed when the transaction commits and kept if it aborts. The truncation
+    /// bypasses MVCC, and the indexes of the table are not truncated.
+    fn truncate(&self, db: &DB, txn: &mut Transaction) -> Result<()>;
+
     fn begin_scan<'a>(
         &'a self,
         db: &DB,
         txn: &'a mut Transaction,
     ) -> Result<Box<dyn TableScanIterator<'a> + 'a>>;
 
+    /// Begin a scan that only returns the visible tuples matching the predicate. The tuples that
+    /// do not match are skipped inside the scan without being returned.
+    fn begin_scan_filtered<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        predicate: TableScanPredicate<'a>,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>>;
+
+    /// Begin a scan that returns only the given attributes of the rows of a table with a tuple
+    /// descriptor. Attribute `i` of the returned tuples is attribute `columns[i]` of the row, and
+    /// the other attributes are not copied out of the page.
+    fn begin_scan_projected<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        columns: &[usize],
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>>;
+
+    /// Begin a scan that starts near the current page of other synchronized scans of the table
+    /// and wraps around to cover the whole table. Tuples are not returned in physical order.
+    fn begin_sync_scan<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>>;
+
+    /// Begin a forward scan positioned just before `start` so that the first tuple returned is
+    /// the one at `start` if it is visible. Pages before `start` are not visited.
+    fn begin_scan_from<'a>(
+        &'a self,
+        db: &DB,
+        txn: &'a mut Transaction,
+        start: ItemPointer,
+    ) -> Result<Box<dyn TableScanIterator<'a> + 'a>>;
+
+    /// Count the tuples visible to the transaction without returning them.
+    fn count_visible(&self, db: &DB, txn: &mut Transaction) -> Result<u64>;
+
     fn fetch_tuple<'a>(
         &'a self,
         db: &'a DB,
@@ -43,6 +178,36 @@ pub trait Table: Relation + Sync + Send {
         snapshot: &Snapshot,
         item_pointer: ItemPointer,
     ) -> Result<Option<TuplePtr<'a>>>;
+
+    /// Fetch the latest version of a tuple with a dirty snapshot. The tuple is returned if it is
+    /// live or inserted or deleted by an in-progress transaction, whose XID is returned with it
+    /// so that the caller can wait for the outcome.
+    fn fetch_tuple_dirty<'a>(
+        &'a self,
+        db: &'a DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        item_pointer: ItemPointer,
+    ) -> Result<Option<(TuplePtr<'a>, Option<XID>)>>;
+
+    /// Fetch the visible tuples among `item_pointers` in order.
+    fn fetch_tuples<'a>(
+        &'a self,
+        db: &'a DB,
+        xid: XID,
+        snapshot: &Snapshot,
+        item_pointers: &[ItemPointer],
+    ) -> Result<Vec<TuplePtr<'a>>> {
+        let mut tuples = Vec::new();
+
+        for item_pointer in item_pointers {
+            if let Some(tuple) = self.fetch_tuple(db, xid, snapshot, *item_pointer)? {
+                tuples.push(tuple);
+            }
+        }
+
+        Ok(tuples)
+    }
 }
 
 pub type TablePtr = Arc<dyn Table>;
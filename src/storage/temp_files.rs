@@ -0,0 +1,191 @@
+use crate::{concurrency::XID, Error, Result};
+
+use std::{
+    collections::HashMap,
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{self, prelude::*, BufReader, BufWriter, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+/// Allocate temporary files for data spilled to disk
+///
+/// The files are removed when they are dropped, when the transaction that owns them ends, or at
+/// startup if the database crashed while they were in use.
+pub struct TempFileManager {
+    path: PathBuf,
+    next_file_id: AtomicUsize,
+    // files of the running transactions
+    transaction_files: Mutex<HashMap<XID, Vec<PathBuf>>>,
+}
+
+/// A temporary file removed when dropped
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFileManager {
+    /// Open the temporary file directory and remove the files left there by a crash.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            DirBuilder::new().recursive(true).create(path)?;
+        } else if !path.is_dir() {
+            return Err(Error::WrongObjectType(format!(
+                "'{}' exists but is not a directory",
+                path.display()
+            )));
+        }
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            next_file_id: AtomicUsize::new(0),
+            transaction_files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Create a temporary file. If `owner` is given, the file is also removed when the
+    /// transaction ends.
+    pub fn create_file(&self, owner: Option<XID>) -> Result<TempFile> {
+        let file_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
+        let mut path = self.path.clone();
+        path.push(format!("temp_{}", file_id));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        if let Some(xid) = owner {
+            let mut guard = self.transaction_files.lock().unwrap();
+            guard.entry(xid).or_default().push(path.clone());
+        }
+
+        Ok(TempFile { path, file })
+    }
+
+    /// Remove the temporary files of a transaction that ends.
+    pub fn remove_transaction_files(&self, xid: XID) {
+        // this may run while a panic unwinds
+        let paths = self
+            .transaction_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&xid);
+
+        // the files that are already dropped are gone
+        for path in paths.into_iter().flatten() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn into_writer(self) -> BufWriter<TempFile> {
+        BufWriter::new(self)
+    }
+
+    /// Read the file from the start.
+    pub fn into_reader(mut self) -> Result<BufReader<TempFile>> {
+        self.file.seek(SeekFrom::Start(0))?;
+        Ok(BufReader::new(self))
+    }
+}
+
+impl Read for TempFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for TempFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{concurrency::IsolationLevel, DBConfig, DB};
+
+    use std::io::prelude::*;
+
+    #[test]
+    fn can_remove_orphaned_temp_files() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let temp_path = config.get_temp_path();
+        let count_files = || std::fs::read_dir(&temp_path).unwrap().count();
+
+        {
+            let db = DB::open(&config).unwrap();
+            let temp_files = db.get_temp_file_manager();
+
+            let mut writer = temp_files.create_file(None).unwrap().into_writer();
+            writer.write_all(&[1u8; 10000]).unwrap();
+            let mut reader = writer.into_inner().unwrap().into_reader().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, vec![1u8; 10000]);
+            drop(reader);
+            assert_eq!(count_files(), 0);
+
+            // removed when the transaction ends even if they are not dropped
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for _ in 0..3 {
+                std::mem::forget(temp_files.create_file(Some(txn.xid())).unwrap());
+            }
+            assert_eq!(count_files(), 3);
+            db.commit_transaction(txn).unwrap();
+            assert_eq!(count_files(), 0);
+
+            // left behind by a crash
+            for _ in 0..3 {
+                let mut file = temp_files.create_file(None).unwrap();
+                file.write_all(b"spilled").unwrap();
+                std::mem::forget(file);
+            }
+            assert_eq!(count_files(), 3);
+        }
+
+        let db = DB::open(&config).unwrap();
+        assert_eq!(count_files(), 0);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+}
@@ -97,25 +97,21 @@ impl MasterRecordFile {
             ));
         }
 
-        let record = match bincode::deserialize::<MasterRecord>(&buffer) {
-            Ok(record) => record,
-            _ => {
-                return Err(Error::DataCorrupted(
-                    "cannot deserialize the master record".to_owned(),
-                ));
-            }
-        };
+        let record = bincode::deserialize::<MasterRecord>(&buffer)?;
 
         Ok(Some(record))
     }
 
     pub fn write_master_record(&self, record: &MasterRecord) -> Result<()> {
+        // the whole file is read back as one record, so a shorter record must not leave the tail
+        // of the previous one behind
         let mut file = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .read(false)
             .open(&self.file_path)?;
-        let mut buffer = bincode::serialize(record).unwrap();
+        let mut buffer = bincode::serialize(record)?;
         let crc = crc32::checksum_ieee(&buffer);
         buffer.write_u32::<LittleEndian>(crc).unwrap();
 
@@ -145,11 +141,16 @@ impl CheckpointManager {
 
     pub fn create_checkpoint(&mut self, db: &DB) -> Result<()> {
         let wal = db.get_wal();
-        let redo_lsn = wal.current_lsn();
+        // writers keep running during the checkpoint. Changes logged after the redo point are
+        // replayed on recovery whether or not their pages are flushed by this checkpoint.
+        let redo_lsn = db
+            .get_transaction_manager()
+            .with_commits_blocked(|| wal.current_lsn());
 
         // record all information needed for the checkpoint
         let next_oid = db.get_state_manager().max_allocated_oid();
         let next_xid = db.get_transaction_manager().read_next_id();
+        let active_xids = db.get_transaction_manager().get_active_xids();
 
         // write in-memory states
         db.get_transaction_manager().checkpoint()?;
@@ -158,8 +159,12 @@ impl CheckpointManager {
         let bufmgr = db.get_buffer_manager();
         bufmgr.sync_pages(db)?;
 
+        // make sure the written pages are on disk before the checkpoint is recorded
+        db.get_storage_manager().sync_all()?;
+
         // write checkpoint log
-        let checkpoint_log = WalLogRecord::create_checkpoint_log(redo_lsn, next_oid, next_xid);
+        let checkpoint_log =
+            WalLogRecord::create_checkpoint_log(redo_lsn, next_oid, next_xid, active_xids);
         let (checkpoint, checkpoint_lsn) = wal.append(XID::default(), checkpoint_log)?;
         wal.flush(Some(checkpoint_lsn))?;
 
@@ -195,7 +200,17 @@ impl CheckpointManager {
 
 #[cfg(test)]
 mod tests {
-    use crate::{concurrency::IsolationLevel, test_util::get_temp_db};
+    use super::CheckpointManager;
+    use crate::{
+        concurrency::{IsolationLevel, XID},
+        storage::{ForkType, RelFileRef, ScanDirection},
+        test_util::get_temp_db,
+        wal::{decode_record, encode_record, LogRecord, WalLogRecord},
+        DBConfig, Error, DB,
+    };
+
+    use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+    use std::{collections::HashSet, sync::Arc, thread};
 
     #[test]
     fn can_create_checkpoint() {
@@ -212,4 +227,203 @@ mod tests {
 
         db_dir.close().unwrap();
     }
+
+    #[test]
+    fn can_sync_relations_on_checkpoint() {
+        let (db, db_dir) = get_temp_db();
+        let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let btree = db
+            .create_index(0, 1, |a: &[u8], b: &[u8]| Ok(a.cmp(b)))
+            .unwrap();
+
+        let data: &[u8] = &[1u8; 100];
+        let item_pointer = heap.insert_tuple(&db, &txn, data).unwrap();
+        btree.insert(&db, data, item_pointer).unwrap();
+
+        db.commit_transaction(txn).unwrap();
+
+        let smgr = db.get_storage_manager();
+        let heap_ref = RelFileRef { db: 0, rel_id: 0 };
+        let btree_ref = RelFileRef { db: 0, rel_id: 1 };
+        let heap_syncs = smgr.sync_count(heap_ref);
+        let btree_syncs = smgr.sync_count(btree_ref);
+
+        db.create_checkpoint().unwrap();
+
+        assert!(smgr.sync_count(heap_ref) > heap_syncs);
+        assert!(smgr.sync_count(btree_ref) > btree_syncs);
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_record_active_transactions_in_checkpoint() {
+        let (db, db_dir) = get_temp_db();
+        let txn1 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let txn2 = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let committed = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let committed_xid = committed.xid();
+        db.commit_transaction(committed).unwrap();
+
+        db.create_checkpoint().unwrap();
+
+        let config = DBConfig::new().root_path(db_dir.path());
+        let mut ckptmgr = CheckpointManager::open(config.get_master_record_path()).unwrap();
+        let checkpoint_pos = ckptmgr.read_master_record().unwrap().last_checkpoint_pos();
+        let checkpoint_log = db
+            .get_wal()
+            .read_checkpoint_record(checkpoint_pos)
+            .unwrap()
+            .unwrap();
+
+        assert!(checkpoint_log.active_xids.contains(&txn1.xid()));
+        assert!(checkpoint_log.active_xids.contains(&txn2.xid()));
+        assert!(!checkpoint_log.active_xids.contains(&committed_xid));
+
+        db.commit_transaction(txn1).unwrap();
+        db.commit_transaction(txn2).unwrap();
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_reject_checkpoint_record_without_active_xids() {
+        let active_xids = vec![XID::from(7u32), XID::from(9u32)];
+        let record = WalLogRecord::create_checkpoint_log(42, 1000, XID::default(), active_xids);
+        let mut buf = encode_record(XID::default(), 0, &record).unwrap();
+
+        match decode_record(&buf).unwrap().payload {
+            LogRecord::Wal(WalLogRecord::Checkpoint(checkpoint_log)) => {
+                assert_eq!(checkpoint_log.redo_pos, 42);
+                assert_eq!(checkpoint_log.next_oid, 1000);
+                assert_eq!(
+                    checkpoint_log.active_xids,
+                    vec![XID::from(7u32), XID::from(9u32)]
+                );
+            }
+            _ => panic!("not a checkpoint record"),
+        }
+
+        // the active xids are a required field of the record
+        buf.truncate(buf.len() - 2 * std::mem::size_of::<XID>() - 8);
+        assert!(matches!(decode_record(&buf), Err(Error::Serialization(_))));
+    }
+
+    #[test]
+    fn can_checkpoint_with_concurrent_inserts() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+
+        {
+            let db = Arc::new(DB::open(&config).unwrap());
+            db.create_table(0, 0).unwrap();
+
+            let threads = (0..4u32)
+                .map(|t| {
+                    let db = db.clone();
+                    thread::spawn(move || {
+                        let heap = db.open_table(0, 0).unwrap().unwrap();
+
+                        for batch in 0..10u32 {
+                            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                            for i in 0..20u32 {
+                                let mut data = Vec::new();
+                                data.write_u32::<LittleEndian>(t * 1000 + batch * 20 + i)
+                                    .unwrap();
+                                data.resize(100, 0);
+                                heap.insert_tuple(&db, &txn, &data).unwrap();
+                            }
+                            db.commit_transaction(txn).unwrap();
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for _ in 0..5 {
+                db.create_checkpoint().unwrap();
+            }
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            // crash without flushing the pages dirtied after the last checkpoint
+        }
+
+        let mut ckptmgr = CheckpointManager::open(config.get_master_record_path()).unwrap();
+        let checkpoint_pos = ckptmgr.read_master_record().unwrap().last_checkpoint_pos();
+
+        let db = DB::open(&config).unwrap();
+        let checkpoint_log = db
+            .get_wal()
+            .read_checkpoint_record(checkpoint_pos)
+            .unwrap()
+            .unwrap();
+        assert!(checkpoint_log.redo_pos <= checkpoint_pos);
+
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut keys = HashSet::new();
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                assert!(keys.insert(LittleEndian::read_u32(tuple.get_data())));
+            }
+        }
+        db.commit_transaction(txn).unwrap();
+
+        let expected = (0..4u32)
+            .flat_map(|t| (0..200u32).map(move |i| t * 1000 + i))
+            .collect::<HashSet<_>>();
+        assert_eq!(keys, expected);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_flush_buffers_without_checkpoint() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let heap_ref = RelFileRef { db: 0, rel_id: 0 };
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for i in 0..10u8 {
+                heap.insert_tuple(&db, &txn, &[i; 100]).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+
+            let syncs = db.get_storage_manager().sync_count(heap_ref);
+            db.flush_all_buffers().unwrap();
+            assert!(db.get_storage_manager().sync_count(heap_ref) > syncs);
+            // crash without a checkpoint
+        }
+
+        let db = DB::open(&config).unwrap();
+        let smgr = db.get_storage_manager();
+        let bufmgr = db.get_buffer_manager();
+        let shandle = smgr.open(heap_ref).unwrap();
+
+        // the page is already up to date on disk so the redo leaves it clean
+        let page_ptr = bufmgr.fetch_page(&db, &shandle, ForkType::Main, 0).unwrap();
+        assert!(!page_ptr.with_read(|page| Ok(page.is_dirty())).unwrap());
+        bufmgr.release_page(page_ptr).unwrap();
+
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        let mut count = 0;
+        {
+            let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+            while iter.next(&db, ScanDirection::Forward).unwrap().is_some() {
+                count += 1;
+            }
+        }
+        db.commit_transaction(txn).unwrap();
+        assert_eq!(count, 10);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
 }
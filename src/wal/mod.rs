@@ -10,15 +10,18 @@ pub use self::{
     wal_log::{CheckpointLog, WalLogRecord},
 };
 
-use self::{reader::WalReader, segment::Segment};
+use self::{
+    reader::WalReader,
+    segment::{Segment, SEGMENT_PAGE_SIZE},
+};
 
-use crate::{concurrency::XID, Error, Result, DB};
+use crate::{concurrency::XID, metrics::Counter, storage, Error, Result, DB};
 
 use std::{
-    fs::{self, DirBuilder, File},
+    fs::{self, DirBuilder, File, OpenOptions},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::{Mutex, RwLock},
+    sync::{Condvar, Mutex, RwLock},
 };
 
 use fs2::FileExt;
@@ -30,14 +33,28 @@ pub fn is_invalid_lsn(lsn: LogPointer) -> bool {
     lsn == 0
 }
 
+/// How the log is forced to disk when it is flushed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalSyncMethod {
+    /// `fsync` on flush, which also flushes the file metadata
+    Fsync,
+    /// `fdatasync` on flush
+    Fdatasync,
+    /// Open the segments with `O_DSYNC` so that every write is synchronous
+    OpenDatasync,
+}
+
+#[derive(Clone)]
 pub struct WalConfig {
     pub segment_capacity: usize,
+    pub sync_method: WalSyncMethod,
 }
 
 impl Default for WalConfig {
     fn default() -> Self {
         Self {
             segment_capacity: 16 * 1024 * 1024,
+            sync_method: WalSyncMethod::Fdatasync,
         }
     }
 }
@@ -46,15 +63,72 @@ impl WalConfig {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.segment_capacity < SEGMENT_PAGE_SIZE
+            || !self.segment_capacity.is_multiple_of(SEGMENT_PAGE_SIZE)
+        {
+            return Err(Error::InvalidConfig(format!(
+                "WAL segment capacity {} is not a positive multiple of {}",
+                self.segment_capacity, SEGMENT_PAGE_SIZE
+            )));
+        }
+
+        Ok(())
+    }
 }
 
+// format version of the serialized log records. bump it together with the format version of the
+// control file when a change to `LogRecord` makes the old records unreadable, so that data
+// directories with old records are rejected before the log is read
+const LOG_RECORD_VERSION: u8 = 1;
+
+// number of records replayed between two checks for a shutdown request
+const REPLAY_INTERRUPT_INTERVAL: usize = 64;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct FullLogRecord<'a> {
     xid: XID,
+    // start position of the previous record
+    prev_lsn: LogPointer,
     #[serde(borrow)]
     payload: LogRecord<'a>,
 }
 
+// serialized like `FullLogRecord` without taking the payload
+#[derive(Serialize)]
+struct FullLogRecordRef<'a, 'b> {
+    xid: XID,
+    prev_lsn: LogPointer,
+    payload: &'b LogRecord<'a>,
+}
+
+fn encode_record(xid: XID, prev_lsn: LogPointer, payload: &LogRecord) -> Result<Vec<u8>> {
+    let mut buf = vec![LOG_RECORD_VERSION];
+    bincode::serialize_into(
+        &mut buf,
+        &FullLogRecordRef {
+            xid,
+            prev_lsn,
+            payload,
+        },
+    )?;
+    Ok(buf)
+}
+
+/// Deserialize a log record with its version byte. Records of other versions, e.g. written by a
+/// newer release, are rejected with `Error::InvalidState`.
+fn decode_record(buf: &[u8]) -> Result<FullLogRecord<'_>> {
+    match buf.split_first() {
+        Some((&LOG_RECORD_VERSION, record)) => Ok(bincode::deserialize(record)?),
+        Some((version, _)) => Err(Error::InvalidState(format!(
+            "unsupported log record version {}, expected {}",
+            version, LOG_RECORD_VERSION
+        ))),
+        None => Err(Error::DataCorrupted("empty log record".to_owned())),
+    }
+}
+
 pub struct Wal {
     #[allow(dead_code)]
     dir: File,
@@ -62,6 +136,13 @@ pub struct Wal {
     capacity: usize,
     segment_creator: Mutex<SegmentCreator>,
     open_segment: RwLock<Segment>,
+    // start position of the last record, only accessed with the open segment write-locked
+    prev_lsn: Mutex<LogPointer>,
+    // flushed position of the open segment, waited on by `wait_for_flush`
+    flushed_lsn: Mutex<LogPointer>,
+    flush_cond: Condvar,
+    records_written: Counter,
+    bytes_written: Counter,
 }
 
 impl Wal {
@@ -104,12 +185,13 @@ impl Wal {
             }
         }
 
-        let mut segment_creator = SegmentCreator::new(&path, config.segment_capacity, last_segno);
+        let mut segment_creator = SegmentCreator::new(&path, config, last_segno);
         let segment = if last_segno == 0 {
             segment_creator.next_segment()
         } else {
             segment_creator.open_segment(last_segno)
         }?;
+        let flushed_lsn = segment.flushed_lsn();
 
         Ok(Wal {
             dir,
@@ -117,40 +199,104 @@ impl Wal {
             capacity: config.segment_capacity,
             segment_creator: Mutex::new(segment_creator),
             open_segment: RwLock::new(segment),
+            prev_lsn: Mutex::new(0),
+            flushed_lsn: Mutex::new(flushed_lsn),
+            flush_cond: Condvar::new(),
+            records_written: Default::default(),
+            bytes_written: Default::default(),
         })
     }
 
     pub fn append(&self, xid: XID, record: LogRecord) -> Result<(LogPointer, LogPointer)> {
-        let full_record = FullLogRecord {
-            xid,
-            payload: record,
-        };
-        let buf = bincode::serialize(&full_record).unwrap();
-        self.append_raw(&buf)
+        let mut guard = self.open_segment.write().unwrap();
+        let mut prev_lsn = self.prev_lsn.lock().unwrap();
+
+        let buf = encode_record(xid, *prev_lsn, &record)?;
+        let (start_pos, end_pos) = self.append_locked(&mut guard, &buf)?;
+
+        *prev_lsn = start_pos;
+        self.records_written.inc();
+        self.bytes_written.add(buf.len() as u64);
+        Ok((start_pos, end_pos))
+    }
+
+    /// Append the records of a transaction while holding the open segment once. Return the start
+    /// and end positions of each record.
+    pub fn append_batch(
+        &self,
+        xid: XID,
+        records: &[LogRecord],
+    ) -> Result<Vec<(LogPointer, LogPointer)>> {
+        let mut guard = self.open_segment.write().unwrap();
+        let mut prev_lsn = self.prev_lsn.lock().unwrap();
+        let mut positions = Vec::with_capacity(records.len());
+
+        for record in records {
+            let buf = encode_record(xid, *prev_lsn, record)?;
+            let (start_pos, end_pos) = self.append_locked(&mut guard, &buf)?;
+
+            *prev_lsn = start_pos;
+            self.records_written.inc();
+            self.bytes_written.add(buf.len() as u64);
+            positions.push((start_pos, end_pos));
+        }
+
+        Ok(positions)
+    }
+
+    /// Return the number of records and bytes appended since the log was opened.
+    pub fn get_write_stats(&self) -> (u64, u64) {
+        (self.records_written.get(), self.bytes_written.get())
     }
 
+    #[cfg(test)]
     fn append_raw<T>(&self, record: &T) -> Result<(LogPointer, LogPointer)>
     where
         T: Deref<Target = [u8]>,
     {
         let mut guard = self.open_segment.write().unwrap();
+        self.append_locked(&mut guard, record)
+    }
 
-        if !guard.sufficient_capacity(record.len()) {
-            if guard.dirty() {
-                guard.flush_page(true)?;
+    fn append_locked<T>(
+        &self,
+        segment: &mut Segment,
+        record: &T,
+    ) -> Result<(LogPointer, LogPointer)>
+    where
+        T: Deref<Target = [u8]>,
+    {
+        if !segment.sufficient_capacity(record.len()) {
+            // the full pages written while appending are not synced yet
+            if segment.dirty() {
+                segment.flush_page(true)?;
+            } else {
+                segment.sync()?;
             }
 
             let mut creator = self.segment_creator.lock().unwrap();
-            *guard = creator.next_segment()?;
+            *segment = creator.next_segment()?;
         }
 
-        let start_pos = guard.current_lsn();
-        match guard.append(record)? {
-            Some(end_pos) => Ok((start_pos, end_pos)),
+        // full pages are written out while appending
+        let pos = segment.append(record)?;
+        self.update_flushed_lsn(segment);
+
+        match pos {
+            Some(pos) => Ok(pos),
             _ => unreachable!(),
         }
     }
 
+    fn update_flushed_lsn(&self, segment: &Segment) {
+        let mut guard = self.flushed_lsn.lock().unwrap();
+
+        if segment.flushed_lsn() > *guard {
+            *guard = segment.flushed_lsn();
+            self.flush_cond.notify_all();
+        }
+    }
+
     pub fn flush(&self, lsn: Option<LogPointer>) -> Result<()> {
         let mut guard = self.open_segment.write().unwrap();
 
@@ -159,7 +305,9 @@ impl Wal {
                 return Ok(());
             }
         }
-        guard.flush_page(false)
+        guard.flush_page(false)?;
+        self.update_flushed_lsn(&guard);
+        Ok(())
     }
 
     pub fn current_lsn(&self) -> LogPointer {
@@ -168,6 +316,30 @@ impl Wal {
         guard.current_lsn()
     }
 
+    pub fn flushed_lsn(&self) -> LogPointer {
+        *self.flushed_lsn.lock().unwrap()
+    }
+
+    /// Block until the log is flushed up to `lsn` by other writers. `lsn` must not be past the
+    /// end of the log, or the wait might never end.
+    pub fn wait_for_flush(&self, lsn: LogPointer) -> Result<()> {
+        let current_lsn = self.current_lsn();
+        if lsn > current_lsn {
+            return Err(Error::InvalidArgument(format!(
+                "LSN {} is past the end of the log {}",
+                lsn, current_lsn
+            )));
+        }
+
+        let mut guard = self.flushed_lsn.lock().unwrap();
+
+        while *guard < lsn {
+            guard = self.flush_cond.wait(guard).unwrap();
+        }
+
+        Ok(())
+    }
+
     pub fn get_reader(&self, start_pos: LogPointer) -> Result<WalReader> {
         WalReader::open(&self.path, self.capacity, start_pos)
     }
@@ -182,7 +354,7 @@ impl Wal {
                 None => Err(Error::DataCorrupted(
                     "cannot load the checkpoint log record".to_owned(),
                 )),
-                Some((_, recbuf)) => match bincode::deserialize::<FullLogRecord>(&recbuf) {
+                Some((_, _, recbuf)) => match decode_record(&recbuf) {
                     Ok(FullLogRecord {
                         payload: LogRecord::Wal(WalLogRecord::Checkpoint(ckpt_log)),
                         ..
@@ -190,9 +362,7 @@ impl Wal {
                     Ok(_) => Err(Error::DataCorrupted(
                         "last checkpoint pos points to non checkpoint record".to_owned(),
                     )),
-                    _ => Err(Error::DataCorrupted(
-                        "cannot deserialize the checkpoint log record".to_owned(),
-                    )),
+                    Err(e) => Err(e),
                 },
             }
         } else {
@@ -200,26 +370,122 @@ impl Wal {
         }
     }
 
+    /// Replay the log records from the redo position. The replay stops at the first record that
+    /// cannot be read or does not link to the previous record, which is the end of valid log. The
+    /// log is reset to end there so that new records overwrite the invalid ones. It is an error if
+    /// valid records are found after the end of valid log.
     pub fn replay_logs(&self, db: &DB, redo_pos: LogPointer) -> Result<()> {
         let reader = self.get_reader(redo_pos)?;
-        for rec in reader.iter() {
+        let mut last_record = None;
+        let mut valid_end = redo_pos;
+        let mut truncated = false;
+
+        for (i, rec) in reader.iter().enumerate() {
             // this is the main redo apply loop
-            let (lsn, recbuf) = rec?;
-            let (xid, redo) = match bincode::deserialize::<FullLogRecord>(&recbuf) {
-                Ok(FullLogRecord { xid, payload }) => (xid, payload),
+
+            // stop between two records. the database stays in crash recovery and the next
+            // startup redoes from the last checkpoint again
+            if i % REPLAY_INTERRUPT_INTERVAL == 0 {
+                db.check_shutdown()?;
+            }
+
+            let (start_pos, lsn, recbuf) = match rec {
+                Ok(rec) => rec,
+                _ => {
+                    truncated = true;
+                    break;
+                }
+            };
+            let (xid, prev_lsn, redo) = match decode_record(&recbuf) {
+                Ok(FullLogRecord {
+                    xid,
+                    prev_lsn,
+                    payload,
+                }) => (xid, prev_lsn, payload),
+                // a valid record that cannot be read is not the end of the log
+                Err(e @ Error::InvalidState(_)) => return Err(e),
                 _ => {
-                    return Err(Error::DataCorrupted(
-                        "invalid log record during recovery".to_owned(),
-                    ))
+                    truncated = true;
+                    break;
                 }
             };
 
+            if let Some(last_record) = last_record {
+                if prev_lsn != last_record {
+                    truncated = true;
+                    break;
+                }
+            }
+
             db.get_transaction_manager().advance_next_xid_past(xid);
             redo.apply(db, xid, lsn)?;
+
+            last_record = Some(start_pos);
+            valid_end = lsn;
+        }
+
+        if truncated {
+            // the replay stops at an invalid record, which is fine if it is a torn record at the
+            // tail of the log. if any record after it still links back into the log, then the log
+            // is corrupted in the middle
+            let mut pos = valid_end;
+            while let Some((start_pos, _, recbuf)) = reader.find_next_record(pos)? {
+                if let Ok(FullLogRecord { prev_lsn, .. }) = decode_record(&recbuf) {
+                    if prev_lsn >= valid_end {
+                        return Err(Error::DataCorrupted(format!(
+                            "invalid log record before the end of log at {}",
+                            start_pos
+                        )));
+                    }
+                }
+
+                pos = start_pos;
+            }
+        }
+
+        drop(reader);
+
+        if truncated {
+            self.reset_end(valid_end)?;
+        }
+
+        if let Some(last_record) = last_record {
+            let _guard = self.open_segment.write().unwrap();
+            *self.prev_lsn.lock().unwrap() = last_record;
         }
 
         Ok(())
     }
+
+    /// Discard everything in the log after the position.
+    fn reset_end(&self, end_pos: LogPointer) -> Result<()> {
+        let mut guard = self.open_segment.write().unwrap();
+        let mut creator = self.segment_creator.lock().unwrap();
+
+        let segno = (end_pos as usize / self.capacity + 1) as u32;
+        let segment_offset = end_pos as usize % self.capacity;
+
+        for later_segno in segno + 1..=creator.last_segno {
+            let path = creator.segno_to_path(later_segno);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let path = creator.segno_to_path(segno);
+        creator.last_segno = segno;
+        *guard = if path.exists() {
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(segment_offset as u64)?;
+            file.sync_all()?;
+
+            creator.open_segment(segno)?
+        } else {
+            creator.create_segment(segno)?
+        };
+
+        Ok(())
+    }
 }
 
 fn filename_to_segno(filename: &str) -> Result<u32> {
@@ -235,28 +501,43 @@ struct SegmentCreator {
     path: PathBuf,
     last_segno: u32,
     capacity: usize,
+    sync_method: WalSyncMethod,
 }
 
 impl SegmentCreator {
-    fn new<P: AsRef<Path>>(path: P, capacity: usize, last_segno: u32) -> Self {
+    fn new<P: AsRef<Path>>(path: P, config: &WalConfig, last_segno: u32) -> Self {
         Self {
             path: path.as_ref().to_path_buf(),
             last_segno,
-            capacity,
+            capacity: config.segment_capacity,
+            sync_method: config.sync_method,
         }
     }
 
     fn open_segment(&self, segno: u32) -> Result<Segment> {
-        Segment::open(segno, self.segno_to_path(segno), self.capacity)
+        Segment::open(
+            segno,
+            self.segno_to_path(segno),
+            self.capacity,
+            self.sync_method,
+        )
+    }
+
+    fn create_segment(&self, segno: u32) -> Result<Segment> {
+        let segment = Segment::create(
+            segno,
+            self.segno_to_path(segno),
+            self.capacity,
+            self.sync_method,
+        )?;
+        // make the new segment file durable
+        storage::sync_dir(&self.path)?;
+        Ok(segment)
     }
 
     fn next_segment(&mut self) -> Result<Segment> {
         self.last_segno += 1;
-        Segment::create(
-            self.last_segno,
-            self.segno_to_path(self.last_segno),
-            self.capacity,
-        )
+        self.create_segment(self.last_segno)
     }
     fn segno_to_path(&self, segno: u32) -> PathBuf {
         let mut path = self.path.clone();
@@ -269,6 +550,8 @@ impl SegmentCreator {
 mod tests {
     use super::*;
 
+    use std::io::{Seek, SeekFrom, Write};
+
     fn create_wal() -> (Wal, tempfile::TempDir) {
         let db_dir = tempfile::tempdir().unwrap();
         let config = WalConfig::new();
@@ -299,6 +582,68 @@ mod tests {
         assert!(db_dir.close().is_ok());
     }
 
+    #[test]
+    fn can_sync_once_per_flush() {
+        let (wal, db_dir) = create_wal();
+        let sync_count = |wal: &Wal| wal.open_segment.read().unwrap().sync_count();
+
+        // full pages are written out while appending but not synced
+        let record: &[u8] = &[42u8; 3000];
+        let mut end_pos = 0;
+        for _ in 0..10 {
+            end_pos = wal.append_raw(&record).unwrap().1;
+        }
+        assert_eq!(sync_count(&wal), 0);
+        assert_eq!(wal.flushed_lsn(), 0);
+
+        wal.flush(None).unwrap();
+        assert_eq!(sync_count(&wal), 1);
+        assert_eq!(wal.flushed_lsn(), end_pos);
+
+        // nothing new to sync
+        wal.flush(None).unwrap();
+        wal.flush(Some(end_pos)).unwrap();
+        assert_eq!(sync_count(&wal), 1);
+
+        let end_pos = wal.append_raw(&record).unwrap().1;
+        wal.flush(Some(end_pos)).unwrap();
+        assert_eq!(sync_count(&wal), 2);
+        assert_eq!(wal.flushed_lsn(), end_pos);
+
+        drop(wal);
+        assert!(db_dir.close().is_ok());
+    }
+
+    #[test]
+    fn can_sync_wal_directory_on_new_segment() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = WalConfig {
+            segment_capacity: 4 * SEGMENT_PAGE_SIZE,
+            ..WalConfig::new()
+        };
+
+        let dir_syncs = storage::dir_sync_count();
+        let wal = Wal::open(db_dir.path(), &config).unwrap();
+        assert_eq!(storage::dir_sync_count(), dir_syncs + 1);
+
+        // fill the first segment
+        let record: &[u8] = &[42u8; 3000];
+        for _ in 0..15 {
+            wal.append_raw(&record).unwrap();
+        }
+        let mut path = db_dir.path().to_path_buf();
+        path.push("00000002");
+        assert!(path.is_file());
+        assert_eq!(storage::dir_sync_count(), dir_syncs + 2);
+
+        // reopening the last segment does not create a file
+        drop(wal);
+        drop(Wal::open(db_dir.path(), &config).unwrap());
+        assert_eq!(storage::dir_sync_count(), dir_syncs + 2);
+
+        assert!(db_dir.close().is_ok());
+    }
+
     #[test]
     fn can_read_wal() {
         let (wal, db_dir) = create_wal();
@@ -313,7 +658,7 @@ mod tests {
         let reader = wal.get_reader(0).unwrap();
         let mut count = 0;
         for rec in reader.iter() {
-            let (_, recbuf) = rec.unwrap();
+            let (_, _, recbuf) = rec.unwrap();
             count += 1;
             assert_eq!(record, &recbuf[..]);
         }
@@ -321,4 +666,426 @@ mod tests {
         assert_eq!(count, 10);
         db_dir.close().unwrap();
     }
+
+    #[test]
+    fn can_resync_from_middle_of_record() {
+        let (wal, db_dir) = create_wal();
+
+        // records larger than a page are split into several chunks
+        let sizes = [100, 3000, 20000, 7, 9000, 500];
+        let mut records = Vec::new();
+        for (i, size) in sizes.iter().enumerate() {
+            let record = (0..*size).map(|j| (i * 31 + j) as u8).collect::<Vec<_>>();
+            let (start, end) = wal.append_raw(&record).unwrap();
+            records.push((start, end, record));
+        }
+        wal.flush(None).unwrap();
+
+        let reader = wal.get_reader(0).unwrap();
+        for (i, (start, end, _)) in records.iter().enumerate() {
+            for pos in [*start, start + 1, start + 3, (start + end) / 2, end - 1] {
+                let next = reader.find_next_record(pos).unwrap();
+                match records.get(i + 1) {
+                    Some((next_start, _, next_record)) => {
+                        let (found_start, _, recbuf) = next.unwrap();
+                        assert_eq!(found_start, *next_start);
+                        assert_eq!(&recbuf, next_record);
+                    }
+                    None => assert!(next.is_none()),
+                }
+            }
+        }
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_chain_deserialization_error() {
+        use std::error::Error as StdError;
+
+        let (wal, db_dir) = create_wal();
+
+        // position 0 is never a valid checkpoint position
+        let record: &[u8] = &[LOG_RECORD_VERSION, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        wal.append_raw(&record).unwrap();
+        let (start_pos, _) = wal.append_raw(&record).unwrap();
+        wal.flush(None).unwrap();
+
+        let err = wal.read_checkpoint_record(start_pos).unwrap_err();
+        assert!(matches!(err, Error::Serialization(_)));
+        assert!(err
+            .source()
+            .and_then(|e| e.downcast_ref::<bincode::ErrorKind>())
+            .is_some());
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_return_serialization_error() {
+        use serde::{ser, Serialize, Serializer};
+
+        struct Unserializable;
+
+        impl Serialize for Unserializable {
+            fn serialize<S: Serializer>(&self, _: S) -> std::result::Result<S::Ok, S::Error> {
+                Err(ser::Error::custom("cannot serialize"))
+            }
+        }
+
+        fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+            Ok(bincode::serialize(value)?)
+        }
+
+        match encode(&Unserializable) {
+            Err(Error::Serialization(e)) => assert!(e.to_string().contains("cannot serialize")),
+            _ => panic!("expected a serialization error"),
+        }
+    }
+
+    #[test]
+    fn can_return_serialization_error_on_append() {
+        use crate::concurrency::TransactionLogRecord;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let (wal, db_dir) = create_wal();
+        let lsn = wal.current_lsn();
+
+        // times before the epoch cannot be serialized
+        let record = TransactionLogRecord::create_transaction_commit_log(
+            UNIX_EPOCH - Duration::from_secs(1),
+            vec![],
+            vec![],
+        );
+        match wal.append(XID::from(1), record) {
+            Err(Error::Serialization(e)) => assert!(e.to_string().contains("UNIX_EPOCH")),
+            _ => panic!("expected a serialization error"),
+        }
+        assert_eq!(wal.current_lsn(), lsn);
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_stop_replay_at_torn_record() {
+        use crate::{concurrency::IsolationLevel, storage::ScanDirection, DBConfig, DB};
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = DBConfig::new().root_path(db_dir.path());
+        let data: &[u8] = &[1u8; 100];
+
+        let count_tuples = |db: &DB| {
+            let heap = db.open_table(0, 0).unwrap().unwrap();
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let mut count = 0;
+            {
+                let mut iter = heap.begin_scan(db, &mut txn).unwrap();
+                while iter.next(db, ScanDirection::Forward).unwrap().is_some() {
+                    count += 1;
+                }
+            }
+            db.commit_transaction(txn).unwrap();
+            count
+        };
+
+        {
+            let db = DB::open(&config).unwrap();
+            let heap = db.create_table(0, 0).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            for _ in 0..10 {
+                heap.insert_tuple(&db, &txn, data).unwrap();
+            }
+            db.commit_transaction(txn).unwrap();
+
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            heap.insert_tuple(&db, &txn, data).unwrap();
+            db.commit_transaction(txn).unwrap();
+        }
+
+        // tear the last record (commit of the second transaction)
+        let mut segment_path = config.get_wal_path();
+        segment_path.push("00000001");
+        let file = OpenOptions::new().write(true).open(&segment_path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        {
+            let db = DB::open(&config).unwrap();
+            assert_eq!(count_tuples(&db), 10);
+
+            let heap = db.open_table(0, 0).unwrap().unwrap();
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            heap.insert_tuple(&db, &txn, data).unwrap();
+            db.commit_transaction(txn).unwrap();
+        }
+
+        {
+            // the records written after recovery continue the chain
+            let db = DB::open(&config).unwrap();
+            assert_eq!(count_tuples(&db), 11);
+        }
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_replay_wal_with_each_sync_method() {
+        use crate::{concurrency::IsolationLevel, storage::ScanDirection, DBConfig, DB};
+
+        for sync_method in [
+            WalSyncMethod::Fsync,
+            WalSyncMethod::Fdatasync,
+            WalSyncMethod::OpenDatasync,
+        ] {
+            let db_dir = tempfile::tempdir().unwrap();
+            let config = DBConfig::new()
+                .root_path(db_dir.path())
+                .wal_sync_method(sync_method);
+
+            {
+                let db = DB::open(&config).unwrap();
+                let heap = db.create_table(0, 0).unwrap();
+
+                // spans several log pages
+                for i in 0..20u8 {
+                    let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+                    heap.insert_tuple(&db, &txn, &[i; 1000]).unwrap();
+                    db.commit_transaction(txn).unwrap();
+                }
+
+                // crash without a checkpoint
+            }
+
+            let db = DB::open(&config).unwrap();
+            let heap = db.open_table(0, 0).unwrap().unwrap();
+            let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            let mut values = Vec::new();
+            {
+                let mut iter = heap.begin_scan(&db, &mut txn).unwrap();
+                while let Some(tuple) = iter.next(&db, ScanDirection::Forward).unwrap() {
+                    values.push(tuple.get_data()[0]);
+                }
+            }
+            db.commit_transaction(txn).unwrap();
+            assert_eq!(values, (0..20u8).collect::<Vec<_>>(), "{:?}", sync_method);
+
+            drop(db);
+            db_dir.close().unwrap();
+        }
+    }
+
+    fn write_two_transactions(config: &crate::DBConfig) {
+        use crate::{concurrency::IsolationLevel, DB};
+
+        let db = DB::open(config).unwrap();
+        let heap = db.create_table(0, 0).unwrap();
+        let data: &[u8] = &[1u8; 100];
+
+        for _ in 0..2 {
+            let txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+            heap.insert_tuple(&db, &txn, data).unwrap();
+            db.commit_transaction(txn).unwrap();
+        }
+    }
+
+    #[test]
+    fn can_interrupt_replay() {
+        use crate::{
+            concurrency::{CancelToken, IsolationLevel},
+            DB,
+        };
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let shutdown_token = CancelToken::new();
+        let config = crate::DBConfig::new()
+            .root_path(db_dir.path())
+            .shutdown_token(shutdown_token.clone());
+        write_two_transactions(&config);
+
+        shutdown_token.cancel();
+        assert!(matches!(DB::open(&config), Err(Error::Interrupted)));
+        let mut ckptmgr = CheckpointManager::open(config.get_master_record_path()).unwrap();
+        let db_state = ckptmgr.read_master_record().unwrap().db_state();
+        assert_eq!(db_state, DBState::InCrashRecovery);
+
+        // the replay starts over on the next startup
+        shutdown_token.reset();
+        let db = DB::open(&config).unwrap();
+        let heap = db.open_table(0, 0).unwrap().unwrap();
+        let mut txn = db.start_transaction(IsolationLevel::ReadCommitted).unwrap();
+        assert_eq!(heap.count_visible(&db, &mut txn).unwrap(), 2);
+
+        // scans are interrupted too
+        db.request_shutdown();
+        assert!(matches!(
+            heap.count_visible(&db, &mut txn),
+            Err(Error::Interrupted)
+        ));
+        drop(txn);
+
+        drop(db);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_recover_from_garbage_tail() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new().root_path(db_dir.path());
+        write_two_transactions(&config);
+
+        let mut segment_path = config.get_wal_path();
+        segment_path.push("00000001");
+        let mut file = OpenOptions::new().write(true).open(&segment_path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.seek(SeekFrom::Start(len - 5)).unwrap();
+        file.write_all(&[0xffu8; 5]).unwrap();
+        drop(file);
+
+        assert!(crate::DB::open(&config).is_ok());
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn cannot_recover_from_interior_corruption() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new().root_path(db_dir.path());
+        write_two_transactions(&config);
+
+        // corrupt the first record
+        let mut segment_path = config.get_wal_path();
+        segment_path.push("00000001");
+        let mut file = OpenOptions::new().write(true).open(&segment_path).unwrap();
+        file.seek(SeekFrom::Start(10)).unwrap();
+        file.write_all(&[0xffu8; 4]).unwrap();
+        drop(file);
+
+        match crate::DB::open(&config) {
+            Err(Error::DataCorrupted(_)) => {}
+            _ => panic!("interior corruption is not detected"),
+        }
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn cannot_replay_unknown_record_version() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let config = crate::DBConfig::new().root_path(db_dir.path());
+        write_two_transactions(&config);
+
+        // records of the current version replay
+        drop(crate::DB::open(&config).unwrap());
+
+        {
+            let wal = Wal::open(config.get_wal_path(), &config.wal_config).unwrap();
+            let record = crate::concurrency::TransactionLogRecord::create_transaction_commit_log(
+                std::time::SystemTime::now(),
+                Vec::new(),
+                Vec::new(),
+            );
+            let mut buf = encode_record(XID::default(), 0, &record).unwrap();
+            buf[0] = LOG_RECORD_VERSION + 1;
+            wal.append_raw(&buf).unwrap();
+            wal.flush(None).unwrap();
+        }
+
+        match crate::DB::open(&config) {
+            Err(Error::InvalidState(e)) => assert!(e.contains("unsupported log record version")),
+            _ => panic!("record of unknown version is replayed"),
+        }
+
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_append_batch() {
+        use crate::concurrency::TransactionLogRecord;
+
+        let (wal, db_dir) = create_wal();
+        let xid = XID::from(42u32);
+
+        let (first_pos, _) = wal
+            .append(
+                xid,
+                TransactionLogRecord::create_transaction_zero_page_log(0),
+            )
+            .unwrap();
+        // large enough to cross segment pages
+        let records = (1..=20)
+            .map(|i| {
+                TransactionLogRecord::create_transaction_commit_log(
+                    std::time::SystemTime::UNIX_EPOCH,
+                    (0..i * 100)
+                        .map(|rel_id| crate::storage::TruncatedRelation {
+                            file_ref: crate::storage::RelFileRef { db: 0, rel_id },
+                            old_file_id: rel_id,
+                            new_file_id: rel_id + 1,
+                        })
+                        .collect(),
+                    Vec::new(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let positions = wal.append_batch(xid, &records).unwrap();
+        assert_eq!(positions.len(), records.len());
+        assert_eq!(wal.get_write_stats().0, 21);
+        wal.flush(None).unwrap();
+
+        let reader = wal.get_reader(0).unwrap();
+        let mut prev_lsn = first_pos;
+        for ((start_pos, end_pos), record) in positions.iter().zip(records.iter()) {
+            let (_, lsn, recbuf) = reader.read_record(*start_pos).unwrap().unwrap();
+            assert_eq!(lsn, *end_pos);
+
+            let full_record = decode_record(&recbuf).unwrap();
+            assert_eq!(full_record.xid, xid);
+            assert_eq!(full_record.prev_lsn, prev_lsn);
+            assert_eq!(
+                format!("{:?}", full_record.payload),
+                format!("{:?}", record)
+            );
+            prev_lsn = *start_pos;
+        }
+
+        drop(reader);
+        db_dir.close().unwrap();
+    }
+
+    #[test]
+    fn can_wait_for_flush() {
+        let (db, db_dir) = crate::test_util::get_temp_db();
+        let heap = db.create_table(0, 0).unwrap();
+
+        let txn = db
+            .start_transaction(crate::concurrency::IsolationLevel::ReadCommitted)
+            .unwrap();
+        heap.insert_tuple(&db, &txn, &[1u8; 100]).unwrap();
+        let lsn = db.get_wal().current_lsn();
+        assert!(db.get_wal().flushed_lsn() < lsn);
+
+        assert!(matches!(
+            db.wait_for_flush(lsn + 1),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                db.wait_for_flush(lsn).unwrap();
+                tx.send(db.get_wal().flushed_lsn()).unwrap();
+            });
+
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(rx.try_recv().is_err());
+
+            // the commit flushes past the insert record
+            db.commit_transaction(txn).unwrap();
+            assert!(rx.recv().unwrap() >= lsn);
+        });
+
+        db_dir.close().unwrap();
+    }
 }
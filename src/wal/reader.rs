@@ -52,7 +52,7 @@ impl WalReader {
             ));
         }
 
-        segments.sort_by(|(a, _), (b, _)| a.cmp(b));
+        segments.sort_by_key(|(a, _)| *a);
 
         let reader = Self {
             start_segno,
@@ -64,7 +64,7 @@ impl WalReader {
     }
 
     pub fn iter(&self) -> WalReaderIterator<'_> {
-        (&self).into_iter()
+        self.into_iter()
     }
 
     fn pos_to_segment(&self, pos: LogPointer) -> Option<&SegmentView> {
@@ -83,14 +83,24 @@ impl WalReader {
         Some(&self.segments[index])
     }
 
-    pub fn read_record(&self, pos: LogPointer) -> Result<Option<(LogPointer, Vec<u8>)>> {
+    /// Read the first record at or after the position. Return the start and end position of the
+    /// record and the record data.
+    pub fn read_record(
+        &self,
+        pos: LogPointer,
+    ) -> Result<Option<(LogPointer, LogPointer, Vec<u8>)>> {
         match self.pos_to_segment(pos) {
             None => Ok(None),
             Some(segment) => {
                 let segment_offset = pos as usize % self.capacity;
+                let segment_start = pos - segment_offset as LogPointer;
 
                 match segment.read_record(segment_offset)? {
-                    Some((recbuf, len)) => Ok(Some((pos + len as LogPointer, recbuf))),
+                    Some((recbuf, start, end)) => Ok(Some((
+                        segment_start + start as LogPointer,
+                        segment_start + end as LogPointer,
+                        recbuf,
+                    ))),
                     None => {
                         if segment_offset == 0 {
                             Ok(None)
@@ -103,10 +113,44 @@ impl WalReader {
             }
         }
     }
+
+    /// Find the first record that starts after the position and can be read. The position does
+    /// not need to be at a record boundary, so this can resync a read that starts in the middle of
+    /// a record. A candidate start is only accepted if its chunks pass the checksum.
+    pub fn find_next_record(
+        &self,
+        pos: LogPointer,
+    ) -> Result<Option<(LogPointer, LogPointer, Vec<u8>)>> {
+        let mut pos = pos + 1;
+
+        while let Some(segment) = self.pos_to_segment(pos) {
+            let segment_offset = pos as usize % self.capacity;
+            let segment_start = pos - segment_offset as LogPointer;
+
+            match segment.find_record_start(segment_offset) {
+                Some(start) => {
+                    let start = segment_start + start as LogPointer;
+
+                    if let Ok(Some(record)) = self.read_record(start) {
+                        if record.0 == start {
+                            return Ok(Some(record));
+                        }
+                    }
+
+                    pos = start + 1;
+                }
+                None => {
+                    pos = segment_start + self.capacity as LogPointer;
+                }
+            }
+        }
+
+        Ok(None)
+    }
 }
 
 impl<'a> IntoIterator for &'a WalReader {
-    type Item = Result<(LogPointer, Vec<u8>)>; // we could never return a log record unless we use streaming iterators
+    type Item = Result<(LogPointer, LogPointer, Vec<u8>)>; // we could never return a log record unless we use streaming iterators
     type IntoIter = WalReaderIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -123,15 +167,15 @@ pub struct WalReaderIterator<'a> {
 }
 
 impl<'a> Iterator for WalReaderIterator<'a> {
-    type Item = Result<(LogPointer, Vec<u8>)>;
+    type Item = Result<(LogPointer, LogPointer, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.reader.read_record(self.next_pos) {
             Err(e) => Some(Err(e)),
             Ok(None) => None,
-            Ok(Some((new_pos, recbuf))) => {
+            Ok(Some((start_pos, new_pos, recbuf))) => {
                 self.next_pos = new_pos;
-                Some(Ok((new_pos, recbuf)))
+                Some(Ok((start_pos, new_pos, recbuf)))
             }
         }
     }
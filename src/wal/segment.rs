@@ -2,6 +2,7 @@ use std::{
     fs::{File, OpenOptions},
     io::{prelude::*, SeekFrom},
     ops::Deref,
+    os::unix::fs::OpenOptionsExt,
     path::Path,
 };
 
@@ -9,10 +10,10 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc::crc32;
 use memmap::Mmap;
 
-use super::LogPointer;
+use super::{LogPointer, WalSyncMethod};
 use crate::{Error, Result};
 
-const SEGMENT_PAGE_SIZE: usize = 0x2000;
+pub(super) const SEGMENT_PAGE_SIZE: usize = 0x2000;
 const RECORD_HEADER_SIZE: usize = 7;
 
 #[derive(Clone, Copy, Debug)]
@@ -46,6 +47,19 @@ pub struct Segment {
     page_flushed: usize,
     page_start: usize,
     capacity: usize,
+    sync_method: WalSyncMethod,
+    // end of the part of the segment forced to disk, only advanced by `sync`
+    synced: usize,
+    #[cfg(test)]
+    sync_count: usize,
+}
+
+fn open_options(sync_method: WalSyncMethod) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    if sync_method == WalSyncMethod::OpenDatasync {
+        options.custom_flags(libc::O_DSYNC);
+    }
+    options
 }
 
 fn check_capacity(capacity: usize) -> Result<()> {
@@ -59,13 +73,19 @@ fn check_capacity(capacity: usize) -> Result<()> {
 }
 
 impl Segment {
-    pub fn create<P: AsRef<Path>>(segno: u32, path: P, capacity: usize) -> Result<Self> {
+    pub fn create<P: AsRef<Path>>(
+        segno: u32,
+        path: P,
+        capacity: usize,
+        sync_method: WalSyncMethod,
+    ) -> Result<Self> {
         check_capacity(capacity)?;
 
-        let file = OpenOptions::new()
+        let file = open_options(sync_method)
             .read(false)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)?;
 
         let segment = Segment {
@@ -76,15 +96,24 @@ impl Segment {
             page_flushed: 0,
             page_start: 0,
             capacity,
+            sync_method,
+            synced: 0,
+            #[cfg(test)]
+            sync_count: 0,
         };
 
         Ok(segment)
     }
 
-    pub fn open<P: AsRef<Path>>(segno: u32, path: P, capacity: usize) -> Result<Self> {
+    pub fn open<P: AsRef<Path>>(
+        segno: u32,
+        path: P,
+        capacity: usize,
+        sync_method: WalSyncMethod,
+    ) -> Result<Self> {
         check_capacity(capacity)?;
 
-        let mut file = OpenOptions::new()
+        let mut file = open_options(sync_method)
             .read(false)
             .write(true)
             .create(false)
@@ -103,7 +132,7 @@ impl Segment {
         let mut page_start = file_size;
 
         file.seek(SeekFrom::End(0))?;
-        if file_size % SEGMENT_PAGE_SIZE != 0 {
+        if !file_size.is_multiple_of(SEGMENT_PAGE_SIZE) {
             let padding = SEGMENT_PAGE_SIZE - (file_size % SEGMENT_PAGE_SIZE);
             let zero_bytes = vec![0u8; padding];
             file.write_all(&zero_bytes[..])?;
@@ -121,12 +150,17 @@ impl Segment {
             page_flushed: 0,
             page_start,
             capacity,
+            sync_method,
+            synced: page_start,
+            #[cfg(test)]
+            sync_count: 0,
         };
 
         Ok(segment)
     }
 
-    pub fn append<T>(&mut self, record: &T) -> Result<Option<LogPointer>>
+    /// Append a record to the segment and return the start and end position of the record.
+    pub fn append<T>(&mut self, record: &T) -> Result<Option<(LogPointer, LogPointer)>>
     where
         T: Deref<Target = [u8]>,
     {
@@ -138,10 +172,11 @@ impl Segment {
         }
 
         let mut record_type = RecordHeaderType::None;
+        let mut start_pos = None;
 
         while length > 0 {
             if SEGMENT_PAGE_SIZE - self.page_allocated <= RECORD_HEADER_SIZE {
-                self.flush_page(true)?;
+                self.write_page(true)?;
             }
 
             let chunk_size = std::cmp::min(
@@ -168,6 +203,10 @@ impl Segment {
                 _ => RecordHeaderType::None,
             };
 
+            if start_pos.is_none() {
+                start_pos = Some(self.current_lsn());
+            }
+
             let chunk = &record[offset..offset + chunk_size];
             let record_start = self.page_allocated;
             // record type
@@ -188,10 +227,48 @@ impl Segment {
             offset += chunk_size;
         }
 
-        Ok(Some(self.current_lsn()))
+        let end_pos = self.current_lsn();
+        Ok(Some((start_pos.unwrap_or(end_pos), end_pos)))
     }
 
+    /// Write out the current page and force the segment to disk. The flushed position only
+    /// advances once the sync completes.
     pub fn flush_page(&mut self, reset: bool) -> Result<()> {
+        self.write_page(reset)?;
+        self.sync()
+    }
+
+    /// Force the written part of the segment to disk with the configured sync method.
+    pub fn sync(&mut self) -> Result<()> {
+        let written = self.page_start + self.page_flushed;
+        if self.synced == written {
+            return Ok(());
+        }
+
+        match self.sync_method {
+            WalSyncMethod::Fsync => self.file.sync_all()?,
+            WalSyncMethod::Fdatasync => self.file.sync_data()?,
+            // the writes are synchronous
+            WalSyncMethod::OpenDatasync => {}
+        }
+        #[cfg(test)]
+        {
+            if self.sync_method != WalSyncMethod::OpenDatasync {
+                self.sync_count += 1;
+            }
+        }
+
+        self.synced = written;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn sync_count(&self) -> usize {
+        self.sync_count
+    }
+
+    // write the allocated part of the current page without forcing it to disk
+    fn write_page(&mut self, reset: bool) -> Result<()> {
         let reset = reset || self.page_allocated + RECORD_HEADER_SIZE >= SEGMENT_PAGE_SIZE;
 
         if reset {
@@ -225,7 +302,7 @@ impl Segment {
     }
 
     pub fn flushed_lsn(&self) -> LogPointer {
-        self.segment_start() + (self.page_start + self.page_flushed) as LogPointer
+        self.segment_start() + self.synced as LogPointer
     }
 
     pub fn sufficient_capacity(&self, record_size: usize) -> bool {
@@ -243,6 +320,7 @@ impl Segment {
 
 pub struct SegmentView {
     mmap: Option<Mmap>,
+    capacity: usize,
 }
 
 impl SegmentView {
@@ -270,12 +348,14 @@ impl SegmentView {
         } else {
             Some(unsafe { Mmap::map(&file)? })
         };
-        let segment = Self { mmap };
+        let segment = Self { mmap, capacity };
 
         Ok(segment)
     }
 
-    pub fn read_record(&self, offset: usize) -> Result<Option<(Vec<u8>, usize)>> {
+    /// Read a record at or after the offset. Return the record and the offsets where the record
+    /// starts and ends.
+    pub fn read_record(&self, offset: usize) -> Result<Option<(Vec<u8>, usize, usize)>> {
         match &self.mmap {
             None => Ok(None),
             Some(mmap) => {
@@ -286,6 +366,7 @@ impl SegmentView {
                 let mut p = offset;
                 let mut buffer = Vec::new();
                 let mut started = false;
+                let mut record_start = offset;
                 loop {
                     if mmap.len() <= p + RECORD_HEADER_SIZE {
                         return Err(Error::DataCorrupted(
@@ -317,13 +398,32 @@ impl SegmentView {
                         _ => {}
                     }
 
-                    started = true;
+                    if !started {
+                        record_start = rec_start;
+                        started = true;
+                    }
 
                     let chunk_length = (unsafe { mmap.get_unchecked(p..p + 2) })
                         .read_u16::<LittleEndian>()
                         .unwrap() as usize;
                     p += 2;
 
+                    // chunks never cross page boundaries
+                    if rec_start % SEGMENT_PAGE_SIZE + RECORD_HEADER_SIZE + chunk_length
+                        > SEGMENT_PAGE_SIZE
+                    {
+                        return Err(Error::DataCorrupted(format!(
+                            "invalid chunk length {} at offset {} in segment",
+                            chunk_length, rec_start
+                        )));
+                    }
+
+                    if buffer.len() + chunk_length > self.capacity {
+                        return Err(Error::DataCorrupted(
+                            "record is larger than the segment".to_owned(),
+                        ));
+                    }
+
                     match mmap.get(rec_start..p + chunk_length + 4) {
                         Some(chunk) => {
                             let (chunk, crc_buf) = chunk.split_at(chunk.len() - 4);
@@ -350,8 +450,98 @@ impl SegmentView {
                     }
                 }
 
-                Ok(Some((buffer, p - offset)))
+                Ok(Some((buffer, record_start, p)))
             }
         }
     }
+
+    /// Find the first offset at or after the given offset where a record starts with a chunk that
+    /// passes the checksum.
+    pub fn find_record_start(&self, offset: usize) -> Option<usize> {
+        let mmap = self.mmap.as_ref()?;
+        let mut p = offset;
+
+        while p + RECORD_HEADER_SIZE <= mmap.len() {
+            let rec_start = p;
+            p += 1;
+
+            match RecordHeaderType::from(mmap[rec_start]) {
+                RecordHeaderType::Full | RecordHeaderType::First => {}
+                _ => continue,
+            }
+
+            let chunk_length = (&mmap[rec_start + 1..rec_start + 3])
+                .read_u16::<LittleEndian>()
+                .unwrap() as usize;
+            let chunk_end = rec_start + 3 + chunk_length;
+
+            if rec_start % SEGMENT_PAGE_SIZE + chunk_length + RECORD_HEADER_SIZE > SEGMENT_PAGE_SIZE
+                || chunk_end + 4 > mmap.len()
+            {
+                continue;
+            }
+
+            let crc_file = (&mmap[chunk_end..chunk_end + 4])
+                .read_u32::<LittleEndian>()
+                .unwrap();
+            if crc32::checksum_ieee(&mmap[rec_start..chunk_end]) == crc_file {
+                return Some(rec_start);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_reject_invalid_chunk_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut path = dir.path().to_path_buf();
+        path.push("segment");
+        let capacity = 4 * SEGMENT_PAGE_SIZE;
+
+        let record = (0..SEGMENT_PAGE_SIZE + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        {
+            let mut segment =
+                Segment::create(1, &path, capacity, WalSyncMethod::Fdatasync).unwrap();
+            segment.append(&record).unwrap().unwrap();
+            segment.flush_page(true).unwrap();
+        }
+
+        let view = SegmentView::open(&path, capacity).unwrap();
+        let (data, start, _) = view.read_record(0).unwrap().unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(data, record);
+        drop(view);
+
+        let corrupt = |offset: u64, chunk_length: u16| {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_u16::<LittleEndian>(chunk_length).unwrap();
+        };
+
+        // the second chunk starts at the beginning of the second page
+        corrupt(SEGMENT_PAGE_SIZE as u64 + 1, 0xffff);
+        let view = SegmentView::open(&path, capacity).unwrap();
+        match view.read_record(0) {
+            Err(Error::DataCorrupted(e)) => assert!(e.starts_with("invalid chunk length")),
+            _ => panic!("chunk length is not checked"),
+        }
+        drop(view);
+
+        corrupt(1, (SEGMENT_PAGE_SIZE - RECORD_HEADER_SIZE + 1) as u16);
+        let view = SegmentView::open(&path, capacity).unwrap();
+        match view.read_record(0) {
+            Err(Error::DataCorrupted(e)) => assert!(e.starts_with("invalid chunk length")),
+            _ => panic!("chunk length is not checked"),
+        }
+
+        dir.close().unwrap();
+    }
 }
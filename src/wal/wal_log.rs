@@ -1,5 +1,6 @@
 use crate::{
     concurrency::XID,
+    storage::{ForkType, RelFileRef},
     wal::{LogPointer, LogRecord},
     Result, DB, OID,
 };
@@ -11,6 +12,8 @@ pub struct CheckpointLog {
     pub redo_pos: LogPointer,
     pub next_oid: OID,
     pub next_xid: XID,
+    /// Transactions running when the checkpoint starts
+    pub active_xids: Vec<XID>,
 }
 
 impl CheckpointLog {
@@ -31,10 +34,24 @@ impl NextOidLog {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TruncateLog {
+    file_ref: RelFileRef,
+    fork: ForkType,
+    nr_pages: usize,
+}
+
+impl TruncateLog {
+    pub fn apply(self, db: &DB, _lsn: LogPointer) -> Result<()> {
+        db.truncate_relation(self.file_ref, self.fork, self.nr_pages)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum WalLogRecord {
     Checkpoint(CheckpointLog),
     NextOid(NextOidLog),
+    Truncate(TruncateLog),
 }
 
 impl WalLogRecord {
@@ -42,6 +59,7 @@ impl WalLogRecord {
         match self {
             WalLogRecord::Checkpoint(checkpoint_log) => checkpoint_log.apply(db, lsn),
             WalLogRecord::NextOid(next_oid_log) => next_oid_log.apply(db, lsn),
+            WalLogRecord::Truncate(truncate_log) => truncate_log.apply(db, lsn),
         }
     }
 
@@ -49,11 +67,13 @@ impl WalLogRecord {
         redo_pos: LogPointer,
         next_oid: OID,
         next_xid: XID,
+        active_xids: Vec<XID>,
     ) -> LogRecord<'a> {
         let checkpoint_record = CheckpointLog {
             redo_pos,
             next_oid,
             next_xid,
+            active_xids,
         };
         LogRecord::create_wal_record(WalLogRecord::Checkpoint(checkpoint_record))
     }
@@ -62,4 +82,17 @@ impl WalLogRecord {
         let next_oid_record = NextOidLog { next_oid };
         LogRecord::create_wal_record(WalLogRecord::NextOid(next_oid_record))
     }
+
+    pub fn create_truncate_log<'a>(
+        file_ref: RelFileRef,
+        fork: ForkType,
+        nr_pages: usize,
+    ) -> LogRecord<'a> {
+        let truncate_record = TruncateLog {
+            file_ref,
+            fork,
+            nr_pages,
+        };
+        LogRecord::create_wal_record(WalLogRecord::Truncate(truncate_record))
+    }
 }